@@ -0,0 +1,47 @@
+//! Parser throughput against serde_json on the nativejson-benchmark corpora.
+//!
+//! The corpora are not checked in. Download them into `benches/data/` first:
+//! https://github.com/miloyip/nativejson-benchmark/tree/master/data
+//! (twitter.json, canada.json, citm_catalog.json). Missing files are skipped.
+
+use std::{fs::read_to_string, path::Path};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use json_parser::{lexer::Lexer, parser::Parser};
+
+const CORPORA: [&str; 3] = ["twitter.json", "canada.json", "citm_catalog.json"];
+
+fn parse(c: &mut Criterion) {
+    let data_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/data");
+
+    for name in CORPORA {
+        let Ok(source) = read_to_string(data_dir.join(name)) else {
+            eprintln!("skipping {}: not found in {}", name, data_dir.display());
+            continue;
+        };
+
+        let mut group = c.benchmark_group(name);
+        group.throughput(Throughput::Bytes(source.len() as u64));
+
+        group.bench_function("json-parser", |b| {
+            b.iter(|| {
+                let mut parser = Parser::new(Lexer::new(black_box(&source)));
+                parser.parse().unwrap()
+            })
+        });
+        group.bench_function("json-parser (owned)", |b| {
+            b.iter(|| {
+                let mut parser = Parser::new(Lexer::new(black_box(&source)));
+                parser.parse().unwrap().into_owned()
+            })
+        });
+        group.bench_function("serde_json", |b| {
+            b.iter(|| serde_json::from_str::<serde_json::Value>(black_box(&source)).unwrap())
+        });
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
@@ -0,0 +1,340 @@
+use std::borrow::Cow;
+
+use crate::{
+    error::ConvertError,
+    json_value::{escape_pointer_segment, JsonValue},
+    lexer::Lexer,
+    parser::Parser,
+    ser,
+};
+
+/// Formats that can be converted to and from `JsonValue`.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+    Csv,
+}
+
+impl Format {
+    /// Guesses the format from a file extension.
+    pub fn from_path(path: &str) -> Option<Format> {
+        let extension = path.rsplit_once('.')?.1.to_ascii_lowercase();
+        match extension.as_str() {
+            "json" | "json5" | "jsonc" => Some(Format::Json),
+            "yaml" | "yml" => Some(Format::Yaml),
+            "toml" => Some(Format::Toml),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Format::Json => "JSON",
+            Format::Yaml => "YAML",
+            Format::Toml => "TOML",
+            Format::Csv => "CSV",
+        }
+    }
+}
+
+/// Reads `source` in the given format into the intermediate `JsonValue` model.
+pub fn parse(source: &str, format: Format, lenient: bool) -> Result<JsonValue<'_>, ConvertError> {
+    match format {
+        Format::Json => {
+            let lexer = if lenient {
+                Lexer::lenient(source)
+            } else {
+                Lexer::new(source)
+            };
+            Ok(Parser::new(lexer).parse()?)
+        }
+        Format::Yaml => {
+            let value: serde_yaml::Value = serde_yaml::from_str(source)?;
+            Ok(ser::to_value(&value)?)
+        }
+        Format::Toml => {
+            let value: toml::Table = toml::from_str(source)?;
+            Ok(from_toml(toml::Value::Table(value)))
+        }
+        Format::Csv => from_csv(source),
+    }
+}
+
+/// Writes a `JsonValue` in the given format.
+pub fn write(value: &JsonValue, format: Format) -> Result<String, ConvertError> {
+    match format {
+        // Other writers end with a newline, keep JSON consistent
+        Format::Json => Ok(format!("{}\n", value.to_json_pretty())),
+        Format::Yaml => Ok(serde_yaml::to_string(value)?),
+        Format::Toml => {
+            if !matches!(value, JsonValue::Object(_)) {
+                return Err(unsupported(format, "", "the root must be an object"));
+            }
+            reject_nulls(value, format, "")?;
+            Ok(toml::to_string_pretty(value)?)
+        }
+        Format::Csv => to_csv(value),
+    }
+}
+
+fn unsupported(format: Format, path: &str, reason: &str) -> ConvertError {
+    ConvertError::Unsupported {
+        format: format.name(),
+        path: if path.is_empty() { "/" } else { path }.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+fn reject_nulls(value: &JsonValue, format: Format, path: &str) -> Result<(), ConvertError> {
+    match value {
+        JsonValue::Null => Err(unsupported(format, path, "null has no equivalent")),
+        JsonValue::Object(entries) => entries.iter().try_for_each(|(k, v)| {
+            reject_nulls(
+                v,
+                format,
+                &format!("{}/{}", path, escape_pointer_segment(k)),
+            )
+        }),
+        JsonValue::Array(elements) => elements
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, v)| reject_nulls(v, format, &format!("{}/{}", path, i))),
+        _ => Ok(()),
+    }
+}
+
+fn from_toml(value: toml::Value) -> JsonValue<'static> {
+    match value {
+        toml::Value::String(s) => JsonValue::String(Cow::Owned(s)),
+        toml::Value::Integer(i) => JsonValue::Number(i as f64),
+        toml::Value::Float(f) => JsonValue::Number(f),
+        toml::Value::Boolean(b) => JsonValue::Boolean(b),
+        // JSON has no date type, so datetimes keep their RFC 3339 text
+        toml::Value::Datetime(d) => JsonValue::String(Cow::Owned(d.to_string())),
+        toml::Value::Array(elements) => {
+            JsonValue::Array(elements.into_iter().map(from_toml).collect())
+        }
+        toml::Value::Table(table) => JsonValue::Object(
+            table
+                .into_iter()
+                .map(|(k, v)| (Cow::Owned(k), from_toml(v)))
+                .collect(),
+        ),
+    }
+}
+
+/// Each row becomes an object keyed by the header line. Cells are typed the way a
+/// human would read them: numbers, booleans, empty cells as null, anything else a string.
+fn from_csv(source: &str) -> Result<JsonValue<'static>, ConvertError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .from_reader(source.as_bytes());
+    let headers = reader.headers()?.clone();
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let row = headers
+            .iter()
+            .zip(record.iter())
+            .map(|(header, cell)| (Cow::Owned(header.to_string()), csv_cell(cell)))
+            .collect();
+        rows.push(JsonValue::Object(row));
+    }
+    Ok(JsonValue::Array(rows))
+}
+
+fn csv_cell(cell: &str) -> JsonValue<'static> {
+    match cell {
+        "" => JsonValue::Null,
+        "true" => JsonValue::Boolean(true),
+        "false" => JsonValue::Boolean(false),
+        _ => match cell.parse::<f64>() {
+            Ok(n) if n.is_finite() => JsonValue::Number(n),
+            _ => JsonValue::String(Cow::Owned(cell.to_string())),
+        },
+    }
+}
+
+/// Only arrays of flat objects fit in a table; columns are the union of all keys,
+/// in the order they are first seen.
+fn to_csv(value: &JsonValue) -> Result<String, ConvertError> {
+    let JsonValue::Array(rows) = value else {
+        return Err(unsupported(
+            Format::Csv,
+            "",
+            "the root must be an array of objects",
+        ));
+    };
+
+    let mut headers: Vec<&str> = Vec::new();
+    for (index, row) in rows.iter().enumerate() {
+        let JsonValue::Object(entries) = row else {
+            return Err(unsupported(
+                Format::Csv,
+                &format!("/{}", index),
+                "rows must be objects",
+            ));
+        };
+        for (key, cell) in entries {
+            if matches!(cell, JsonValue::Object(_) | JsonValue::Array(_)) {
+                let path = format!("/{}/{}", index, escape_pointer_segment(key));
+                return Err(unsupported(
+                    Format::Csv,
+                    &path,
+                    "nested values can't be a cell",
+                ));
+            }
+            if !headers.contains(&key.as_ref()) {
+                headers.push(key);
+            }
+        }
+    }
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(&headers)?;
+    for row in rows {
+        let record: Vec<String> = headers
+            .iter()
+            .map(|header| match row.get(header) {
+                Some(JsonValue::String(s)) => s.to_string(),
+                Some(JsonValue::Null) | None => String::new(),
+                Some(other) => other.to_json(),
+            })
+            .collect();
+        writer.write_record(&record)?;
+    }
+
+    let bytes = writer
+        .into_inner()
+        .map_err(|err| ConvertError::Csv(err.into_error().into()))?;
+    Ok(String::from_utf8(bytes).expect("CSV writer only receives UTF-8 input"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(source: &str, from: Format, to: Format) -> Result<String, ConvertError> {
+        write(&parse(source, from, false)?, to)
+    }
+
+    #[test]
+    fn guesses_formats_from_extensions() {
+        assert_eq!(Format::from_path("a/b.JSONC"), Some(Format::Json));
+        assert_eq!(Format::from_path("x.yml"), Some(Format::Yaml));
+        assert_eq!(Format::from_path("x.toml"), Some(Format::Toml));
+        assert_eq!(Format::from_path("x.csv"), Some(Format::Csv));
+        assert_eq!(Format::from_path("Makefile"), None);
+    }
+
+    #[test]
+    fn converts_json_to_yaml_and_back() {
+        let yaml = convert(
+            r#"{"name": "x", "count": 3, "ratio": 0.5, "tags": ["a"], "none": null}"#,
+            Format::Json,
+            Format::Yaml,
+        )
+        .unwrap();
+        assert_eq!(
+            yaml,
+            "name: x\ncount: 3\nratio: 0.5\ntags:\n- a\nnone: null\n"
+        );
+        assert_eq!(
+            parse(&yaml, Format::Yaml, false).unwrap().to_json(),
+            r#"{"name": "x", "count": 3, "ratio": 0.5, "tags": ["a"], "none": null}"#
+        );
+    }
+
+    #[test]
+    fn converts_toml_keeping_integers_and_datetimes() {
+        let json = parse(
+            "title = \"t\"\nwhen = 1979-05-27T07:32:00Z\n[server]\nport = 80\n",
+            Format::Toml,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            json.to_json(),
+            r#"{"server": {"port": 80}, "title": "t", "when": "1979-05-27T07:32:00Z"}"#
+        );
+        assert_eq!(
+            write(&json, Format::Toml).unwrap(),
+            "title = \"t\"\nwhen = \"1979-05-27T07:32:00Z\"\n\n[server]\nport = 80\n"
+        );
+    }
+
+    #[test]
+    fn rejects_what_toml_cannot_represent() {
+        let err = convert("[1]", Format::Json, Format::Toml).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot represent / in TOML: the root must be an object"
+        );
+        let err = convert(r#"{"a": {"b/c": null}}"#, Format::Json, Format::Toml).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot represent /a/b~1c in TOML: null has no equivalent"
+        );
+    }
+
+    #[test]
+    fn types_csv_cells() {
+        assert_eq!(
+            parse(
+                "name,age,admin,note\nann,31,true,\nbob,x,false,hi\n",
+                Format::Csv,
+                false
+            )
+            .unwrap()
+            .to_json(),
+            r#"[{"name": "ann", "age": 31, "admin": true, "note": null}, {"name": "bob", "age": "x", "admin": false, "note": "hi"}]"#
+        );
+    }
+
+    #[test]
+    fn writes_csv_with_the_union_of_all_columns() {
+        assert_eq!(
+            convert(
+                r#"[{"a": 1, "b": "x,y"}, {"c": true, "a": null}]"#,
+                Format::Json,
+                Format::Csv
+            )
+            .unwrap(),
+            "a,b,c\n1,\"x,y\",\n,,true\n"
+        );
+    }
+
+    #[test]
+    fn rejects_what_csv_cannot_represent() {
+        let err = convert(r#"{"a": 1}"#, Format::Json, Format::Csv).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot represent / in CSV: the root must be an array of objects"
+        );
+        let err = convert("[1]", Format::Json, Format::Csv).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot represent /0 in CSV: rows must be objects"
+        );
+        let err = convert(r#"[{"a": [1]}]"#, Format::Json, Format::Csv).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot represent /0/a in CSV: nested values can't be a cell"
+        );
+    }
+
+    #[test]
+    fn parses_lenient_json() {
+        assert_eq!(
+            parse("{a: 1, // note\n}", Format::Json, true)
+                .unwrap()
+                .to_json(),
+            r#"{"a": 1}"#
+        );
+        assert!(parse("{a: 1}", Format::Json, false).is_err());
+    }
+}
@@ -0,0 +1,641 @@
+use std::borrow::Cow;
+
+use serde::de::{self, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+
+use crate::{
+    error::SerdeError,
+    json_value::JsonValue,
+    lexer::{Lexer, Token},
+};
+
+/// Deserializes a `T` straight from JSON text, without building a `JsonValue` first.
+/// Strings without escape sequences can be borrowed from `source`.
+pub fn from_str<'de, T: Deserialize<'de>>(source: &'de str) -> Result<T, SerdeError> {
+    let mut deserializer = Deserializer::new(Lexer::new(source));
+    let value = T::deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(value)
+}
+
+/// Converts a `JsonValue` into any `T: Deserialize`.
+pub fn from_value<'de, T: Deserialize<'de>>(value: JsonValue<'de>) -> Result<T, SerdeError> {
+    T::deserialize(value)
+}
+
+/// Streaming deserializer that pulls tokens from a `Lexer` on demand.
+pub struct Deserializer<'de> {
+    lexer: Lexer<'de>,
+    peeked: Option<Token<'de>>,
+}
+
+impl<'de> Deserializer<'de> {
+    pub fn new(lexer: Lexer<'de>) -> Self {
+        Deserializer {
+            lexer,
+            peeked: None,
+        }
+    }
+
+    /// Checks that nothing but whitespace follows the deserialized value.
+    pub fn end(&mut self) -> Result<(), SerdeError> {
+        match self.next()? {
+            Token::EOF => Ok(()),
+            _ => Err(SerdeError::TrailingCharacters(self.lexer.position())),
+        }
+    }
+
+    fn peek(&mut self) -> Result<&Token<'de>, SerdeError> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.lexer.next_token()?);
+        }
+        Ok(self.peeked.as_ref().unwrap())
+    }
+
+    fn next(&mut self) -> Result<Token<'de>, SerdeError> {
+        match self.peeked.take() {
+            Some(token) => Ok(token),
+            None => Ok(self.lexer.next_token()?),
+        }
+    }
+
+    fn expect(&mut self, expected: Token<'de>) -> Result<(), SerdeError> {
+        let token = self.next()?;
+        if token == expected {
+            Ok(())
+        } else {
+            Err(self.unexpected(&token, &expected.to_string()))
+        }
+    }
+
+    fn unexpected(&self, found: &Token<'de>, expected: &str) -> SerdeError {
+        SerdeError::Custom(format!(
+            "Expected {} but found {} at position {}",
+            expected,
+            found,
+            self.lexer.position()
+        ))
+    }
+}
+
+/// Deserializer for keys and variant names that hands out borrowed strings when it can.
+struct StrDeserializer<'de>(Cow<'de, str>);
+
+impl<'de> de::Deserializer<'de> for StrDeserializer<'de> {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visit_cow(self.0, visitor)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, SerdeError> for StrDeserializer<'de> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+fn visit_cow<'de, V: Visitor<'de>>(s: Cow<'de, str>, visitor: V) -> Result<V::Value, SerdeError> {
+    match s {
+        Cow::Borrowed(s) => visitor.visit_borrowed_str(s),
+        Cow::Owned(s) => visitor.visit_string(s),
+    }
+}
+
+fn visit_number<'de, V: Visitor<'de>>(n: f64, visitor: V) -> Result<V::Value, SerdeError> {
+    // The lexer only produces f64, so integral values are handed to integer visitors.
+    if n.fract() == 0.0 && n >= 0.0 && n < u64::MAX as f64 {
+        visitor.visit_u64(n as u64)
+    } else if n.fract() == 0.0 && n >= i64::MIN as f64 && n < 0.0 {
+        visitor.visit_i64(n as i64)
+    } else {
+        visitor.visit_f64(n)
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.next()? {
+            Token::LBrace => {
+                let value = visitor.visit_map(ObjectAccess {
+                    de: &mut *self,
+                    first: true,
+                })?;
+                self.expect(Token::RBrace)?;
+                Ok(value)
+            }
+            Token::LBracket => {
+                let value = visitor.visit_seq(ArrayAccess {
+                    de: &mut *self,
+                    first: true,
+                })?;
+                self.expect(Token::RBracket)?;
+                Ok(value)
+            }
+            Token::String(s) => visit_cow(s, visitor),
+            Token::Number(n) => visit_number(n, visitor),
+            Token::Boolean(b) => visitor.visit_bool(b),
+            Token::Null => visitor.visit_unit(),
+            token => Err(self.unexpected(&token, "a value")),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        if *self.peek()? == Token::Null {
+            self.next()?;
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        match self.next()? {
+            // Unit variants are written as a bare string: "Variant"
+            Token::String(variant) => {
+                visitor.visit_enum(de::value::CowStrDeserializer::new(variant))
+            }
+            // Everything else is an object with a single key: {"Variant": ...}
+            Token::LBrace => {
+                let value = visitor.visit_enum(VariantAccess { de: &mut *self })?;
+                self.expect(Token::RBrace)?;
+                Ok(value)
+            }
+            token => Err(self.unexpected(&token, "an enum")),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+struct ArrayAccess<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    first: bool,
+}
+
+impl<'de> SeqAccess<'de> for ArrayAccess<'_, 'de> {
+    type Error = SerdeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, SerdeError> {
+        if *self.de.peek()? == Token::RBracket {
+            return Ok(None);
+        }
+        if !self.first {
+            self.de.expect(Token::Comma)?;
+            if self.de.lexer.is_lenient() && *self.de.peek()? == Token::RBracket {
+                return Ok(None);
+            }
+        }
+        self.first = false;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+}
+
+struct ObjectAccess<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    first: bool,
+}
+
+impl<'de> MapAccess<'de> for ObjectAccess<'_, 'de> {
+    type Error = SerdeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, SerdeError> {
+        if *self.de.peek()? == Token::RBrace {
+            return Ok(None);
+        }
+        if !self.first {
+            self.de.expect(Token::Comma)?;
+            if self.de.lexer.is_lenient() && *self.de.peek()? == Token::RBrace {
+                return Ok(None);
+            }
+        }
+        self.first = false;
+        match self.de.next()? {
+            Token::String(key) => seed.deserialize(StrDeserializer(key)).map(Some),
+            Token::Identifier(key) => seed
+                .deserialize(StrDeserializer(Cow::Borrowed(key)))
+                .map(Some),
+            token => Err(self.de.unexpected(&token, "a string key")),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, SerdeError> {
+        self.de.expect(Token::Colon)?;
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+struct VariantAccess<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'de> de::EnumAccess<'de> for VariantAccess<'_, 'de> {
+    type Error = SerdeError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self), SerdeError> {
+        let variant = match self.de.next()? {
+            Token::String(variant) => seed.deserialize(StrDeserializer(variant))?,
+            token => return Err(self.de.unexpected(&token, "a variant name")),
+        };
+        self.de.expect(Token::Colon)?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for VariantAccess<'_, 'de> {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<(), SerdeError> {
+        de::Deserialize::deserialize(&mut *self.de)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, SerdeError> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        de::Deserializer::deserialize_seq(&mut *self.de, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        de::Deserializer::deserialize_map(&mut *self.de, visitor)
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for JsonValue<'a> {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // JsonValue is covariant, so borrowing for 'de also satisfies any shorter 'a
+        let value: JsonValue<'de> = deserializer.deserialize_any(JsonValueVisitor)?;
+        Ok(value)
+    }
+}
+
+/// Object key that stays borrowed when the deserializer allows it.
+struct Key<'de>(Cow<'de, str>);
+
+impl<'de> Deserialize<'de> for Key<'de> {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_str(KeyVisitor)
+    }
+}
+
+struct KeyVisitor;
+
+impl<'de> Visitor<'de> for KeyVisitor {
+    type Value = Key<'de>;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a string key")
+    }
+
+    fn visit_borrowed_str<E>(self, v: &'de str) -> Result<Key<'de>, E> {
+        Ok(Key(Cow::Borrowed(v)))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Key<'de>, E> {
+        Ok(Key(Cow::Owned(v.to_string())))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Key<'de>, E> {
+        Ok(Key(Cow::Owned(v)))
+    }
+}
+
+struct JsonValueVisitor;
+
+impl<'de> Visitor<'de> for JsonValueVisitor {
+    type Value = JsonValue<'de>;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("any JSON value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<JsonValue<'de>, E> {
+        Ok(JsonValue::Boolean(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<JsonValue<'de>, E> {
+        Ok(JsonValue::Number(v as f64))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<JsonValue<'de>, E> {
+        Ok(JsonValue::Number(v as f64))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<JsonValue<'de>, E> {
+        Ok(JsonValue::Number(v))
+    }
+
+    fn visit_borrowed_str<E>(self, v: &'de str) -> Result<JsonValue<'de>, E> {
+        Ok(JsonValue::String(Cow::Borrowed(v)))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<JsonValue<'de>, E> {
+        Ok(JsonValue::String(Cow::Owned(v.to_string())))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<JsonValue<'de>, E> {
+        Ok(JsonValue::String(Cow::Owned(v)))
+    }
+
+    fn visit_unit<E>(self) -> Result<JsonValue<'de>, E> {
+        Ok(JsonValue::Null)
+    }
+
+    fn visit_none<E>(self) -> Result<JsonValue<'de>, E> {
+        Ok(JsonValue::Null)
+    }
+
+    fn visit_some<D: de::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<JsonValue<'de>, D::Error> {
+        Deserialize::deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<JsonValue<'de>, A::Error> {
+        let mut elements = Vec::new();
+        while let Some(element) = seq.next_element()? {
+            elements.push(element);
+        }
+        Ok(JsonValue::Array(elements))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<JsonValue<'de>, A::Error> {
+        let mut entries = Vec::new();
+        while let Some((Key(key), value)) = map.next_entry()? {
+            entries.push((key, value));
+        }
+        Ok(JsonValue::Object(entries))
+    }
+}
+
+impl<'de> IntoDeserializer<'de, SerdeError> for JsonValue<'de> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl<'de> de::Deserializer<'de> for JsonValue<'de> {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self {
+            JsonValue::Object(entries) => {
+                let entries = entries.into_iter().map(|(k, v)| (StrDeserializer(k), v));
+                let mut map = de::value::MapDeserializer::new(entries);
+                let value = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(value)
+            }
+            JsonValue::Array(elements) => {
+                let mut seq = de::value::SeqDeserializer::new(elements.into_iter());
+                let value = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(value)
+            }
+            JsonValue::String(s) => visit_cow(s, visitor),
+            JsonValue::Number(n) => visit_number(n, visitor),
+            JsonValue::Boolean(b) => visitor.visit_bool(b),
+            JsonValue::Null => visitor.visit_unit(),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self {
+            JsonValue::Null => visitor.visit_none(),
+            other => visitor.visit_some(other),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        match self {
+            JsonValue::String(variant) => {
+                visitor.visit_enum(de::value::CowStrDeserializer::new(variant))
+            }
+            JsonValue::Object(mut entries) if entries.len() == 1 => {
+                let (variant, value) = entries.remove(0);
+                visitor.visit_enum(ValueVariantAccess { variant, value })
+            }
+            other => Err(SerdeError::Custom(format!(
+                "Expected an enum but found {}",
+                other.to_json()
+            ))),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+struct ValueVariantAccess<'de> {
+    variant: Cow<'de, str>,
+    value: JsonValue<'de>,
+}
+
+impl<'de> de::EnumAccess<'de> for ValueVariantAccess<'de> {
+    type Error = SerdeError;
+    type Variant = JsonValue<'de>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, JsonValue<'de>), SerdeError> {
+        let variant = seed.deserialize(StrDeserializer(self.variant))?;
+        Ok((variant, self.value))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for JsonValue<'de> {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<(), SerdeError> {
+        Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, SerdeError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ser::to_value;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Config<'a> {
+        #[serde(borrow)]
+        name: Cow<'a, str>,
+        port: u16,
+        tags: Vec<String>,
+        timeout: Option<f64>,
+        mode: Mode,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Mode {
+        Fast,
+        Limited { max: u32 },
+    }
+
+    #[test]
+    fn deserializes_structs_from_text() {
+        let config: Config = from_str(
+            r#"{"name": "api", "port": 80, "tags": ["x"], "timeout": null, "mode": "Fast"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                name: Cow::Borrowed("api"),
+                port: 80,
+                tags: vec!["x".to_string()],
+                timeout: None,
+                mode: Mode::Fast,
+            }
+        );
+    }
+
+    #[test]
+    fn borrows_strings_without_escapes() {
+        let config: Config = from_str(
+            r#"{"name": "api", "port": 80, "tags": [], "timeout": 1.5, "mode": {"Limited": {"max": 3}}}"#,
+        )
+        .unwrap();
+        assert!(matches!(config.name, Cow::Borrowed("api")));
+        assert_eq!(config.mode, Mode::Limited { max: 3 });
+
+        let escaped: Cow<str> = from_str(r#""a\nb""#).unwrap();
+        assert_eq!(escaped, "a\nb");
+    }
+
+    #[test]
+    fn rejects_trailing_characters() {
+        assert!(matches!(
+            from_str::<u32>("1 2"),
+            Err(SerdeError::TrailingCharacters(_))
+        ));
+    }
+
+    #[test]
+    fn numbers_beyond_u64_stay_floats() {
+        assert_eq!(
+            from_str::<u64>("18446744073709549568").unwrap(),
+            18446744073709549568
+        );
+        assert!(from_str::<u64>("18446744073709551616").is_err());
+        assert_eq!(
+            from_str::<f64>("18446744073709551616").unwrap(),
+            18446744073709551616.0
+        );
+    }
+
+    #[test]
+    fn round_trips_through_json_value() {
+        let config = Config {
+            name: Cow::Borrowed("db"),
+            port: 5432,
+            tags: vec!["a".to_string(), "b".to_string()],
+            timeout: Some(2.5),
+            mode: Mode::Limited { max: 10 },
+        };
+        let value = to_value(&config).unwrap();
+        assert_eq!(from_value::<Config>(value).unwrap(), config);
+    }
+
+    #[test]
+    fn deserializes_json_value_itself() {
+        let value: JsonValue = from_str(r#"{"a": [1, true, null]}"#).unwrap();
+        assert_eq!(value.to_json(), r#"{"a": [1, true, null]}"#);
+    }
+}
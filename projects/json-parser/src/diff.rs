@@ -0,0 +1,216 @@
+use crate::{
+    json_value::{escape_pointer_segment, JsonValue},
+    patch::PatchOp,
+};
+
+const RED: &str = "\x1b[91m";
+const GREEN: &str = "\x1b[92m";
+const YELLOW: &str = "\x1b[93m";
+const RESET: &str = "\x1b[0m";
+
+/// A single structural difference, addressed by JSON pointer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change<'a> {
+    Added {
+        path: String,
+        value: JsonValue<'a>,
+    },
+    Removed {
+        path: String,
+        value: JsonValue<'a>,
+    },
+    Changed {
+        path: String,
+        old: JsonValue<'a>,
+        new: JsonValue<'a>,
+    },
+}
+
+/// Walks both trees and lists what has to change to turn `a` into `b`.
+pub fn diff<'a>(a: &JsonValue<'a>, b: &JsonValue<'a>) -> Vec<Change<'a>> {
+    let mut changes = Vec::new();
+    diff_at(String::new(), a, b, &mut changes);
+    changes
+}
+
+fn diff_at<'a>(path: String, a: &JsonValue<'a>, b: &JsonValue<'a>, changes: &mut Vec<Change<'a>>) {
+    match (a, b) {
+        (JsonValue::Object(old), JsonValue::Object(new)) => {
+            for (key, old_value) in old {
+                let child = format!("{}/{}", path, escape_pointer_segment(key));
+                match new.iter().find(|(k, _)| k == key) {
+                    Some((_, new_value)) => diff_at(child, old_value, new_value, changes),
+                    None => changes.push(Change::Removed {
+                        path: child,
+                        value: old_value.clone(),
+                    }),
+                }
+            }
+            for (key, new_value) in new {
+                if !old.iter().any(|(k, _)| k == key) {
+                    changes.push(Change::Added {
+                        path: format!("{}/{}", path, escape_pointer_segment(key)),
+                        value: new_value.clone(),
+                    });
+                }
+            }
+        }
+        (JsonValue::Array(old), JsonValue::Array(new)) => {
+            let common = old.len().min(new.len());
+            for index in 0..common {
+                diff_at(
+                    format!("{}/{}", path, index),
+                    &old[index],
+                    &new[index],
+                    changes,
+                );
+            }
+            // Removals go from the back so earlier indices stay valid while patching
+            for index in (common..old.len()).rev() {
+                changes.push(Change::Removed {
+                    path: format!("{}/{}", path, index),
+                    value: old[index].clone(),
+                });
+            }
+            for (index, value) in new.iter().enumerate().skip(common) {
+                changes.push(Change::Added {
+                    path: format!("{}/{}", path, index),
+                    value: value.clone(),
+                });
+            }
+        }
+        _ if a.semantic_eq(b) => {}
+        _ => changes.push(Change::Changed {
+            path,
+            old: a.clone(),
+            new: b.clone(),
+        }),
+    }
+}
+
+/// Converts a diff into RFC 6902 operations.
+pub fn to_patch<'a>(changes: &[Change<'a>]) -> Vec<PatchOp<'a>> {
+    changes
+        .iter()
+        .map(|change| match change {
+            Change::Added { path, value } => PatchOp::Add {
+                path: path.clone(),
+                value: value.clone(),
+            },
+            Change::Removed { path, .. } => PatchOp::Remove { path: path.clone() },
+            Change::Changed { path, new, .. } => PatchOp::Replace {
+                path: path.clone(),
+                value: new.clone(),
+            },
+        })
+        .collect()
+}
+
+/// Renders a diff one change per line, optionally with ANSI colors.
+pub fn render(changes: &[Change], color: bool) -> String {
+    let paint = |code: &str, line: String| {
+        if color {
+            format!("{}{}{}", code, line, RESET)
+        } else {
+            line
+        }
+    };
+
+    let mut out = String::new();
+    for change in changes {
+        let line = match change {
+            Change::Added { path, value } => paint(
+                GREEN,
+                format!("+ {}: {}", display_path(path), value.to_json()),
+            ),
+            Change::Removed { path, value } => paint(
+                RED,
+                format!("- {}: {}", display_path(path), value.to_json()),
+            ),
+            Change::Changed { path, old, new } => paint(
+                YELLOW,
+                format!(
+                    "~ {}: {} -> {}",
+                    display_path(path),
+                    old.to_json(),
+                    new.to_json()
+                ),
+            ),
+        };
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+fn display_path(path: &str) -> &str {
+    if path.is_empty() {
+        "/"
+    } else {
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::Lexer, parser::Parser, patch};
+
+    fn json(source: &str) -> JsonValue<'_> {
+        Parser::new(Lexer::new(source)).parse().unwrap()
+    }
+
+    #[test]
+    fn lists_removed_then_added_members_in_document_order() {
+        let a = json(r#"{"keep": 1, "gone": 2, "nested": {"x": 1}, "also gone": 3}"#);
+        let b = json(r#"{"new": 4, "keep": 1, "nested": {"x": 2}, "a/b": 5}"#);
+        let rendered = render(&diff(&a, &b), false);
+        assert_eq!(
+            rendered,
+            "- /gone: 2\n~ /nested/x: 1 -> 2\n- /also gone: 3\n+ /new: 4\n+ /a~1b: 5\n"
+        );
+    }
+
+    #[test]
+    fn removes_array_elements_from_the_back() {
+        let changes = diff(&json("[1, 2, 3, 4]"), &json("[1, 5]"));
+        assert_eq!(render(&changes, false), "~ /1: 2 -> 5\n- /3: 4\n- /2: 3\n");
+    }
+
+    #[test]
+    fn ignores_member_order() {
+        assert!(diff(&json(r#"{"a": 1, "b": 2}"#), &json(r#"{"b": 2, "a": 1}"#)).is_empty());
+    }
+
+    #[test]
+    fn reports_root_changes_with_a_slash() {
+        let changes = diff(&json("[1]"), &json(r#"{"a": 1}"#));
+        assert_eq!(render(&changes, false), "~ /: [1] -> {\"a\": 1}\n");
+    }
+
+    #[test]
+    fn patch_turns_the_old_document_into_the_new_one() {
+        let pairs = [
+            (
+                r#"{"a": [1, 2, 3], "b": {"c": null}}"#,
+                r#"{"a": [3], "b": {"d": true}, "e": 1}"#,
+            ),
+            ("[1, [2, 3]]", "[[2], 1, 4, 5]"),
+        ];
+        for (a, b) in pairs {
+            let (mut old, new) = (json(a), json(b));
+            let ops = to_patch(&diff(&old, &new));
+            patch::apply(&mut old, &ops).unwrap();
+            assert!(old.semantic_eq(&new), "{} -> {}", a, b);
+        }
+    }
+
+    #[test]
+    fn colors_changes() {
+        let changes = diff(&json("[1]"), &json("[2]"));
+        assert_eq!(
+            render(&changes, true),
+            format!("{}~ /0: 1 -> 2{}\n", YELLOW, RESET)
+        );
+    }
+}
@@ -11,6 +11,85 @@ pub enum LexError {
     #[error("Unterminated string starting at position {0}")]
     UnterminatedString(usize),
 
+    #[error("Unterminated comment starting at position {0}")]
+    UnterminatedComment(usize),
+
+    #[error("Trailing comma at position {0}")]
+    TrailingComma(usize),
+
     #[error("Invalid JSON structure: {0}")]
     InvalidJson(String),
 }
+
+#[derive(Debug, Error)]
+pub enum SerdeError {
+    #[error(transparent)]
+    Lex(#[from] LexError),
+
+    #[error("Trailing characters after JSON value at position {0}")]
+    TrailingCharacters(usize),
+
+    #[error("Map key must be a string, found {0}")]
+    KeyMustBeString(String),
+
+    #[error("{0}")]
+    Custom(String),
+}
+
+impl serde::ser::Error for SerdeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        SerdeError::Custom(msg.to_string())
+    }
+}
+
+impl serde::de::Error for SerdeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        SerdeError::Custom(msg.to_string())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PatchError {
+    #[error("Invalid JSON pointer: {0}")]
+    InvalidPointer(String),
+
+    #[error("Path not found: {0}")]
+    PathNotFound(String),
+
+    #[error("Invalid array index in path: {0}")]
+    InvalidIndex(String),
+
+    #[error("Cannot move {0} into its own child {1}")]
+    MoveIntoChild(String, String),
+
+    #[error("Test failed at {0}: found {1}")]
+    TestFailed(String, String),
+}
+
+#[derive(Debug, Error)]
+pub enum ConvertError {
+    #[error("Cannot represent {path} in {format}: {reason}")]
+    Unsupported {
+        format: &'static str,
+        path: String,
+        reason: String,
+    },
+
+    #[error(transparent)]
+    Json(#[from] LexError),
+
+    #[error(transparent)]
+    Serde(#[from] SerdeError),
+
+    #[error("YAML error: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+
+    #[error("TOML error: {0}")]
+    TomlDe(#[from] toml::de::Error),
+
+    #[error("TOML error: {0}")]
+    TomlSer(#[from] toml::ser::Error),
+
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+}
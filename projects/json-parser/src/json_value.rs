@@ -1,16 +1,20 @@
-use std::fmt;
+use std::{borrow::Cow, fmt};
 
-#[derive(Debug, PartialEq)]
-pub enum JsonValue {
-    Object(Vec<(String, Box<JsonValue>)>),
-    Array(Vec<Box<JsonValue>>),
-    String(String),
+use crate::parser::Comments;
+
+/// A parsed JSON value. Strings and keys borrow from the source text whenever they
+/// contain no escape sequences; use `into_owned` to detach a value from its input.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonValue<'a> {
+    Object(Vec<(Cow<'a, str>, JsonValue<'a>)>),
+    Array(Vec<JsonValue<'a>>),
+    String(Cow<'a, str>),
     Number(f64),
     Boolean(bool),
     Null,
 }
 
-impl fmt::Display for JsonValue {
+impl fmt::Display for JsonValue<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             JsonValue::Object(entries) => {
@@ -32,8 +36,8 @@ impl fmt::Display for JsonValue {
     }
 }
 
-impl JsonValue {
-    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+impl<'a> JsonValue<'a> {
+    pub fn get(&self, key: &str) -> Option<&JsonValue<'a>> {
         if let JsonValue::Object(entries) = self {
             for (k, v) in entries {
                 if k == key {
@@ -44,24 +48,66 @@ impl JsonValue {
         None
     }
 
-    pub fn set(&mut self, key: String, value: JsonValue) {
+    pub fn set(&mut self, key: String, value: JsonValue<'a>) {
         if let JsonValue::Object(entries) = self {
             for (k, v) in entries.iter_mut() {
-                if k == &key {
-                    *v = Box::new(value);
+                if *k == key {
+                    *v = value;
                     return;
                 }
             }
-            entries.push((key, Box::new(value)));
+            entries.push((Cow::Owned(key), value));
+        }
+    }
+
+    /// Copies every borrowed string so the value no longer depends on the source text.
+    pub fn into_owned(self) -> JsonValue<'static> {
+        match self {
+            JsonValue::Object(entries) => JsonValue::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (Cow::Owned(k.into_owned()), v.into_owned()))
+                    .collect(),
+            ),
+            JsonValue::Array(elements) => {
+                JsonValue::Array(elements.into_iter().map(JsonValue::into_owned).collect())
+            }
+            JsonValue::String(s) => JsonValue::String(Cow::Owned(s.into_owned())),
+            JsonValue::Number(n) => JsonValue::Number(n),
+            JsonValue::Boolean(b) => JsonValue::Boolean(b),
+            JsonValue::Null => JsonValue::Null,
+        }
+    }
+
+    /// Compares values the way JSON does: object member order is irrelevant.
+    pub fn semantic_eq(&self, other: &JsonValue<'_>) -> bool {
+        match (self, other) {
+            (JsonValue::Object(a), JsonValue::Object(b)) => {
+                a.len() == b.len()
+                    && a.iter().all(|(k, v)| {
+                        b.iter()
+                            .any(|(other_k, other_v)| k == other_k && v.semantic_eq(other_v))
+                    })
+            }
+            (JsonValue::Array(a), JsonValue::Array(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.semantic_eq(y))
+            }
+            _ => self == other,
         }
     }
 
+    pub fn to_json_pretty(&self) -> String {
+        let mut out = String::new();
+        self.write_commented(&mut out, &Comments::default(), "", 0, false);
+        out
+    }
+
     pub fn to_json(&self) -> String {
         match self {
             JsonValue::Object(entries) => {
                 let entries_str: Vec<String> = entries
                     .iter()
-                    .map(|(k, v)| format!("\"{}\": {}", k, v.to_json()))
+                    .map(|(k, v)| format!("{}: {}", quote(k), v.to_json()))
                     .collect();
                 format!("{{{}}}", entries_str.join(", "))
             }
@@ -69,10 +115,120 @@ impl JsonValue {
                 let elements_str: Vec<String> = elements.iter().map(|v| v.to_json()).collect();
                 format!("[{}]", elements_str.join(", "))
             }
-            JsonValue::String(s) => format!("\"{}\"", s),
+            JsonValue::String(s) => quote(s),
+            // JSON has no infinities or NaN, as produced by a lenient parse
+            JsonValue::Number(n) if !n.is_finite() => "null".to_string(),
             JsonValue::Number(n) => n.to_string(),
             JsonValue::Boolean(b) => b.to_string(),
             JsonValue::Null => "null".to_string(),
         }
     }
+
+    /// Pretty-prints the value as JSON5, re-emitting comments captured by a lenient parse
+    /// as `//` lines and keeping `Infinity` and `NaN`.
+    pub fn to_json_with_comments(&self, comments: &Comments) -> String {
+        let mut out = String::new();
+        write_comments(&mut out, comments.leading.get(""), 0);
+        self.write_commented(&mut out, comments, "", 0, true);
+        out
+    }
+
+    fn write_commented(
+        &self,
+        out: &mut String,
+        comments: &Comments,
+        pointer: &str,
+        depth: usize,
+        json5: bool,
+    ) {
+        let (open, close, children): (char, char, Vec<(Option<&str>, &JsonValue)>) = match self {
+            JsonValue::Object(entries) => (
+                '{',
+                '}',
+                entries.iter().map(|(k, v)| (Some(k.as_ref()), v)).collect(),
+            ),
+            JsonValue::Array(elements) => ('[', ']', elements.iter().map(|v| (None, v)).collect()),
+            JsonValue::Number(n) if json5 && n.is_nan() => {
+                out.push_str("NaN");
+                return;
+            }
+            JsonValue::Number(n) if json5 && n.is_infinite() => {
+                out.push_str(if *n > 0.0 { "Infinity" } else { "-Infinity" });
+                return;
+            }
+            scalar => {
+                out.push_str(&scalar.to_json());
+                return;
+            }
+        };
+
+        let dangling = comments.dangling.get(pointer);
+        if children.is_empty() && dangling.is_none() {
+            out.push(open);
+            out.push(close);
+            return;
+        }
+
+        out.push(open);
+        out.push('\n');
+        let count = children.len();
+        for (index, (key, value)) in children.into_iter().enumerate() {
+            let segment = key.map_or_else(|| index.to_string(), |k| k.to_string());
+            let child_pointer = format!("{}/{}", pointer, escape_pointer_segment(&segment));
+            write_comments(out, comments.leading.get(&child_pointer), depth + 1);
+            out.push_str(&indent(depth + 1));
+            if let Some(key) = key {
+                out.push_str(&quote(key));
+                out.push_str(": ");
+            }
+            value.write_commented(out, comments, &child_pointer, depth + 1, json5);
+            if index + 1 < count {
+                out.push(',');
+            }
+            out.push('\n');
+        }
+        write_comments(out, dangling, depth + 1);
+        out.push_str(&indent(depth));
+        out.push(close);
+    }
+}
+
+/// Escapes a key for use in a JSON pointer (RFC 6901).
+pub fn escape_pointer_segment(segment: &str) -> String {
+    segment.replace('~', "~0").replace('/', "~1")
+}
+
+fn indent(depth: usize) -> String {
+    "  ".repeat(depth)
+}
+
+fn write_comments(out: &mut String, comments: Option<&Vec<String>>, depth: usize) {
+    for comment in comments.into_iter().flatten() {
+        for line in comment.lines() {
+            out.push_str(&indent(depth));
+            out.push_str("// ");
+            out.push_str(line.trim());
+            out.push('\n');
+        }
+    }
+}
+
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\u{0008}' => quoted.push_str("\\b"),
+            '\u{000C}' => quoted.push_str("\\f"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
 }
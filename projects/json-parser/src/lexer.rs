@@ -1,23 +1,25 @@
-use std::{fmt::Display, str::Chars};
+use std::{borrow::Cow, fmt::Display, str::CharIndices};
 
 use crate::error::LexError;
 
+/// String tokens borrow from the source unless they contain escape sequences.
 #[derive(Debug, PartialEq)]
-pub enum Token {
+pub enum Token<'a> {
     LBrace,
     RBrace,
     LBracket,
     RBracket,
     Colon,
     Comma,
-    String(String),
+    String(Cow<'a, str>),
     Number(f64),
     Boolean(bool),
     Null,
+    Identifier(&'a str),
     EOF,
 }
 
-impl Display for Token {
+impl Display for Token<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let token_str = match self {
             Token::LBrace => "LBrace",
@@ -30,6 +32,7 @@ impl Display for Token {
             Token::Number(_) => "Number",
             Token::Boolean(_) => "Boolean",
             Token::Null => "Null",
+            Token::Identifier(_) => "Identifier",
             Token::EOF => "EOF",
         };
         write!(f, "{}", token_str)
@@ -37,28 +40,70 @@ impl Display for Token {
 }
 
 pub struct Lexer<'a> {
-    input: Chars<'a>,
+    source: &'a str,
+    input: CharIndices<'a>,
     current: Option<char>,
+    offset: usize,
     position: usize,
+    lenient: bool,
+    comments: Vec<String>,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(source: &'a str) -> Self {
         let mut lexer = Lexer {
-            input: source.chars(),
+            source,
+            input: source.char_indices(),
             current: None,
+            offset: 0,
             position: 0,
+            lenient: false,
+            comments: Vec::new(),
         };
         lexer.bump(); // Initialize first character
         lexer
     }
 
+    /// Creates a lexer for JSON5 / JSONC input: comments, single-quoted strings,
+    /// unquoted keys, hex numbers, `Infinity` and `NaN` are accepted.
+    pub fn lenient(source: &'a str) -> Self {
+        let mut lexer = Lexer::new(source);
+        lexer.lenient = true;
+        lexer
+    }
+
+    pub fn is_lenient(&self) -> bool {
+        self.lenient
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Returns the comments skipped since the last call, oldest first.
+    pub fn take_comments(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.comments)
+    }
+
+    fn peek_next(&self) -> Option<char> {
+        self.input.clone().next().map(|(_, c)| c)
+    }
+
     fn bump(&mut self) {
-        self.current = self.input.next();
+        match self.input.next() {
+            Some((offset, c)) => {
+                self.offset = offset;
+                self.current = Some(c);
+            }
+            None => {
+                self.offset = self.source.len();
+                self.current = None;
+            }
+        }
         self.position += 1;
     }
 
-    pub fn next_token(&mut self) -> Result<Token, LexError> {
+    pub fn next_token(&mut self) -> Result<Token<'a>, LexError> {
         while let Some(c) = self.current {
             match c {
                 '{' => {
@@ -85,6 +130,13 @@ impl<'a> Lexer<'a> {
                     self.bump();
                     return Ok(Token::Comma);
                 }
+                '/' if self.lenient => {
+                    self.lex_comment()?;
+                    continue;
+                }
+                '\'' if self.lenient => return self.lex_string(),
+                '+' | '.' if self.lenient => return self.lex_number(),
+                _ if self.lenient && is_identifier_start(c) => return self.lex_identifier(),
                 'n' => return self.lex_null(),
                 't' => return self.lex_true(),
                 'f' => return self.lex_false(),
@@ -100,21 +152,80 @@ impl<'a> Lexer<'a> {
         Ok(Token::EOF) // End of input
     }
 
-    fn lex_null(&mut self) -> Result<Token, LexError> {
+    fn lex_null(&mut self) -> Result<Token<'a>, LexError> {
         self.expect_keyword("null")?;
         Ok(Token::Null)
     }
 
-    fn lex_true(&mut self) -> Result<Token, LexError> {
+    fn lex_true(&mut self) -> Result<Token<'a>, LexError> {
         self.expect_keyword("true")?;
         Ok(Token::Boolean(true))
     }
 
-    fn lex_false(&mut self) -> Result<Token, LexError> {
+    fn lex_false(&mut self) -> Result<Token<'a>, LexError> {
         self.expect_keyword("false")?;
         Ok(Token::Boolean(false))
     }
 
+    fn lex_identifier(&mut self) -> Result<Token<'a>, LexError> {
+        let start = self.offset;
+        while let Some(c) = self.current {
+            if is_identifier_start(c) || c.is_ascii_digit() {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+
+        let ident = &self.source[start..self.offset];
+        match ident {
+            "null" => Ok(Token::Null),
+            "true" => Ok(Token::Boolean(true)),
+            "false" => Ok(Token::Boolean(false)),
+            "Infinity" => Ok(Token::Number(f64::INFINITY)),
+            "NaN" => Ok(Token::Number(f64::NAN)),
+            _ => Ok(Token::Identifier(ident)),
+        }
+    }
+
+    fn lex_comment(&mut self) -> Result<(), LexError> {
+        let start = self.position;
+        self.bump(); // Skip first '/'
+        let mut text = String::new();
+        match self.current {
+            Some('/') => {
+                self.bump();
+                while let Some(c) = self.current {
+                    if c == '\n' {
+                        break;
+                    }
+                    text.push(c);
+                    self.bump();
+                }
+            }
+            Some('*') => {
+                self.bump();
+                loop {
+                    match self.current {
+                        Some('*') if self.peek_next() == Some('/') => {
+                            self.bump();
+                            self.bump();
+                            break;
+                        }
+                        Some(c) => {
+                            text.push(c);
+                            self.bump();
+                        }
+                        None => return Err(LexError::UnterminatedComment(start)),
+                    }
+                }
+            }
+            _ => return Err(LexError::UnexpectedChar('/', start)),
+        }
+        self.comments.push(text.trim().to_string());
+        Ok(())
+    }
+
     fn expect_keyword(&mut self, keyword: &str) -> Result<(), LexError> {
         for expected in keyword.chars() {
             if Some(expected) != self.current {
@@ -128,20 +239,35 @@ impl<'a> Lexer<'a> {
         Ok(())
     }
 
-    fn lex_string(&mut self) -> Result<Token, LexError> {
+    fn lex_string(&mut self) -> Result<Token<'a>, LexError> {
+        let quote = self.current;
         self.bump(); // Skip opening quote
-        let mut value = String::new();
+        let start = self.offset;
+        // Only allocate once an escape sequence forces us to build a new string
+        let mut owned: Option<String> = None;
         while let Some(c) = self.current {
             match c {
-                '"' => {
+                _ if Some(c) == quote => {
+                    let value = match owned {
+                        Some(value) => Cow::Owned(value),
+                        None => Cow::Borrowed(&self.source[start..self.offset]),
+                    };
                     self.bump(); // Skip closing quote
                     return Ok(Token::String(value));
                 }
                 '\\' => {
+                    let value =
+                        owned.get_or_insert_with(|| self.source[start..self.offset].to_string());
+                    self.bump(); // Skip backslash
                     let escaped = self.read_escape_sequence()?;
                     value.push(escaped);
+                    continue;
+                }
+                _ => {
+                    if let Some(value) = owned.as_mut() {
+                        value.push(c);
+                    }
                 }
-                _ => value.push(c),
             }
             self.bump();
         }
@@ -153,6 +279,7 @@ impl<'a> Lexer<'a> {
             self.bump(); // Consume the escape character
             match c {
                 '"' => Ok('"'),
+                '\'' if self.lenient => Ok('\''),
                 '\\' => Ok('\\'),
                 '/' => Ok('/'),
                 'b' => Ok('\u{0008}'),
@@ -164,7 +291,7 @@ impl<'a> Lexer<'a> {
                     let mut hex = String::new();
                     for _ in 0..4 {
                         if let Some(h) = self.current {
-                            if h.is_digit(16) {
+                            if h.is_ascii_hexdigit() {
                                 hex.push(h);
                                 self.bump();
                             } else {
@@ -177,7 +304,7 @@ impl<'a> Lexer<'a> {
                     let code_point = u32::from_str_radix(&hex, 16)
                         .map_err(|_| LexError::UnexpectedChar('\0', self.position))?;
                     Ok(std::char::from_u32(code_point)
-                        .ok_or_else(|| LexError::UnexpectedChar('\0', self.position))?)
+                        .ok_or(LexError::UnexpectedChar('\0', self.position))?)
                 }
                 _ => Err(LexError::UnexpectedChar(c, self.position)),
             }
@@ -186,20 +313,170 @@ impl<'a> Lexer<'a> {
         }
     }
 
-    fn lex_number(&mut self) -> Result<Token, LexError> {
-        let mut number_str = String::new();
+    fn lex_number(&mut self) -> Result<Token<'a>, LexError> {
+        let start = self.offset;
+        let mut sign = 1.0;
+        if let Some(c @ ('-' | '+')) = self.current {
+            if c == '+' && !self.lenient {
+                return Err(LexError::UnexpectedChar(c, self.position));
+            }
+            if c == '-' {
+                sign = -1.0;
+            }
+            self.bump();
+        }
+
+        if self.lenient {
+            match self.current {
+                Some('I') => {
+                    self.expect_keyword("Infinity")?;
+                    return Ok(Token::Number(sign * f64::INFINITY));
+                }
+                Some('N') => {
+                    self.expect_keyword("NaN")?;
+                    return Ok(Token::Number(f64::NAN));
+                }
+                Some('0') if matches!(self.peek_next(), Some('x' | 'X')) => {
+                    return self.lex_hex_number(sign);
+                }
+                _ => {}
+            }
+        }
+
         while let Some(c) = self.current {
-            if c.is_digit(10) || c == '.' || c == '-' {
-                number_str.push(c);
+            if c.is_ascii_digit() || matches!(c, '.' | '-' | '+' | 'e' | 'E') {
                 self.bump();
             } else {
                 break;
             }
         }
 
+        let number_str = &self.source[start..self.offset];
         match number_str.parse::<f64>() {
             Ok(number) => Ok(Token::Number(number)),
-            Err(_) => Err(LexError::InvalidNumber(number_str, self.position)),
+            Err(_) => Err(LexError::InvalidNumber(
+                number_str.to_string(),
+                self.position,
+            )),
+        }
+    }
+
+    fn lex_hex_number(&mut self, sign: f64) -> Result<Token<'a>, LexError> {
+        self.bump(); // Skip '0'
+        self.bump(); // Skip 'x'
+        let start = self.offset;
+        while let Some(c) = self.current {
+            if c.is_ascii_hexdigit() {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+
+        let hex = &self.source[start..self.offset];
+        match u64::from_str_radix(hex, 16) {
+            Ok(number) => Ok(Token::Number(sign * number as f64)),
+            Err(_) => Err(LexError::InvalidNumber(format!("0x{}", hex), self.position)),
         }
     }
 }
+
+fn is_identifier_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_' || c == '$'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex_all(mut lexer: Lexer<'_>) -> Result<Vec<Token<'_>>, LexError> {
+        let mut tokens = Vec::new();
+        loop {
+            match lexer.next_token()? {
+                Token::EOF => return Ok(tokens),
+                token => tokens.push(token),
+            }
+        }
+    }
+
+    #[test]
+    fn lexes_json5_extensions_in_lenient_mode() {
+        let lexer = Lexer::lenient("{key: 'single', n: 0x1F, p: +.5, i: -Infinity}");
+        assert_eq!(
+            lex_all(lexer).unwrap(),
+            vec![
+                Token::LBrace,
+                Token::Identifier("key"),
+                Token::Colon,
+                Token::String(Cow::Borrowed("single")),
+                Token::Comma,
+                Token::Identifier("n"),
+                Token::Colon,
+                Token::Number(31.0),
+                Token::Comma,
+                Token::Identifier("p"),
+                Token::Colon,
+                Token::Number(0.5),
+                Token::Comma,
+                Token::Identifier("i"),
+                Token::Colon,
+                Token::Number(f64::NEG_INFINITY),
+                Token::RBrace,
+            ]
+        );
+    }
+
+    #[test]
+    fn lexes_nan() {
+        let mut lexer = Lexer::lenient("NaN");
+        assert!(matches!(lexer.next_token().unwrap(), Token::Number(n) if n.is_nan()));
+    }
+
+    #[test]
+    fn collects_comments_in_lenient_mode() {
+        let mut lexer = Lexer::lenient("// line\n/* block */ 1");
+        assert_eq!(lexer.next_token().unwrap(), Token::Number(1.0));
+        assert_eq!(lexer.take_comments(), vec!["line", "block"]);
+        assert!(lexer.take_comments().is_empty());
+    }
+
+    #[test]
+    fn rejects_unterminated_block_comments() {
+        let mut lexer = Lexer::lenient("/* open");
+        assert!(matches!(
+            lexer.next_token(),
+            Err(LexError::UnterminatedComment(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_json5_extensions_in_strict_mode() {
+        for source in [
+            "// comment\n1",
+            "/* c */ 1",
+            "'single'",
+            "+1",
+            "key",
+            "0x10",
+        ] {
+            assert!(
+                lex_all(Lexer::new(source)).is_err(),
+                "{} should be rejected",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn strings_borrow_unless_escaped() {
+        let mut lexer = Lexer::new(r#""plain" "tab\t""#);
+        assert!(matches!(
+            lexer.next_token().unwrap(),
+            Token::String(Cow::Borrowed("plain"))
+        ));
+        assert_eq!(
+            lexer.next_token().unwrap(),
+            Token::String(Cow::Owned("tab\t".to_string()))
+        );
+    }
+}
@@ -0,0 +1,9 @@
+pub mod convert;
+pub mod de;
+pub mod diff;
+pub mod error;
+pub mod json_value;
+pub mod lexer;
+pub mod parser;
+pub mod patch;
+pub mod ser;
@@ -1,20 +1,24 @@
-use clap::{Parser, ValueHint};
-use json_value::JsonValue;
-use std::fs::read_to_string;
-
-pub mod error;
-pub mod json_value;
-pub mod lexer;
-pub mod parser;
+use clap::{Parser, Subcommand, ValueEnum, ValueHint};
+use json_parser::{
+    convert::{self, Format},
+    de, diff,
+    json_value::JsonValue,
+    lexer, parser, patch, ser,
+};
+use std::{fs::read_to_string, io::IsTerminal, process};
 
 #[derive(Parser, Debug)]
 #[command(name = "JSON Parser")]
 #[command(version = "0.1.0")]
 #[command(about = "Parses and manipulates JSON data")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input JSON file
-    #[arg(value_hint = ValueHint::FilePath)]
-    input: String,
+    #[arg(required = true, value_hint = ValueHint::FilePath)]
+    input: Option<String>,
 
     /// Key to query
     #[arg(required = false)]
@@ -23,12 +27,146 @@ struct Cli {
     /// Key-value to set (format: key=value)
     #[arg(short, long, value_hint = ValueHint::Other)]
     set: Option<String>,
+
+    /// Accept JSON5 / JSONC input (comments, trailing commas, unquoted keys, ...)
+    #[arg(short, long, global = true)]
+    lenient: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compare two JSON documents structurally
+    Diff {
+        /// Original JSON file
+        #[arg(value_hint = ValueHint::FilePath)]
+        a: String,
+
+        /// Updated JSON file
+        #[arg(value_hint = ValueHint::FilePath)]
+        b: String,
+
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = DiffFormat::Text)]
+        format: DiffFormat,
+
+        /// Disable colored output
+        #[arg(long)]
+        no_color: bool,
+    },
+    /// Apply an RFC 6902 JSON Patch document
+    Patch {
+        /// JSON file to patch
+        #[arg(value_hint = ValueHint::FilePath)]
+        doc: String,
+
+        /// JSON Patch file (array of operations)
+        #[arg(value_hint = ValueHint::FilePath)]
+        patch: String,
+    },
+    /// Convert between JSON, YAML, TOML and CSV
+    Convert {
+        /// File to convert
+        #[arg(value_hint = ValueHint::FilePath)]
+        input: String,
+
+        /// Input format (inferred from the file extension by default)
+        #[arg(long, value_enum)]
+        from: Option<Format>,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = Format::Json)]
+        to: Format,
+    },
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum DiffFormat {
+    /// Human-readable, one change per line
+    Text,
+    /// RFC 6902 JSON Patch document
+    Patch,
+}
+
+fn read_file(path: &str) -> String {
+    read_to_string(path).expect("Something went wrong reading the file")
+}
+
+fn parse_json(source: &str, lenient: bool) -> JsonValue<'_> {
+    let lexer = if lenient {
+        lexer::Lexer::lenient(source)
+    } else {
+        lexer::Lexer::new(source)
+    };
+    parser::Parser::new(lexer)
+        .parse()
+        .expect("Failed to parse JSON")
 }
 
 fn main() {
     let cli = Cli::parse();
-    let file_contents = read_to_string(&cli.input).expect("Something went wrong reading the file");
-    let lexer = lexer::Lexer::new(&file_contents);
+
+    match cli.command {
+        Some(Command::Diff {
+            a,
+            b,
+            format,
+            no_color,
+        }) => {
+            let (a, b) = (read_file(&a), read_file(&b));
+            let changes = diff::diff(&parse_json(&a, cli.lenient), &parse_json(&b, cli.lenient));
+            match format {
+                DiffFormat::Text => {
+                    let color = !no_color && std::io::stdout().is_terminal();
+                    print!("{}", diff::render(&changes, color));
+                }
+                DiffFormat::Patch => {
+                    let ops = ser::to_value(&diff::to_patch(&changes))
+                        .expect("Failed to serialize patch");
+                    println!("{}", ops.to_json_pretty());
+                }
+            }
+            if !changes.is_empty() {
+                process::exit(1);
+            }
+        }
+        Some(Command::Patch { doc, patch }) => {
+            let (doc, patch) = (read_file(&doc), read_file(&patch));
+            let mut document = parse_json(&doc, cli.lenient);
+            let ops: Vec<patch::PatchOp> = de::from_value(parse_json(&patch, cli.lenient))
+                .expect("Invalid JSON Patch document");
+            if let Err(err) = patch::apply(&mut document, &ops) {
+                eprintln!("Error: {}", err);
+                process::exit(1);
+            }
+            println!("{}", document.to_json_pretty());
+        }
+        Some(Command::Convert { input, from, to }) => {
+            let source = read_file(&input);
+            let from = from
+                .or_else(|| Format::from_path(&input))
+                .unwrap_or(Format::Json);
+            let converted = convert::parse(&source, from, cli.lenient)
+                .and_then(|value| convert::write(&value, to));
+            match converted {
+                Ok(output) => print!("{}", output),
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    process::exit(1);
+                }
+            }
+        }
+        None => query(cli),
+    }
+}
+
+fn query(cli: Cli) {
+    let input = cli.input.expect("input is required without a subcommand");
+    let file_contents = read_to_string(&input).expect("Something went wrong reading the file");
+    let lexer = if cli.lenient {
+        lexer::Lexer::lenient(&file_contents)
+    } else {
+        lexer::Lexer::new(&file_contents)
+    };
     let mut parser = parser::Parser::new(lexer);
 
     let mut json_value = parser.parse().expect("Failed to parse JSON");
@@ -47,9 +185,17 @@ fn main() {
         let parts: Vec<&str> = set.splitn(2, '=').collect();
         if parts.len() == 2 {
             let key = parts[0].to_string();
-            let value = parts[1].to_string(); // You can enhance this to parse values appropriately
-            json_value.set(key, JsonValue::String(value));
-            println!("Updated JSON: {}", json_value.to_json());
+            let value = parts[1].to_string();
+            json_value.set(key, JsonValue::String(value.into()));
+            if cli.lenient {
+                let comments = parser.comments();
+                println!(
+                    "Updated JSON: {}",
+                    json_value.to_json_with_comments(comments)
+                );
+            } else {
+                println!("Updated JSON: {}", json_value.to_json());
+            }
         } else {
             println!("Invalid format for set. Use key=value");
         }
@@ -1,126 +1,268 @@
+use std::{borrow::Cow, collections::HashMap};
+
 use crate::{
     error::LexError,
-    json_value::JsonValue,
+    json_value::{escape_pointer_segment, JsonValue},
     lexer::{Lexer, Token},
 };
 
+/// Comments collected by a lenient parse, keyed by the JSON pointer of the value they belong to.
+#[derive(Debug, Default)]
+pub struct Comments {
+    /// Comments written directly before an object member or array element.
+    pub leading: HashMap<String, Vec<String>>,
+    /// Comments written after the last member of a container, before its closing bracket.
+    pub dangling: HashMap<String, Vec<String>>,
+}
+
 pub struct Parser<'a> {
     lexer: Lexer<'a>,
-    current_token: Token,
+    current_token: Token<'a>,
+    path: Vec<String>,
+    comments: Comments,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(lexer: Lexer<'a>) -> Self {
-        let mut parser = Parser {
+        Parser {
             lexer,
             current_token: Token::EOF,
-        };
-        parser.bump();
-        parser
+            path: Vec::new(),
+            comments: Comments::default(),
+        }
+    }
+
+    /// Comments seen so far; only populated when the lexer runs in lenient mode.
+    pub fn comments(&self) -> &Comments {
+        &self.comments
+    }
+
+    pub fn into_comments(self) -> Comments {
+        self.comments
+    }
+
+    fn bump(&mut self) -> Result<(), LexError> {
+        self.current_token = self.lexer.next_token()?;
+        Ok(())
     }
 
-    fn bump(&mut self) {
-        self.current_token = self.lexer.next_token().unwrap();
+    /// Moves the current token out so its string can be reused without copying.
+    fn take_token(&mut self) -> Result<Token<'a>, LexError> {
+        let next = self.lexer.next_token()?;
+        Ok(std::mem::replace(&mut self.current_token, next))
     }
 
-    pub fn parse(&mut self) -> Result<JsonValue, LexError> {
+    fn unexpected(&self) -> LexError {
+        LexError::UnexpectedChar(
+            self.current_token.to_string().chars().next().unwrap(),
+            self.lexer.position(),
+        )
+    }
+
+    fn pointer(&self) -> String {
+        self.path
+            .iter()
+            .map(|segment| format!("/{}", escape_pointer_segment(segment)))
+            .collect()
+    }
+
+    fn attach_comments(&mut self, dangling: bool) {
+        if !self.lexer.is_lenient() {
+            return;
+        }
+        let comments = self.lexer.take_comments();
+        if comments.is_empty() {
+            return;
+        }
+        let pointer = self.pointer();
+        let target = if dangling {
+            &mut self.comments.dangling
+        } else {
+            &mut self.comments.leading
+        };
+        target.entry(pointer).or_default().extend(comments);
+    }
+
+    pub fn parse(&mut self) -> Result<JsonValue<'a>, LexError> {
+        self.bump()?;
+        self.attach_comments(false);
         match self.current_token {
             Token::LBrace => self.parse_object(),
             Token::LBracket => self.parse_array(),
-            _ => Err(LexError::UnexpectedChar(
-                self.current_token.to_string().chars().next().unwrap(),
-                0,
-            )),
+            _ => Err(self.unexpected()),
         }
     }
 
-    fn parse_object(&mut self) -> Result<JsonValue, LexError> {
+    fn parse_object(&mut self) -> Result<JsonValue<'a>, LexError> {
         let mut members = Vec::new();
-        self.bump(); // Consume '{'
+        self.bump()?; // Consume '{'
 
         while self.current_token != Token::RBrace {
-            let key = match &self.current_token {
-                Token::String(s) => s.clone(),
-                _ => {
-                    return Err(LexError::UnexpectedChar(
-                        self.current_token.to_string().chars().next().unwrap(),
-                        0,
-                    ))
-                }
+            let key = match self.current_token {
+                Token::String(_) | Token::Identifier(_) => match self.take_token()? {
+                    Token::String(s) => s,
+                    Token::Identifier(s) => Cow::Borrowed(s),
+                    _ => unreachable!(),
+                },
+                _ => return Err(self.unexpected()),
             };
 
-            self.bump(); // Consume key
+            self.enter(|| key.to_string());
+            self.attach_comments(false);
             if self.current_token != Token::Colon {
-                return Err(LexError::UnexpectedChar(':', 0));
+                return Err(LexError::UnexpectedChar(':', self.lexer.position()));
             }
-            self.bump(); // Consume ':'
+            self.bump()?; // Consume ':'
 
             let value = self.parse_value()?;
-            members.push((key, Box::new(value)));
+            self.path.pop();
+            members.push((key, value));
 
             if self.current_token == Token::Comma {
-                self.bump(); // Consume ',' and continue
+                self.bump()?; // Consume ',' and continue
+                self.reject_trailing_comma(Token::RBrace)?;
             } else if self.current_token != Token::RBrace {
-                return Err(LexError::UnexpectedChar(
-                    self.current_token.to_string().chars().next().unwrap(),
-                    0,
-                ));
+                return Err(self.unexpected());
             }
         }
 
-        self.bump(); // Consume '}'
+        self.attach_comments(true);
+        self.bump()?; // Consume '}'
         Ok(JsonValue::Object(members))
     }
 
-    fn parse_array(&mut self) -> Result<JsonValue, LexError> {
+    fn parse_array(&mut self) -> Result<JsonValue<'a>, LexError> {
         let mut elements = Vec::new();
-        self.bump(); // Consume '['
+        self.bump()?; // Consume '['
 
         while self.current_token != Token::RBracket {
+            let index = elements.len();
+            self.enter(|| index.to_string());
+            self.attach_comments(false);
             let value = self.parse_value()?;
-            elements.push(Box::new(value));
+            self.path.pop();
+            elements.push(value);
 
             if self.current_token == Token::Comma {
-                self.bump(); // Consume ',' and continue
+                self.bump()?; // Consume ',' and continue
+                self.reject_trailing_comma(Token::RBracket)?;
             } else if self.current_token != Token::RBracket {
-                return Err(LexError::UnexpectedChar(
-                    self.current_token.to_string().chars().next().unwrap(),
-                    0,
-                ));
+                return Err(self.unexpected());
             }
         }
 
-        self.bump(); // Consume ']'
+        self.attach_comments(true);
+        self.bump()?; // Consume ']'
         Ok(JsonValue::Array(elements))
     }
 
-    fn parse_value(&mut self) -> Result<JsonValue, LexError> {
+    /// Tracks the JSON pointer of the value being parsed; skipped outside lenient mode
+    /// since it only serves to attach comments.
+    fn enter(&mut self, segment: impl FnOnce() -> String) {
+        if self.lexer.is_lenient() {
+            let segment = segment();
+            self.path.push(segment);
+        }
+    }
+
+    fn reject_trailing_comma(&self, closing: Token<'a>) -> Result<(), LexError> {
+        if self.current_token == closing && !self.lexer.is_lenient() {
+            return Err(LexError::TrailingComma(self.lexer.position()));
+        }
+        Ok(())
+    }
+
+    fn parse_value(&mut self) -> Result<JsonValue<'a>, LexError> {
         match &self.current_token {
-            Token::String(s) => {
-                let value = JsonValue::String(s.clone());
-                self.bump(); // Consume the string
-                Ok(value)
-            }
+            Token::String(_) => match self.take_token()? {
+                Token::String(s) => Ok(JsonValue::String(s)),
+                _ => unreachable!(),
+            },
             Token::Number(n) => {
                 let value = JsonValue::Number(*n);
-                self.bump(); // Consume the number
+                self.bump()?; // Consume the number
                 Ok(value)
             }
             Token::Boolean(b) => {
                 let value = JsonValue::Boolean(*b);
-                self.bump(); // Consume the boolean
+                self.bump()?; // Consume the boolean
                 Ok(value)
             }
             Token::Null => {
-                self.bump(); // Consume null
+                self.bump()?; // Consume null
                 Ok(JsonValue::Null)
             }
             Token::LBrace => self.parse_object(),
             Token::LBracket => self.parse_array(),
-            _ => Err(LexError::UnexpectedChar(
-                self.current_token.to_string().chars().next().unwrap(),
-                0,
-            )),
+            _ => Err(self.unexpected()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_lenient(source: &str) -> (JsonValue<'_>, Comments) {
+        let mut parser = Parser::new(Lexer::lenient(source));
+        let value = parser.parse().unwrap();
+        (value, parser.into_comments())
+    }
+
+    #[test]
+    fn accepts_trailing_commas_only_in_lenient_mode() {
+        assert!(matches!(
+            Parser::new(Lexer::new("[1, 2,]")).parse(),
+            Err(LexError::TrailingComma(_))
+        ));
+        assert!(matches!(
+            Parser::new(Lexer::new(r#"{"a": 1,}"#)).parse(),
+            Err(LexError::TrailingComma(_))
+        ));
+
+        let (value, _) = parse_lenient("{a: [1, 2,],}");
+        assert_eq!(value.to_json(), r#"{"a": [1, 2]}"#);
+    }
+
+    #[test]
+    fn attaches_comments_to_json_pointers() {
+        let (_, comments) = parse_lenient(
+            "// top\n{\n  // about a\n  a: 1,\n  b: [\n    // first\n    true,\n    // nothing left\n  ],\n}",
+        );
+        assert_eq!(comments.leading[""], vec!["top"]);
+        assert_eq!(comments.leading["/a"], vec!["about a"]);
+        assert_eq!(comments.leading["/b/0"], vec!["first"]);
+        assert_eq!(comments.dangling["/b"], vec!["nothing left"]);
+    }
+
+    #[test]
+    fn round_trips_comments() {
+        let (value, comments) =
+            parse_lenient("{\n  // the name\n  name: 'x', /* empty */ list: [],\n}");
+        assert_eq!(
+            value.to_json_with_comments(&comments),
+            "{\n  // the name\n  \"name\": \"x\",\n  // empty\n  \"list\": []\n}"
+        );
+    }
+
+    #[test]
+    fn only_json5_output_keeps_infinity_and_nan() {
+        let (value, comments) = parse_lenient("[Infinity, -Infinity, NaN, 1]");
+        assert_eq!(value.to_json(), "[null, null, null, 1]");
+        assert_eq!(
+            value.to_json_pretty(),
+            "[\n  null,\n  null,\n  null,\n  1\n]"
+        );
+        assert_eq!(
+            value.to_json_with_comments(&comments),
+            "[\n  Infinity,\n  -Infinity,\n  NaN,\n  1\n]"
+        );
+    }
+
+    #[test]
+    fn strict_mode_collects_no_comments() {
+        let mut parser = Parser::new(Lexer::new(r#"{"a": 1}"#));
+        parser.parse().unwrap();
+        assert!(parser.comments().leading.is_empty());
+    }
+}
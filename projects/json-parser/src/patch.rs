@@ -0,0 +1,365 @@
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::PatchError,
+    json_value::{escape_pointer_segment, JsonValue},
+};
+
+/// A single RFC 6902 JSON Patch operation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOp<'a> {
+    Add {
+        path: String,
+        #[serde(borrow)]
+        value: JsonValue<'a>,
+    },
+    Remove {
+        path: String,
+    },
+    Replace {
+        path: String,
+        #[serde(borrow)]
+        value: JsonValue<'a>,
+    },
+    Move {
+        from: String,
+        path: String,
+    },
+    Copy {
+        from: String,
+        path: String,
+    },
+    Test {
+        path: String,
+        #[serde(borrow)]
+        value: JsonValue<'a>,
+    },
+}
+
+/// Applies every operation in order. The document is left untouched if any operation fails.
+pub fn apply<'a>(doc: &mut JsonValue<'a>, ops: &[PatchOp<'a>]) -> Result<(), PatchError> {
+    let mut patched = doc.clone();
+    for op in ops {
+        apply_op(&mut patched, op)?;
+    }
+    *doc = patched;
+    Ok(())
+}
+
+fn apply_op<'a>(doc: &mut JsonValue<'a>, op: &PatchOp<'a>) -> Result<(), PatchError> {
+    match op {
+        PatchOp::Add { path, value } => add(doc, path, value.clone()),
+        PatchOp::Remove { path } => remove(doc, path).map(|_| ()),
+        PatchOp::Replace { path, value } => {
+            let target = resolve_mut(doc, path)?;
+            *target = value.clone();
+            Ok(())
+        }
+        PatchOp::Move { from, path } => {
+            if path.starts_with(&format!("{}/", from)) {
+                return Err(PatchError::MoveIntoChild(from.clone(), path.clone()));
+            }
+            let value = remove(doc, from)?;
+            add(doc, path, value)
+        }
+        PatchOp::Copy { from, path } => {
+            let value = resolve(doc, from)?.clone();
+            add(doc, path, value)
+        }
+        PatchOp::Test { path, value } => {
+            let actual = resolve(doc, path)?;
+            if actual.semantic_eq(value) {
+                Ok(())
+            } else {
+                Err(PatchError::TestFailed(path.clone(), actual.to_json()))
+            }
+        }
+    }
+}
+
+/// Splits a JSON pointer (RFC 6901) into unescaped reference tokens.
+pub fn parse_pointer(pointer: &str) -> Result<Vec<String>, PatchError> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    if !pointer.starts_with('/') {
+        return Err(PatchError::InvalidPointer(pointer.to_string()));
+    }
+    Ok(pointer[1..]
+        .split('/')
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+/// Joins reference tokens back into a JSON pointer.
+pub fn to_pointer(tokens: &[String]) -> String {
+    tokens
+        .iter()
+        .map(|token| format!("/{}", escape_pointer_segment(token)))
+        .collect()
+}
+
+fn array_index(token: &str, len: usize, pointer: &str) -> Result<usize, PatchError> {
+    // Only plain digits are valid array indices, without leading zeros or signs
+    if token.is_empty()
+        || !token.bytes().all(|b| b.is_ascii_digit())
+        || (token.len() > 1 && token.starts_with('0'))
+    {
+        return Err(PatchError::InvalidIndex(pointer.to_string()));
+    }
+    match token.parse::<usize>() {
+        Ok(index) if index < len => Ok(index),
+        _ => Err(PatchError::InvalidIndex(pointer.to_string())),
+    }
+}
+
+pub fn resolve<'v, 'a>(
+    doc: &'v JsonValue<'a>,
+    pointer: &str,
+) -> Result<&'v JsonValue<'a>, PatchError> {
+    let mut current = doc;
+    for token in parse_pointer(pointer)? {
+        current = match current {
+            JsonValue::Object(entries) => entries
+                .iter()
+                .find(|(k, _)| *k == token)
+                .map(|(_, v)| v)
+                .ok_or_else(|| PatchError::PathNotFound(pointer.to_string()))?,
+            JsonValue::Array(elements) => &elements[array_index(&token, elements.len(), pointer)?],
+            _ => return Err(PatchError::PathNotFound(pointer.to_string())),
+        };
+    }
+    Ok(current)
+}
+
+fn resolve_mut<'v, 'a>(
+    doc: &'v mut JsonValue<'a>,
+    pointer: &str,
+) -> Result<&'v mut JsonValue<'a>, PatchError> {
+    let mut current = doc;
+    for token in parse_pointer(pointer)? {
+        current = match current {
+            JsonValue::Object(entries) => entries
+                .iter_mut()
+                .find(|(k, _)| *k == token)
+                .map(|(_, v)| v)
+                .ok_or_else(|| PatchError::PathNotFound(pointer.to_string()))?,
+            JsonValue::Array(elements) => {
+                let index = array_index(&token, elements.len(), pointer)?;
+                &mut elements[index]
+            }
+            _ => return Err(PatchError::PathNotFound(pointer.to_string())),
+        };
+    }
+    Ok(current)
+}
+
+/// Resolves the container holding the last token of `pointer`.
+fn split_parent(pointer: &str) -> Result<(String, String), PatchError> {
+    let mut tokens = parse_pointer(pointer)?;
+    let last = tokens
+        .pop()
+        .ok_or_else(|| PatchError::InvalidPointer(pointer.to_string()))?;
+    Ok((to_pointer(&tokens), last))
+}
+
+fn add<'a>(doc: &mut JsonValue<'a>, pointer: &str, value: JsonValue<'a>) -> Result<(), PatchError> {
+    if pointer.is_empty() {
+        *doc = value;
+        return Ok(());
+    }
+    let (parent, last) = split_parent(pointer)?;
+    match resolve_mut(doc, &parent)? {
+        JsonValue::Object(entries) => {
+            match entries.iter_mut().find(|(k, _)| *k == last) {
+                Some((_, v)) => *v = value,
+                None => entries.push((Cow::Owned(last), value)),
+            }
+            Ok(())
+        }
+        JsonValue::Array(elements) => {
+            let index = if last == "-" {
+                elements.len()
+            } else {
+                // Inserting right after the last element is allowed
+                array_index(&last, elements.len() + 1, pointer)?
+            };
+            elements.insert(index, value);
+            Ok(())
+        }
+        _ => Err(PatchError::PathNotFound(pointer.to_string())),
+    }
+}
+
+fn remove<'a>(doc: &mut JsonValue<'a>, pointer: &str) -> Result<JsonValue<'a>, PatchError> {
+    let (parent, last) = split_parent(pointer)?;
+    match resolve_mut(doc, &parent)? {
+        JsonValue::Object(entries) => {
+            let index = entries
+                .iter()
+                .position(|(k, _)| *k == last)
+                .ok_or_else(|| PatchError::PathNotFound(pointer.to_string()))?;
+            Ok(entries.remove(index).1)
+        }
+        JsonValue::Array(elements) => {
+            let index = array_index(&last, elements.len(), pointer)?;
+            Ok(elements.remove(index))
+        }
+        _ => Err(PatchError::PathNotFound(pointer.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{de, lexer::Lexer, parser::Parser};
+
+    fn json(source: &str) -> JsonValue<'_> {
+        Parser::new(Lexer::new(source)).parse().unwrap()
+    }
+
+    fn patched(doc: &str, ops: &str) -> Result<String, PatchError> {
+        let mut doc = json(doc);
+        let ops: Vec<PatchOp> = de::from_str(ops).unwrap();
+        apply(&mut doc, &ops)?;
+        Ok(doc.to_json())
+    }
+
+    #[test]
+    fn adds_members_and_array_elements() {
+        assert_eq!(
+            patched(
+                r#"{"a": [1, 3]}"#,
+                r#"[{"op": "add", "path": "/b", "value": true},
+                    {"op": "add", "path": "/a/1", "value": 2},
+                    {"op": "add", "path": "/a/-", "value": 4},
+                    {"op": "add", "path": "/a/4", "value": 5}]"#
+            )
+            .unwrap(),
+            r#"{"a": [1, 2, 3, 4, 5], "b": true}"#
+        );
+        assert!(matches!(
+            patched(
+                r#"{"a": []}"#,
+                r#"[{"op": "add", "path": "/a/1", "value": 1}]"#
+            ),
+            Err(PatchError::InvalidIndex(_))
+        ));
+    }
+
+    #[test]
+    fn removes_and_replaces() {
+        assert_eq!(
+            patched(
+                r#"{"a": 1, "b": [1, 2]}"#,
+                r#"[{"op": "remove", "path": "/a"},
+                    {"op": "replace", "path": "/b/0", "value": "x"}]"#
+            )
+            .unwrap(),
+            r#"{"b": ["x", 2]}"#
+        );
+        assert!(matches!(
+            patched(r#"{}"#, r#"[{"op": "remove", "path": "/a"}]"#),
+            Err(PatchError::PathNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn moves_and_copies() {
+        assert_eq!(
+            patched(
+                r#"{"a": {"x": 1}, "b": []}"#,
+                r#"[{"op": "copy", "from": "/a/x", "path": "/b/0"},
+                    {"op": "move", "from": "/a", "path": "/c"}]"#
+            )
+            .unwrap(),
+            r#"{"b": [1], "c": {"x": 1}}"#
+        );
+    }
+
+    #[test]
+    fn rejects_moving_into_own_child() {
+        assert!(matches!(
+            patched(
+                r#"{"a": {"b": {}}}"#,
+                r#"[{"op": "move", "from": "/a", "path": "/a/b/c"}]"#
+            ),
+            Err(PatchError::MoveIntoChild(_, _))
+        ));
+        // A sibling that merely shares the prefix is fine
+        assert_eq!(
+            patched(
+                r#"{"a": 1}"#,
+                r#"[{"op": "move", "from": "/a", "path": "/ab"}]"#
+            )
+            .unwrap(),
+            r#"{"ab": 1}"#
+        );
+    }
+
+    #[test]
+    fn tests_values_semantically() {
+        assert!(patched(
+            r#"{"a": {"x": 1, "y": 2}}"#,
+            r#"[{"op": "test", "path": "/a", "value": {"y": 2, "x": 1}}]"#
+        )
+        .is_ok());
+        assert!(matches!(
+            patched(r#"{"a": 1}"#, r#"[{"op": "test", "path": "/a", "value": 2}]"#),
+            Err(PatchError::TestFailed(_, found)) if found == "1"
+        ));
+    }
+
+    #[test]
+    fn leaves_document_untouched_when_an_operation_fails() {
+        let mut doc = json(r#"{"a": 1}"#);
+        let ops: Vec<PatchOp> = de::from_str(
+            r#"[{"op": "remove", "path": "/a"}, {"op": "test", "path": "/a", "value": 1}]"#,
+        )
+        .unwrap();
+        assert!(apply(&mut doc, &ops).is_err());
+        assert_eq!(doc.to_json(), r#"{"a": 1}"#);
+    }
+
+    #[test]
+    fn unescapes_pointer_tokens() {
+        assert_eq!(
+            parse_pointer("/a~1b/m~0n/~01").unwrap(),
+            vec!["a/b", "m~n", "~1"]
+        );
+        assert_eq!(parse_pointer("").unwrap(), Vec::<String>::new());
+        assert!(matches!(
+            parse_pointer("a"),
+            Err(PatchError::InvalidPointer(_))
+        ));
+
+        let tokens = vec!["a/b".to_string(), "m~n".to_string()];
+        assert_eq!(to_pointer(&tokens), "/a~1b/m~0n");
+
+        let doc = json(r#"{"a/b": {"m~n": 7}}"#);
+        assert_eq!(
+            resolve(&doc, "/a~1b/m~0n").unwrap(),
+            &JsonValue::Number(7.0)
+        );
+    }
+
+    #[test]
+    fn rejects_leading_zeros_and_signs_in_array_indices() {
+        let doc = json(r#"{"a": [1, 2]}"#);
+        for pointer in ["/a/01", "/a/-1", "/a/+1", "/a/ 1"] {
+            assert!(
+                matches!(resolve(&doc, pointer), Err(PatchError::InvalidIndex(_))),
+                "{}",
+                pointer
+            );
+        }
+        assert!(matches!(
+            resolve(&doc, "/a/2"),
+            Err(PatchError::InvalidIndex(_))
+        ));
+        assert_eq!(resolve(&doc, "/a/0").unwrap(), &JsonValue::Number(1.0));
+    }
+}
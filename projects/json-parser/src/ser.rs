@@ -0,0 +1,428 @@
+use std::borrow::Cow;
+
+use serde::ser::{self, Serialize};
+
+use crate::{error::SerdeError, json_value::JsonValue};
+
+/// Converts any `T: Serialize` into a `JsonValue`.
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<JsonValue<'static>, SerdeError> {
+    value.serialize(Serializer)
+}
+
+impl Serialize for JsonValue<'_> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use ser::{SerializeMap, SerializeSeq};
+
+        match self {
+            JsonValue::Object(entries) => {
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (k, v) in entries {
+                    map.serialize_entry(k, v)?;
+                }
+                map.end()
+            }
+            JsonValue::Array(elements) => {
+                let mut seq = serializer.serialize_seq(Some(elements.len()))?;
+                for v in elements {
+                    seq.serialize_element(v)?;
+                }
+                seq.end()
+            }
+            JsonValue::String(s) => serializer.serialize_str(s),
+            // Keep integers integral so formats like YAML and TOML don't print `3.0`
+            JsonValue::Number(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => {
+                serializer.serialize_i64(*n as i64)
+            }
+            JsonValue::Number(n) => serializer.serialize_f64(*n),
+            JsonValue::Boolean(b) => serializer.serialize_bool(*b),
+            JsonValue::Null => serializer.serialize_unit(),
+        }
+    }
+}
+
+/// Serializer whose output is a `JsonValue` tree.
+pub struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = JsonValue<'static>;
+    type Error = SerdeError;
+
+    type SerializeSeq = SerializeArray;
+    type SerializeTuple = SerializeArray;
+    type SerializeTupleStruct = SerializeArray;
+    type SerializeTupleVariant = SerializeArrayVariant;
+    type SerializeMap = SerializeObject;
+    type SerializeStruct = SerializeObject;
+    type SerializeStructVariant = SerializeObjectVariant;
+
+    fn serialize_bool(self, v: bool) -> Result<JsonValue<'static>, SerdeError> {
+        Ok(JsonValue::Boolean(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<JsonValue<'static>, SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<JsonValue<'static>, SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<JsonValue<'static>, SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<JsonValue<'static>, SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<JsonValue<'static>, SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<JsonValue<'static>, SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<JsonValue<'static>, SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<JsonValue<'static>, SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<JsonValue<'static>, SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<JsonValue<'static>, SerdeError> {
+        Ok(JsonValue::Number(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<JsonValue<'static>, SerdeError> {
+        Ok(JsonValue::String(Cow::Owned(v.to_string())))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<JsonValue<'static>, SerdeError> {
+        Ok(JsonValue::String(Cow::Owned(v.to_string())))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<JsonValue<'static>, SerdeError> {
+        let elements = v.iter().map(|b| JsonValue::Number(*b as f64)).collect();
+        Ok(JsonValue::Array(elements))
+    }
+
+    fn serialize_none(self) -> Result<JsonValue<'static>, SerdeError> {
+        Ok(JsonValue::Null)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(
+        self,
+        value: &T,
+    ) -> Result<JsonValue<'static>, SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<JsonValue<'static>, SerdeError> {
+        Ok(JsonValue::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<JsonValue<'static>, SerdeError> {
+        Ok(JsonValue::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<JsonValue<'static>, SerdeError> {
+        Ok(JsonValue::String(Cow::Borrowed(variant)))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<JsonValue<'static>, SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<JsonValue<'static>, SerdeError> {
+        let inner = value.serialize(Serializer)?;
+        Ok(JsonValue::Object(vec![(Cow::Borrowed(variant), inner)]))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeArray, SerdeError> {
+        Ok(SerializeArray {
+            elements: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeArray, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeArray, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeArrayVariant, SerdeError> {
+        Ok(SerializeArrayVariant {
+            variant,
+            elements: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeObject, SerdeError> {
+        Ok(SerializeObject {
+            entries: Vec::with_capacity(len.unwrap_or(0)),
+            next_key: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeObject, SerdeError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeObjectVariant, SerdeError> {
+        Ok(SerializeObjectVariant {
+            variant,
+            entries: Vec::with_capacity(len),
+        })
+    }
+}
+
+pub struct SerializeArray {
+    elements: Vec<JsonValue<'static>>,
+}
+
+impl ser::SerializeSeq for SerializeArray {
+    type Ok = JsonValue<'static>;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.elements.push(to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<JsonValue<'static>, SerdeError> {
+        Ok(JsonValue::Array(self.elements))
+    }
+}
+
+impl ser::SerializeTuple for SerializeArray {
+    type Ok = JsonValue<'static>;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<JsonValue<'static>, SerdeError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeArray {
+    type Ok = JsonValue<'static>;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<JsonValue<'static>, SerdeError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+pub struct SerializeArrayVariant {
+    variant: &'static str,
+    elements: Vec<JsonValue<'static>>,
+}
+
+impl ser::SerializeTupleVariant for SerializeArrayVariant {
+    type Ok = JsonValue<'static>;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.elements.push(to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<JsonValue<'static>, SerdeError> {
+        Ok(JsonValue::Object(vec![(
+            Cow::Borrowed(self.variant),
+            JsonValue::Array(self.elements),
+        )]))
+    }
+}
+
+pub struct SerializeObject {
+    entries: Vec<(Cow<'static, str>, JsonValue<'static>)>,
+    next_key: Option<Cow<'static, str>>,
+}
+
+impl ser::SerializeMap for SerializeObject {
+    type Ok = JsonValue<'static>;
+    type Error = SerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerdeError> {
+        // JSON object keys are always strings, so scalar keys are stringified.
+        let key = match to_value(key)? {
+            JsonValue::String(s) => s,
+            JsonValue::Number(n) => Cow::Owned(n.to_string()),
+            JsonValue::Boolean(b) => Cow::Owned(b.to_string()),
+            other => return Err(SerdeError::KeyMustBeString(other.to_json())),
+        };
+        self.next_key = Some(key);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        let key = self.next_key.take().ok_or_else(|| {
+            SerdeError::Custom("serialize_value called before serialize_key".into())
+        })?;
+        self.entries.push((key, to_value(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<JsonValue<'static>, SerdeError> {
+        Ok(JsonValue::Object(self.entries))
+    }
+}
+
+impl ser::SerializeStruct for SerializeObject {
+    type Ok = JsonValue<'static>;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        self.entries.push((Cow::Borrowed(key), to_value(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<JsonValue<'static>, SerdeError> {
+        Ok(JsonValue::Object(self.entries))
+    }
+}
+
+pub struct SerializeObjectVariant {
+    variant: &'static str,
+    entries: Vec<(Cow<'static, str>, JsonValue<'static>)>,
+}
+
+impl ser::SerializeStructVariant for SerializeObjectVariant {
+    type Ok = JsonValue<'static>;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        self.entries.push((Cow::Borrowed(key), to_value(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<JsonValue<'static>, SerdeError> {
+        Ok(JsonValue::Object(vec![(
+            Cow::Borrowed(self.variant),
+            JsonValue::Object(self.entries),
+        )]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Serialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize)]
+    struct Config {
+        name: &'static str,
+        port: u16,
+        tags: Vec<&'static str>,
+        timeout: Option<f64>,
+    }
+
+    #[derive(Serialize)]
+    enum Shape {
+        Circle(f64),
+        Rect { w: u32, h: u32 },
+        Empty,
+    }
+
+    #[test]
+    fn serializes_structs_into_objects_in_field_order() {
+        let config = Config {
+            name: "api",
+            port: 8080,
+            tags: vec!["a", "b"],
+            timeout: None,
+        };
+        assert_eq!(
+            to_value(&config).unwrap().to_json(),
+            r#"{"name": "api", "port": 8080, "tags": ["a", "b"], "timeout": null}"#
+        );
+    }
+
+    #[test]
+    fn serializes_enum_variants_externally_tagged() {
+        assert_eq!(
+            to_value(&Shape::Circle(1.5)).unwrap().to_json(),
+            r#"{"Circle": 1.5}"#
+        );
+        assert_eq!(
+            to_value(&Shape::Rect { w: 2, h: 3 }).unwrap().to_json(),
+            r#"{"Rect": {"w": 2, "h": 3}}"#
+        );
+        assert_eq!(to_value(&Shape::Empty).unwrap().to_json(), r#""Empty""#);
+    }
+
+    #[test]
+    fn rejects_non_string_map_keys() {
+        let map = BTreeMap::from([(vec![1], 2)]);
+        assert!(matches!(
+            to_value(&map),
+            Err(SerdeError::KeyMustBeString(_))
+        ));
+    }
+
+    #[test]
+    fn stringifies_integer_map_keys() {
+        let map = BTreeMap::from([(1, true)]);
+        assert_eq!(to_value(&map).unwrap().to_json(), r#"{"1": true}"#);
+    }
+}
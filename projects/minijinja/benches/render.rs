@@ -0,0 +1,71 @@
+//! Rendering throughput for an email-style template: a layout, a macro call per line item
+//! and a context with many unrelated variables, which macro calls must not copy.
+
+use std::collections::HashMap;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use minijinja::{environment::Environment, loader::MemoryLoader, values::Value, vm::Vm};
+
+const LAYOUT: &str = r#"<html><body>
+{% block body %}{% endblock %}
+<footer>{{ company }} &middot; {% block footer %}Unsubscribe{% endblock %}</footer>
+</body></html>"#;
+
+const EMAIL: &str = r#"{% extends "layout.html" %}
+{% macro line(item, currency="EUR") %}
+<tr><td>{{ item.name | title }}</td><td>{{ item.qty }}</td><td>{{ item.price }} {{ currency }}</td></tr>
+{% endmacro %}
+{% block body %}
+<p>Hello {{ user.name }},</p>
+<table>
+{% for item in items if item.qty > 0 %}{{ line(item) }}{% else %}<tr><td>No items</td></tr>{% endfor %}
+</table>
+<p>{{ items | length }} items, total {{ items | map(attribute="price") | join(" + ") }}</p>
+{% endblock %}"#;
+
+fn context(vm: &mut Vm, items: usize) {
+    vm.set_variable("company", Value::String("Example Ltd".to_string()));
+    vm.set_variable(
+        "user",
+        Value::Dict(HashMap::from([(
+            "name".to_string(),
+            Value::String("Alice".to_string()),
+        )])),
+    );
+    vm.set_variable(
+        "items",
+        Value::List(
+            (0..items)
+                .map(|i| {
+                    Value::Dict(HashMap::from([
+                        ("name".to_string(), Value::String(format!("item {}", i))),
+                        ("qty".to_string(), Value::Integer(i as i64 % 5)),
+                        ("price".to_string(), Value::Integer(i as i64 * 3)),
+                    ]))
+                })
+                .collect(),
+        ),
+    );
+    for i in 0..500 {
+        vm.set_variable(&format!("unused_{}", i), Value::Integer(i));
+    }
+}
+
+fn render(c: &mut Criterion) {
+    let mut loader = MemoryLoader::new();
+    loader.add_template("layout.html", LAYOUT);
+    loader.add_template("email.html", EMAIL);
+    let mut env = Environment::new();
+    env.set_loader(loader);
+
+    for items in [10, 100] {
+        let mut vm = Vm::new(&env);
+        context(&mut vm, items);
+        c.bench_function(&format!("email ({} items)", items), |b| {
+            b.iter(|| vm.render_template(black_box("email.html")).unwrap())
+        });
+    }
+}
+
+criterion_group!(benches, render);
+criterion_main!(benches);
@@ -1,36 +1,209 @@
-use crate::values::Value;
+use core::fmt;
+
+use crate::{lexer::Span, values::Value};
 
 #[derive(Clone)]
 pub enum AstNode {
     Text(String),
-    Variable(String, Vec<(String, Vec<Value>)>), // Variable name and a list of filters (filter name, args)
+    Variable(Expr),
     IfBlock {
-        condition: String,
+        condition: Expr,
         then_block: Box<Ast>,
         else_block: Option<Box<Ast>>,
     },
     ForBlock {
-        loop_var: String,
-        collection_var: String,
+        /// One name, or several to unpack each item into
+        targets: Vec<String>,
+        collection: Expr,
+        /// `{% for x in xs if cond %}` skips items before the loop counters see them
+        filter: Option<Expr>,
+        body: Box<Ast>,
+        /// Rendered when there was nothing to iterate over
+        else_block: Option<Box<Ast>>,
+    },
+    Break,
+    Continue,
+    MacroDef {
+        name: String,
+        params: Vec<Param>,
+        body: Box<Ast>,
+    },
+    /// `{% call m(...) %}body{% endcall %}` passes the body to the macro as `caller()`
+    CallBlock {
+        call: Expr,
+        body: Box<Ast>,
+    },
+    Set {
+        target: String,
+        value: Expr,
+    },
+    /// `{% set x %}...{% endset %}` captures the rendered body
+    SetBlock {
+        target: String,
         body: Box<Ast>,
     },
-    MacroCall {
+    /// Assignments that are only visible inside the body
+    With {
+        assignments: Vec<(String, Expr)>,
+        body: Box<Ast>,
+    },
+    /// `{% import "macros.html" as m %}` makes the template's macros available as `m.name()`
+    Import {
+        template: Expr,
+        alias: String,
+    },
+    Extends(Expr),
+    Block {
+        name: String,
+        body: Box<Ast>,
+    },
+    /// `{{ super() }}` inside a block renders the parent template's version of it
+    Super,
+    Include {
+        name: Expr,
+        ignore_missing: bool,
+    },
+    AutoEscape {
+        enabled: Expr,
+        body: Box<Ast>,
+    },
+    /// `{% trans %}...{% pluralize %}...{% endtrans %}`, with the bodies turned into
+    /// gettext messages such as `"Hello %(name)s!"`
+    Trans {
+        singular: String,
+        plural: Option<String>,
+        /// The variable that picks the plural form
+        count: Option<String>,
+        /// Values for the placeholders, from the tag or from the variables of the same name
+        variables: Vec<(String, Expr)>,
+    },
+}
+
+/// A macro parameter, optionally with a default value.
+#[derive(Clone)]
+pub struct Param {
+    pub name: String,
+    pub default: Option<Expr>,
+}
+
+/// An expression inside `{{ ... }}` or a block tag.
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Literal(Value),
+    Name(String),
+    List(Vec<Expr>),
+    Dict(Vec<(Expr, Expr)>),
+    GetAttr(Box<Expr>, String),
+    GetItem(Box<Expr>, Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    /// `then_expr if condition else else_expr`
+    Conditional {
+        condition: Box<Expr>,
+        then_expr: Box<Expr>,
+        else_expr: Option<Box<Expr>>,
+    },
+    /// A macro call such as `greet("World")`, or a method call such as `dict.items()`
+    Call {
+        func: Box<Expr>,
+        args: Vec<Expr>,
+        kwargs: Kwargs,
+    },
+    Filter {
+        expr: Box<Expr>,
         name: String,
-        args: Vec<String>,
+        args: Vec<Expr>,
+        kwargs: Kwargs,
     },
+    /// `expr is name(args)`; `is not` wraps it in `not`
+    Test {
+        expr: Box<Expr>,
+        name: String,
+        args: Vec<Expr>,
+    },
+}
+
+/// Keyword arguments of a call, in source order.
+pub type Kwargs = Vec<(String, Expr)>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnaryOp {
+    Not,
+    Neg,
+    Pos,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    FloorDiv,
+    Rem,
+    Pow,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    In,
+    NotIn,
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::FloorDiv => "//",
+            BinaryOp::Rem => "%",
+            BinaryOp::Pow => "**",
+            BinaryOp::Concat => "~",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::And => "and",
+            BinaryOp::Or => "or",
+            BinaryOp::In => "in",
+            BinaryOp::NotIn => "not in",
+        };
+        write!(f, "{}", symbol)
+    }
 }
 
 #[derive(Clone)]
 pub struct Ast {
     pub nodes: Vec<AstNode>,
+    /// Where each node is in the template source; `spans[i]` belongs to `nodes[i]`
+    pub spans: Vec<Span>,
 }
 
 impl Ast {
     pub fn new() -> Ast {
-        Ast { nodes: Vec::new() }
+        Ast {
+            nodes: Vec::new(),
+            spans: Vec::new(),
+        }
     }
 
-    pub fn add_node(&mut self, node: AstNode) {
+    pub fn add_node(&mut self, node: AstNode, span: Span) {
         self.nodes.push(node);
+        self.spans.push(span);
+    }
+}
+
+impl Default for Ast {
+    fn default() -> Ast {
+        Ast::new()
     }
 }
@@ -0,0 +1,579 @@
+use std::{collections::HashMap, mem, rc::Rc};
+
+use crate::{
+    ast::{Ast, AstNode, BinaryOp, Expr, Kwargs, Param, UnaryOp},
+    error::{Error, ErrorKind},
+    lexer::Span,
+    macros::Macro,
+    values::Value,
+};
+
+/// What a `Call` instruction invokes.
+pub enum Callee {
+    /// A macro by name, or `caller()` inside a macro invoked by a call block
+    Macro(String),
+    /// `name.attr(...)`: a macro of an imported template, or a method of the variable
+    Attribute(String, String),
+    /// A method of the value below the arguments on the stack
+    Method(String),
+}
+
+/// One step of the stack machine. Jump targets are indices into the same instruction list.
+pub enum Instruction {
+    /// Writes template text as is
+    EmitRaw(String),
+    /// Pops a value and writes it under the current escaping policy
+    Emit,
+    LoadConst(Value),
+    Lookup(String),
+    /// Pops a value and assigns it in the innermost scope
+    StoreLocal(String),
+    BuildList(usize),
+    /// Pops that many key/value pairs
+    BuildDict(usize),
+    GetAttr(String),
+    GetItem,
+    UnaryOp(UnaryOp),
+    /// Any operator except `and`/`or`, which compile to jumps
+    BinaryOp(BinaryOp),
+    Jump(usize),
+    /// Pops the condition
+    JumpIfFalse(usize),
+    /// Keeps a falsy value as the result of `and`, or pops it and evaluates the right side
+    JumpIfFalseOrPop(usize),
+    JumpIfTrueOrPop(usize),
+    /// Skips a macro parameter's default when the caller passed the argument
+    JumpIfBound(String, usize),
+    /// Pops the keyword and positional arguments, then the value to filter
+    ApplyFilter {
+        name: String,
+        args: usize,
+        kwargs: Vec<String>,
+    },
+    /// Pops the arguments, then the value to test
+    PerformTest {
+        name: String,
+        args: usize,
+    },
+    /// Pops the keyword and positional arguments. A call block passes its body as `caller`.
+    Call {
+        callee: Callee,
+        args: usize,
+        kwargs: Vec<String>,
+        caller: Option<Rc<Program>>,
+    },
+    PushScope,
+    PopScope,
+    /// Pops whether to escape until the matching `PopAutoEscape`
+    PushAutoEscape,
+    PopAutoEscape,
+    /// Redirects output into a buffer until `EndCapture` pushes it as a value
+    BeginCapture,
+    EndCapture,
+    /// Pops the iterable and opens a scope for the loop. A filtered loop first runs
+    /// through the items once, marking the ones to keep, then `RestartLoop`s over them.
+    PushLoop {
+        targets: Vec<String>,
+        filtered: bool,
+    },
+    /// Binds the next item, or jumps to the target when the items are exhausted
+    Iterate(usize),
+    KeepItem,
+    RestartLoop,
+    PushDidNotIterate,
+    PopLoop,
+    DefineMacro(Rc<Macro>),
+    /// Pops the template name and binds its macros to the alias
+    Import(String),
+    /// Pops the parent template name, which is rendered once this template finishes
+    Extends,
+    Block(String),
+    Super,
+    /// Pops the template name
+    Include {
+        ignore_missing: bool,
+    },
+    /// Pops the values of `variables` and writes the translation of the message with
+    /// them filled in
+    Trans {
+        singular: String,
+        plural: Option<String>,
+        count: Option<String>,
+        variables: Vec<String>,
+    },
+}
+
+/// The template a program was compiled from, for pointing errors at it.
+#[derive(Default)]
+pub struct Source {
+    pub name: String,
+    pub text: String,
+}
+
+/// A compiled template, or the body of a block, macro or call block.
+#[derive(Default)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    /// The tag each instruction was compiled from; `spans[i]` belongs to `instructions[i]`
+    pub spans: Vec<Span>,
+    /// Every block defined anywhere in the template, by name
+    pub blocks: HashMap<String, Rc<Program>>,
+    pub source: Rc<Source>,
+}
+
+impl Program {
+    /// Points an error raised by the instruction at `index` at its tag, unless the error
+    /// came from another template that located it already. Failures to write the output
+    /// are not the template's fault and stay unlocated.
+    pub fn locate(&self, error: Error, index: usize) -> Error {
+        match self.spans.get(index) {
+            Some(span) if error.span().is_none() && *error.kind() != ErrorKind::Output => error
+                .with_location(&self.source.text, *span)
+                .with_template(&self.source.name),
+            _ => error,
+        }
+    }
+
+    /// The macros defined by the template, for `import`.
+    pub fn macros(&self) -> impl Iterator<Item = &Rc<Macro>> {
+        self.instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::DefineMacro(macro_def) => Some(macro_def),
+                _ => None,
+            })
+    }
+}
+
+pub fn compile(name: &str, source: &str, ast: &Ast) -> Program {
+    let mut compiler = Compiler::new(Rc::new(Source {
+        name: name.to_string(),
+        text: source.to_string(),
+    }));
+
+    // A child template only renders through its parent's blocks, so its top level
+    // keeps just the statements that define something
+    let extends = ast
+        .nodes
+        .iter()
+        .any(|node| matches!(node, AstNode::Extends(_)));
+    for (node, span) in ast.nodes.iter().zip(&ast.spans) {
+        let start = compiler.instructions.len();
+        compiler.span = *span;
+        compiler.compile_node(node);
+        let keep = matches!(
+            node,
+            AstNode::Extends(_)
+                | AstNode::MacroDef { .. }
+                | AstNode::Import { .. }
+                | AstNode::Set { .. }
+        );
+        if extends && !keep {
+            // Blocks inside the dropped code have been registered already
+            compiler.instructions.truncate(start);
+            compiler.spans.truncate(start);
+        }
+    }
+    compiler.finish()
+}
+
+/// Compiles a macro that is not part of a template source.
+pub fn compile_macro(name: &str, params: &[Param], body: &Ast) -> Macro {
+    Compiler::default().compile_macro(name, params, body)
+}
+
+#[derive(Default)]
+struct Compiler {
+    instructions: Vec<Instruction>,
+    spans: Vec<Span>,
+    /// The tag being compiled, which the emitted instructions are attributed to
+    span: Span,
+    source: Rc<Source>,
+    blocks: HashMap<String, Rc<Program>>,
+    /// The `Iterate` instruction of each enclosing loop and the `break` jumps to patch
+    /// once its end is known, innermost last
+    loops: Vec<(usize, Vec<usize>)>,
+}
+
+impl Compiler {
+    fn new(source: Rc<Source>) -> Compiler {
+        Compiler {
+            source,
+            ..Compiler::default()
+        }
+    }
+
+    fn finish(self) -> Program {
+        Program {
+            instructions: self.instructions,
+            spans: self.spans,
+            blocks: self.blocks,
+            source: self.source,
+        }
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        self.instructions.push(instruction);
+        self.spans.push(self.span);
+        self.instructions.len() - 1
+    }
+
+    /// Points the jump at `at` to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let target = self.instructions.len();
+        match &mut self.instructions[at] {
+            Instruction::Jump(jump)
+            | Instruction::JumpIfFalse(jump)
+            | Instruction::JumpIfFalseOrPop(jump)
+            | Instruction::JumpIfTrueOrPop(jump)
+            | Instruction::JumpIfBound(_, jump)
+            | Instruction::Iterate(jump) => *jump = target,
+            _ => {}
+        }
+    }
+
+    /// Compiles a nested body into its own program, sharing the block table.
+    fn compile_program(&mut self, ast: &Ast) -> Rc<Program> {
+        let mut compiler = Compiler::new(Rc::clone(&self.source));
+        compiler.compile_nodes(ast);
+        for (name, block) in compiler.blocks.drain() {
+            self.blocks.entry(name).or_insert(block);
+        }
+        Rc::new(compiler.finish())
+    }
+
+    fn compile_nodes(&mut self, ast: &Ast) {
+        for (node, span) in ast.nodes.iter().zip(&ast.spans) {
+            let outer = mem::replace(&mut self.span, *span);
+            self.compile_node(node);
+            self.span = outer;
+        }
+    }
+
+    /// Compiles a macro body behind a prelude that evaluates defaults for missing arguments.
+    fn compile_macro(&self, name: &str, params: &[Param], body: &Ast) -> Macro {
+        let mut compiler = Compiler::new(Rc::clone(&self.source));
+        compiler.span = self.span;
+        for param in params {
+            if let Some(default) = &param.default {
+                let skip = compiler.emit(Instruction::JumpIfBound(param.name.clone(), 0));
+                compiler.compile_expr(default);
+                compiler.emit(Instruction::StoreLocal(param.name.clone()));
+                compiler.patch(skip);
+            }
+        }
+        compiler.compile_nodes(body);
+        Macro {
+            name: name.to_string(),
+            params: params.to_vec(),
+            program: Rc::new(compiler.finish()),
+        }
+    }
+
+    fn compile_node(&mut self, node: &AstNode) {
+        match node {
+            AstNode::Text(text) => {
+                self.emit(Instruction::EmitRaw(text.clone()));
+            }
+            AstNode::Variable(expr) => {
+                self.compile_expr(expr);
+                self.emit(Instruction::Emit);
+            }
+            AstNode::IfBlock {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                self.compile_expr(condition);
+                let jump_to_else = self.emit(Instruction::JumpIfFalse(0));
+                self.compile_nodes(then_block);
+                if let Some(else_block) = else_block {
+                    let jump_to_end = self.emit(Instruction::Jump(0));
+                    self.patch(jump_to_else);
+                    self.compile_nodes(else_block);
+                    self.patch(jump_to_end);
+                } else {
+                    self.patch(jump_to_else);
+                }
+            }
+            AstNode::ForBlock {
+                targets,
+                collection,
+                filter,
+                body,
+                else_block,
+            } => {
+                self.compile_expr(collection);
+                self.emit(Instruction::PushLoop {
+                    targets: targets.clone(),
+                    filtered: filter.is_some(),
+                });
+                if let Some(filter) = filter {
+                    let iterate = self.emit(Instruction::Iterate(0));
+                    self.compile_expr(filter);
+                    self.emit(Instruction::JumpIfFalse(iterate));
+                    self.emit(Instruction::KeepItem);
+                    self.emit(Instruction::Jump(iterate));
+                    self.patch(iterate);
+                    self.emit(Instruction::RestartLoop);
+                }
+
+                let iterate = self.emit(Instruction::Iterate(0));
+                self.loops.push((iterate, Vec::new()));
+                self.compile_nodes(body);
+                self.emit(Instruction::Jump(iterate));
+                self.patch(iterate);
+                if let Some((_, breaks)) = self.loops.pop() {
+                    for jump in breaks {
+                        self.patch(jump);
+                    }
+                }
+
+                if let Some(else_block) = else_block {
+                    self.emit(Instruction::PushDidNotIterate);
+                    self.emit(Instruction::PopLoop);
+                    let jump_to_end = self.emit(Instruction::JumpIfFalse(0));
+                    self.compile_nodes(else_block);
+                    self.patch(jump_to_end);
+                } else {
+                    self.emit(Instruction::PopLoop);
+                }
+            }
+            // The parser rejects loop controls outside of a loop of the same program, so a
+            // missing loop is never compiled into a jump that would go nowhere
+            AstNode::Break => {
+                if !self.loops.is_empty() {
+                    let jump = self.emit(Instruction::Jump(0));
+                    if let Some((_, breaks)) = self.loops.last_mut() {
+                        breaks.push(jump);
+                    }
+                }
+            }
+            AstNode::Continue => {
+                if let Some(&(iterate, _)) = self.loops.last() {
+                    self.emit(Instruction::Jump(iterate));
+                }
+            }
+            AstNode::MacroDef { name, params, body } => {
+                let macro_def = self.compile_macro(name, params, body);
+                self.emit(Instruction::DefineMacro(Rc::new(macro_def)));
+            }
+            AstNode::CallBlock { call, body } => {
+                if let Expr::Call { func, args, kwargs } = call {
+                    let caller = self.compile_program(body);
+                    self.compile_call(func, args, kwargs, Some(caller));
+                    self.emit(Instruction::Emit);
+                }
+            }
+            AstNode::Set { target, value } => {
+                self.compile_expr(value);
+                self.emit(Instruction::StoreLocal(target.clone()));
+            }
+            AstNode::SetBlock { target, body } => {
+                self.emit(Instruction::BeginCapture);
+                self.emit(Instruction::PushScope);
+                self.compile_nodes(body);
+                self.emit(Instruction::PopScope);
+                self.emit(Instruction::EndCapture);
+                self.emit(Instruction::StoreLocal(target.clone()));
+            }
+            AstNode::With { assignments, body } => {
+                // Every assignment is evaluated before any of them become visible
+                for (_, value) in assignments {
+                    self.compile_expr(value);
+                }
+                self.emit(Instruction::PushScope);
+                for (name, _) in assignments.iter().rev() {
+                    self.emit(Instruction::StoreLocal(name.clone()));
+                }
+                self.compile_nodes(body);
+                self.emit(Instruction::PopScope);
+            }
+            AstNode::Import { template, alias } => {
+                self.compile_expr(template);
+                self.emit(Instruction::Import(alias.clone()));
+            }
+            AstNode::Extends(parent) => {
+                self.compile_expr(parent);
+                self.emit(Instruction::Extends);
+            }
+            AstNode::Block { name, body } => {
+                let block = self.compile_program(body);
+                self.blocks.entry(name.clone()).or_insert(block);
+                self.emit(Instruction::Block(name.clone()));
+            }
+            AstNode::Super => {
+                self.emit(Instruction::Super);
+            }
+            AstNode::Include {
+                name,
+                ignore_missing,
+            } => {
+                self.compile_expr(name);
+                self.emit(Instruction::Include {
+                    ignore_missing: *ignore_missing,
+                });
+            }
+            AstNode::AutoEscape { enabled, body } => {
+                self.compile_expr(enabled);
+                self.emit(Instruction::PushAutoEscape);
+                self.compile_nodes(body);
+                self.emit(Instruction::PopAutoEscape);
+            }
+            AstNode::Trans {
+                singular,
+                plural,
+                count,
+                variables,
+            } => {
+                for (_, value) in variables {
+                    self.compile_expr(value);
+                }
+                self.emit(Instruction::Trans {
+                    singular: singular.clone(),
+                    plural: plural.clone(),
+                    count: count.clone(),
+                    variables: variables.iter().map(|(name, _)| name.clone()).collect(),
+                });
+            }
+        }
+    }
+
+    fn compile_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal(value) => {
+                self.emit(Instruction::LoadConst(value.clone()));
+            }
+            Expr::Name(name) => {
+                self.emit(Instruction::Lookup(name.clone()));
+            }
+            Expr::List(items) => {
+                for item in items {
+                    self.compile_expr(item);
+                }
+                self.emit(Instruction::BuildList(items.len()));
+            }
+            Expr::Dict(entries) => {
+                for (key, value) in entries {
+                    self.compile_expr(key);
+                    self.compile_expr(value);
+                }
+                self.emit(Instruction::BuildDict(entries.len()));
+            }
+            Expr::GetAttr(expr, name) => {
+                self.compile_expr(expr);
+                self.emit(Instruction::GetAttr(name.clone()));
+            }
+            Expr::GetItem(expr, key) => {
+                self.compile_expr(expr);
+                self.compile_expr(key);
+                self.emit(Instruction::GetItem);
+            }
+            Expr::Unary(op, expr) => {
+                self.compile_expr(expr);
+                self.emit(Instruction::UnaryOp(*op));
+            }
+            Expr::Binary(op @ (BinaryOp::And | BinaryOp::Or), lhs, rhs) => {
+                self.compile_expr(lhs);
+                let jump = self.emit(if *op == BinaryOp::And {
+                    Instruction::JumpIfFalseOrPop(0)
+                } else {
+                    Instruction::JumpIfTrueOrPop(0)
+                });
+                self.compile_expr(rhs);
+                self.patch(jump);
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                self.compile_expr(lhs);
+                self.compile_expr(rhs);
+                self.emit(Instruction::BinaryOp(*op));
+            }
+            Expr::Conditional {
+                condition,
+                then_expr,
+                else_expr,
+            } => {
+                self.compile_expr(condition);
+                let jump_to_else = self.emit(Instruction::JumpIfFalse(0));
+                self.compile_expr(then_expr);
+                let jump_to_end = self.emit(Instruction::Jump(0));
+                self.patch(jump_to_else);
+                match else_expr {
+                    Some(else_expr) => self.compile_expr(else_expr),
+                    // A conditional without `else` renders nothing
+                    None => {
+                        self.emit(Instruction::LoadConst(Value::String(String::new())));
+                    }
+                }
+                self.patch(jump_to_end);
+            }
+            Expr::Call { func, args, kwargs } => self.compile_call(func, args, kwargs, None),
+            Expr::Filter {
+                expr,
+                name,
+                args,
+                kwargs,
+            } => {
+                self.compile_expr(expr);
+                let kwargs = self.compile_args(args, kwargs);
+                self.emit(Instruction::ApplyFilter {
+                    name: name.clone(),
+                    args: args.len(),
+                    kwargs,
+                });
+            }
+            Expr::Test { expr, name, args } => {
+                self.compile_expr(expr);
+                for arg in args {
+                    self.compile_expr(arg);
+                }
+                self.emit(Instruction::PerformTest {
+                    name: name.clone(),
+                    args: args.len(),
+                });
+            }
+        }
+    }
+
+    fn compile_call(
+        &mut self,
+        func: &Expr,
+        args: &[Expr],
+        kwargs: &Kwargs,
+        caller: Option<Rc<Program>>,
+    ) {
+        let callee = match func {
+            Expr::Name(name) => Callee::Macro(name.clone()),
+            Expr::GetAttr(receiver, attr) => match &**receiver {
+                Expr::Name(name) => Callee::Attribute(name.clone(), attr.clone()),
+                receiver => {
+                    self.compile_expr(receiver);
+                    Callee::Method(attr.clone())
+                }
+            },
+            // The parser only produces calls of names and attributes
+            _ => return,
+        };
+        let kwargs = self.compile_args(args, kwargs);
+        self.emit(Instruction::Call {
+            callee,
+            args: args.len(),
+            kwargs,
+            caller,
+        });
+    }
+
+    /// Pushes the positional and then the keyword arguments, returning the keyword names.
+    fn compile_args(&mut self, args: &[Expr], kwargs: &Kwargs) -> Vec<String> {
+        for arg in args {
+            self.compile_expr(arg);
+        }
+        kwargs
+            .iter()
+            .map(|(name, arg)| {
+                self.compile_expr(arg);
+                name.clone()
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,170 @@
+use std::{collections::HashMap, fmt};
+
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+
+use crate::values::Value;
+
+/// Lets data files in any serde format feed templates directly, e.g.
+/// `serde_json::from_str::<Value>(source)`.
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any template value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Value, E> {
+        Ok(Value::Bool(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::Integer(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Value, E> {
+        // Integers beyond `i64` fall back to floats rather than failing
+        Ok(i64::try_from(v).map_or(Value::Float(v as f64), Value::Integer))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Value, E> {
+        Ok(Value::Float(v))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::String(v.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Value, E> {
+        Ok(Value::String(v))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Value, E> {
+        Ok(Value::List(
+            v.iter().map(|b| Value::Integer(*b as i64)).collect(),
+        ))
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        Value::deserialize(deserializer)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::None)
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Value, D::Error> {
+        Value::deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut items = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(item) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(Value::List(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut entries = HashMap::with_capacity(map.size_hint().unwrap_or(0));
+        // Formats like YAML allow non-string keys, which are stringified like `to_value` does
+        while let Some((key, value)) = map.next_entry::<Value, Value>()? {
+            let key = match key {
+                Value::String(s) | Value::SafeString(s) => s,
+                key @ (Value::Integer(_) | Value::Float(_) | Value::Bool(_)) => key.to_string(),
+                key => {
+                    return Err(de::Error::custom(format!(
+                        "Dict keys must be strings or numbers, found {}",
+                        key.type_name()
+                    )))
+                }
+            };
+            entries.insert(key, value);
+        }
+        Ok(Value::Dict(entries))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    #[test]
+    fn json_documents_become_values() {
+        let value: Value =
+            serde_json::from_str(r#"{"a": [1, 2.5, true, null, "x"], "b": {}}"#).unwrap();
+        assert_eq!(
+            value,
+            Value::Dict(HashMap::from([
+                (
+                    "a".to_string(),
+                    Value::List(vec![
+                        Value::Integer(1),
+                        Value::Float(2.5),
+                        Value::Bool(true),
+                        Value::None,
+                        string("x"),
+                    ]),
+                ),
+                ("b".to_string(), Value::Dict(HashMap::new())),
+            ]))
+        );
+    }
+
+    #[test]
+    fn values_round_trip_through_json() {
+        let value = Value::Dict(HashMap::from([
+            ("n".to_string(), Value::Integer(-3)),
+            (
+                "items".to_string(),
+                Value::List(vec![string("a"), Value::None, Value::Float(0.5)]),
+            ),
+        ]));
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), value);
+    }
+
+    #[test]
+    fn unsigned_integers_beyond_i64_become_floats() {
+        let value: Value = serde_json::from_str("18446744073709551615").unwrap();
+        assert_eq!(value, Value::Float(u64::MAX as f64));
+        let value: Value = serde_json::from_str("9223372036854775807").unwrap();
+        assert_eq!(value, Value::Integer(i64::MAX));
+    }
+
+    #[test]
+    fn yaml_keys_that_are_not_strings_are_stringified() {
+        let value: Value = serde_yaml::from_str("1: a\ntrue: b\n2.5: c\nname: d\n").unwrap();
+        assert_eq!(
+            value,
+            Value::Dict(HashMap::from([
+                ("1".to_string(), string("a")),
+                ("true".to_string(), string("b")),
+                ("2.5".to_string(), string("c")),
+                ("name".to_string(), string("d")),
+            ]))
+        );
+        let err = serde_yaml::from_str::<Value>("[1, 2]: a\n").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Dict keys must be strings or numbers, found list"));
+    }
+}
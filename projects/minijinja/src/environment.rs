@@ -0,0 +1,184 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use crate::{
+    compiler::{compile, Program},
+    error::{Error, ErrorKind},
+    filters::{Args, FilterFn, FilterRegistry},
+    functions::{FunctionFn, FunctionRegistry},
+    is_tests::{TestFn, TestRegistry},
+    lexer::WhitespaceConfig,
+    loader::Loader,
+    parser::Parser,
+    values::Value,
+};
+
+/// How values are escaped when they are written into the output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AutoEscape {
+    None,
+    Html,
+}
+
+/// Escapes HTML and XML templates, judging by the extension of the template name.
+pub fn default_auto_escape(name: &str) -> AutoEscape {
+    let extension = name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("html" | "htm" | "xhtml" | "xml") => AutoEscape::Html,
+        _ => AutoEscape::None,
+    }
+}
+
+/// How templates treat missing variables, attributes and keys.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum UndefinedBehavior {
+    /// Renders as an empty string, is false and iterates as an empty sequence
+    #[default]
+    Lenient,
+    /// Like `Lenient`, but attributes and methods of undefined values are undefined too
+    Chainable,
+    /// Using an undefined value for anything but `is defined` or `default` is an error
+    Strict,
+}
+
+/// Holds the template loader and caches compiled templates by name.
+pub struct Environment {
+    loader: Option<Box<dyn Loader>>,
+    templates: RefCell<HashMap<String, Rc<Program>>>,
+    auto_escape: fn(&str) -> AutoEscape,
+    filters: FilterRegistry,
+    tests: TestRegistry,
+    functions: FunctionRegistry,
+    undefined: UndefinedBehavior,
+    whitespace: WhitespaceConfig,
+}
+
+impl Environment {
+    pub fn new() -> Environment {
+        Environment {
+            loader: None,
+            templates: RefCell::new(HashMap::new()),
+            auto_escape: default_auto_escape,
+            filters: FilterRegistry::new(),
+            tests: TestRegistry::new(),
+            functions: FunctionRegistry::new(),
+            undefined: UndefinedBehavior::default(),
+            whitespace: WhitespaceConfig::default(),
+        }
+    }
+
+    pub fn set_filter_registry(&mut self, filters: FilterRegistry) {
+        self.filters = filters;
+    }
+
+    pub fn add_filter(&mut self, name: &str, filter: FilterFn) {
+        self.filters.add_filter(name, filter);
+    }
+
+    pub fn apply_filter(&self, name: &str, value: &Value, args: &Args) -> Result<Value, Error> {
+        self.filters.apply_filter(self, name, value, args)
+    }
+
+    pub fn set_test_registry(&mut self, tests: TestRegistry) {
+        self.tests = tests;
+    }
+
+    pub fn add_test(&mut self, name: &str, test: TestFn) {
+        self.tests.add_test(name, test);
+    }
+
+    pub fn perform_test(&self, name: &str, value: &Value, args: &[Value]) -> Result<bool, Error> {
+        self.tests.perform_test(self, name, value, args)
+    }
+
+    pub fn set_function_registry(&mut self, functions: FunctionRegistry) {
+        self.functions = functions;
+    }
+
+    /// Adds a global function. Macros of the same name take precedence.
+    pub fn add_function(&mut self, name: &str, function: FunctionFn) {
+        self.functions.add_function(name, function);
+    }
+
+    pub fn call_function(&self, name: &str, args: &Args) -> Result<Value, Error> {
+        self.functions.call_function(self, name, args)
+    }
+
+    pub fn set_undefined_behavior(&mut self, behavior: UndefinedBehavior) {
+        self.undefined = behavior;
+    }
+
+    pub fn undefined_behavior(&self) -> UndefinedBehavior {
+        self.undefined
+    }
+
+    /// Chooses the escaping policy for each template by name. Use `|_| AutoEscape::Html`
+    /// to escape everything regardless of the name.
+    pub fn set_auto_escape_callback(&mut self, callback: fn(&str) -> AutoEscape) {
+        self.auto_escape = callback;
+    }
+
+    pub fn auto_escape_for(&self, name: &str) -> AutoEscape {
+        (self.auto_escape)(name)
+    }
+
+    /// Removes the first newline after each block tag.
+    pub fn set_trim_blocks(&mut self, enabled: bool) {
+        self.whitespace.trim_blocks = enabled;
+        self.templates.borrow_mut().clear();
+    }
+
+    /// Removes the indentation before block tags that start a line.
+    pub fn set_lstrip_blocks(&mut self, enabled: bool) {
+        self.whitespace.lstrip_blocks = enabled;
+        self.templates.borrow_mut().clear();
+    }
+
+    pub fn set_loader(&mut self, loader: impl Loader + 'static) {
+        self.loader = Some(Box::new(loader));
+        self.templates.borrow_mut().clear();
+    }
+
+    /// Compiles the template on first use and returns the cached program afterwards.
+    pub fn get_template(&self, name: &str) -> Result<Rc<Program>, Error> {
+        self.load_template(name)?.ok_or_else(|| {
+            Error::new(
+                ErrorKind::TemplateNotFound,
+                format!("Template '{}' not found", name),
+            )
+        })
+    }
+
+    /// Like `get_template`, but a missing template is `None` rather than an error.
+    pub fn load_template(&self, name: &str) -> Result<Option<Rc<Program>>, Error> {
+        if let Some(program) = self.templates.borrow().get(name) {
+            return Ok(Some(Rc::clone(program)));
+        }
+
+        let Some(loader) = &self.loader else {
+            return Ok(None);
+        };
+        let source = loader
+            .get_source(name)
+            .map_err(|message| Error::new(ErrorKind::Loader, message))?;
+        let Some(source) = source else {
+            return Ok(None);
+        };
+        let mut parser = Parser::new(&source);
+        parser.set_whitespace(self.whitespace);
+        let ast = parser.parse().map_err(|err| err.with_template(name))?;
+
+        let program = Rc::new(compile(name, &source, &ast));
+        self.templates
+            .borrow_mut()
+            .insert(name.to_string(), Rc::clone(&program));
+        Ok(Some(program))
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Environment {
+        Environment::new()
+    }
+}
@@ -0,0 +1,189 @@
+use std::{fmt, io};
+
+use crate::{lexer::Span, sandbox::SandboxError};
+
+/// What went wrong, independent of the message wording.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    /// The template source could not be parsed
+    Syntax,
+    TemplateNotFound,
+    /// A loader failed to read a template
+    Loader,
+    UnknownFilter,
+    UnknownTest,
+    /// A call of a name that is neither a macro nor a function
+    UnknownFunction,
+    /// A translation catalog could not be read or parsed
+    Catalog,
+    /// A call of a macro an imported template doesn't define
+    UnknownMacro,
+    /// An operation the values don't support, such as `1 + "a"`, or bad arguments
+    InvalidOperation,
+    /// An undefined value used where the undefined behavior forbids it
+    Undefined,
+    /// Rendering ran into a limit of the sandbox
+    Sandbox(SandboxError),
+    /// The rendered output could not be written
+    Output,
+}
+
+/// An error from loading, parsing or rendering a template, with the place in the
+/// template it refers to when known.
+#[derive(Debug, Clone)]
+pub struct Error {
+    // Boxed to keep `Result`s small on the happy path
+    repr: Box<Repr>,
+}
+
+#[derive(Debug, Clone)]
+struct Repr {
+    kind: ErrorKind,
+    message: String,
+    template: Option<String>,
+    location: Option<Location>,
+}
+
+#[derive(Debug, Clone)]
+struct Location {
+    span: Span,
+    line: usize,
+    column: usize,
+    /// The line the span starts on, and how many of its characters the span covers
+    source_line: String,
+    width: usize,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Error {
+        Error {
+            repr: Box::new(Repr {
+                kind,
+                message: message.into(),
+                template: None,
+                location: None,
+            }),
+        }
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.repr.kind
+    }
+
+    /// The message without location or excerpt.
+    pub fn message(&self) -> &str {
+        &self.repr.message
+    }
+
+    pub fn template_name(&self) -> Option<&str> {
+        self.repr.template.as_deref()
+    }
+
+    /// The 1-based line the error refers to.
+    pub fn line(&self) -> Option<usize> {
+        self.repr.location.as_ref().map(|location| location.line)
+    }
+
+    /// The 1-based column, in characters, the error refers to.
+    pub fn column(&self) -> Option<usize> {
+        self.repr.location.as_ref().map(|location| location.column)
+    }
+
+    /// The byte range of the template source the error refers to.
+    pub fn span(&self) -> Option<Span> {
+        self.repr.location.as_ref().map(|location| location.span)
+    }
+
+    /// Names the template, unless an inner template was named already.
+    pub fn with_template(mut self, name: &str) -> Error {
+        if self.repr.template.is_none() {
+            self.repr.template = Some(name.to_string());
+        }
+        self
+    }
+
+    /// Points the error at `span` of `source`, unless it points somewhere already.
+    pub fn with_location(mut self, source: &str, span: Span) -> Error {
+        if self.repr.location.is_some() || span.start > source.len() {
+            return self;
+        }
+        let (line, column) = span.location(source);
+        let line_start = source[..span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[span.start..]
+            .find('\n')
+            .map_or(source.len(), |i| span.start + i);
+        self.repr.location = Some(Location {
+            span,
+            line,
+            column,
+            source_line: source[line_start..line_end]
+                .trim_end_matches('\r')
+                .to_string(),
+            width: source
+                .get(span.start..span.end.clamp(span.start, line_end))
+                .map_or(1, |covered| covered.chars().count()),
+        });
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.repr.message)?;
+        let name = self.repr.template.as_deref().unwrap_or("<template>");
+        let Some(location) = &self.repr.location else {
+            if let Some(name) = &self.repr.template {
+                write!(f, " (in {})", name)?;
+            }
+            return Ok(());
+        };
+
+        // The offending line with a caret under the span, in the style of rustc
+        let gutter = " ".repeat(location.line.to_string().len());
+        let before: String = location
+            .source_line
+            .chars()
+            .take(location.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        write!(
+            f,
+            "\n{} --> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}",
+            gutter,
+            name,
+            location.line,
+            location.column,
+            gutter,
+            location.line,
+            location.source_line,
+            gutter,
+            before,
+            "^".repeat(location.width.max(1))
+        )
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Errors from values, filters and tests are plain messages.
+impl From<String> for Error {
+    fn from(message: String) -> Error {
+        Error::new(ErrorKind::InvalidOperation, message)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::new(
+            ErrorKind::Output,
+            format!("Failed to write output: {}", err),
+        )
+    }
+}
+
+impl From<SandboxError> for Error {
+    fn from(violation: SandboxError) -> Error {
+        let message = violation.to_string();
+        Error::new(ErrorKind::Sandbox(violation), message)
+    }
+}
@@ -1,7 +1,98 @@
-use crate::values::Value;
-use std::collections::HashMap;
+use crate::{
+    environment::Environment,
+    error::{Error, ErrorKind},
+    sandbox::SizeBudget,
+    values::Value,
+};
+use std::{cmp::Ordering, collections::HashMap, iter, rc::Rc};
 
-pub type FilterFn = fn(&Value) -> Result<Value, String>;
+pub type FilterFn = fn(&Environment, &Value, &Args) -> Result<Value, String>;
+
+/// Arguments passed to a filter in `value | name(positional, keyword=value)`.
+#[derive(Default)]
+pub struct Args {
+    pub positional: Vec<Value>,
+    pub keyword: HashMap<String, Value>,
+    /// Set when rendering in a sandbox
+    pub budget: Option<Rc<SizeBudget>>,
+}
+
+impl Args {
+    pub fn new(positional: Vec<Value>, keyword: HashMap<String, Value>) -> Args {
+        Args {
+            positional,
+            keyword,
+            budget: None,
+        }
+    }
+
+    /// Checks a string of `len` bytes against the sandbox's budget, before building it.
+    pub fn check_len(&self, len: usize) -> Result<(), String> {
+        self.budget
+            .as_ref()
+            .map_or(Ok(()), |budget| budget.check_len(len))
+    }
+
+    /// Checks a list of `items` against the sandbox's budget, before building it.
+    pub fn check_items(&self, items: usize) -> Result<(), String> {
+        self.budget
+            .as_ref()
+            .map_or(Ok(()), |budget| budget.check_items(items))
+    }
+
+    /// Rejects arguments that `params` doesn't name, by position or keyword.
+    pub fn check(&self, filter: &str, params: &[&str]) -> Result<(), String> {
+        self.check_params("Filter", filter, params)
+    }
+
+    /// Like `check`, for a callable other than a filter, e.g. `kind` "Function".
+    pub fn check_params(&self, kind: &str, name: &str, params: &[&str]) -> Result<(), String> {
+        if self.positional.len() > params.len() {
+            return Err(format!(
+                "{} {} takes at most {} arguments, got {}",
+                kind,
+                name,
+                params.len(),
+                self.positional.len()
+            ));
+        }
+        match self.keyword.keys().find(|k| !params.contains(&k.as_str())) {
+            Some(unknown) => Err(format!(
+                "{} {} got an unexpected argument '{}'",
+                kind, name, unknown
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Looks an argument up by keyword, then by position.
+    pub fn get(&self, index: usize, name: &str) -> Option<&Value> {
+        self.keyword
+            .get(name)
+            .or_else(|| self.positional.get(index))
+    }
+
+    pub fn int(&self, index: usize, name: &str, default: i64) -> Result<i64, String> {
+        match self.get(index, name) {
+            None => Ok(default),
+            Some(Value::Integer(i)) => Ok(*i),
+            Some(other) => Err(format!(
+                "Argument '{}' must be an integer, got {}",
+                name,
+                other.type_name()
+            )),
+        }
+    }
+
+    pub fn bool(&self, index: usize, name: &str, default: bool) -> bool {
+        self.get(index, name).map_or(default, Value::is_truthy)
+    }
+
+    pub fn string(&self, index: usize, name: &str, default: &str) -> String {
+        self.get(index, name)
+            .map_or_else(|| default.to_string(), |v| v.to_string())
+    }
+}
 
 pub struct FilterRegistry {
     filters: HashMap<String, FilterFn>,
@@ -13,16 +104,59 @@ impl FilterRegistry {
             filters: HashMap::new(),
         };
 
-        registry.add_filter("upper", |v| match v {
-            Value::String(s) => Ok(Value::String(s.to_uppercase())),
-            _ => Err("upper filter only works on strings".to_string()),
+        registry.add_filter("upper", |_, v, args| {
+            args.check("upper", &[])?;
+            match v {
+                Value::String(s) => Ok(Value::String(s.to_uppercase())),
+                Value::SafeString(s) => Ok(Value::SafeString(s.to_uppercase())),
+                _ => Err("upper filter only works on strings".to_string()),
+            }
+        });
+
+        registry.add_filter("lower", |_, v, args| {
+            args.check("lower", &[])?;
+            match v {
+                Value::String(s) => Ok(Value::String(s.to_lowercase())),
+                Value::SafeString(s) => Ok(Value::SafeString(s.to_lowercase())),
+                _ => Err("lower filter only works on strings".to_string()),
+            }
         });
 
-        registry.add_filter("lower", |v| match v {
-            Value::String(s) => Ok(Value::String(s.to_lowercase())),
-            _ => Err("lower filter only works on strings".to_string()),
+        registry.add_filter("safe", |_, v, args| {
+            args.check("safe", &[])?;
+            Ok(Value::SafeString(v.to_string()))
         });
 
+        registry.add_filter("escape", escape);
+        registry.add_filter("e", escape);
+        registry.add_filter("default", default);
+        registry.add_filter("d", default);
+        registry.add_filter("length", length);
+        registry.add_filter("count", length);
+        registry.add_filter("join", join);
+        registry.add_filter("replace", replace);
+        registry.add_filter("trim", trim);
+        registry.add_filter("title", title);
+        registry.add_filter("capitalize", capitalize);
+        registry.add_filter("first", first);
+        registry.add_filter("last", last);
+        registry.add_filter("sort", sort);
+        registry.add_filter("reverse", reverse);
+        registry.add_filter("unique", unique);
+        registry.add_filter("map", map);
+        registry.add_filter("select", select);
+        registry.add_filter("reject", reject);
+        registry.add_filter("round", round);
+        registry.add_filter("int", int);
+        registry.add_filter("float", float);
+        registry.add_filter("tojson", tojson);
+        registry.add_filter("indent", indent);
+        registry.add_filter("truncate", truncate);
+        registry.add_filter("wordcount", wordcount);
+        registry.add_filter("dictsort", dictsort);
+        registry.add_filter("batch", batch);
+        registry.add_filter("slice", slice);
+
         registry
     }
 
@@ -30,18 +164,707 @@ impl FilterRegistry {
         self.filters.insert(name.to_string(), filter);
     }
 
-    pub fn apply_filter(&self, name: &str, value: &Value) -> Result<Value, String> {
-        if let Some(filter) = self.filters.get(name) {
-            filter(value)
+    pub fn apply_filter(
+        &self,
+        env: &Environment,
+        name: &str,
+        value: &Value,
+        args: &Args,
+    ) -> Result<Value, Error> {
+        match self.filters.get(name) {
+            Some(filter) => Ok(filter(env, value, args)?),
+            None => Err(Error::new(
+                ErrorKind::UnknownFilter,
+                format!("Filter {} not found", name),
+            )),
+        }
+    }
+}
+
+/// Escapes a value for HTML, leaving strings marked safe untouched.
+fn escape(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("escape", &[])?;
+    match value {
+        Value::SafeString(_) => Ok(value.clone()),
+        _ => Ok(Value::SafeString(escape_html(&value.to_string()))),
+    }
+}
+
+pub fn escape_html(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Replaces undefined values and `none` (or any falsy value with `boolean=true`) with a fallback.
+fn default(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("default", &["default_value", "boolean"])?;
+    let fallback = args
+        .get(0, "default_value")
+        .cloned()
+        .unwrap_or_else(|| Value::String(String::new()));
+    let missing = match value {
+        Value::None | Value::Undefined => true,
+        _ => args.bool(1, "boolean", false) && !value.is_truthy(),
+    };
+    Ok(if missing { fallback } else { value.clone() })
+}
+
+fn length(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("length", &[])?;
+    let len = match value {
+        Value::String(s) | Value::SafeString(s) => s.chars().count(),
+        Value::List(list) => list.len(),
+        Value::Dict(dict) => dict.len(),
+        _ => return Err(format!("{} has no length", value.type_name())),
+    };
+    Ok(Value::Integer(len as i64))
+}
+
+fn join(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("join", &["d", "attribute"])?;
+    let separator = args.string(0, "d", "");
+    let items = attribute_values(value.to_list()?, args.get(1, "attribute"))?;
+    let joined: Vec<String> = items.iter().map(|item| item.to_string()).collect();
+    Ok(Value::String(joined.join(&separator)))
+}
+
+fn replace(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("replace", &["old", "new", "count"])?;
+    let (Some(old), Some(new)) = (args.get(0, "old"), args.get(1, "new")) else {
+        return Err("Filter replace needs the old and new strings".to_string());
+    };
+    let (s, old, new) = (value.to_string(), old.to_string(), new.to_string());
+    let count = match args.get(2, "count") {
+        Some(_) => usize::try_from(args.int(2, "count", 0)?.max(0)).unwrap_or(usize::MAX),
+        None => usize::MAX,
+    };
+
+    // Work out the length first, as replacing can multiply it
+    let matches = s.match_indices(&old).take(count).count();
+    let len = new
+        .len()
+        .checked_mul(matches)
+        .and_then(|added| (s.len() - old.len() * matches).checked_add(added));
+    let mut replaced = string_with_capacity(args, "replace", len)?;
+    let mut last = 0;
+    for (start, _) in s.match_indices(&old).take(count) {
+        replaced.push_str(&s[last..start]);
+        replaced.push_str(&new);
+        last = start + old.len();
+    }
+    replaced.push_str(&s[last..]);
+    Ok(Value::String(replaced))
+}
+
+fn trim(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("trim", &["chars"])?;
+    let s = value.to_string();
+    let trimmed = match args.get(0, "chars") {
+        Some(chars) => {
+            let chars: Vec<char> = chars.to_string().chars().collect();
+            s.trim_matches(chars.as_slice()).to_string()
+        }
+        None => s.trim().to_string(),
+    };
+    Ok(Value::String(trimmed))
+}
+
+fn title(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("title", &[])?;
+    let mut titled = String::new();
+    let mut word_start = true;
+    for c in value.to_string().chars() {
+        if word_start {
+            titled.extend(c.to_uppercase());
         } else {
-            Err(format!("Filter {} not found", name))
+            titled.extend(c.to_lowercase());
         }
+        word_start = !c.is_alphanumeric();
     }
+    Ok(Value::String(titled))
 }
 
-pub fn apply_filter(name: &str, value: &Value) -> Result<Value, String> {
-    let registry = FilterRegistry::new();
-    registry.apply_filter(name, value)
+fn capitalize(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("capitalize", &[])?;
+    let s = value.to_string();
+    let mut chars = s.chars();
+    let capitalized = match chars.next() {
+        Some(first) => first
+            .to_uppercase()
+            .chain(chars.as_str().to_lowercase().chars())
+            .collect(),
+        None => String::new(),
+    };
+    Ok(Value::String(capitalized))
+}
+
+fn first(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("first", &[])?;
+    Ok(value.to_list()?.into_iter().next().unwrap_or(Value::None))
+}
+
+fn last(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("last", &[])?;
+    Ok(value.to_list()?.pop().unwrap_or(Value::None))
+}
+
+/// Orders values for `sort`, `unique` and `dictsort`; strings ignore case unless asked not to.
+fn sort_key(value: &Value, case_sensitive: bool) -> Value {
+    match value {
+        Value::String(s) | Value::SafeString(s) if !case_sensitive => {
+            Value::String(s.to_lowercase())
+        }
+        _ => value.clone(),
+    }
+}
+
+fn sort(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("sort", &["reverse", "case_sensitive", "attribute"])?;
+    let case_sensitive = args.bool(1, "case_sensitive", false);
+    let items = value.to_list()?;
+    let keys = attribute_values(items.clone(), args.get(2, "attribute"))?;
+
+    let mut keyed: Vec<(Value, Value)> = keys
+        .iter()
+        .map(|key| sort_key(key, case_sensitive))
+        .zip(items)
+        .collect();
+    let mut error = None;
+    keyed.sort_by(|(a, _), (b, _)| {
+        a.compare(b).unwrap_or_else(|err| {
+            error.get_or_insert(err);
+            Ordering::Equal
+        })
+    });
+    if let Some(err) = error {
+        return Err(err);
+    }
+    if args.bool(0, "reverse", false) {
+        keyed.reverse();
+    }
+    Ok(Value::List(
+        keyed.into_iter().map(|(_, item)| item).collect(),
+    ))
+}
+
+fn reverse(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("reverse", &[])?;
+    match value {
+        Value::String(s) | Value::SafeString(s) => Ok(Value::String(s.chars().rev().collect())),
+        _ => Ok(Value::List(value.to_list()?.into_iter().rev().collect())),
+    }
+}
+
+fn unique(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("unique", &["case_sensitive", "attribute"])?;
+    let case_sensitive = args.bool(0, "case_sensitive", false);
+    let items = value.to_list()?;
+    let keys = attribute_values(items.clone(), args.get(1, "attribute"))?;
+
+    let mut seen: Vec<Value> = Vec::new();
+    let mut unique = Vec::new();
+    for (key, item) in keys.iter().zip(items) {
+        let key = sort_key(key, case_sensitive);
+        if !seen.iter().any(|s| s.loose_eq(&key)) {
+            seen.push(key);
+            unique.push(item);
+        }
+    }
+    Ok(Value::List(unique))
+}
+
+/// Resolves a dotted attribute path such as `"user.name"` on each item, if one is given.
+fn attribute_values(items: Vec<Value>, attribute: Option<&Value>) -> Result<Vec<Value>, String> {
+    let Some(attribute) = attribute else {
+        return Ok(items);
+    };
+    let path = attribute.to_string();
+    items
+        .into_iter()
+        .map(|item| {
+            path.split('.')
+                .try_fold(item, |value, part| match part.parse::<i64>() {
+                    Ok(index) => value.get_item(&Value::Integer(index)),
+                    Err(_) => value.get_attr(part),
+                })
+        })
+        .collect()
+}
+
+/// The attribute path a built-in filter will resolve on its items, if any.
+pub fn attribute_argument<'a>(filter: &str, args: &'a Args) -> Option<&'a Value> {
+    match filter {
+        "join" | "unique" => args.get(1, "attribute"),
+        "sort" => args.get(2, "attribute"),
+        _ => args.keyword.get("attribute"),
+    }
+}
+
+/// `map(attribute="name")` picks an attribute from every item, `map("upper")` applies a filter.
+fn map(env: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    let items = value.to_list()?;
+    if let Some(attribute) = args.keyword.get("attribute") {
+        args.check("map", &["attribute", "default"])?;
+        let default = args.keyword.get("default");
+        let path = attribute.to_string();
+        let mapped = items
+            .into_iter()
+            .map(
+                |item| match (attribute_values(vec![item], Some(attribute)), default) {
+                    (Ok(mut found), _) => Ok(found.remove(0)),
+                    (Err(_), Some(default)) => Ok(default.clone()),
+                    (Err(_), None) => Err(format!("Item has no attribute '{}'", path)),
+                },
+            )
+            .collect::<Result<_, String>>()?;
+        return Ok(Value::List(mapped));
+    }
+
+    let Some((filter, rest)) = args.positional.split_first() else {
+        return Err("Filter map needs a filter name or attribute=".to_string());
+    };
+    let mut filter_args = Args::new(rest.to_vec(), args.keyword.clone());
+    filter_args.budget = args.budget.clone();
+    let mapped = items
+        .iter()
+        .map(|item| {
+            env.apply_filter(&filter.to_string(), item, &filter_args)
+                .map_err(|err| err.to_string())
+        })
+        .collect::<Result<_, String>>()?;
+    Ok(Value::List(mapped))
+}
+
+fn select(env: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    filter_by_test(env, value, args, true)
+}
+
+fn reject(env: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    filter_by_test(env, value, args, false)
+}
+
+/// Keeps the items for which the named test (or plain truthiness) equals `keep`.
+fn filter_by_test(
+    env: &Environment,
+    value: &Value,
+    args: &Args,
+    keep: bool,
+) -> Result<Value, String> {
+    if !args.keyword.is_empty() {
+        return Err("Filters select and reject take no keyword arguments".to_string());
+    }
+    let mut selected = Vec::new();
+    for item in value.to_list()? {
+        let passed = match args.positional.split_first() {
+            Some((test, test_args)) => env
+                .perform_test(&test.to_string(), &item, test_args)
+                .map_err(|err| err.to_string())?,
+            None => item.is_truthy(),
+        };
+        if passed == keep {
+            selected.push(item);
+        }
+    }
+    Ok(Value::List(selected))
+}
+
+fn round(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("round", &["precision", "method"])?;
+    let number = match value {
+        Value::Integer(i) => *i as f64,
+        Value::Float(f) => *f,
+        _ => return Err(format!("Cannot round {}", value.type_name())),
+    };
+    let precision = args.int(0, "precision", 0)?;
+    // Beyond this, the factor is no longer a finite, nonzero float
+    if !(-308..=308).contains(&precision) {
+        return Err(format!(
+            "Filter round needs a precision from -308 to 308, got {}",
+            precision
+        ));
+    }
+    let factor = 10f64.powi(precision as i32);
+    let scaled = number * factor;
+    if !scaled.is_finite() {
+        // Too precise to change anything
+        return Ok(Value::Float(number));
+    }
+    let rounded = match args.string(1, "method", "common").as_str() {
+        "common" => scaled.round(),
+        "ceil" => scaled.ceil(),
+        "floor" => scaled.floor(),
+        method => return Err(format!("Unknown rounding method '{}'", method)),
+    };
+    Ok(Value::Float(rounded / factor))
+}
+
+fn int(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("int", &["default", "base"])?;
+    let default = args.int(0, "default", 0)?;
+    let base = args.int(1, "base", 10)?;
+    if !(2..=36).contains(&base) {
+        return Err(format!(
+            "Filter int needs a base from 2 to 36, got {}",
+            base
+        ));
+    }
+    let converted = match value {
+        Value::Integer(i) => Some(*i),
+        Value::Float(f) if f.is_finite() => Some(f.trunc() as i64),
+        Value::Bool(b) => Some(*b as i64),
+        Value::String(s) | Value::SafeString(s) => {
+            let s = s.trim();
+            let digits = match base {
+                16 => s.trim_start_matches("0x"),
+                8 => s.trim_start_matches("0o"),
+                2 => s.trim_start_matches("0b"),
+                _ => s,
+            };
+            i64::from_str_radix(digits, base as u32).ok().or_else(|| {
+                s.parse::<f64>()
+                    .ok()
+                    .filter(|f| f.is_finite())
+                    .map(|f| f as i64)
+            })
+        }
+        _ => None,
+    };
+    Ok(Value::Integer(converted.unwrap_or(default)))
+}
+
+fn float(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("float", &["default"])?;
+    let default = match args.get(0, "default") {
+        None => 0.0,
+        Some(Value::Integer(i)) => *i as f64,
+        Some(Value::Float(f)) => *f,
+        Some(other) => {
+            return Err(format!(
+                "Argument 'default' must be a number, got {}",
+                other.type_name()
+            ))
+        }
+    };
+    let converted = match value {
+        Value::Integer(i) => Some(*i as f64),
+        Value::Float(f) => Some(*f),
+        Value::Bool(b) => Some(*b as i64 as f64),
+        Value::String(s) | Value::SafeString(s) => s.trim().parse().ok(),
+        _ => None,
+    };
+    Ok(Value::Float(converted.unwrap_or(default)))
+}
+
+/// Serializes to JSON that is safe to embed in HTML, including inside `<script>` tags.
+fn tojson(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("tojson", &["indent"])?;
+    let indent = match args.get(0, "indent") {
+        None | Some(Value::None) => None,
+        Some(_) => Some(args.int(0, "indent", 0)?.max(0) as usize),
+    };
+    let mut out = String::new();
+    write_json(value, indent, 0, &mut out, args)?;
+    Ok(Value::SafeString(out))
+}
+
+/// Writes `value` as JSON, checking the output against the sandbox's budget in `args`
+/// as it grows.
+fn write_json(
+    value: &Value,
+    indent: Option<usize>,
+    level: usize,
+    out: &mut String,
+    args: &Args,
+) -> Result<(), String> {
+    let newline = |out: &mut String, level: usize| {
+        if let Some(width) = indent {
+            out.push('\n');
+            push_spaces(args, "tojson", out, width.checked_mul(level))?;
+        }
+        Ok::<_, String>(())
+    };
+    match value {
+        Value::None | Value::Undefined => out.push_str("null"),
+        Value::Bool(b) => out.push_str(&b.to_string()),
+        Value::Integer(i) => out.push_str(&i.to_string()),
+        Value::Float(f) if f.is_finite() => out.push_str(&format!("{:?}", f)),
+        Value::Float(_) => out.push_str("null"),
+        Value::String(s) | Value::SafeString(s) => write_json_string(s, out),
+        Value::Object(object) => write_json_string(&object.render(), out),
+        Value::List(list) => {
+            out.push('[');
+            for (i, item) in list.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                    if indent.is_none() {
+                        out.push(' ');
+                    }
+                }
+                newline(out, level + 1)?;
+                write_json(item, indent, level + 1, out, args)?;
+                args.check_len(out.len())?;
+            }
+            if !list.is_empty() {
+                newline(out, level)?;
+            }
+            out.push(']');
+        }
+        Value::Dict(dict) => {
+            // Sorted keys keep the output stable between renders
+            let mut keys: Vec<&String> = dict.keys().collect();
+            keys.sort();
+            out.push('{');
+            for (i, key) in keys.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                    if indent.is_none() {
+                        out.push(' ');
+                    }
+                }
+                newline(out, level + 1)?;
+                write_json_string(key, out);
+                out.push_str(": ");
+                write_json(&dict[*key], indent, level + 1, out, args)?;
+                args.check_len(out.len())?;
+            }
+            if !keys.is_empty() {
+                newline(out, level)?;
+            }
+            out.push('}');
+        }
+    }
+    Ok(())
+}
+
+fn write_json_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '<' | '>' | '&' | '\'' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn indent(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("indent", &["width", "first", "blank"])?;
+    let prefix = match args.get(0, "width") {
+        Some(Value::String(s)) => s.clone(),
+        _ => {
+            let width = args.int(0, "width", 4)?.max(0);
+            let mut prefix = String::new();
+            push_spaces(args, "indent", &mut prefix, usize::try_from(width).ok())?;
+            prefix
+        }
+    };
+    let (first, blank) = (args.bool(1, "first", false), args.bool(2, "blank", false));
+
+    let s = value.to_string();
+    let lines = s.split('\n').count();
+    let len = prefix
+        .len()
+        .checked_mul(lines)
+        .and_then(|len| len.checked_add(s.len()));
+    let mut out = string_with_capacity(args, "indent", len)?;
+    for (i, line) in s.split('\n').enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        let wanted = if i == 0 { first } else { true };
+        if wanted && (blank || !line.trim().is_empty()) {
+            out.push_str(&prefix);
+        }
+        out.push_str(line);
+    }
+    Ok(Value::String(out))
+}
+
+/// Shortens text to `length` characters, cutting at a word boundary unless `killwords` is set.
+/// Text that is at most `leeway` characters too long is left alone.
+fn truncate(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("truncate", &["length", "killwords", "end", "leeway"])?;
+    let length = args.int(0, "length", 255)?.max(0) as usize;
+    let killwords = args.bool(1, "killwords", false);
+    let end = args.string(2, "end", "...");
+    let leeway = args.int(3, "leeway", 5)?.max(0) as usize;
+
+    let s = value.to_string();
+    let chars: Vec<char> = s.chars().collect();
+    if chars.len() <= length + leeway {
+        return Ok(Value::String(s));
+    }
+    let keep = length.saturating_sub(end.chars().count());
+    let mut kept: String = chars[..keep].iter().collect();
+    if !killwords {
+        if let Some((head, _)) = kept.rsplit_once(' ') {
+            kept = head.to_string();
+        }
+    }
+    Ok(Value::String(kept + &end))
+}
+
+fn wordcount(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("wordcount", &[])?;
+    let count = value
+        .to_string()
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty())
+        .count();
+    Ok(Value::Integer(count as i64))
+}
+
+/// Turns a dict into a list of `[key, value]` pairs sorted by key or value.
+fn dictsort(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("dictsort", &["case_sensitive", "by", "reverse"])?;
+    let Value::Dict(dict) = value else {
+        return Err(format!(
+            "Filter dictsort expects a dict, got {}",
+            value.type_name()
+        ));
+    };
+    let case_sensitive = args.bool(0, "case_sensitive", false);
+    let by_value = match args.string(1, "by", "key").as_str() {
+        "key" => false,
+        "value" => true,
+        other => {
+            return Err(format!(
+                "Can only sort by 'key' or 'value', not '{}'",
+                other
+            ))
+        }
+    };
+
+    let mut pairs: Vec<(&String, &Value)> = dict.iter().collect();
+    let mut error = None;
+    pairs.sort_by(|a, b| {
+        let (a, b) = if by_value {
+            (sort_key(a.1, case_sensitive), sort_key(b.1, case_sensitive))
+        } else {
+            (
+                sort_key(&Value::String(a.0.clone()), case_sensitive),
+                sort_key(&Value::String(b.0.clone()), case_sensitive),
+            )
+        };
+        a.compare(&b).unwrap_or_else(|err| {
+            error.get_or_insert(err);
+            Ordering::Equal
+        })
+    });
+    if let Some(err) = error {
+        return Err(err);
+    }
+    if args.bool(2, "reverse", false) {
+        pairs.reverse();
+    }
+    Ok(Value::List(
+        pairs
+            .into_iter()
+            .map(|(k, v)| Value::List(vec![Value::String(k.clone()), v.clone()]))
+            .collect(),
+    ))
+}
+
+/// Splits items into rows of `linecount`, padding the last row with `fill_with` if given.
+fn batch(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("batch", &["linecount", "fill_with"])?;
+    let size = args.int(0, "linecount", 0)?;
+    if size <= 0 {
+        return Err("Filter batch needs a positive linecount".to_string());
+    }
+    let mut rows: Vec<Value> = value
+        .to_list()?
+        .chunks(size as usize)
+        .map(|chunk| Value::List(chunk.to_vec()))
+        .collect();
+    if let (Some(fill), Some(Value::List(last))) = (args.get(1, "fill_with"), rows.last_mut()) {
+        args.check_items(size as usize)?;
+        last.try_reserve_exact(size as usize - last.len())
+            .map_err(|_| too_large("batch"))?;
+        last.resize(size as usize, fill.clone());
+    }
+    Ok(Value::List(rows))
+}
+
+/// Splits items into `slices` columns of nearly equal length, the longer ones first.
+fn slice(_: &Environment, value: &Value, args: &Args) -> Result<Value, String> {
+    args.check("slice", &["slices", "fill_with"])?;
+    let slices = args.int(0, "slices", 0)?;
+    if slices <= 0 {
+        return Err("Filter slice needs a positive number of slices".to_string());
+    }
+    let slices = slices as usize;
+    let items = value.to_list()?;
+    let per_slice = items.len() / slices;
+    let with_extra = items.len() % slices;
+    let fill = args.get(1, "fill_with");
+
+    args.check_items(slices)?;
+    let mut columns = Vec::new();
+    columns
+        .try_reserve_exact(slices)
+        .map_err(|_| too_large("slice"))?;
+    let mut start = 0;
+    for n in 0..slices {
+        let len = per_slice + usize::from(n < with_extra);
+        let mut column = items[start..start + len].to_vec();
+        if let (Some(fill), true) = (fill, n >= with_extra && with_extra > 0) {
+            column.push(fill.clone());
+        }
+        columns.push(Value::List(column));
+        start += len;
+    }
+    Ok(Value::List(columns))
+}
+
+fn too_large(filter: &str) -> String {
+    format!("Filter {} would produce a value too large to hold", filter)
+}
+
+/// An empty string with room for `len` bytes, where `None` is a length that overflowed.
+/// Lengths over the sandbox's budget or that can't be allocated are an error rather than
+/// an abort.
+fn string_with_capacity(args: &Args, filter: &str, len: Option<usize>) -> Result<String, String> {
+    let len = len.ok_or_else(|| too_large(filter))?;
+    args.check_len(len)?;
+    let mut s = String::new();
+    s.try_reserve_exact(len).map_err(|_| too_large(filter))?;
+    Ok(s)
+}
+
+fn push_spaces(
+    args: &Args,
+    filter: &str,
+    out: &mut String,
+    n: Option<usize>,
+) -> Result<(), String> {
+    let n = n.ok_or_else(|| too_large(filter))?;
+    args.check_len(out.len().saturating_add(n))?;
+    out.try_reserve(n).map_err(|_| too_large(filter))?;
+    out.extend(iter::repeat_n(' ', n));
+    Ok(())
+}
+
+impl Default for FilterRegistry {
+    fn default() -> FilterRegistry {
+        FilterRegistry::new()
+    }
 }
 
 impl Clone for FilterRegistry {
@@ -51,3 +874,118 @@ impl Clone for FilterRegistry {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(name: &str, value: Value, args: Vec<Value>) -> Result<Value, Error> {
+        Environment::new().apply_filter(name, &value, &Args::new(args, HashMap::new()))
+    }
+
+    fn list(items: impl IntoIterator<Item = i64>) -> Value {
+        Value::List(items.into_iter().map(Value::Integer).collect())
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    #[test]
+    fn int_parses_in_any_base_from_2_to_36() {
+        for (input, base, expected) in [
+            ("42", 10, 42),
+            ("0x1f", 16, 31),
+            ("0b101", 2, 5),
+            ("z", 36, 35),
+        ] {
+            let value = apply(
+                "int",
+                string(input),
+                vec![Value::Integer(0), Value::Integer(base)],
+            );
+            assert_eq!(value.unwrap(), Value::Integer(expected), "{}", input);
+        }
+        let value = apply("int", string("nope"), vec![Value::Integer(7)]);
+        assert_eq!(value.unwrap(), Value::Integer(7));
+
+        for base in [-1, 0, 1, 37, i64::MAX] {
+            let err = apply(
+                "int",
+                string("1"),
+                vec![Value::Integer(0), Value::Integer(base)],
+            );
+            assert_eq!(
+                err.unwrap_err().message(),
+                format!("Filter int needs a base from 2 to 36, got {}", base)
+            );
+        }
+    }
+
+    #[test]
+    fn round_rejects_precisions_out_of_range() {
+        let round = |value, precision| apply("round", value, vec![Value::Integer(precision)]);
+        assert_eq!(round(Value::Float(1.25), 1).unwrap(), Value::Float(1.3));
+        assert_eq!(
+            round(Value::Integer(1234), -2).unwrap(),
+            Value::Float(1200.0)
+        );
+        assert_eq!(round(Value::Float(1.5), 308).unwrap(), Value::Float(1.5));
+        assert_eq!(round(Value::Float(1e10), 308).unwrap(), Value::Float(1e10));
+        assert_eq!(round(Value::Integer(10), -308).unwrap(), Value::Float(0.0));
+        for precision in [i64::MAX, 309, -309, -400] {
+            let err = round(Value::Float(1.5), precision).unwrap_err();
+            assert!(err.message().contains("precision from -308 to 308"));
+        }
+    }
+
+    #[test]
+    fn batch_and_slice_split_lists() {
+        let batched = apply(
+            "batch",
+            list(1..=3),
+            vec![Value::Integer(2), Value::Integer(0)],
+        );
+        assert_eq!(
+            batched.unwrap(),
+            Value::List(vec![list([1, 2]), list([3, 0])])
+        );
+        let sliced = apply("slice", list(1..=5), vec![Value::Integer(2)]);
+        assert_eq!(
+            sliced.unwrap(),
+            Value::List(vec![list([1, 2, 3]), list([4, 5])])
+        );
+    }
+
+    #[test]
+    fn indent_and_tojson_format_text() {
+        let indented = apply("indent", string("a\n\nb"), vec![Value::Integer(2)]);
+        assert_eq!(indented.unwrap(), string("a\n\n  b"));
+
+        let dict = Value::Dict(HashMap::from([
+            ("b".to_string(), list([1])),
+            ("a".to_string(), string("</script>")),
+        ]));
+        let json = apply("tojson", dict, vec![Value::Integer(1)]);
+        assert_eq!(
+            json.unwrap(),
+            Value::SafeString(
+                "{\n \"a\": \"\\u003c/script\\u003e\",\n \"b\": [\n  1\n ]\n}".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn huge_sizes_are_errors_rather_than_aborts() {
+        let huge = Value::Integer(i64::MAX);
+        for (name, value, args) in [
+            ("slice", list([1]), vec![huge.clone()]),
+            ("batch", list([1]), vec![huge.clone(), Value::None]),
+            ("indent", string("a\nb"), vec![huge.clone()]),
+            ("tojson", list([1]), vec![huge.clone()]),
+        ] {
+            let err = apply(name, value, args).unwrap_err();
+            assert_eq!(err.message(), too_large(name));
+        }
+    }
+}
@@ -0,0 +1,535 @@
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    environment::Environment,
+    error::{Error, ErrorKind},
+    filters::Args,
+    values::{Object, Value},
+};
+
+pub type FunctionFn = fn(&Environment, &Args) -> Result<Value, String>;
+
+/// Keeps `range()` from allocating more than templates could reasonably loop over.
+const MAX_RANGE: i128 = 1_000_000;
+
+/// Global functions such as `range(10)`, callable wherever a macro is.
+pub struct FunctionRegistry {
+    functions: HashMap<String, FunctionFn>,
+}
+
+impl FunctionRegistry {
+    pub fn new() -> FunctionRegistry {
+        let mut registry = FunctionRegistry {
+            functions: HashMap::new(),
+        };
+
+        registry.add_function("range", range);
+        registry.add_function("dict", |_, args| {
+            if !args.positional.is_empty() {
+                return Err("dict() only takes keyword arguments".to_string());
+            }
+            Ok(Value::Dict(args.keyword.clone()))
+        });
+        registry.add_function("lipsum", lipsum);
+        registry.add_function("now", |_, args| {
+            args.check_params("Function", "now", &[])?;
+            let elapsed = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(|err| err.to_string())?;
+            Ok(Value::Object(Rc::new(DateTime::from_timestamp(
+                elapsed.as_secs() as i64,
+            ))))
+        });
+        registry.add_function("cycler", |_, args| {
+            if args.positional.is_empty() || !args.keyword.is_empty() {
+                return Err("cycler() takes one or more items".to_string());
+            }
+            Ok(Value::Object(Rc::new(Cycler {
+                items: args.positional.clone(),
+                position: Cell::new(0),
+            })))
+        });
+
+        registry
+    }
+
+    pub fn add_function(&mut self, name: &str, function: FunctionFn) {
+        self.functions.insert(name.to_string(), function);
+    }
+
+    pub fn call_function(
+        &self,
+        env: &Environment,
+        name: &str,
+        args: &Args,
+    ) -> Result<Value, Error> {
+        match self.functions.get(name) {
+            Some(function) => Ok(function(env, args)?),
+            None => Err(Error::new(
+                ErrorKind::UnknownFunction,
+                format!("Function {} not found", name),
+            )),
+        }
+    }
+}
+
+impl Default for FunctionRegistry {
+    fn default() -> FunctionRegistry {
+        FunctionRegistry::new()
+    }
+}
+
+/// `range(stop)`, `range(start, stop)` or `range(start, stop, step)`, as in Python.
+fn range(_: &Environment, args: &Args) -> Result<Value, String> {
+    if !args.keyword.is_empty() {
+        return Err("range() takes no keyword arguments".to_string());
+    }
+    let numbers = args
+        .positional
+        .iter()
+        .map(|value| match value {
+            Value::Integer(i) => Ok(*i),
+            value => Err(format!("range() needs integers, not {}", value.type_name())),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let (start, stop, step) = match numbers[..] {
+        [stop] => (0, stop, 1),
+        [start, stop] => (start, stop, 1),
+        [start, stop, step] => (start, stop, step),
+        _ => return Err("range() takes one to three arguments".to_string()),
+    };
+    if step == 0 {
+        return Err("range() step must not be zero".to_string());
+    }
+
+    let (distance, stride) = (stop as i128 - start as i128, step as i128);
+    let len = if step > 0 {
+        (distance + stride - 1) / stride
+    } else {
+        (distance + stride + 1) / stride
+    };
+    if len > MAX_RANGE {
+        return Err(format!("range() is limited to {} items", MAX_RANGE));
+    }
+    args.check_items(len.max(0) as usize)?;
+    // Every item lies between start and stop, but the products on the way may not
+    Ok(Value::List(
+        (0..len.max(0))
+            .map(|i| Value::Integer((start as i128 + i * stride) as i64))
+            .collect(),
+    ))
+}
+
+const LIPSUM_WORDS: &[&str] = &[
+    "lorem",
+    "ipsum",
+    "dolor",
+    "sit",
+    "amet",
+    "consectetur",
+    "adipiscing",
+    "elit",
+    "sed",
+    "do",
+    "eiusmod",
+    "tempor",
+    "incididunt",
+    "ut",
+    "labore",
+    "et",
+    "dolore",
+    "magna",
+    "aliqua",
+    "enim",
+    "ad",
+    "minim",
+    "veniam",
+    "quis",
+    "nostrud",
+    "exercitation",
+    "ullamco",
+    "laboris",
+    "nisi",
+    "aliquip",
+    "ex",
+    "ea",
+    "commodo",
+    "consequat",
+    "duis",
+    "aute",
+    "irure",
+    "in",
+    "reprehenderit",
+    "voluptate",
+    "velit",
+    "esse",
+    "cillum",
+    "eu",
+    "fugiat",
+    "nulla",
+    "pariatur",
+    "excepteur",
+    "sint",
+    "occaecat",
+    "cupidatat",
+    "non",
+    "proident",
+    "sunt",
+    "culpa",
+    "qui",
+    "officia",
+    "deserunt",
+    "mollit",
+    "anim",
+    "id",
+    "est",
+    "laborum",
+];
+
+/// `lipsum(n=5, html=true, min=20, max=100)`: `n` paragraphs of placeholder text, each
+/// `min` to `max` words long. The text is the same on every call, so output stays
+/// stable between renders.
+fn lipsum(_: &Environment, args: &Args) -> Result<Value, String> {
+    args.check_params("Function", "lipsum", &["n", "html", "min", "max"])?;
+    let n = args.int(0, "n", 5)?.max(0) as usize;
+    let html = args.bool(1, "html", true);
+    let min = args.int(2, "min", 20)?.max(1) as usize;
+    let max = (args.int(3, "max", 100)?.max(0) as usize).max(min);
+    if n > 100 || max > 1000 {
+        return Err("lipsum() makes at most 100 paragraphs of 1000 words".to_string());
+    }
+
+    // A fixed-seed linear congruential generator picks the words
+    let mut state: u64 = 0x5eed;
+    let mut random = |bound: usize| {
+        state = state
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        (state >> 33) as usize % bound
+    };
+    let paragraphs: Vec<String> = (0..n)
+        .map(|_| {
+            let words = min + random(max - min + 1);
+            let mut paragraph = String::new();
+            let (mut last, mut since_comma, mut since_period) = (usize::MAX, 0, 0);
+            let (mut next_comma, mut next_period) = (3 + random(6), 10 + random(11));
+            for i in 0..words {
+                let mut word = random(LIPSUM_WORDS.len());
+                if word == last {
+                    word = (word + 1) % LIPSUM_WORDS.len();
+                }
+                last = word;
+                let word = LIPSUM_WORDS[word];
+                if since_period == 0 {
+                    if i > 0 {
+                        paragraph.push(' ');
+                    }
+                    paragraph.push_str(&word[..1].to_uppercase());
+                    paragraph.push_str(&word[1..]);
+                } else {
+                    paragraph.push(' ');
+                    paragraph.push_str(word);
+                }
+                since_comma += 1;
+                since_period += 1;
+                if i + 1 == words || since_period >= next_period {
+                    paragraph.push('.');
+                    (since_comma, since_period) = (0, 0);
+                    next_period = 10 + random(11);
+                } else if since_comma >= next_comma {
+                    paragraph.push(',');
+                    since_comma = 0;
+                    next_comma = 3 + random(6);
+                }
+            }
+            paragraph
+        })
+        .collect();
+
+    Ok(if html {
+        Value::SafeString(
+            paragraphs
+                .iter()
+                .map(|paragraph| format!("<p>{}</p>", paragraph))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        )
+    } else {
+        Value::String(paragraphs.join("\n\n"))
+    })
+}
+
+/// The result of `now()`: a UTC date and time that outputs in ISO 8601 format and has
+/// `year`, `month`, `day`, `hour`, `minute`, `second`, `weekday` (Monday is 0) and
+/// `timestamp` attributes.
+struct DateTime {
+    timestamp: i64,
+    year: i64,
+    month: i64,
+    day: i64,
+    seconds_of_day: i64,
+}
+
+impl DateTime {
+    fn from_timestamp(timestamp: i64) -> DateTime {
+        // Howard Hinnant's days-to-civil algorithm
+        let days = timestamp.div_euclid(86_400) + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        };
+        DateTime {
+            timestamp,
+            year: year_of_era + era * 400 + (month <= 2) as i64,
+            month,
+            day: day_of_year - (153 * month_index + 2) / 5 + 1,
+            seconds_of_day: timestamp.rem_euclid(86_400),
+        }
+    }
+}
+
+impl Object for DateTime {
+    fn type_name(&self) -> &'static str {
+        "datetime"
+    }
+
+    fn render(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year,
+            self.month,
+            self.day,
+            self.seconds_of_day / 3600,
+            self.seconds_of_day / 60 % 60,
+            self.seconds_of_day % 60
+        )
+    }
+
+    fn get_attr(&self, name: &str) -> Option<Value> {
+        let value = match name {
+            "year" => self.year,
+            "month" => self.month,
+            "day" => self.day,
+            "hour" => self.seconds_of_day / 3600,
+            "minute" => self.seconds_of_day / 60 % 60,
+            "second" => self.seconds_of_day % 60,
+            // 1970-01-01 was a Thursday
+            "weekday" => (self.timestamp.div_euclid(86_400) + 3).rem_euclid(7),
+            "timestamp" => self.timestamp,
+            _ => return None,
+        };
+        Some(Value::Integer(value))
+    }
+
+    fn call_method(&self, name: &str, args: &[Value]) -> Result<Value, String> {
+        match (name, args) {
+            ("isoformat", []) => Ok(Value::String(self.render())),
+            _ => Err(format!(
+                "datetime has no method '{}' taking {} arguments",
+                name,
+                args.len()
+            )),
+        }
+    }
+}
+
+/// The result of `cycler(a, b, ...)`: `next()` returns the current item and moves on,
+/// wrapping around, `reset()` goes back to the first and `current` is the current item.
+struct Cycler {
+    items: Vec<Value>,
+    position: Cell<usize>,
+}
+
+impl Cycler {
+    fn current(&self) -> Value {
+        self.items[self.position.get()].clone()
+    }
+}
+
+impl Object for Cycler {
+    fn type_name(&self) -> &'static str {
+        "cycler"
+    }
+
+    fn render(&self) -> String {
+        self.current().to_string()
+    }
+
+    fn get_attr(&self, name: &str) -> Option<Value> {
+        (name == "current").then(|| self.current())
+    }
+
+    fn call_method(&self, name: &str, args: &[Value]) -> Result<Value, String> {
+        match (name, args) {
+            ("next", []) => {
+                let item = self.current();
+                self.position
+                    .set((self.position.get() + 1) % self.items.len());
+                Ok(item)
+            }
+            ("reset", []) => {
+                self.position.set(0);
+                Ok(Value::None)
+            }
+            _ => Err(format!(
+                "cycler has no method '{}' taking {} arguments",
+                name,
+                args.len()
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &str, args: Vec<Value>) -> Result<Value, Error> {
+        call_with(name, args, HashMap::new())
+    }
+
+    fn call_with(
+        name: &str,
+        args: Vec<Value>,
+        kwargs: HashMap<String, Value>,
+    ) -> Result<Value, Error> {
+        Environment::new().call_function(name, &Args::new(args, kwargs))
+    }
+
+    fn ints(numbers: &[i64]) -> Vec<Value> {
+        numbers.iter().copied().map(Value::Integer).collect()
+    }
+
+    #[test]
+    fn range_counts_like_python() {
+        for (args, expected) in [
+            (&[3][..], &[0, 1, 2][..]),
+            (&[2, 5], &[2, 3, 4]),
+            (&[0, 10, 4], &[0, 4, 8]),
+            (&[5, 0, -2], &[5, 3, 1]),
+            (&[5, 2], &[]),
+            (&[-2], &[]),
+        ] {
+            let value = call("range", ints(args)).unwrap();
+            assert_eq!(value, Value::List(ints(expected)), "{:?}", args);
+        }
+
+        for (args, message) in [
+            (ints(&[]), "range() takes one to three arguments"),
+            (ints(&[1, 2, 0]), "range() step must not be zero"),
+            (vec![Value::Float(1.0)], "range() needs integers, not float"),
+            (ints(&[2_000_000]), "range() is limited to 1000000 items"),
+        ] {
+            assert_eq!(call("range", args).unwrap_err().message(), message);
+        }
+    }
+
+    #[test]
+    fn range_steps_across_the_whole_integer_range() {
+        let value = call("range", ints(&[i64::MIN, i64::MAX, i64::MAX])).unwrap();
+        assert_eq!(value, Value::List(ints(&[i64::MIN, -1, i64::MAX - 1])));
+        let value = call("range", ints(&[i64::MAX, i64::MIN, i64::MIN])).unwrap();
+        assert_eq!(value, Value::List(ints(&[i64::MAX, -1])));
+        assert!(call("range", ints(&[i64::MIN, i64::MAX])).is_err());
+    }
+
+    #[test]
+    fn dict_collects_keyword_arguments() {
+        let kwargs = HashMap::from([("a".to_string(), Value::Integer(1))]);
+        let value = call_with("dict", Vec::new(), kwargs.clone()).unwrap();
+        assert_eq!(value, Value::Dict(kwargs));
+        let err = call("dict", ints(&[1])).unwrap_err();
+        assert_eq!(err.message(), "dict() only takes keyword arguments");
+    }
+
+    #[test]
+    fn lipsum_is_stable_placeholder_text() {
+        let html = call("lipsum", ints(&[2])).unwrap();
+        assert_eq!(html, call("lipsum", ints(&[2])).unwrap());
+        let Value::SafeString(html) = html else {
+            panic!("lipsum() should be markup by default");
+        };
+        assert_eq!(html.matches("<p>").count(), 2);
+
+        let text = call("lipsum", vec![Value::Integer(1), Value::Bool(false)]).unwrap();
+        let Value::String(text) = text else {
+            panic!("lipsum(html=false) should be plain text");
+        };
+        let words = text.split_whitespace().count();
+        assert!((20..=100).contains(&words), "{} words", words);
+        assert!(text.starts_with(char::is_uppercase) && text.ends_with('.'));
+
+        let err = call("lipsum", ints(&[101])).unwrap_err();
+        assert_eq!(
+            err.message(),
+            "lipsum() makes at most 100 paragraphs of 1000 words"
+        );
+    }
+
+    #[test]
+    fn datetimes_format_as_iso_8601() {
+        // 2024-02-29 13:45:30 UTC, a Thursday
+        let date = Value::Object(Rc::new(DateTime::from_timestamp(1_709_214_330)));
+        assert_eq!(date.to_string(), "2024-02-29T13:45:30Z");
+        for (attr, expected) in [
+            ("year", 2024),
+            ("month", 2),
+            ("day", 29),
+            ("hour", 13),
+            ("minute", 45),
+            ("second", 30),
+            ("weekday", 3),
+        ] {
+            assert_eq!(
+                date.get_attr(attr).unwrap(),
+                Value::Integer(expected),
+                "{}",
+                attr
+            );
+        }
+        assert_eq!(
+            date.call_method("isoformat", &[]).unwrap(),
+            Value::String("2024-02-29T13:45:30Z".to_string())
+        );
+        let before_epoch = DateTime::from_timestamp(-1);
+        assert_eq!(before_epoch.render(), "1969-12-31T23:59:59Z");
+
+        let now = call("now", Vec::new()).unwrap();
+        assert_eq!(now.type_name(), "datetime");
+        assert!(now
+            .get_attr("year")
+            .unwrap()
+            .compare(&Value::Integer(2024))
+            .unwrap()
+            .is_ge());
+        assert!(call("now", ints(&[1])).is_err());
+    }
+
+    #[test]
+    fn cyclers_wrap_around_and_reset() {
+        let cycler = call("cycler", ints(&[1, 2])).unwrap();
+        let next = || cycler.call_method("next", &[]).unwrap();
+        assert_eq!([next(), next(), next()], [1, 2, 1].map(Value::Integer));
+        assert_eq!(cycler.get_attr("current").unwrap(), Value::Integer(2));
+        assert_eq!(cycler.to_string(), "2");
+        cycler.call_method("reset", &[]).unwrap();
+        assert_eq!(next(), Value::Integer(1));
+
+        let err = cycler.call_method("next", &ints(&[1])).unwrap_err();
+        assert_eq!(err, "cycler has no method 'next' taking 1 arguments");
+        let err = call("cycler", Vec::new()).unwrap_err();
+        assert_eq!(err.message(), "cycler() takes one or more items");
+    }
+}
@@ -0,0 +1,613 @@
+use std::{collections::HashMap, fs, mem, path::Path};
+
+use crate::error::{Error, ErrorKind};
+
+/// Translated messages for `{% trans %}` blocks and the `_`, `gettext` and `ngettext`
+/// functions. Messages without a translation render as written in the template.
+pub trait Translations {
+    fn gettext(&self, msgid: &str) -> Option<&str>;
+
+    /// Picks the plural form of `singular`/`plural` for the count `n`.
+    fn ngettext(&self, singular: &str, plural: &str, n: i64) -> Option<&str>;
+}
+
+/// A gettext catalog for one language, loaded from a `.po` or compiled `.mo` file.
+pub struct Catalog {
+    /// Translations by message id; the plural forms of a plural message in order
+    messages: HashMap<String, Vec<String>>,
+    plural: PluralRule,
+}
+
+impl Catalog {
+    pub fn new() -> Catalog {
+        Catalog {
+            messages: HashMap::new(),
+            plural: PluralRule::default(),
+        }
+    }
+
+    /// Loads a catalog, choosing the format by the file's extension.
+    pub fn load(path: &Path) -> Result<Catalog, Error> {
+        let read_error = |err| {
+            Error::new(
+                ErrorKind::Catalog,
+                format!("Failed to read {}: {}", path.display(), err),
+            )
+        };
+        let catalog = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("po") => Catalog::from_po(&fs::read_to_string(path).map_err(read_error)?),
+            Some("mo") => Catalog::from_mo(&fs::read(path).map_err(read_error)?),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Catalog,
+                    format!("{}: expected a .po or .mo file", path.display()),
+                ))
+            }
+        };
+        catalog.map_err(|err| {
+            Error::new(
+                ErrorKind::Catalog,
+                format!("{}: {}", path.display(), err.message()),
+            )
+        })
+    }
+
+    /// Parses the source of a `.po` file. Fuzzy and untranslated entries are skipped,
+    /// as `msgfmt` does.
+    pub fn from_po(source: &str) -> Result<Catalog, Error> {
+        let mut catalog = Catalog::new();
+        let mut entry = PoEntry::default();
+        let mut field = None;
+
+        for (number, line) in source.lines().enumerate() {
+            let line = line.trim();
+            let error = |message: &str| {
+                Error::new(
+                    ErrorKind::Catalog,
+                    format!("Line {}: {}", number + 1, message),
+                )
+            };
+            if line.is_empty() || line.starts_with('#') {
+                if !entry.translations.is_empty() {
+                    catalog.add_po_entry(mem::take(&mut entry))?;
+                }
+                if line.starts_with("#,") && line.contains("fuzzy") {
+                    entry.fuzzy = true;
+                }
+                field = None;
+                continue;
+            }
+
+            let (keyword, rest) = match line.find(|c: char| c.is_whitespace()) {
+                Some(i) if !line.starts_with('"') => (&line[..i], line[i..].trim_start()),
+                _ => ("", line),
+            };
+            let text = unquote(rest).ok_or_else(|| error("Expected a quoted string"))?;
+            if keyword.is_empty() {
+                let Some(field) = field else {
+                    return Err(error("String continues no message"));
+                };
+                entry.field(field).push_str(&text);
+                continue;
+            }
+
+            // A new message starts once the previous one has its translation
+            if matches!(keyword, "msgctxt" | "msgid") && !entry.translations.is_empty() {
+                catalog.add_po_entry(mem::take(&mut entry))?;
+            }
+            field = Some(match keyword {
+                "msgctxt" => PoField::Context,
+                "msgid" => PoField::Id,
+                "msgid_plural" => PoField::IdPlural,
+                "msgstr" => PoField::Translation(0),
+                _ => match keyword
+                    .strip_prefix("msgstr[")
+                    .and_then(|index| index.strip_suffix(']'))
+                    .and_then(|index| index.parse().ok())
+                {
+                    Some(index) => PoField::Translation(index),
+                    None => return Err(error(&format!("Unknown keyword '{}'", keyword))),
+                },
+            });
+            if let Some(field) = field {
+                *entry.field(field) = text;
+            }
+        }
+        if !entry.translations.is_empty() {
+            catalog.add_po_entry(entry)?;
+        }
+        Ok(catalog)
+    }
+
+    /// Parses a compiled `.mo` file, in either byte order.
+    pub fn from_mo(bytes: &[u8]) -> Result<Catalog, Error> {
+        let invalid = || Error::new(ErrorKind::Catalog, "Not a valid .mo file");
+        let read_u32 = |offset: usize, big_endian: bool| -> Result<usize, Error> {
+            let word: [u8; 4] = bytes
+                .get(offset..offset + 4)
+                .and_then(|word| word.try_into().ok())
+                .ok_or_else(invalid)?;
+            Ok(if big_endian {
+                u32::from_be_bytes(word)
+            } else {
+                u32::from_le_bytes(word)
+            } as usize)
+        };
+        let big_endian = match read_u32(0, false)? {
+            0x9504_12de => false,
+            0xde12_0495 => true,
+            _ => return Err(invalid()),
+        };
+        let read_string = |table: usize, index: usize| -> Result<&str, Error> {
+            let length = read_u32(table + index * 8, big_endian)?;
+            let offset = read_u32(table + index * 8 + 4, big_endian)?;
+            let bytes = bytes.get(offset..offset + length).ok_or_else(invalid)?;
+            std::str::from_utf8(bytes)
+                .map_err(|_| Error::new(ErrorKind::Catalog, "Only UTF-8 catalogs are supported"))
+        };
+
+        let count = read_u32(8, big_endian)?;
+        let (originals, translations) = (read_u32(12, big_endian)?, read_u32(16, big_endian)?);
+        let mut catalog = Catalog::new();
+        for index in 0..count {
+            // Plural messages are stored as "singular\0plural"
+            let msgid = read_string(originals, index)?;
+            let msgid = msgid.split('\0').next().unwrap_or_default();
+            let forms = read_string(translations, index)?
+                .split('\0')
+                .map(str::to_string)
+                .collect();
+            catalog.add_message(msgid, forms)?;
+        }
+        Ok(catalog)
+    }
+
+    /// Adds the translation of a message, or its plural forms in the catalog's order.
+    /// The message with the empty id is the header, which sets the plural rule.
+    pub fn add_message(&mut self, msgid: &str, forms: Vec<String>) -> Result<(), Error> {
+        if msgid.is_empty() {
+            let header = forms.first().map(String::as_str).unwrap_or_default();
+            if let Some(plural_forms) = header
+                .lines()
+                .find_map(|line| line.strip_prefix("Plural-Forms:"))
+            {
+                self.plural = PluralRule::parse(plural_forms)?;
+            }
+            return Ok(());
+        }
+        self.messages.insert(msgid.to_string(), forms);
+        Ok(())
+    }
+
+    fn add_po_entry(&mut self, entry: PoEntry) -> Result<(), Error> {
+        let untranslated = entry.translations.iter().all(String::is_empty);
+        if entry.fuzzy && !entry.id.is_empty() || untranslated {
+            return Ok(());
+        }
+        // Messages with a context are keyed the way `.mo` files store them
+        let msgid = match entry.context {
+            Some(context) => format!("{}\u{4}{}", context, entry.id),
+            None => entry.id,
+        };
+        self.add_message(&msgid, entry.translations)
+    }
+}
+
+impl Default for Catalog {
+    fn default() -> Catalog {
+        Catalog::new()
+    }
+}
+
+impl Translations for Catalog {
+    fn gettext(&self, msgid: &str) -> Option<&str> {
+        let translation = self.messages.get(msgid)?.first()?;
+        Some(translation)
+            .filter(|s| !s.is_empty())
+            .map(String::as_str)
+    }
+
+    fn ngettext(&self, singular: &str, _plural: &str, n: i64) -> Option<&str> {
+        let translation = self.messages.get(singular)?.get(self.plural.index(n))?;
+        Some(translation)
+            .filter(|s| !s.is_empty())
+            .map(String::as_str)
+    }
+}
+
+/// Replaces `%(name)s` placeholders with `value(name)` and `%%` with `%`, the way
+/// gettext messages extracted from Jinja templates are written. Any other `%` is kept.
+pub fn interpolate(
+    message: &str,
+    mut value: impl FnMut(&str) -> Option<String>,
+) -> Result<String, String> {
+    let mut output = String::with_capacity(message.len());
+    let mut rest = message;
+    while let Some(i) = rest.find('%') {
+        output.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(after) = rest.strip_prefix("%%") {
+            output.push('%');
+            rest = after;
+        } else if let Some((name, after)) = rest
+            .strip_prefix("%(")
+            .and_then(|after| after.split_once(")s"))
+        {
+            let value = value(name)
+                .ok_or_else(|| format!("Message '{}' uses unknown variable '{}'", message, name))?;
+            output.push_str(&value);
+            rest = after;
+        } else {
+            output.push('%');
+            rest = &rest[1..];
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+#[derive(Default)]
+struct PoEntry {
+    context: Option<String>,
+    id: String,
+    /// Unused, as plural messages are looked up by their singular like in `.mo` files
+    id_plural: String,
+    translations: Vec<String>,
+    fuzzy: bool,
+}
+
+#[derive(Clone, Copy)]
+enum PoField {
+    Context,
+    Id,
+    IdPlural,
+    Translation(usize),
+}
+
+impl PoEntry {
+    fn field(&mut self, field: PoField) -> &mut String {
+        match field {
+            PoField::Context => self.context.get_or_insert_with(String::new),
+            PoField::Id => &mut self.id,
+            PoField::IdPlural => &mut self.id_plural,
+            PoField::Translation(index) => {
+                if self.translations.len() <= index {
+                    self.translations.resize(index + 1, String::new());
+                }
+                &mut self.translations[index]
+            }
+        }
+    }
+}
+
+/// Parses a `.po` string literal, resolving its escapes.
+fn unquote(s: &str) -> Option<String> {
+    let inner = s.strip_prefix('"')?.strip_suffix('"')?;
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unquoted.push(c);
+            continue;
+        }
+        unquoted.push(match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            c => c,
+        });
+    }
+    Some(unquoted)
+}
+
+/// Which plural form to use for a count, from a catalog's `Plural-Forms` header such as
+/// `nplurals=3; plural=(n%10==1 && n%100!=11 ? 0 : n%10>=2 && n%10<=4 ? 1 : 2);`.
+struct PluralRule {
+    expr: PluralExpr,
+}
+
+impl Default for PluralRule {
+    /// The rule of English and most catalogs' source language
+    fn default() -> PluralRule {
+        PluralRule {
+            expr: PluralExpr::Binary(
+                "!=",
+                Box::new(PluralExpr::N),
+                Box::new(PluralExpr::Number(1)),
+            ),
+        }
+    }
+}
+
+impl PluralRule {
+    fn parse(header: &str) -> Result<PluralRule, Error> {
+        let formula = header
+            .split(';')
+            .find_map(|part| part.trim().strip_prefix("plural="))
+            .ok_or_else(|| Error::new(ErrorKind::Catalog, "Plural-Forms has no plural rule"))?;
+        let mut parser = PluralParser {
+            source: formula.as_bytes(),
+            position: 0,
+        };
+        let expr = parser.parse_ternary();
+        parser.skip_whitespace();
+        match expr {
+            Some(expr) if parser.position == formula.len() => Ok(PluralRule { expr }),
+            _ => Err(Error::new(
+                ErrorKind::Catalog,
+                format!("Invalid plural rule '{}'", formula),
+            )),
+        }
+    }
+
+    fn index(&self, n: i64) -> usize {
+        self.expr.eval(n.saturating_abs()).max(0) as usize
+    }
+}
+
+/// The C expression subset plural rules are written in.
+enum PluralExpr {
+    N,
+    Number(i64),
+    Not(Box<PluralExpr>),
+    Binary(&'static str, Box<PluralExpr>, Box<PluralExpr>),
+    Conditional(Box<PluralExpr>, Box<PluralExpr>, Box<PluralExpr>),
+}
+
+impl PluralExpr {
+    fn eval(&self, n: i64) -> i64 {
+        match self {
+            PluralExpr::N => n,
+            PluralExpr::Number(number) => *number,
+            PluralExpr::Not(expr) => (expr.eval(n) == 0) as i64,
+            PluralExpr::Conditional(condition, then_expr, else_expr) => {
+                if condition.eval(n) != 0 {
+                    then_expr.eval(n)
+                } else {
+                    else_expr.eval(n)
+                }
+            }
+            PluralExpr::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.eval(n), rhs.eval(n));
+                match *op {
+                    "||" => (lhs != 0 || rhs != 0) as i64,
+                    "&&" => (lhs != 0 && rhs != 0) as i64,
+                    "==" => (lhs == rhs) as i64,
+                    "!=" => (lhs != rhs) as i64,
+                    "<" => (lhs < rhs) as i64,
+                    "<=" => (lhs <= rhs) as i64,
+                    ">" => (lhs > rhs) as i64,
+                    ">=" => (lhs >= rhs) as i64,
+                    "+" => lhs.wrapping_add(rhs),
+                    "-" => lhs.wrapping_sub(rhs),
+                    "*" => lhs.wrapping_mul(rhs),
+                    "/" => lhs.checked_div(rhs).unwrap_or(0),
+                    _ => lhs.checked_rem(rhs).unwrap_or(0),
+                }
+            }
+        }
+    }
+}
+
+struct PluralParser<'a> {
+    source: &'a [u8],
+    position: usize,
+}
+
+/// Binary operators from the loosest to the tightest binding level.
+const PLURAL_OPERATORS: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["==", "!="],
+    &["<=", ">=", "<", ">"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+impl PluralParser<'_> {
+    fn parse_ternary(&mut self) -> Option<PluralExpr> {
+        let condition = self.parse_binary(0)?;
+        if !self.eat("?") {
+            return Some(condition);
+        }
+        let then_expr = self.parse_ternary()?;
+        if !self.eat(":") {
+            return None;
+        }
+        let else_expr = self.parse_ternary()?;
+        Some(PluralExpr::Conditional(
+            Box::new(condition),
+            Box::new(then_expr),
+            Box::new(else_expr),
+        ))
+    }
+
+    fn parse_binary(&mut self, level: usize) -> Option<PluralExpr> {
+        let Some(operators) = PLURAL_OPERATORS.get(level) else {
+            return self.parse_unary();
+        };
+        let mut expr = self.parse_binary(level + 1)?;
+        while let Some(op) = operators.iter().find(|op| self.eat(op)) {
+            let rhs = self.parse_binary(level + 1)?;
+            expr = PluralExpr::Binary(op, Box::new(expr), Box::new(rhs));
+        }
+        Some(expr)
+    }
+
+    fn parse_unary(&mut self) -> Option<PluralExpr> {
+        if self.eat("!") {
+            return Some(PluralExpr::Not(Box::new(self.parse_unary()?)));
+        }
+        if self.eat("(") {
+            let expr = self.parse_ternary()?;
+            return self.eat(")").then_some(expr);
+        }
+        if self.eat("n") {
+            return Some(PluralExpr::N);
+        }
+        self.skip_whitespace();
+        let digits = self.source[self.position..]
+            .iter()
+            .take_while(|b| b.is_ascii_digit())
+            .count();
+        let number = std::str::from_utf8(&self.source[self.position..self.position + digits])
+            .ok()?
+            .parse()
+            .ok()?;
+        self.position += digits;
+        Some(PluralExpr::Number(number))
+    }
+
+    /// Consumes `token` after any whitespace. `!` and `<`/`>` don't match the start of
+    /// `!=`, `<=` and `>=`.
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        let rest = &self.source[self.position..];
+        let longer = matches!(token, "!" | "<" | ">") && rest.get(1) == Some(&b'=');
+        if rest.starts_with(token.as_bytes()) && !longer {
+            self.position += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .source
+            .get(self.position)
+            .is_some_and(u8::is_ascii_whitespace)
+        {
+            self.position += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RUSSIAN_PO: &str = r#"
+# Translator comment
+msgid ""
+msgstr ""
+"Content-Type: text/plain; charset=UTF-8\n"
+"Plural-Forms: nplurals=3; plural=(n%10==1 && n%100!=11 ? 0 : "
+"n%10>=2 && n%10<=4 && (n%100<10 || n%100>=20) ? 1 : 2);\n"
+
+msgid "file"
+msgid_plural "files"
+msgstr[0] "файл"
+msgstr[1] "файла"
+msgstr[2] "файлов"
+
+msgctxt "menu"
+msgid "Open"
+msgstr "Открыть"
+
+#, fuzzy
+msgid "Close"
+msgstr "Закрыть"
+
+msgid "Untranslated"
+msgstr ""
+"#;
+
+    /// A little-endian `.mo` file with the given messages, sorted as `msgfmt` would.
+    fn mo_file(messages: &[(&str, &str)]) -> Vec<u8> {
+        let count = messages.len();
+        let (originals, translations) = (28, 28 + count * 8);
+        let (mut tables, mut strings) = (Vec::new(), Vec::new());
+        let mut offset = 28 + count * 16;
+        let originals_then_translations = messages.iter().map(|message| message.0);
+        for s in originals_then_translations.chain(messages.iter().map(|message| message.1)) {
+            tables.extend((s.len() as u32).to_le_bytes());
+            tables.extend((offset as u32).to_le_bytes());
+            strings.extend(s.as_bytes());
+            strings.push(0);
+            offset += s.len() + 1;
+        }
+        let mut bytes = Vec::new();
+        for word in [0x9504_12de, 0, count, originals, translations, 0, 0] {
+            bytes.extend((word as u32).to_le_bytes());
+        }
+        bytes.extend(tables);
+        bytes.extend(strings);
+        bytes
+    }
+
+    #[test]
+    fn po_catalogs_follow_their_plural_rule() {
+        let catalog = Catalog::from_po(RUSSIAN_PO).unwrap();
+        for (n, form) in [
+            (1, "файл"),
+            (3, "файла"),
+            (5, "файлов"),
+            (11, "файлов"),
+            (21, "файл"),
+            (-2, "файла"),
+        ] {
+            assert_eq!(catalog.ngettext("file", "files", n), Some(form), "{}", n);
+        }
+        assert_eq!(catalog.gettext("menu\u{4}Open"), Some("Открыть"));
+        assert_eq!(catalog.gettext("Open"), None);
+        assert_eq!(catalog.gettext("Close"), None);
+        assert_eq!(catalog.gettext("Untranslated"), None);
+    }
+
+    #[test]
+    fn po_errors_name_the_line() {
+        for (source, message) in [
+            (
+                "msgid \"a\nmsgstr \"b\"",
+                "Line 1: Expected a quoted string",
+            ),
+            ("\"stray\"", "Line 1: String continues no message"),
+            (
+                "msgid \"a\"\nmsgfoo \"b\"",
+                "Line 2: Unknown keyword 'msgfoo'",
+            ),
+            (
+                "msgid \"\"\nmsgstr \"Plural-Forms: nplurals=2; plural=n +;\\n\"",
+                "Invalid plural rule 'n +'",
+            ),
+        ] {
+            let Err(err) = Catalog::from_po(source) else {
+                panic!("{:?} should not parse", source);
+            };
+            assert_eq!(err.message(), message);
+            assert_eq!(err.kind(), &ErrorKind::Catalog);
+        }
+    }
+
+    #[test]
+    fn mo_catalogs_hold_singular_and_plural_messages() {
+        let bytes = mo_file(&[
+            ("", "Plural-Forms: nplurals=2; plural=n>1;\n"),
+            ("cat\0cats", "chat\0chats"),
+            ("hello", "bonjour"),
+        ]);
+        let catalog = Catalog::from_mo(&bytes).unwrap();
+        assert_eq!(catalog.gettext("hello"), Some("bonjour"));
+        assert_eq!(catalog.ngettext("cat", "cats", 1), Some("chat"));
+        assert_eq!(catalog.ngettext("cat", "cats", 2), Some("chats"));
+
+        for truncated in [&bytes[..3], &bytes[..30], &bytes[..bytes.len() - 10]] {
+            let err = Catalog::from_mo(truncated).err().unwrap();
+            assert_eq!(err.message(), "Not a valid .mo file");
+        }
+    }
+
+    #[test]
+    fn interpolate_fills_named_placeholders() {
+        let value = |name: &str| (name == "n").then(|| "3".to_string());
+        assert_eq!(
+            interpolate("%(n)s at 100%% or 5%", value).unwrap(),
+            "3 at 100% or 5%"
+        );
+        assert_eq!(
+            interpolate("%(x)s", value).unwrap_err(),
+            "Message '%(x)s' uses unknown variable 'x'"
+        );
+    }
+}
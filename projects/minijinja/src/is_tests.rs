@@ -0,0 +1,318 @@
+use crate::{
+    environment::Environment,
+    error::{Error, ErrorKind},
+    values::Value,
+};
+use std::collections::HashMap;
+
+pub type TestFn = fn(&Environment, &Value, &[Value]) -> Result<bool, String>;
+
+/// Tests used as `value is name` or `value is name(args)`, and by `select`/`reject`.
+pub struct TestRegistry {
+    tests: HashMap<String, TestFn>,
+}
+
+impl TestRegistry {
+    pub fn new() -> TestRegistry {
+        let mut registry = TestRegistry {
+            tests: HashMap::new(),
+        };
+
+        registry.add_test("defined", |_, v, args| {
+            no_args("defined", args)?;
+            Ok(*v != Value::Undefined)
+        });
+        registry.add_test("undefined", |_, v, args| {
+            no_args("undefined", args)?;
+            Ok(*v == Value::Undefined)
+        });
+        registry.add_test("none", |_, v, args| {
+            no_args("none", args)?;
+            Ok(*v == Value::None)
+        });
+        registry.add_test("boolean", |_, v, args| {
+            no_args("boolean", args)?;
+            Ok(matches!(v, Value::Bool(_)))
+        });
+        registry.add_test("number", |_, v, args| {
+            no_args("number", args)?;
+            Ok(matches!(v, Value::Integer(_) | Value::Float(_)))
+        });
+        registry.add_test("integer", |_, v, args| {
+            no_args("integer", args)?;
+            Ok(matches!(v, Value::Integer(_)))
+        });
+        registry.add_test("float", |_, v, args| {
+            no_args("float", args)?;
+            Ok(matches!(v, Value::Float(_)))
+        });
+        registry.add_test("string", |_, v, args| {
+            no_args("string", args)?;
+            Ok(v.as_str().is_some())
+        });
+        registry.add_test("mapping", |_, v, args| {
+            no_args("mapping", args)?;
+            Ok(matches!(v, Value::Dict(_)))
+        });
+        registry.add_test("sequence", |_, v, args| {
+            no_args("sequence", args)?;
+            Ok(matches!(v, Value::List(_)) || v.as_str().is_some())
+        });
+        registry.add_test("iterable", |_, v, args| {
+            no_args("iterable", args)?;
+            Ok(matches!(v, Value::List(_) | Value::Dict(_)) || v.as_str().is_some())
+        });
+        registry.add_test("escaped", |_, v, args| {
+            no_args("escaped", args)?;
+            Ok(matches!(v, Value::SafeString(_)))
+        });
+        registry.add_test("lower", |_, v, args| {
+            no_args("lower", args)?;
+            Ok(v.as_str().is_some_and(|s| s.to_lowercase() == s))
+        });
+        registry.add_test("upper", |_, v, args| {
+            no_args("upper", args)?;
+            Ok(v.as_str().is_some_and(|s| s.to_uppercase() == s))
+        });
+        registry.add_test("truthy", |_, v, args| {
+            no_args("truthy", args)?;
+            Ok(v.is_truthy())
+        });
+        registry.add_test("falsy", |_, v, args| {
+            no_args("falsy", args)?;
+            Ok(!v.is_truthy())
+        });
+        registry.add_test("odd", |_, v, args| {
+            no_args("odd", args)?;
+            Ok(integer("odd", v)? % 2 != 0)
+        });
+        registry.add_test("even", |_, v, args| {
+            no_args("even", args)?;
+            Ok(integer("even", v)? % 2 == 0)
+        });
+        registry.add_test("divisibleby", divisibleby);
+        registry.add_test("sameas", sameas);
+        registry.add_test("eq", eq);
+        registry.add_test("equalto", eq);
+        registry.add_test("==", eq);
+        registry.add_test("ne", ne);
+        registry.add_test("!=", ne);
+        registry.add_test("gt", gt);
+        registry.add_test(">", gt);
+        registry.add_test("ge", ge);
+        registry.add_test(">=", ge);
+        registry.add_test("lt", lt);
+        registry.add_test("<", lt);
+        registry.add_test("le", le);
+        registry.add_test("<=", le);
+        registry.add_test("in", |_, v, args| arg("in", args)?.contains(v));
+
+        registry
+    }
+
+    pub fn add_test(&mut self, name: &str, test: TestFn) {
+        self.tests.insert(name.to_string(), test);
+    }
+
+    pub fn perform_test(
+        &self,
+        env: &Environment,
+        name: &str,
+        value: &Value,
+        args: &[Value],
+    ) -> Result<bool, Error> {
+        match self.tests.get(name) {
+            Some(test) => Ok(test(env, value, args)?),
+            None => Err(Error::new(
+                ErrorKind::UnknownTest,
+                format!("Test {} not found", name),
+            )),
+        }
+    }
+}
+
+impl Default for TestRegistry {
+    fn default() -> TestRegistry {
+        TestRegistry::new()
+    }
+}
+
+fn no_args(test: &str, args: &[Value]) -> Result<(), String> {
+    if !args.is_empty() {
+        return Err(format!("Test {} takes no arguments", test));
+    }
+    Ok(())
+}
+
+/// The single argument of a test such as `divisibleby(3)`.
+fn arg<'a>(test: &str, args: &'a [Value]) -> Result<&'a Value, String> {
+    match args {
+        [arg] => Ok(arg),
+        _ => Err(format!("Test {} takes exactly one argument", test)),
+    }
+}
+
+fn integer(test: &str, value: &Value) -> Result<i64, String> {
+    match value {
+        Value::Integer(i) => Ok(*i),
+        _ => Err(format!("Test {} only works on integers", test)),
+    }
+}
+
+fn divisibleby(_: &Environment, value: &Value, args: &[Value]) -> Result<bool, String> {
+    match integer("divisibleby", arg("divisibleby", args)?)? {
+        0 => Err("Division by zero".to_string()),
+        // `i64::MIN % -1` overflows, but every integer is divisible by -1
+        n => Ok(integer("divisibleby", value)?.checked_rem(n).unwrap_or(0) == 0),
+    }
+}
+
+/// Values have no identity once they are in a template, so `sameas` compares type and
+/// value without the coercions of `==`; `1 is sameas 1.0` is false.
+fn sameas(_: &Environment, value: &Value, args: &[Value]) -> Result<bool, String> {
+    Ok(value == arg("sameas", args)?)
+}
+
+fn eq(_: &Environment, value: &Value, args: &[Value]) -> Result<bool, String> {
+    Ok(value.loose_eq(arg("eq", args)?))
+}
+
+fn ne(_: &Environment, value: &Value, args: &[Value]) -> Result<bool, String> {
+    Ok(!value.loose_eq(arg("ne", args)?))
+}
+
+fn gt(_: &Environment, value: &Value, args: &[Value]) -> Result<bool, String> {
+    Ok(value.compare(arg("gt", args)?)?.is_gt())
+}
+
+fn ge(_: &Environment, value: &Value, args: &[Value]) -> Result<bool, String> {
+    Ok(value.compare(arg("ge", args)?)?.is_ge())
+}
+
+fn lt(_: &Environment, value: &Value, args: &[Value]) -> Result<bool, String> {
+    Ok(value.compare(arg("lt", args)?)?.is_lt())
+}
+
+fn le(_: &Environment, value: &Value, args: &[Value]) -> Result<bool, String> {
+    Ok(value.compare(arg("le", args)?)?.is_le())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{environment::UndefinedBehavior, loader::MemoryLoader, vm::Vm};
+
+    fn test(name: &str, value: Value, args: &[Value]) -> Result<bool, Error> {
+        Environment::new().perform_test(name, &value, args)
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    #[test]
+    fn type_tests_check_the_kind_of_value() {
+        let list = Value::List(vec![]);
+        let dict = Value::Dict(HashMap::new());
+        for (name, passing, failing) in [
+            ("none", Value::None, Value::Undefined),
+            ("boolean", Value::Bool(false), Value::Integer(0)),
+            ("number", Value::Float(1.5), string("1")),
+            ("integer", Value::Integer(1), Value::Float(1.0)),
+            ("float", Value::Float(1.0), Value::Integer(1)),
+            ("string", string("a"), list.clone()),
+            ("mapping", dict.clone(), list.clone()),
+            ("sequence", string("ab"), dict.clone()),
+            ("iterable", dict, Value::Integer(1)),
+            ("escaped", Value::SafeString("a".to_string()), string("a")),
+            ("lower", string("ab1"), string("aB")),
+            ("upper", string("AB1"), string("aB")),
+            ("truthy", Value::Integer(1), list.clone()),
+            ("falsy", list, string("x")),
+        ] {
+            assert!(test(name, passing, &[]).unwrap(), "{}", name);
+            assert!(!test(name, failing, &[]).unwrap(), "{}", name);
+        }
+
+        let err = test("none", Value::None, &[Value::Integer(1)]).unwrap_err();
+        assert_eq!(err.message(), "Test none takes no arguments");
+        let err = test("frobnicated", Value::None, &[]).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::UnknownTest);
+    }
+
+    #[test]
+    fn number_tests_need_integers() {
+        assert!(test("odd", Value::Integer(-3), &[]).unwrap());
+        assert!(test("even", Value::Integer(0), &[]).unwrap());
+        assert!(test("divisibleby", Value::Integer(-9), &[Value::Integer(3)]).unwrap());
+        assert!(!test("divisibleby", Value::Integer(10), &[Value::Integer(3)]).unwrap());
+        assert!(test(
+            "divisibleby",
+            Value::Integer(i64::MIN),
+            &[Value::Integer(-1)]
+        )
+        .unwrap());
+
+        let err = test("divisibleby", Value::Integer(1), &[Value::Integer(0)]).unwrap_err();
+        assert_eq!(err.message(), "Division by zero");
+        let err = test("divisibleby", Value::Integer(1), &[]).unwrap_err();
+        assert_eq!(err.message(), "Test divisibleby takes exactly one argument");
+        let err = test("odd", Value::Float(3.0), &[]).unwrap_err();
+        assert_eq!(err.message(), "Test odd only works on integers");
+    }
+
+    #[test]
+    fn comparison_tests_compare_with_their_argument() {
+        let two = || vec![Value::Integer(2)];
+        for (name, value, expected) in [
+            ("eq", Value::Float(2.0), true),
+            ("==", Value::Integer(3), false),
+            ("ne", Value::Integer(3), true),
+            ("gt", Value::Integer(3), true),
+            ("ge", Value::Integer(2), true),
+            ("lt", Value::Integer(2), false),
+            ("<=", Value::Integer(1), true),
+        ] {
+            assert_eq!(test(name, value, &two()).unwrap(), expected, "{}", name);
+        }
+        assert!(test("sameas", Value::None, &[Value::None]).unwrap());
+        assert!(test("in", string("b"), &[string("abc")]).unwrap());
+        assert!(!test("in", Value::Integer(4), &[Value::List(two())]).unwrap());
+        assert!(test("gt", string("a"), &two()).is_err());
+    }
+
+    fn render_with_undefined(behavior: UndefinedBehavior, source: &str) -> Result<String, Error> {
+        let mut loader = MemoryLoader::new();
+        loader.add_template("page", source);
+        let mut env = Environment::new();
+        env.set_loader(loader);
+        env.set_undefined_behavior(behavior);
+        Vm::new(&env).render_template("page")
+    }
+
+    #[test]
+    fn defined_works_in_every_undefined_behavior() {
+        let source = "{{ missing is defined }} {{ missing is undefined }} \
+                      {{ missing is none }} {{ missing is falsy }}";
+        for behavior in [
+            UndefinedBehavior::Lenient,
+            UndefinedBehavior::Chainable,
+            UndefinedBehavior::Strict,
+        ] {
+            let output = render_with_undefined(behavior, source);
+            assert_eq!(output.unwrap(), "false true false true", "{:?}", behavior);
+        }
+    }
+
+    #[test]
+    fn only_chainable_lets_tests_look_into_undefined_values() {
+        let source = "{{ missing.attr is defined }}";
+        let output = render_with_undefined(UndefinedBehavior::Chainable, source);
+        assert_eq!(output.unwrap(), "false");
+        for behavior in [UndefinedBehavior::Lenient, UndefinedBehavior::Strict] {
+            let err = render_with_undefined(behavior, source).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::Undefined, "{:?}", behavior);
+            assert_eq!(err.message(), "Cannot get 'attr' of an undefined value");
+        }
+    }
+}
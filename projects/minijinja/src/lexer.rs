@@ -0,0 +1,596 @@
+use core::fmt;
+use std::mem;
+
+use crate::error::{Error, ErrorKind};
+
+/// Byte range of a token in the template source.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// Returns the 1-based line and column of the start of the span.
+    pub fn location(&self, source: &str) -> (usize, usize) {
+        let before = &source[..self.start.min(source.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before
+            .rsplit_once('\n')
+            .map_or(before, |(_, rest)| rest)
+            .chars()
+            .count()
+            + 1;
+        (line, column)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Text(String),
+    VariableStart,
+    VariableEnd,
+    BlockStart,
+    BlockEnd,
+    Ident(String),
+    Str(String),
+    Integer(i64),
+    Float(f64),
+    Pipe,
+    Comma,
+    Colon,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    LBrace,
+    RBrace,
+    Dot,
+    Tilde,
+    Plus,
+    Minus,
+    Star,
+    StarStar,
+    Slash,
+    SlashSlash,
+    Percent,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Assign,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Text(_) => write!(f, "template text"),
+            Token::VariableStart => write!(f, "'{{{{'"),
+            Token::VariableEnd => write!(f, "'}}}}'"),
+            Token::BlockStart => write!(f, "'{{%'"),
+            Token::BlockEnd => write!(f, "'%}}'"),
+            Token::Ident(name) => write!(f, "'{}'", name),
+            Token::Str(s) => write!(f, "string {:?}", s),
+            Token::Integer(i) => write!(f, "number {}", i),
+            Token::Float(fl) => write!(f, "number {}", fl),
+            Token::Pipe => write!(f, "'|'"),
+            Token::Comma => write!(f, "','"),
+            Token::Colon => write!(f, "':'"),
+            Token::LParen => write!(f, "'('"),
+            Token::RParen => write!(f, "')'"),
+            Token::LBracket => write!(f, "'['"),
+            Token::RBracket => write!(f, "']'"),
+            Token::LBrace => write!(f, "'{{'"),
+            Token::RBrace => write!(f, "'}}'"),
+            Token::Dot => write!(f, "'.'"),
+            Token::Tilde => write!(f, "'~'"),
+            Token::Plus => write!(f, "'+'"),
+            Token::Minus => write!(f, "'-'"),
+            Token::Star => write!(f, "'*'"),
+            Token::StarStar => write!(f, "'**'"),
+            Token::Slash => write!(f, "'/'"),
+            Token::SlashSlash => write!(f, "'//'"),
+            Token::Percent => write!(f, "'%'"),
+            Token::Eq => write!(f, "'=='"),
+            Token::Ne => write!(f, "'!='"),
+            Token::Lt => write!(f, "'<'"),
+            Token::Le => write!(f, "'<='"),
+            Token::Gt => write!(f, "'>'"),
+            Token::Ge => write!(f, "'>='"),
+            Token::Assign => write!(f, "'='"),
+        }
+    }
+}
+
+/// A syntax error pointing at the character at `offset` in `source`.
+pub fn error_at(source: &str, offset: usize, message: &str) -> Error {
+    let len = source[offset..].chars().next().map_or(0, char::len_utf8);
+    let span = Span {
+        start: offset,
+        end: offset + len,
+    };
+    Error::new(ErrorKind::Syntax, message).with_location(source, span)
+}
+
+/// Whitespace handling around block tags and comments, on top of the `-` and `+` markers.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WhitespaceConfig {
+    /// Removes the first newline after a block tag
+    pub trim_blocks: bool,
+    /// Removes spaces and tabs before a block tag that starts a line
+    pub lstrip_blocks: bool,
+}
+
+/// What to strip from the start of the text after a tag.
+#[derive(Default)]
+enum Trim {
+    #[default]
+    None,
+    Newline,
+    Whitespace,
+}
+
+struct Lexer<'a> {
+    source: &'a str,
+    offset: usize,
+    tokens: Vec<(Token, Span)>,
+    whitespace: WhitespaceConfig,
+    trim_next: Trim,
+}
+
+/// Splits a template into text and the tokens inside `{{ ... }}` and `{% ... %}` tags.
+/// Comments are dropped and the contents of `{% raw %}` blocks become text.
+pub fn tokenize(source: &str, whitespace: WhitespaceConfig) -> Result<Vec<(Token, Span)>, Error> {
+    let mut lexer = Lexer {
+        source,
+        offset: 0,
+        tokens: Vec::new(),
+        whitespace,
+        trim_next: Trim::None,
+    };
+    while lexer.offset < source.len() {
+        lexer.lex_text();
+        let rest = lexer.rest();
+        let (start, end) = match rest.get(..2) {
+            Some("{#") => {
+                lexer.lex_comment()?;
+                continue;
+            }
+            Some("{{") => (Token::VariableStart, Token::VariableEnd),
+            Some(_) => (Token::BlockStart, Token::BlockEnd),
+            None => break,
+        };
+        let opened_at = lexer.offset;
+        let has_marker = matches!(rest.as_bytes().get(2), Some(b'-' | b'+'));
+        lexer.push(start, if has_marker { 3 } else { 2 });
+        lexer.lex_tag(end)?;
+
+        if let [.., (Token::BlockStart, _), (Token::Ident(name), _), (Token::BlockEnd, _)] =
+            &lexer.tokens[..]
+        {
+            if name == "raw" {
+                lexer.tokens.truncate(lexer.tokens.len() - 3);
+                lexer.lex_raw(opened_at)?;
+            }
+        }
+    }
+    Ok(lexer.tokens)
+}
+
+impl<'a> Lexer<'a> {
+    fn rest(&self) -> &'a str {
+        &self.source[self.offset..]
+    }
+
+    fn push(&mut self, token: Token, len: usize) {
+        let span = Span {
+            start: self.offset,
+            end: self.offset + len,
+        };
+        self.tokens.push((token, span));
+        self.offset += len;
+    }
+
+    fn error(&self, offset: usize, message: &str) -> Error {
+        error_at(self.source, offset, message)
+    }
+
+    /// Consumes text up to the next tag opening, or to the end of the source.
+    fn lex_text(&mut self) {
+        let rest = self.rest();
+        let len = rest
+            .match_indices('{')
+            .map(|(i, _)| i)
+            .find(|&i| matches!(rest.as_bytes().get(i + 1), Some(b'{' | b'%' | b'#')))
+            .unwrap_or(rest.len());
+        self.push_text(len);
+    }
+
+    /// Consumes `len` bytes of text, stripping whitespace as the tags around it ask for.
+    fn push_text(&mut self, len: usize) {
+        let start = self.offset;
+        let rest = self.rest();
+        let raw = &rest[..len];
+        let mut text = match mem::take(&mut self.trim_next) {
+            Trim::None => raw,
+            Trim::Newline => raw
+                .strip_prefix("\r\n")
+                .or_else(|| raw.strip_prefix('\n'))
+                .unwrap_or(raw),
+            Trim::Whitespace => raw.trim_start(),
+        };
+
+        let tag = &rest[len..];
+        match tag.as_bytes().get(2) {
+            Some(b'-') => text = text.trim_end(),
+            Some(b'+') => {}
+            _ if self.whitespace.lstrip_blocks
+                && (tag.starts_with("{%") || tag.starts_with("{#")) =>
+            {
+                // Only when nothing but spaces and tabs precede the tag on its line
+                let text_start = start + (raw.len() - text.len());
+                let line_start = match text.rfind('\n') {
+                    Some(newline) => Some(newline + 1),
+                    None if text_start == 0 || self.source.as_bytes()[text_start - 1] == b'\n' => {
+                        Some(0)
+                    }
+                    None => None,
+                };
+                if let Some(line_start) = line_start {
+                    if text[line_start..]
+                        .trim_start_matches([' ', '\t'])
+                        .is_empty()
+                    {
+                        text = &text[..line_start];
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        if !text.is_empty() {
+            let span = Span {
+                start,
+                end: start + len,
+            };
+            self.tokens.push((Token::Text(text.to_string()), span));
+        }
+        self.offset += len;
+    }
+
+    /// Sets up whitespace control for the text after a tag, given the marker before its end.
+    fn close_tag(&mut self, marker: Option<char>, is_block: bool) {
+        self.trim_next = match marker {
+            Some('-') => Trim::Whitespace,
+            Some(_) => Trim::None,
+            None if is_block && self.whitespace.trim_blocks => Trim::Newline,
+            None => Trim::None,
+        };
+    }
+
+    fn lex_comment(&mut self) -> Result<(), Error> {
+        let rest = self.rest();
+        let Some(end) = rest[2..].find("#}").map(|end| end + 2) else {
+            return Err(self.error(self.offset, "Unclosed comment"));
+        };
+        let marker = rest[..end]
+            .chars()
+            .last()
+            .filter(|c| end > 2 && matches!(c, '-' | '+'));
+        self.offset += end + 2;
+        self.close_tag(marker, true);
+        Ok(())
+    }
+
+    /// Consumes everything up to `{% endraw %}` as text.
+    fn lex_raw(&mut self, opened_at: usize) -> Result<(), Error> {
+        let rest = self.rest();
+        let end_tag = rest.match_indices("{%").find_map(|(start, _)| {
+            let tag = &rest[start + 2..];
+            let tag = tag.strip_prefix(['-', '+']).unwrap_or(tag);
+            let tag = tag.trim_start().strip_prefix("endraw")?.trim_start();
+            let marker = tag.chars().next().filter(|c| matches!(c, '-' | '+'));
+            let close_len = if marker.is_some() { 3 } else { 2 };
+            tag[close_len - 2..].starts_with("%}").then(|| {
+                let end = rest.len() - tag.len() + close_len;
+                (start, end, marker)
+            })
+        });
+        let Some((start, end, marker)) = end_tag else {
+            return Err(self.error(opened_at, "Missing '{% endraw %}' for 'raw' block"));
+        };
+
+        self.push_text(start);
+        self.offset += end - start;
+        self.close_tag(marker, true);
+        Ok(())
+    }
+
+    fn lex_tag(&mut self, end: Token) -> Result<(), Error> {
+        let opened_at = self.offset - 2;
+        let closing = if end == Token::VariableEnd {
+            "}}"
+        } else {
+            "%}"
+        };
+        // Braces of dict literals must not be mistaken for the end of the tag
+        let mut depth = 0usize;
+
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.offset += rest.len() - trimmed.len();
+
+            let Some(c) = trimmed.chars().next() else {
+                return Err(self.error(opened_at, "Unclosed tag"));
+            };
+            if depth == 0 {
+                let marker = Some(c).filter(|c| matches!(c, '-' | '+'));
+                let len = match marker {
+                    Some(_) if trimmed[1..].starts_with(closing) => 3,
+                    None if trimmed.starts_with(closing) => 2,
+                    _ => 0,
+                };
+                if len > 0 {
+                    let is_block = end == Token::BlockEnd;
+                    self.push(end, len);
+                    self.close_tag(marker, is_block);
+                    return Ok(());
+                }
+            }
+
+            match c {
+                'a'..='z' | 'A'..='Z' | '_' => {
+                    let len = trimmed
+                        .find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '_'))
+                        .unwrap_or(trimmed.len());
+                    self.push(Token::Ident(trimmed[..len].to_string()), len);
+                }
+                '0'..='9' => self.lex_number(trimmed)?,
+                '"' | '\'' => self.lex_string(trimmed, c)?,
+                '{' => {
+                    depth += 1;
+                    self.push(Token::LBrace, 1);
+                }
+                '}' if depth > 0 => {
+                    depth -= 1;
+                    self.push(Token::RBrace, 1);
+                }
+                _ => {
+                    let (token, len) = match trimmed.get(..2).unwrap_or(trimmed) {
+                        "**" => (Token::StarStar, 2),
+                        "//" => (Token::SlashSlash, 2),
+                        "==" => (Token::Eq, 2),
+                        "!=" => (Token::Ne, 2),
+                        "<=" => (Token::Le, 2),
+                        ">=" => (Token::Ge, 2),
+                        _ => match c {
+                            '|' => (Token::Pipe, 1),
+                            ',' => (Token::Comma, 1),
+                            ':' => (Token::Colon, 1),
+                            '(' => (Token::LParen, 1),
+                            ')' => (Token::RParen, 1),
+                            '[' => (Token::LBracket, 1),
+                            ']' => (Token::RBracket, 1),
+                            '.' => (Token::Dot, 1),
+                            '~' => (Token::Tilde, 1),
+                            '+' => (Token::Plus, 1),
+                            '-' => (Token::Minus, 1),
+                            '*' => (Token::Star, 1),
+                            '/' => (Token::Slash, 1),
+                            '%' => (Token::Percent, 1),
+                            '<' => (Token::Lt, 1),
+                            '>' => (Token::Gt, 1),
+                            '=' => (Token::Assign, 1),
+                            _ => {
+                                return Err(self
+                                    .error(self.offset, &format!("Unexpected character '{}'", c)))
+                            }
+                        },
+                    };
+                    self.push(token, len);
+                }
+            }
+        }
+    }
+
+    fn lex_number(&mut self, input: &str) -> Result<(), Error> {
+        let digits = |s: &str| s.find(|ch: char| !ch.is_ascii_digit()).unwrap_or(s.len());
+        let mut len = digits(input);
+        let is_float = input[len..].starts_with('.')
+            && input[len + 1..].starts_with(|ch: char| ch.is_ascii_digit());
+        if is_float {
+            len += 1 + digits(&input[len + 1..]);
+        }
+
+        let text = &input[..len];
+        let token = if is_float {
+            text.parse().map(Token::Float).ok()
+        } else {
+            text.parse().map(Token::Integer).ok()
+        };
+        match token {
+            Some(token) => {
+                self.push(token, len);
+                Ok(())
+            }
+            None => Err(self.error(self.offset, &format!("Invalid number '{}'", text))),
+        }
+    }
+
+    fn lex_string(&mut self, input: &str, quote: char) -> Result<(), Error> {
+        let mut value = String::new();
+        let mut chars = input.char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, 'r')) => value.push('\r'),
+                    Some((_, escaped)) => value.push(escaped),
+                    None => break,
+                },
+                c if c == quote => {
+                    self.push(Token::Str(value), i + 1);
+                    return Ok(());
+                }
+                c => value.push(c),
+            }
+        }
+        Err(self.error(self.offset, "Unterminated string"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        tokens_with(source, WhitespaceConfig::default())
+    }
+
+    fn tokens_with(source: &str, whitespace: WhitespaceConfig) -> Vec<Token> {
+        tokenize(source, whitespace)
+            .unwrap()
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect()
+    }
+
+    fn text(s: &str) -> Token {
+        Token::Text(s.to_string())
+    }
+
+    fn ident(s: &str) -> Token {
+        Token::Ident(s.to_string())
+    }
+
+    #[test]
+    fn splits_text_and_tags_with_spans() {
+        let tokens = tokenize("a {{ x }}", WhitespaceConfig::default()).unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                (text("a "), Span { start: 0, end: 2 }),
+                (Token::VariableStart, Span { start: 2, end: 4 }),
+                (ident("x"), Span { start: 5, end: 6 }),
+                (Token::VariableEnd, Span { start: 7, end: 9 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn lexes_operators_and_literals() {
+        assert_eq!(
+            tokens("{{ a.b[0] ** 2 // 3 != 'x\\n' ~ 1.5 }}"),
+            vec![
+                Token::VariableStart,
+                ident("a"),
+                Token::Dot,
+                ident("b"),
+                Token::LBracket,
+                Token::Integer(0),
+                Token::RBracket,
+                Token::StarStar,
+                Token::Integer(2),
+                Token::SlashSlash,
+                Token::Integer(3),
+                Token::Ne,
+                Token::Str("x\n".to_string()),
+                Token::Tilde,
+                Token::Float(1.5),
+                Token::VariableEnd,
+            ]
+        );
+    }
+
+    #[test]
+    fn dict_braces_do_not_close_tags() {
+        assert_eq!(
+            tokens("{{ {'a': {}} }}"),
+            vec![
+                Token::VariableStart,
+                Token::LBrace,
+                Token::Str("a".to_string()),
+                Token::Colon,
+                Token::LBrace,
+                Token::RBrace,
+                Token::RBrace,
+                Token::VariableEnd,
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_comments_and_keeps_raw_blocks_as_text() {
+        assert_eq!(tokens("a{# {{ x }} #}b"), vec![text("a"), text("b")]);
+        assert_eq!(
+            tokens("{% raw %}{{ x }}{% endraw %}"),
+            vec![text("{{ x }}")]
+        );
+    }
+
+    #[test]
+    fn applies_whitespace_markers() {
+        assert_eq!(
+            tokens("a  {{- x -}}  b"),
+            vec![
+                text("a"),
+                Token::VariableStart,
+                ident("x"),
+                Token::VariableEnd,
+                text("b")
+            ]
+        );
+
+        let whitespace = WhitespaceConfig {
+            trim_blocks: true,
+            lstrip_blocks: true,
+        };
+        assert_eq!(
+            tokens_with("  {% if x %}\nyes\n  {%+ endif %}", whitespace),
+            vec![
+                Token::BlockStart,
+                ident("if"),
+                ident("x"),
+                Token::BlockEnd,
+                text("yes\n  "),
+                Token::BlockStart,
+                ident("endif"),
+                Token::BlockEnd,
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_errors_with_locations() {
+        let err = tokenize("line\n  {{ x", WhitespaceConfig::default()).unwrap_err();
+        assert_eq!(err.message(), "Unclosed tag");
+        assert_eq!((err.line(), err.column()), (Some(2), Some(3)));
+
+        for (source, message) in [
+            ("{{ 'open }}", "Unterminated string"),
+            ("{# open", "Unclosed comment"),
+            (
+                "{{ 99999999999999999999 }}",
+                "Invalid number '99999999999999999999'",
+            ),
+            ("{{ x ? y }}", "Unexpected character '?'"),
+            ("{% raw %}x", "Missing '{% endraw %}' for 'raw' block"),
+        ] {
+            let err = tokenize(source, WhitespaceConfig::default()).unwrap_err();
+            assert_eq!(err.message(), message);
+            assert_eq!(err.kind(), &ErrorKind::Syntax);
+        }
+    }
+
+    #[test]
+    fn locates_spans_by_line_and_character() {
+        let source = "ab\nçd{{";
+        assert_eq!(Span { start: 5, end: 6 }.location(source), (2, 2));
+        assert_eq!(Span { start: 6, end: 8 }.location(source), (2, 3));
+        assert_eq!(Span { start: 0, end: 1 }.location(source), (1, 1));
+    }
+}
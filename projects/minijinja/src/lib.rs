@@ -0,0 +1,18 @@
+pub mod ast;
+pub mod compiler;
+pub mod de;
+pub mod environment;
+pub mod error;
+pub mod filters;
+pub mod functions;
+pub mod i18n;
+pub mod is_tests;
+pub mod lexer;
+pub mod loader;
+pub mod macros;
+pub mod parser;
+pub mod sandbox;
+pub mod ser;
+pub mod stream;
+pub mod values;
+pub mod vm;
@@ -0,0 +1,89 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+};
+
+/// Looks up template sources by name for `extends`, `include` and `Environment::get_template`.
+pub trait Loader {
+    /// Returns the source of the template, or `None` if there is no template with that name.
+    fn get_source(&self, name: &str) -> Result<Option<String>, String>;
+}
+
+/// Loads templates from files below a root directory.
+pub struct FileSystemLoader {
+    root: PathBuf,
+}
+
+impl FileSystemLoader {
+    pub fn new(root: impl Into<PathBuf>) -> FileSystemLoader {
+        FileSystemLoader { root: root.into() }
+    }
+}
+
+impl Loader for FileSystemLoader {
+    fn get_source(&self, name: &str) -> Result<Option<String>, String> {
+        // Template names must not escape the root directory
+        let path = Path::new(name);
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(format!("Invalid template name '{}'", name));
+        }
+
+        match fs::read_to_string(self.root.join(path)) {
+            Ok(source) => Ok(Some(source)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(format!("Failed to read template '{}': {}", name, err)),
+        }
+    }
+}
+
+/// Serves templates registered in memory.
+pub struct MemoryLoader {
+    templates: HashMap<String, String>,
+}
+
+impl MemoryLoader {
+    pub fn new() -> MemoryLoader {
+        MemoryLoader {
+            templates: HashMap::new(),
+        }
+    }
+
+    pub fn add_template(&mut self, name: &str, source: &str) {
+        self.templates.insert(name.to_string(), source.to_string());
+    }
+}
+
+impl Default for MemoryLoader {
+    fn default() -> MemoryLoader {
+        MemoryLoader::new()
+    }
+}
+
+impl Loader for MemoryLoader {
+    fn get_source(&self, name: &str) -> Result<Option<String>, String> {
+        Ok(self.templates.get(name).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_system_loader_stays_below_its_root() {
+        let loader = FileSystemLoader::new(env!("CARGO_MANIFEST_DIR"));
+        assert!(loader.get_source("Cargo.toml").unwrap().is_some());
+        assert_eq!(loader.get_source("missing.html"), Ok(None));
+        for name in ["../Cargo.toml", "/etc/passwd", "src/../Cargo.toml"] {
+            assert_eq!(
+                loader.get_source(name),
+                Err(format!("Invalid template name '{}'", name))
+            );
+        }
+    }
+}
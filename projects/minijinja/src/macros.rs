@@ -1,15 +1,57 @@
-use crate::{ast::Ast, values::Value};
-use std::collections::HashMap;
+use crate::{
+    ast::{Ast, Param},
+    compiler::{compile_macro, Program},
+    values::Value,
+};
+use std::{collections::HashMap, rc::Rc};
 
 #[derive(Clone)]
 pub struct Macro {
     pub name: String,
-    pub params: Vec<String>,
-    pub body: Ast,
+    pub params: Vec<Param>,
+    pub program: Rc<Program>,
 }
 
+impl Macro {
+    /// Matches call arguments to parameters by position and then by name. Parameters
+    /// that were not passed are `None` and fall back to their default.
+    pub fn bind(
+        &self,
+        args: Vec<Value>,
+        mut kwargs: HashMap<String, Value>,
+    ) -> Result<Vec<Option<Value>>, String> {
+        if args.len() > self.params.len() {
+            return Err(format!(
+                "Macro {} expected at most {} arguments, but got {}",
+                self.name,
+                self.params.len(),
+                args.len()
+            ));
+        }
+
+        let mut values: Vec<Option<Value>> = args.into_iter().map(Some).collect();
+        values.resize(self.params.len(), None);
+        for (param, value) in self.params.iter().zip(&mut values) {
+            if let Some(arg) = kwargs.remove(&param.name) {
+                if value.is_some() {
+                    return Err(format!(
+                        "Macro {} got multiple values for argument '{}'",
+                        self.name, param.name
+                    ));
+                }
+                *value = Some(arg);
+            }
+        }
+        if let Some(name) = kwargs.keys().next() {
+            return Err(format!("Macro {} has no parameter '{}'", self.name, name));
+        }
+        Ok(values)
+    }
+}
+
+#[derive(Clone)]
 pub struct MacroRegistry {
-    macros: HashMap<String, Macro>,
+    macros: HashMap<String, Rc<Macro>>,
 }
 
 impl MacroRegistry {
@@ -19,54 +61,21 @@ impl MacroRegistry {
         }
     }
 
-    pub fn define_macro(&mut self, name: &str, params: Vec<String>, body: Ast) {
-        let new_macro = Macro {
-            name: name.to_string(),
-            params,
-            body,
-        };
-        self.macros.insert(name.to_string(), new_macro);
+    pub fn define_macro(&mut self, name: &str, params: Vec<Param>, body: Ast) {
+        self.add_macro(Rc::new(compile_macro(name, &params, &body)));
     }
 
-    pub fn call_macro(
-        &self,
-        name: &str,
-        args: Vec<Value>,
-        vm: &mut crate::vm::Vm,
-    ) -> Result<String, String> {
-        if let Some(macro_def) = self.macros.get(name) {
-            if macro_def.params.len() != args.len() {
-                return Err(format!(
-                    "Expected {} arguments, but got {}",
-                    macro_def.params.len(),
-                    args.len()
-                ));
-            }
-
-            // Temporarily borrow vm mutably
-            let result = {
-                let vm = vm; // mutable borrow is used here
-
-                // Set macro parameters in the VM context
-                for (param, arg) in macro_def.params.iter().zip(args.into_iter()) {
-                    vm.set_variable(param, arg);
-                }
-
-                // Render the macro body
-                vm.render(&macro_def.body)
-            };
+    pub fn add_macro(&mut self, macro_def: Rc<Macro>) {
+        self.macros.insert(macro_def.name.clone(), macro_def);
+    }
 
-            result
-        } else {
-            Err(format!("Macro {} not found", name))
-        }
+    pub fn get_macro(&self, name: &str) -> Option<Rc<Macro>> {
+        self.macros.get(name).cloned()
     }
 }
 
-impl Clone for MacroRegistry {
-    fn clone(&self) -> MacroRegistry {
-        MacroRegistry {
-            macros: self.macros.clone(),
-        }
+impl Default for MacroRegistry {
+    fn default() -> MacroRegistry {
+        MacroRegistry::new()
     }
 }
@@ -1,74 +1,243 @@
-mod ast;
-mod filters;
-mod macros;
-mod parser;
-mod values;
-mod vm;
-
-use filters::FilterRegistry;
-use macros::MacroRegistry;
-use parser::Parser;
-use values::Value;
-use vm::Vm;
+use clap::{Args, Parser, Subcommand, ValueHint};
+use minijinja::{
+    environment::{Environment, UndefinedBehavior},
+    i18n::Catalog,
+    loader::{FileSystemLoader, Loader},
+    values::Value,
+    vm::Vm,
+};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    rc::Rc,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+#[derive(Parser, Debug)]
+#[command(name = "minijinja")]
+#[command(version = "0.1.0")]
+#[command(about = "Renders Jinja-style templates")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Render a template with variables from data files and the command line
+    Render(RenderArgs),
+}
+
+#[derive(Args, Debug)]
+struct RenderArgs {
+    /// Template file to render
+    #[arg(value_hint = ValueHint::FilePath)]
+    template: PathBuf,
+
+    /// JSON, YAML or TOML file with variables (repeatable, later files win)
+    #[arg(short, long, value_hint = ValueHint::FilePath)]
+    data: Vec<PathBuf>,
+
+    /// Variable to set (format: key=value); values that parse as JSON keep their type
+    #[arg(long = "var", value_name = "KEY=VALUE")]
+    vars: Vec<String>,
+
+    /// Gettext catalog (.po or .mo) to translate the template with
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    translations: Option<PathBuf>,
+
+    /// Write the output to a file instead of stdout
+    #[arg(short, long, value_hint = ValueHint::FilePath)]
+    output: Option<PathBuf>,
+
+    /// Directory for extended, included and imported templates (default: the template's directory)
+    #[arg(long, value_hint = ValueHint::DirPath)]
+    templates: Option<PathBuf>,
+
+    /// Only parse the template and report errors
+    #[arg(long, conflicts_with = "watch")]
+    check: bool,
+
+    /// Render again whenever the template, data files or template directory change
+    #[arg(long)]
+    watch: bool,
+
+    /// Fail on undefined variables instead of rendering them as empty
+    #[arg(long)]
+    strict: bool,
+
+    /// Remove the first newline after each block tag
+    #[arg(long)]
+    trim_blocks: bool,
+
+    /// Remove the indentation before block tags that start a line
+    #[arg(long)]
+    lstrip_blocks: bool,
+}
+
+/// Serves the template given on the command line under its file name, and everything
+/// else from the template directory.
+struct CliLoader {
+    name: String,
+    path: PathBuf,
+    templates: FileSystemLoader,
+}
+
+impl Loader for CliLoader {
+    fn get_source(&self, name: &str) -> Result<Option<String>, String> {
+        if name == self.name {
+            return fs::read_to_string(&self.path)
+                .map(Some)
+                .map_err(|err| format!("Failed to read {}: {}", self.path.display(), err));
+        }
+        self.templates.get_source(name)
+    }
+}
 
 fn main() {
-    // Complex template with various features:
-    let template = r#"
-        Hello, {{ name | upper }}!
-        
-        {% if is_logged_in %}
-            Welcome back, {{ name }}!
-        {% else %}
-            Please log in.
-        {% endif %}
-        
-        {% macro greet_user(greeting, user) %}
-            {{ greeting }}, {{ user }}!
-        {% endmacro %}
-        
-        {{ greet_user('Hi', name) }}
-        
-        {% for item in items %}
-            Item: {{ item }}
-        {% endfor %}
-    "#;
-
-    // Set up the parser to tokenize and parse the template
-    let mut parser = Parser::new(template);
-    let ast = match parser.parse() {
-        Ok(ast) => ast,
-        Err(e) => {
-            eprintln!("Error parsing template: {}", e);
-            return;
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Render(args) if args.check => match check(&args) {
+            Ok(()) => eprintln!("{}: OK", args.template.display()),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                process::exit(1);
+            }
+        },
+        Command::Render(args) if args.watch => watch(&args),
+        Command::Render(args) => {
+            if let Err(err) = render(&args) {
+                eprintln!("Error: {}", err);
+                process::exit(1);
+            }
         }
+    }
+}
+
+fn template_name(args: &RenderArgs) -> String {
+    args.template
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// The directory other templates are loaded from: `--templates`, or else the directory
+/// of the template being rendered.
+fn template_root(args: &RenderArgs) -> PathBuf {
+    match &args.templates {
+        Some(dir) => dir.clone(),
+        None => match args.template.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        },
+    }
+}
+
+fn environment(args: &RenderArgs) -> Environment {
+    let templates = template_root(args);
+    let mut env = Environment::new();
+    env.set_loader(CliLoader {
+        name: template_name(args),
+        path: args.template.clone(),
+        templates: FileSystemLoader::new(templates),
+    });
+    if args.strict {
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+    }
+    env.set_trim_blocks(args.trim_blocks);
+    env.set_lstrip_blocks(args.lstrip_blocks);
+    env
+}
+
+fn check(args: &RenderArgs) -> Result<(), String> {
+    environment(args)
+        .get_template(&template_name(args))
+        .map_err(|err| err.to_string())?;
+    Ok(())
+}
+
+fn render(args: &RenderArgs) -> Result<(), String> {
+    let env = environment(args);
+    let mut vm = Vm::new(&env);
+    for path in &args.data {
+        vm.set_context(&load_data(path)?)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+    }
+    for var in &args.vars {
+        let Some((key, value)) = var.split_once('=') else {
+            return Err(format!("Invalid variable '{}', expected key=value", var));
+        };
+        let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.into()));
+        vm.set_variable(key, value);
+    }
+    if let Some(path) = &args.translations {
+        let catalog = Catalog::load(path).map_err(|err| err.to_string())?;
+        vm.set_translations(Rc::new(catalog));
+    }
+
+    let name = template_name(args);
+    match &args.output {
+        // Rendered in full first, so a failed render leaves the previous file in place
+        Some(path) => {
+            let output = vm.render_template(&name).map_err(|err| err.to_string())?;
+            fs::write(path, output)
+                .map_err(|err| format!("Failed to write {}: {}", path.display(), err))
+        }
+        None => vm
+            .render_to(&name, &mut io::stdout().lock())
+            .map_err(|err| err.to_string()),
+    }
+}
+
+/// Reads variables from a data file, choosing the format by its extension.
+fn load_data(path: &Path) -> Result<Value, String> {
+    let source = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+    let value = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::from_str(&source).map_err(|err| err.to_string()),
+        Some("yaml" | "yml") => serde_yaml::from_str(&source).map_err(|err| err.to_string()),
+        Some("toml") => toml::from_str(&source).map_err(|err| err.to_string()),
+        _ => Err("unknown format, expected .json, .yaml or .toml".to_string()),
     };
+    value.map_err(|err| format!("{}: {}", path.display(), err))
+}
 
-    // Prepare the runtime environment
-    let mut vm = Vm::new();
-
-    // Set up context variables to simulate data passed to the template
-    vm.set_variable("name", Value::String("Alice".to_string()));
-    vm.set_variable("is_logged_in", Value::Bool(true));
-    vm.set_variable(
-        "items",
-        Value::List(vec![
-            Value::String("Item 1".to_string()),
-            Value::String("Item 2".to_string()),
-            Value::String("Item 3".to_string()),
-        ]),
-    );
-
-    // Register macros
-    let macro_registry = MacroRegistry::new();
-    vm.set_macro_registry(macro_registry);
-
-    // Register filters
-    let filter_registry = FilterRegistry::new();
-    vm.set_filter_registry(filter_registry);
-
-    // Render the template
-    match vm.render(&ast) {
-        Ok(output) => println!("Rendered output:\n{}", output),
-        Err(e) => eprintln!("Error rendering template: {}", e),
+/// Polls modification times rather than subscribing to file system events, which keeps
+/// the CLI free of platform-specific dependencies.
+fn watch(args: &RenderArgs) {
+    let mut inputs = vec![args.template.clone()];
+    inputs.extend(args.data.iter().cloned());
+    inputs.extend(args.translations.iter().cloned());
+    // Includes, imports and parents are loaded from here
+    inputs.push(template_root(args));
+
+    let mut last_seen = None;
+    loop {
+        let seen = modification_times(&inputs);
+        if last_seen.as_ref() != Some(&seen) {
+            match render(args) {
+                Ok(()) => eprintln!("Rendered {}", args.template.display()),
+                Err(err) => eprintln!("Error: {}", err),
+            }
+            last_seen = Some(seen);
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+fn modification_times(paths: &[PathBuf]) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let mut times = Vec::new();
+    for path in paths {
+        let modified = fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        times.push((path.clone(), modified));
+        if let Ok(entries) = fs::read_dir(path) {
+            let mut children: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+            children.sort();
+            times.extend(modification_times(&children));
+        }
     }
+    times
 }
@@ -1,170 +1,1031 @@
 use crate::{
-    ast::{Ast, AstNode},
-    macros::MacroRegistry,
+    ast::{Ast, AstNode, BinaryOp, Expr, Kwargs, Param, UnaryOp},
+    error::{Error, ErrorKind},
+    lexer::{error_at, tokenize, Span, Token, WhitespaceConfig},
+    values::Value,
 };
 
-pub struct Parser {
-    tokens: Vec<Token>,
-    position: usize,
-    macro_registry: MacroRegistry, // Handle macros within the parser
-}
+/// How deeply blocks and expressions may nest. Parsing, compiling and rendering all
+/// recurse into nested code, so this keeps hostile templates from overflowing the stack.
+const MAX_DEPTH: usize = 64;
 
-#[derive(Debug, PartialEq)]
-pub enum Token {
-    Text(String),
-    Variable(String),
-    OpenBlock,
-    CloseBlock,
-    If,
-    Else,
-    EndIf,
-    For(String, String), // variable, collection
-    EndFor,
-    Macro(String, Vec<String>), // macro name, params
-    EndMacro,
-    Filter(String, String), // variable, filter name
+pub struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(Token, Span)>,
+    position: usize,
+    /// How many `for` bodies enclose the current position, to validate `break`/`continue`
+    loop_depth: usize,
+    /// How many blocks and expressions enclose the current position, up to `MAX_DEPTH`
+    depth: usize,
+    whitespace: WhitespaceConfig,
 }
 
-impl Parser {
-    pub fn new(input: &str) -> Parser {
-        let tokens = lex(input);
+impl<'a> Parser<'a> {
+    pub fn new(input: &'a str) -> Parser<'a> {
         Parser {
-            tokens,
+            source: input,
+            tokens: Vec::new(),
             position: 0,
-            macro_registry: MacroRegistry::new(),
+            loop_depth: 0,
+            depth: 0,
+            whitespace: WhitespaceConfig::default(),
         }
     }
 
-    pub fn parse(&mut self) -> Result<Ast, String> {
+    pub fn set_whitespace(&mut self, whitespace: WhitespaceConfig) {
+        self.whitespace = whitespace;
+    }
+
+    pub fn parse(&mut self) -> Result<Ast, Error> {
+        self.tokens = tokenize(self.source, self.whitespace)?;
+        self.position = 0;
+        self.depth = 0;
+        let (ast, _) = self.parse_until(&[], None)?;
+        Ok(ast)
+    }
+
+    /// Parses nodes until a block tag named in `end` and returns that tag's name.
+    /// `opened` names the block being parsed, for the error when the template ends first.
+    fn parse_until(
+        &mut self,
+        end: &[&str],
+        opened: Option<(&str, Span)>,
+    ) -> Result<(Ast, String), Error> {
         let mut ast = Ast::new();
-        while let Some(token) = self.tokens.get(self.position) {
+        while let Some((token, span)) = self.next() {
+            let start = self.position - 1;
             match token {
-                Token::Text(text) => ast.add_node(AstNode::Text(text.to_string())),
-                Token::Variable(variable) => {
-                    ast.add_node(AstNode::Variable(variable.to_string(), Vec::new()))
+                Token::Text(text) => ast.add_node(AstNode::Text(text), span),
+                Token::VariableStart => {
+                    let node = self.parse_interpolation()?;
+                    ast.add_node(node, self.tag_span(start));
                 }
-                Token::OpenBlock => {
-                    // Parse control structures like `if` and `for`
-                    self.parse_block(&mut ast)?
+                Token::BlockStart => {
+                    let (tag, tag_span) = self.expect_ident()?;
+                    if end.contains(&tag.as_str()) {
+                        return Ok((ast, tag));
+                    }
+                    let node = self.nested(|parser| parser.parse_block(&tag, tag_span))?;
+                    ast.add_node(node, self.tag_span(start));
                 }
-                _ => return Err(format!("Unexpected token: {:?}", token)),
+                token => return Err(self.error(span, &format!("Unexpected {}", token))),
             }
-            self.position += 1;
         }
-        Ok(ast)
+
+        match opened {
+            Some((name, span)) => Err(self.error(
+                span,
+                &format!("Missing '{}' for '{}' block", end[end.len() - 1], name),
+            )),
+            None => Ok((ast, String::new())),
+        }
     }
 
-    fn parse_block(&mut self, ast: &mut Ast) -> Result<(), String> {
-        self.position += 1; // Skip the block opening token
+    fn parse_interpolation(&mut self) -> Result<AstNode, Error> {
+        let is_super = self.peek() == Some(&Token::Ident("super".to_string()))
+            && self.peek_nth(1) == Some(&Token::LParen);
+        let node = if is_super {
+            let (_, span) = self.expect_ident()?;
+            self.expect(Token::LParen)?;
+            if !self.eat(&Token::RParen) {
+                return Err(self.error(span, "super() takes no arguments"));
+            }
+            AstNode::Super
+        } else {
+            AstNode::Variable(self.parse_expr()?)
+        };
+        self.expect(Token::VariableEnd)?;
+        Ok(node)
+    }
 
-        let current_position = self.position; // Capture current position for later use
+    fn parse_block(&mut self, tag: &str, span: Span) -> Result<AstNode, Error> {
+        match tag {
+            "if" => self.parse_if(span),
+            "for" => {
+                let mut targets = vec![self.expect_ident()?.0];
+                while self.eat(&Token::Comma) {
+                    targets.push(self.expect_ident()?.0);
+                }
+                self.expect_keyword("in")?;
+                // A conditional expression would swallow the loop filter's `if`
+                let collection = self.parse_or()?;
+                let filter = if self.eat_keyword("if") {
+                    Some(self.parse_expr()?)
+                } else {
+                    None
+                };
+                self.expect(Token::BlockEnd)?;
 
-        match self.tokens.get(current_position) {
-            Some(Token::If) => {
-                self.position = current_position + 1;
-                let condition_var =
-                    if let Some(Token::Variable(var)) = self.tokens.get(self.position) {
-                        self.position += 1;
-                        var.to_string()
+                self.loop_depth += 1;
+                let body = self.parse_until(&["else", "endfor"], Some((tag, span)));
+                self.loop_depth -= 1;
+                let (body, end) = body?;
+                self.expect(Token::BlockEnd)?;
+                let else_block = if end == "else" {
+                    let (else_block, _) = self.parse_until(&["endfor"], Some((tag, span)))?;
+                    self.expect(Token::BlockEnd)?;
+                    Some(Box::new(else_block))
+                } else {
+                    None
+                };
+                Ok(AstNode::ForBlock {
+                    targets,
+                    collection,
+                    filter,
+                    body: Box::new(body),
+                    else_block,
+                })
+            }
+            "call" => {
+                let call = self.parse_expr()?;
+                if !matches!(call, Expr::Call { .. }) {
+                    return Err(self.error(span, "Expected a macro call after 'call'"));
+                }
+                self.expect(Token::BlockEnd)?;
+                let loop_depth = std::mem::take(&mut self.loop_depth);
+                let body = self.parse_until(&["endcall"], Some((tag, span)));
+                self.loop_depth = loop_depth;
+                let (body, _) = body?;
+                self.expect(Token::BlockEnd)?;
+                Ok(AstNode::CallBlock {
+                    call,
+                    body: Box::new(body),
+                })
+            }
+            "set" => {
+                let (target, _) = self.expect_ident()?;
+                if self.eat(&Token::Assign) {
+                    let value = self.parse_expr()?;
+                    self.expect(Token::BlockEnd)?;
+                    return Ok(AstNode::Set { target, value });
+                }
+                self.expect(Token::BlockEnd)?;
+                // The captured body can't jump out of an enclosing loop
+                let loop_depth = std::mem::take(&mut self.loop_depth);
+                let body = self.parse_until(&["endset"], Some((tag, span)));
+                self.loop_depth = loop_depth;
+                let (body, _) = body?;
+                self.expect(Token::BlockEnd)?;
+                Ok(AstNode::SetBlock {
+                    target,
+                    body: Box::new(body),
+                })
+            }
+            "with" => {
+                let mut assignments = Vec::new();
+                while !self.eat(&Token::BlockEnd) {
+                    if !assignments.is_empty() {
+                        self.expect(Token::Comma)?;
+                    }
+                    let (name, _) = self.expect_ident()?;
+                    self.expect(Token::Assign)?;
+                    assignments.push((name, self.parse_expr()?));
+                }
+                let (body, _) = self.parse_until(&["endwith"], Some((tag, span)))?;
+                self.expect(Token::BlockEnd)?;
+                Ok(AstNode::With {
+                    assignments,
+                    body: Box::new(body),
+                })
+            }
+            "import" => {
+                let template = self.parse_expr()?;
+                self.expect_keyword("as")?;
+                let (alias, _) = self.expect_ident()?;
+                self.expect(Token::BlockEnd)?;
+                Ok(AstNode::Import { template, alias })
+            }
+            "break" | "continue" => {
+                if self.loop_depth == 0 {
+                    return Err(self.error(span, &format!("'{}' outside of a loop", tag)));
+                }
+                self.expect(Token::BlockEnd)?;
+                Ok(if tag == "break" {
+                    AstNode::Break
+                } else {
+                    AstNode::Continue
+                })
+            }
+            "macro" => {
+                let (name, _) = self.expect_ident()?;
+                self.expect(Token::LParen)?;
+                let params = self.parse_list(Token::RParen, |parser| {
+                    let (name, _) = parser.expect_ident()?;
+                    let default = if parser.eat(&Token::Assign) {
+                        Some(parser.parse_expr()?)
                     } else {
-                        return Err("Expected variable after 'if'".to_string());
+                        None
                     };
+                    Ok(Param { name, default })
+                })?;
+                self.expect(Token::BlockEnd)?;
+                // Loops around a macro definition don't apply inside its body
+                let loop_depth = std::mem::take(&mut self.loop_depth);
+                let body = self.parse_until(&["endmacro"], Some((tag, span)));
+                self.loop_depth = loop_depth;
+                let (body, _) = body?;
+                self.expect(Token::BlockEnd)?;
+                Ok(AstNode::MacroDef {
+                    name,
+                    params,
+                    body: Box::new(body),
+                })
+            }
+            "extends" => {
+                let parent = self.parse_expr()?;
+                self.expect(Token::BlockEnd)?;
+                Ok(AstNode::Extends(parent))
+            }
+            "block" => {
+                let (name, _) = self.expect_ident()?;
+                self.expect(Token::BlockEnd)?;
+                // Blocks are rendered as their own program, outside of any enclosing loop
+                let loop_depth = std::mem::take(&mut self.loop_depth);
+                let body = self.parse_until(&["endblock"], Some((tag, span)));
+                self.loop_depth = loop_depth;
+                let (body, _) = body?;
+                // `{% endblock name %}` may repeat the block name
+                if let Some(Token::Ident(end_name)) = self.peek() {
+                    if *end_name != name {
+                        let end_span = self.tokens[self.position].1;
+                        return Err(self.error(
+                            end_span,
+                            &format!("Expected 'endblock {}', found '{}'", name, end_name),
+                        ));
+                    }
+                    self.position += 1;
+                }
+                self.expect(Token::BlockEnd)?;
+                Ok(AstNode::Block {
+                    name,
+                    body: Box::new(body),
+                })
+            }
+            "include" => {
+                let name = self.parse_expr()?;
+                let ignore_missing = self.eat_keyword("ignore");
+                if ignore_missing {
+                    self.expect_keyword("missing")?;
+                }
+                self.expect(Token::BlockEnd)?;
+                Ok(AstNode::Include {
+                    name,
+                    ignore_missing,
+                })
+            }
+            "autoescape" => {
+                let enabled = self.parse_expr()?;
+                self.expect(Token::BlockEnd)?;
+                let (body, _) = self.parse_until(&["endautoescape"], Some((tag, span)))?;
+                self.expect(Token::BlockEnd)?;
+                Ok(AstNode::AutoEscape {
+                    enabled,
+                    body: Box::new(body),
+                })
+            }
+            "trans" => self.parse_trans(span),
+            _ => Err(self.error(span, &format!("Unknown block tag '{}'", tag))),
+        }
+    }
 
-                let then_block = self.parse_block_body()?;
+    /// Parses the rest of `{% trans [trimmed] [name=expr, ...] %}` and its bodies, which
+    /// may only contain text and `{{ name }}`.
+    fn parse_trans(&mut self, span: Span) -> Result<AstNode, Error> {
+        let mut trimmed = false;
+        let mut variables = Vec::new();
+        while !self.eat(&Token::BlockEnd) {
+            if !variables.is_empty() {
+                self.expect(Token::Comma)?;
+            }
+            let (name, _) = self.expect_ident()?;
+            if self.eat(&Token::Assign) {
+                variables.push((name, self.parse_expr()?));
+            } else if name == "trimmed" || name == "notrimmed" {
+                trimmed = name == "trimmed";
+            } else {
+                // `{% trans user %}` is short for `{% trans user=user %}`
+                variables.push((name.clone(), Expr::Name(name)));
+            }
+        }
 
-                let else_block = if let Some(Token::Else) = self.tokens.get(self.position) {
+        let (body, end) = self.parse_until(&["pluralize", "endtrans"], Some(("trans", span)))?;
+        let singular = self.trans_message(&body, &mut variables, trimmed)?;
+        let (plural, count) = if end == "pluralize" {
+            let count = match self.peek() {
+                Some(Token::Ident(_)) => Some(self.expect_ident()?.0),
+                _ => None,
+            };
+            self.expect(Token::BlockEnd)?;
+            let (body, _) = self.parse_until(&["endtrans"], Some(("trans", span)))?;
+            let plural = self.trans_message(&body, &mut variables, trimmed)?;
+            // Without a name, the first variable counts, as in Jinja
+            let Some(count) = count.or_else(|| variables.first().map(|(name, _)| name.clone()))
+            else {
+                return Err(self.error(span, "'pluralize' needs a variable to count"));
+            };
+            if !variables.iter().any(|(name, _)| *name == count) {
+                variables.push((count.clone(), Expr::Name(count.clone())));
+            }
+            (Some(plural), Some(count))
+        } else {
+            (None, None)
+        };
+        self.expect(Token::BlockEnd)?;
+        Ok(AstNode::Trans {
+            singular,
+            plural,
+            count,
+            variables,
+        })
+    }
+
+    /// Turns a `trans` body into a gettext message, adding the variables it uses.
+    fn trans_message(
+        &self,
+        body: &Ast,
+        variables: &mut Vec<(String, Expr)>,
+        trimmed: bool,
+    ) -> Result<String, Error> {
+        let mut message = String::new();
+        for (node, span) in body.nodes.iter().zip(&body.spans) {
+            match node {
+                AstNode::Text(text) => message.push_str(&text.replace('%', "%%")),
+                AstNode::Variable(Expr::Name(name)) => {
+                    message.push_str(&format!("%({})s", name));
+                    if !variables.iter().any(|(variable, _)| variable == name) {
+                        variables.push((name.clone(), Expr::Name(name.clone())));
+                    }
+                }
+                _ => {
+                    return Err(self.error(
+                        *span,
+                        "Only text and simple variables are allowed in 'trans' blocks",
+                    ))
+                }
+            }
+        }
+        Ok(if trimmed {
+            trim_lines(&message)
+        } else {
+            message
+        })
+    }
+
+    /// Parses the rest of an `if` or `elif` tag; `elif` chains nest as the else branch.
+    fn parse_if(&mut self, span: Span) -> Result<AstNode, Error> {
+        let condition = self.parse_expr()?;
+        self.expect(Token::BlockEnd)?;
+        let (then_block, end) = self.parse_until(&["elif", "else", "endif"], Some(("if", span)))?;
+        let else_block = match end.as_str() {
+            "elif" => {
+                // `{% elif` was consumed by `parse_until`
+                let elif_span = self.tag_span(self.position - 2);
+                let mut else_block = Ast::new();
+                else_block.add_node(self.parse_if(span)?, elif_span);
+                Some(Box::new(else_block))
+            }
+            "else" => {
+                self.expect(Token::BlockEnd)?;
+                let (else_block, _) = self.parse_until(&["endif"], Some(("if", span)))?;
+                self.expect(Token::BlockEnd)?;
+                Some(Box::new(else_block))
+            }
+            _ => {
+                self.expect(Token::BlockEnd)?;
+                None
+            }
+        };
+        Ok(AstNode::IfBlock {
+            condition,
+            then_block: Box::new(then_block),
+            else_block,
+        })
+    }
+
+    /// Parses a full expression, including the `a if cond else b` conditional.
+    fn parse_expr(&mut self) -> Result<Expr, Error> {
+        self.nested(Self::parse_conditional)
+    }
+
+    fn parse_conditional(&mut self) -> Result<Expr, Error> {
+        let expr = self.parse_or()?;
+        if !self.eat_keyword("if") {
+            return Ok(expr);
+        }
+        let condition = self.parse_or()?;
+        let else_expr = if self.eat_keyword("else") {
+            Some(Box::new(self.parse_expr()?))
+        } else {
+            None
+        };
+        Ok(Expr::Conditional {
+            condition: Box::new(condition),
+            then_expr: Box::new(expr),
+            else_expr,
+        })
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, Error> {
+        let depth = self.depth;
+        let mut expr = self.parse_and()?;
+        while self.eat_keyword("or") {
+            self.deeper()?;
+            expr = binary(BinaryOp::Or, expr, self.parse_and()?);
+        }
+        self.depth = depth;
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, Error> {
+        let depth = self.depth;
+        let mut expr = self.parse_not()?;
+        while self.eat_keyword("and") {
+            self.deeper()?;
+            expr = binary(BinaryOp::And, expr, self.parse_not()?);
+        }
+        self.depth = depth;
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, Error> {
+        if self.eat_keyword("not") {
+            let expr = self.nested(Self::parse_not)?;
+            return Ok(Expr::Unary(UnaryOp::Not, Box::new(expr)));
+        }
+        self.parse_compare()
+    }
+
+    fn parse_compare(&mut self) -> Result<Expr, Error> {
+        let depth = self.depth;
+        let mut expr = self.parse_math1()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Eq) => BinaryOp::Eq,
+                Some(Token::Ne) => BinaryOp::Ne,
+                Some(Token::Lt) => BinaryOp::Lt,
+                Some(Token::Le) => BinaryOp::Le,
+                Some(Token::Gt) => BinaryOp::Gt,
+                Some(Token::Ge) => BinaryOp::Ge,
+                Some(Token::Ident(name)) if name == "in" => BinaryOp::In,
+                Some(Token::Ident(name))
+                    if name == "not"
+                        && matches!(self.peek_nth(1), Some(Token::Ident(next)) if next == "in") =>
+                {
                     self.position += 1;
-                    Some(Box::new(self.parse_block_body()?))
+                    BinaryOp::NotIn
+                }
+                _ => break,
+            };
+            self.position += 1;
+            self.deeper()?;
+            expr = binary(op, expr, self.parse_math1()?);
+        }
+        self.depth = depth;
+        Ok(expr)
+    }
+
+    fn parse_math1(&mut self) -> Result<Expr, Error> {
+        self.parse_binary(
+            &[(Token::Plus, BinaryOp::Add), (Token::Minus, BinaryOp::Sub)],
+            Self::parse_concat,
+        )
+    }
+
+    fn parse_concat(&mut self) -> Result<Expr, Error> {
+        self.parse_binary(&[(Token::Tilde, BinaryOp::Concat)], Self::parse_math2)
+    }
+
+    fn parse_math2(&mut self) -> Result<Expr, Error> {
+        self.parse_binary(
+            &[
+                (Token::Star, BinaryOp::Mul),
+                (Token::Slash, BinaryOp::Div),
+                (Token::SlashSlash, BinaryOp::FloorDiv),
+                (Token::Percent, BinaryOp::Rem),
+            ],
+            Self::parse_pow,
+        )
+    }
+
+    fn parse_pow(&mut self) -> Result<Expr, Error> {
+        self.parse_binary(&[(Token::StarStar, BinaryOp::Pow)], Self::parse_unary)
+    }
+
+    /// Parses one left-associative precedence level.
+    fn parse_binary(
+        &mut self,
+        ops: &[(Token, BinaryOp)],
+        operand: fn(&mut Self) -> Result<Expr, Error>,
+    ) -> Result<Expr, Error> {
+        let depth = self.depth;
+        let mut expr = operand(self)?;
+        while let Some(op) = ops
+            .iter()
+            .find(|(token, _)| self.peek() == Some(token))
+            .map(|(_, op)| *op)
+        {
+            self.position += 1;
+            self.deeper()?;
+            expr = binary(op, expr, operand(self)?);
+        }
+        self.depth = depth;
+        Ok(expr)
+    }
+
+    /// Parses a unary expression; filters and tests apply to the whole of it, so `-x | abs`
+    /// is `abs(-x)`.
+    fn parse_unary(&mut self) -> Result<Expr, Error> {
+        let expr = self.parse_sign()?;
+        self.parse_filters(expr)
+    }
+
+    fn parse_sign(&mut self) -> Result<Expr, Error> {
+        let op = if self.eat(&Token::Minus) {
+            UnaryOp::Neg
+        } else if self.eat(&Token::Plus) {
+            UnaryOp::Pos
+        } else {
+            return self.parse_postfix();
+        };
+        Ok(Expr::Unary(op, Box::new(self.nested(Self::parse_sign)?)))
+    }
+
+    fn parse_filters(&mut self, mut expr: Expr) -> Result<Expr, Error> {
+        let depth = self.depth;
+        loop {
+            if self.eat(&Token::Pipe) {
+                self.deeper()?;
+                let (name, _) = self.expect_ident()?;
+                let (args, kwargs) = if self.eat(&Token::LParen) {
+                    self.parse_call_args()?
                 } else {
-                    None
+                    (Vec::new(), Vec::new())
+                };
+                expr = Expr::Filter {
+                    expr: Box::new(expr),
+                    name,
+                    args,
+                    kwargs,
                 };
+            } else if self.eat_keyword("is") {
+                self.deeper()?;
+                expr = self.parse_test(expr)?;
+            } else {
+                break;
+            }
+        }
+        self.depth = depth;
+        Ok(expr)
+    }
 
-                ast.add_node(AstNode::IfBlock {
-                    condition: condition_var,
-                    then_block: Box::new(then_block),
-                    else_block,
-                });
+    /// Parses `[not] name`, `name(args)` or `name arg` after `is`.
+    fn parse_test(&mut self, expr: Expr) -> Result<Expr, Error> {
+        let negated = self.eat_keyword("not");
+        let (name, span) = self.expect_ident()?;
+        let args = if self.eat(&Token::LParen) {
+            let (args, kwargs) = self.parse_call_args()?;
+            if !kwargs.is_empty() {
+                return Err(self.error(span, "Tests take no keyword arguments"));
             }
+            args
+        } else if self.starts_test_arg() {
+            // `x is divisibleby 3` passes a single argument without parentheses
+            vec![self.parse_postfix()?]
+        } else {
+            Vec::new()
+        };
+        let test = Expr::Test {
+            expr: Box::new(expr),
+            name,
+            args,
+        };
+        Ok(if negated {
+            Expr::Unary(UnaryOp::Not, Box::new(test))
+        } else {
+            test
+        })
+    }
 
-            _ => return Err("Unknown block type".to_string()),
+    fn starts_test_arg(&self) -> bool {
+        match self.peek() {
+            Some(Token::Str(_) | Token::Integer(_) | Token::Float(_)) => true,
+            Some(Token::LBracket | Token::LBrace) => true,
+            Some(Token::Ident(name)) => !matches!(
+                name.as_str(),
+                "and" | "or" | "not" | "in" | "is" | "if" | "else"
+            ),
+            _ => false,
         }
-        Ok(())
     }
 
-    fn parse_block_body(&mut self) -> Result<Ast, String> {
-        let mut block_ast = Ast::new();
-        while let Some(token) = self.tokens.get(self.position) {
-            match token {
-                Token::CloseBlock | Token::EndIf | Token::EndFor | Token::EndMacro => {
-                    self.position += 1;
-                    break;
+    /// Parses `(a, b, key=c)` after the opening parenthesis.
+    fn parse_call_args(&mut self) -> Result<(Vec<Expr>, Kwargs), Error> {
+        let mut args = Vec::new();
+        let mut kwargs = Vec::new();
+        let items = self.parse_list(Token::RParen, |parser| {
+            let is_keyword = matches!(parser.peek(), Some(Token::Ident(_)))
+                && parser.peek_nth(1) == Some(&Token::Assign);
+            if is_keyword {
+                let (name, _) = parser.expect_ident()?;
+                parser.expect(Token::Assign)?;
+                Ok((Some(name), parser.parse_expr()?))
+            } else {
+                Ok((None, parser.parse_expr()?))
+            }
+        })?;
+        for (name, expr) in items {
+            match name {
+                Some(name) => kwargs.push((name, expr)),
+                None if kwargs.is_empty() => args.push(expr),
+                None => {
+                    let span = self.tokens[self.position - 1].1;
+                    return Err(self.error(span, "Positional argument follows keyword argument"));
                 }
-                _ => {
-                    let node = self.parse_token()?;
-                    block_ast.nodes.extend(node.nodes);
+            }
+        }
+        Ok((args, kwargs))
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expr, Error> {
+        let depth = self.depth;
+        let mut expr = self.parse_primary()?;
+        loop {
+            if self.eat(&Token::Dot) {
+                self.deeper()?;
+                expr = match self.next_or_eof()? {
+                    (Token::Ident(name), _) => Expr::GetAttr(Box::new(expr), name),
+                    // `items.0` is the same as `items[0]`
+                    (Token::Integer(i), _) => {
+                        Expr::GetItem(Box::new(expr), Box::new(Expr::Literal(Value::Integer(i))))
+                    }
+                    (token, span) => {
+                        return Err(self.error(
+                            span,
+                            &format!("Expected an attribute name, found {}", token),
+                        ))
+                    }
+                };
+            } else if self.eat(&Token::LBracket) {
+                self.deeper()?;
+                let index = self.parse_expr()?;
+                self.expect(Token::RBracket)?;
+                expr = Expr::GetItem(Box::new(expr), Box::new(index));
+            } else if self.peek() == Some(&Token::LParen) {
+                let (_, span) = self.next_or_eof()?;
+                if !matches!(expr, Expr::Name(_) | Expr::GetAttr(..)) {
+                    return Err(self.error(span, "Only macros and methods can be called"));
                 }
+                self.deeper()?;
+                let (args, kwargs) = self.parse_call_args()?;
+                expr = Expr::Call {
+                    func: Box::new(expr),
+                    args,
+                    kwargs,
+                };
+            } else {
+                break;
             }
         }
-        Ok(block_ast)
+        self.depth = depth;
+        Ok(expr)
     }
 
-    fn parse_token(&mut self) -> Result<Ast, String> {
-        let mut node_ast = Ast::new();
-        match self.tokens.get(self.position) {
-            Some(Token::Text(text)) => {
-                node_ast.add_node(AstNode::Text(text.to_string()));
-                self.position += 1;
+    fn parse_primary(&mut self) -> Result<Expr, Error> {
+        let (token, span) = self.next_or_eof()?;
+        match token {
+            Token::Str(s) => Ok(Expr::Literal(Value::String(s))),
+            Token::Integer(i) => Ok(Expr::Literal(Value::Integer(i))),
+            Token::Float(f) => Ok(Expr::Literal(Value::Float(f))),
+            Token::Ident(name) => Ok(match name.as_str() {
+                "true" | "True" => Expr::Literal(Value::Bool(true)),
+                "false" | "False" => Expr::Literal(Value::Bool(false)),
+                "none" | "None" => Expr::Literal(Value::None),
+                _ => Expr::Name(name),
+            }),
+            Token::LParen => {
+                let expr = self.parse_expr()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
             }
-            Some(Token::Variable(variable)) => {
-                node_ast.add_node(AstNode::Variable(variable.to_string(), Vec::new()));
-                self.position += 1;
+            Token::LBracket => Ok(Expr::List(
+                self.parse_list(Token::RBracket, Self::parse_expr)?,
+            )),
+            Token::LBrace => Ok(Expr::Dict(self.parse_list(Token::RBrace, |parser| {
+                let key = parser.parse_expr()?;
+                parser.expect(Token::Colon)?;
+                Ok((key, parser.parse_expr()?))
+            })?)),
+            token => Err(self.error(span, &format!("Expected an expression, found {}", token))),
+        }
+    }
+
+    /// Parses comma separated items up to and including the `close` token.
+    fn parse_list<T>(
+        &mut self,
+        close: Token,
+        mut item: impl FnMut(&mut Self) -> Result<T, Error>,
+    ) -> Result<Vec<T>, Error> {
+        let mut items = Vec::new();
+        while !self.eat(&close) {
+            items.push(item(self)?);
+            if !self.eat(&Token::Comma) {
+                self.expect(close)?;
+                break;
             }
-            _ => {
-                return Err(format!(
-                    "Unexpected token: {:?}",
-                    self.tokens.get(self.position)
-                ))
+        }
+        Ok(items)
+    }
+
+    /// Runs `parse` one nesting level deeper, failing beyond `MAX_DEPTH`.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, Error>) -> Result<T, Error> {
+        let depth = self.depth;
+        self.deeper()?;
+        let result = parse(self);
+        self.depth = depth;
+        result
+    }
+
+    /// Goes one nesting level deeper without recursing, as `a + b + c` does: it is
+    /// `(a + b) + c`. The caller restores the depth once the whole chain is parsed.
+    fn deeper(&mut self) -> Result<(), Error> {
+        if self.depth == MAX_DEPTH {
+            let span = self
+                .tokens
+                .get(self.position)
+                .or(self.tokens.last())
+                .map(|(_, span)| *span)
+                .unwrap_or_default();
+            return Err(self.error(
+                span,
+                &format!("Templates can't nest more than {} levels deep", MAX_DEPTH),
+            ));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<(Token, Span)> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn next_or_eof(&mut self) -> Result<(Token, Span), Error> {
+        self.next()
+            .ok_or_else(|| error_at(self.source, self.source.len(), "Unexpected end of template"))
+    }
+
+    fn peek_nth(&self, n: usize) -> Option<&Token> {
+        self.tokens.get(self.position + n).map(|(token, _)| token)
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(name)) if name == keyword => {
+                self.position += 1;
+                true
             }
+            _ => false,
         }
-        Ok(node_ast)
     }
-}
 
-fn lex(input: &str) -> Vec<Token> {
-    let mut tokens = Vec::new();
-    let mut chars = input.chars().peekable();
-
-    while let Some(c) = chars.next() {
-        if c == '{' && chars.peek() == Some(&'{') {
-            chars.next(); // consume second '{'
-            let variable: String = chars.by_ref().take_while(|&ch| ch != '}').collect();
-            tokens.push(Token::Variable(variable.trim().to_string()));
-        } else if c == '{' && chars.peek() == Some(&'%') {
-            chars.next(); // consume second '%'
-            let block: String = chars.by_ref().take_while(|&ch| ch != '%').collect();
-            tokens.push(lex_block(block.trim()));
+    fn eat(&mut self, expected: &Token) -> bool {
+        if self.peek() == Some(expected) {
+            self.position += 1;
+            true
         } else {
-            tokens.push(Token::Text(c.to_string()));
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), Error> {
+        match self.next_or_eof()? {
+            (token, _) if token == expected => Ok(()),
+            (token, span) => {
+                Err(self.error(span, &format!("Expected {}, found {}", expected, token)))
+            }
+        }
+    }
+
+    fn expect_ident(&mut self) -> Result<(String, Span), Error> {
+        match self.next_or_eof()? {
+            (Token::Ident(name), span) => Ok((name, span)),
+            (token, span) => Err(self.error(span, &format!("Expected a name, found {}", token))),
         }
     }
-    tokens
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), Error> {
+        match self.expect_ident()? {
+            (name, _) if name == keyword => Ok(()),
+            (name, span) => {
+                Err(self.error(span, &format!("Expected '{}', found '{}'", keyword, name)))
+            }
+        }
+    }
+
+    /// The span from the tag opening at token `start` to its closing delimiter, which is
+    /// what runtime errors in the tag point at.
+    fn tag_span(&self, start: usize) -> Span {
+        let (_, open) = self.tokens[start];
+        let close = self.tokens[start..]
+            .iter()
+            .find(|(token, _)| matches!(token, Token::VariableEnd | Token::BlockEnd))
+            .map_or(open, |(_, span)| *span);
+        Span {
+            start: open.start,
+            end: close.end,
+        }
+    }
+
+    fn error(&self, span: Span, message: &str) -> Error {
+        Error::new(ErrorKind::Syntax, message).with_location(self.source, span)
+    }
 }
 
-fn lex_block(input: &str) -> Token {
-    let parts: Vec<&str> = input.split_whitespace().collect();
-    match parts.as_slice() {
-        ["if", _condition_var] => Token::If,
-        ["for", var_name, "in", collection_var] => {
-            Token::For(var_name.to_string(), collection_var.to_string())
-        }
-        ["macro", name, params @ ..] => Token::Macro(
-            name.to_string(),
-            params.iter().map(|s| s.to_string()).collect(),
-        ),
-        ["else"] => Token::Else,
-        ["endif"] => Token::EndIf,
-        ["endfor"] => Token::EndFor,
-        ["endmacro"] => Token::EndMacro,
-        _ => Token::OpenBlock, // fallback, should handle more cases
+/// Strips a message and joins its lines with single spaces, for `{% trans trimmed %}`.
+fn trim_lines(message: &str) -> String {
+    message
+        .trim()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr {
+    Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Result<Ast, Error> {
+        Parser::new(source).parse()
+    }
+
+    fn expr(source: &str) -> Expr {
+        let ast = parse(&format!("{{{{ {} }}}}", source)).unwrap();
+        match ast.nodes.into_iter().next() {
+            Some(AstNode::Variable(expr)) => expr,
+            _ => panic!("expected an interpolation"),
+        }
+    }
+
+    #[test]
+    fn respects_operator_precedence() {
+        let Expr::Binary(BinaryOp::Add, _, rhs) = expr("1 + 2 * 3") else {
+            panic!("expected an addition");
+        };
+        assert!(matches!(*rhs, Expr::Binary(BinaryOp::Mul, _, _)));
+
+        let Expr::Binary(BinaryOp::Or, lhs, _) = expr("a and b or c") else {
+            panic!("expected 'or'");
+        };
+        assert!(matches!(*lhs, Expr::Binary(BinaryOp::And, _, _)));
+
+        // As in Jinja, unary minus binds tighter than `**`
+        assert!(matches!(expr("-x ** 2"), Expr::Binary(BinaryOp::Pow, _, _)));
+    }
+
+    #[test]
+    fn parses_postfix_chains_and_filters() {
+        let Expr::Filter {
+            expr: inner,
+            name,
+            args,
+            kwargs,
+        } = expr("a.b[1].c(2)|join(', ', attr='x')")
+        else {
+            panic!("expected a filter");
+        };
+        assert_eq!(name, "join");
+        assert_eq!(args.len(), 1);
+        assert_eq!(kwargs[0].0, "attr");
+        assert!(matches!(*inner, Expr::Call { .. }));
+
+        assert!(matches!(
+            expr("x is not divisibleby 3"),
+            Expr::Unary(UnaryOp::Not, _)
+        ));
+        assert!(matches!(
+            expr("a if b"),
+            Expr::Conditional {
+                else_expr: None,
+                ..
+            }
+        ));
+        assert!(matches!(
+            expr("x not in [1]"),
+            Expr::Binary(BinaryOp::NotIn, _, _)
+        ));
+    }
+
+    #[test]
+    fn limits_how_deeply_code_nests() {
+        use crate::compiler::compile;
+
+        let nested = |open: &str, inner: &str, close: &str, depth: usize| {
+            format!("{}{}{}", open.repeat(depth), inner, close.repeat(depth))
+        };
+        for source in [
+            format!("{{{{ {} }}}}", nested("(", "1", ")", MAX_DEPTH - 1)),
+            // The innermost condition is one level deeper than its block
+            nested("{% if 1 %}", "x", "{% endif %}", MAX_DEPTH - 1),
+            format!("{{{{ 1{} }}}}", " + 1".repeat(MAX_DEPTH - 1)),
+            // Chains next to each other don't add up
+            format!("{{{{ f({}) }}}}", "x.a.b.c + y.a.b.c, ".repeat(100)),
+        ] {
+            let ast = parse(&source).unwrap();
+            compile("page", &source, &ast);
+        }
+        for source in [
+            format!("{{{{ {} }}}}", nested("(", "1", ")", 2000)),
+            format!("{{{{ {} }}}}", nested("[", "1", "]", 2000)),
+            format!("{{{{ {} }}}}", nested("{'a': ", "1", "}", 2000)),
+            format!("{{{{ {}1 }}}}", "not ".repeat(2000)),
+            format!("{{{{ {}1 }}}}", "- ".repeat(2000)),
+            nested("{% if 1 %}", "x", "{% endif %}", 2000),
+            nested("{% for x in y %}", "x", "{% endfor %}", 2000),
+            // Chains nest too: `a + b + c` is `(a + b) + c`
+            format!("{{{{ 1{} }}}}", " + 1".repeat(2000)),
+            format!("{{{{ x{} }}}}", " or x".repeat(2000)),
+            format!("{{{{ x{} }}}}", "|abs".repeat(2000)),
+            format!("{{{{ x{} }}}}", ".a".repeat(2000)),
+            format!("{{{{ x{} }}}}", "[0]".repeat(2000)),
+        ] {
+            let Err(err) = parse(&source) else {
+                panic!("{} should not parse", source);
+            };
+            assert_eq!(err.kind(), &ErrorKind::Syntax);
+            assert_eq!(
+                err.message(),
+                "Templates can't nest more than 64 levels deep"
+            );
+        }
+    }
+
+    #[test]
+    fn parses_nested_blocks_with_spans() {
+        let ast = parse("a{% for x in xs if x %}{{ x }}{% else %}none{% endfor %}").unwrap();
+        // Blocks are located by their opening tag
+        assert_eq!(ast.spans[1], Span { start: 1, end: 23 });
+        let AstNode::ForBlock {
+            targets,
+            filter,
+            body,
+            else_block,
+            ..
+        } = &ast.nodes[1]
+        else {
+            panic!("expected a for block");
+        };
+        assert_eq!(targets, &["x"]);
+        assert!(filter.is_some());
+        assert_eq!(body.nodes.len(), 1);
+        assert!(else_block.is_some());
+    }
+
+    #[test]
+    fn reports_syntax_errors_at_the_offending_token() {
+        for (source, message, column) in [
+            ("{% if x %}", "Missing 'endif' for 'if' block", 4),
+            (
+                "{{ a.b[1](2) }}",
+                "Only macros and methods can be called",
+                10,
+            ),
+            ("{% break %}", "'break' outside of a loop", 4),
+            (
+                "{% for x in y %}{% block b %}{% break %}{% endblock %}{% endfor %}",
+                "'break' outside of a loop",
+                33,
+            ),
+            (
+                "{% for x in y %}{% block b %}{% continue %}{% endblock %}{% endfor %}",
+                "'continue' outside of a loop",
+                33,
+            ),
+            ("{% frobnicate %}", "Unknown block tag 'frobnicate'", 4),
+            (
+                "{{ f(a=1, 2) }}",
+                "Positional argument follows keyword argument",
+                12,
+            ),
+            ("{{ 1 + }}", "Expected an expression, found '}}'", 8),
+            ("{% endfor %}", "Unknown block tag 'endfor'", 4),
+            ("{{ x", "Unclosed tag", 1),
+        ] {
+            let Err(err) = parse(source) else {
+                panic!("{} should not parse", source);
+            };
+            assert_eq!(err.message(), message, "{}", source);
+            assert_eq!(err.column(), Some(column), "{}", source);
+        }
     }
 }
@@ -0,0 +1,277 @@
+use std::{
+    cell::Cell,
+    collections::HashSet,
+    error::Error,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::values::Value;
+
+/// Attributes of the `loop` variable, which every template may use.
+const LOOP_ATTRIBUTES: &[&str] = &[
+    "index",
+    "index0",
+    "revindex",
+    "revindex0",
+    "first",
+    "last",
+    "length",
+    "cycle",
+];
+
+/// Limits for rendering templates written by untrusted authors.
+///
+/// The attribute allowlist covers attribute and method names, item lookups by string
+/// and the `attribute` argument of filters. Filters such as `tojson` still see whole
+/// values, so the context should only contain what template authors may read.
+#[derive(Clone, Debug)]
+pub struct Sandbox {
+    max_recursion: usize,
+    max_iterations: usize,
+    max_output: usize,
+    timeout: Duration,
+    allowed_attributes: Option<HashSet<String>>,
+}
+
+impl Sandbox {
+    pub fn new() -> Sandbox {
+        Sandbox {
+            max_recursion: 100,
+            max_iterations: 100_000,
+            max_output: 1 << 20,
+            timeout: Duration::from_secs(1),
+            allowed_attributes: None,
+        }
+    }
+
+    /// Caps how deeply macro calls, `caller()` and includes may nest.
+    pub fn set_max_recursion(&mut self, depth: usize) {
+        self.max_recursion = depth;
+    }
+
+    /// Caps the number of loop iterations over the whole render.
+    pub fn set_max_iterations(&mut self, iterations: usize) {
+        self.max_iterations = iterations;
+    }
+
+    /// Caps the size of the output in bytes, and of any string a template builds.
+    pub fn set_max_output(&mut self, bytes: usize) {
+        self.max_output = bytes;
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Allows an attribute name. Once any attribute is allowed, all others are rejected.
+    pub fn allow_attribute(&mut self, name: &str) {
+        self.allowed_attributes
+            .get_or_insert_with(HashSet::new)
+            .insert(name.to_string());
+    }
+
+    pub fn check_recursion(&self, depth: usize) -> Result<(), SandboxError> {
+        if depth > self.max_recursion {
+            return Err(SandboxError::RecursionLimit(self.max_recursion));
+        }
+        Ok(())
+    }
+
+    pub fn check_iterations(&self, iterations: usize) -> Result<(), SandboxError> {
+        if iterations > self.max_iterations {
+            return Err(SandboxError::IterationLimit(self.max_iterations));
+        }
+        Ok(())
+    }
+
+    pub fn check_output(&self, bytes: usize) -> Result<(), SandboxError> {
+        if bytes > self.max_output {
+            return Err(SandboxError::OutputLimit(self.max_output));
+        }
+        Ok(())
+    }
+
+    pub fn check_time(&self, started: Instant) -> Result<(), SandboxError> {
+        if started.elapsed() > self.timeout {
+            return Err(SandboxError::Timeout(self.timeout));
+        }
+        Ok(())
+    }
+
+    /// Checks a value a template has built. Lists longer than the iteration limit are
+    /// rejected too, since building them is how templates would get around it.
+    pub fn check_size(&self, value: &Value) -> Result<(), SandboxError> {
+        match value {
+            Value::String(s) | Value::SafeString(s) => self.check_output(s.len()),
+            Value::List(items) => self.check_iterations(items.len()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The limits `check_size` applies, for filters and functions to check before they
+    /// allocate a value rather than after.
+    pub fn size_budget(&self) -> SizeBudget {
+        SizeBudget {
+            max_len: self.max_output,
+            max_items: self.max_iterations,
+            exceeded: Cell::new(None),
+        }
+    }
+
+    /// Checks an attribute name, or each part of a dotted path such as `"user.name"`.
+    pub fn check_attribute(&self, path: &str) -> Result<(), SandboxError> {
+        let Some(allowed) = &self.allowed_attributes else {
+            return Ok(());
+        };
+        let denied = path.split('.').find(|name| {
+            name.parse::<i64>().is_err()
+                && !allowed.contains(*name)
+                && !LOOP_ATTRIBUTES.contains(name)
+        });
+        match denied {
+            Some(name) => Err(SandboxError::AttributeNotAllowed(name.to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for Sandbox {
+    fn default() -> Sandbox {
+        Sandbox::new()
+    }
+}
+
+/// How large a string or list a filter or function may build in a sandbox. Filters report
+/// errors as strings, so the budget keeps the limit it ran into for the VM to report.
+pub struct SizeBudget {
+    max_len: usize,
+    max_items: usize,
+    exceeded: Cell<Option<SandboxError>>,
+}
+
+impl SizeBudget {
+    /// Checks the length in bytes of a string about to be built.
+    pub fn check_len(&self, len: usize) -> Result<(), String> {
+        self.check(len > self.max_len, SandboxError::OutputLimit(self.max_len))
+    }
+
+    /// Checks the length of a list about to be built.
+    pub fn check_items(&self, items: usize) -> Result<(), String> {
+        self.check(
+            items > self.max_items,
+            SandboxError::IterationLimit(self.max_items),
+        )
+    }
+
+    /// The limit a check ran into, if any.
+    pub fn take_exceeded(&self) -> Option<SandboxError> {
+        self.exceeded.take()
+    }
+
+    fn check(&self, exceeded: bool, violation: SandboxError) -> Result<(), String> {
+        if !exceeded {
+            return Ok(());
+        }
+        let message = violation.to_string();
+        self.exceeded.set(Some(violation));
+        Err(message)
+    }
+}
+
+/// The sandbox limit a render ran into.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SandboxError {
+    RecursionLimit(usize),
+    IterationLimit(usize),
+    OutputLimit(usize),
+    Timeout(Duration),
+    AttributeNotAllowed(String),
+}
+
+impl fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SandboxError::RecursionLimit(depth) => {
+                write!(f, "Recursion limit of {} exceeded", depth)
+            }
+            SandboxError::IterationLimit(iterations) => {
+                write!(f, "Loop iteration limit of {} exceeded", iterations)
+            }
+            SandboxError::OutputLimit(bytes) => {
+                write!(f, "Output limit of {} bytes exceeded", bytes)
+            }
+            SandboxError::Timeout(timeout) => write!(f, "Rendering took longer than {:?}", timeout),
+            SandboxError::AttributeNotAllowed(name) => {
+                write!(f, "Access to attribute '{}' is not allowed", name)
+            }
+        }
+    }
+}
+
+impl Error for SandboxError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attribute_allowlist_covers_every_part_of_a_path() {
+        let mut sandbox = Sandbox::new();
+        assert_eq!(sandbox.check_attribute("anything.at.all"), Ok(()));
+
+        sandbox.allow_attribute("user");
+        sandbox.allow_attribute("name");
+        assert_eq!(sandbox.check_attribute("user.name"), Ok(()));
+        assert_eq!(sandbox.check_attribute("user.0.name"), Ok(()));
+        // Attributes of `loop` are always allowed
+        assert_eq!(sandbox.check_attribute("revindex"), Ok(()));
+        assert_eq!(
+            sandbox.check_attribute("user.password"),
+            Err(SandboxError::AttributeNotAllowed("password".to_string()))
+        );
+    }
+
+    #[test]
+    fn size_budget_remembers_the_limit_it_ran_into() {
+        let mut sandbox = Sandbox::new();
+        sandbox.set_max_output(10);
+        sandbox.set_max_iterations(3);
+        let budget = sandbox.size_budget();
+
+        assert_eq!(budget.check_len(10), Ok(()));
+        assert_eq!(budget.check_items(3), Ok(()));
+        assert_eq!(budget.take_exceeded(), None);
+
+        assert_eq!(
+            budget.check_len(11),
+            Err("Output limit of 10 bytes exceeded".to_string())
+        );
+        assert_eq!(budget.take_exceeded(), Some(SandboxError::OutputLimit(10)));
+        assert!(budget.check_items(4).is_err());
+        assert_eq!(
+            budget.take_exceeded(),
+            Some(SandboxError::IterationLimit(3))
+        );
+        assert_eq!(budget.take_exceeded(), None);
+    }
+
+    #[test]
+    fn check_size_applies_to_strings_and_lists() {
+        let mut sandbox = Sandbox::new();
+        sandbox.set_max_output(3);
+        sandbox.set_max_iterations(1);
+        assert_eq!(
+            sandbox.check_size(&Value::String("abc".to_string())),
+            Ok(())
+        );
+        assert_eq!(
+            sandbox.check_size(&Value::SafeString("abcd".to_string())),
+            Err(SandboxError::OutputLimit(3))
+        );
+        assert_eq!(
+            sandbox.check_size(&Value::List(vec![Value::None, Value::None])),
+            Err(SandboxError::IterationLimit(1))
+        );
+    }
+}
@@ -0,0 +1,505 @@
+use std::{collections::HashMap, fmt};
+
+use serde::ser::{self, Serialize};
+
+use crate::values::Value;
+
+/// Converts any `T: Serialize` into a `Value`. Structs and maps become `Dict`,
+/// sequences and tuples become `List`, and unit and `None` become `Value::None`.
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value, SerdeError> {
+    value.serialize(Serializer)
+}
+
+/// Error raised while converting to or from a `Value`.
+#[derive(Debug)]
+pub struct SerdeError(String);
+
+impl fmt::Display for SerdeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SerdeError {}
+
+impl ser::Error for SerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        SerdeError(msg.to_string())
+    }
+}
+
+impl serde::de::Error for SerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        SerdeError(msg.to_string())
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use ser::{SerializeMap, SerializeSeq};
+
+        match self {
+            Value::String(s) | Value::SafeString(s) => serializer.serialize_str(s),
+            Value::Integer(i) => serializer.serialize_i64(*i),
+            Value::Float(f) => serializer.serialize_f64(*f),
+            Value::Bool(b) => serializer.serialize_bool(*b),
+            Value::List(items) => {
+                let mut seq = serializer.serialize_seq(Some(items.len()))?;
+                for item in items {
+                    seq.serialize_element(item)?;
+                }
+                seq.end()
+            }
+            Value::Dict(dict) => {
+                // Sorted, so serialized output doesn't depend on hash order
+                let mut entries: Vec<_> = dict.iter().collect();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (k, v) in entries {
+                    map.serialize_entry(k, v)?;
+                }
+                map.end()
+            }
+            Value::None | Value::Undefined => serializer.serialize_unit(),
+            Value::Object(object) => serializer.serialize_str(&object.render()),
+        }
+    }
+}
+
+/// Integers beyond `i64` fall back to floats rather than failing.
+fn unsigned(v: u64) -> Value {
+    i64::try_from(v).map_or(Value::Float(v as f64), Value::Integer)
+}
+
+/// Serializer whose output is a `Value` tree.
+pub struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = Value;
+    type Error = SerdeError;
+
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeListVariant;
+    type SerializeMap = SerializeDict;
+    type SerializeStruct = SerializeDict;
+    type SerializeStructVariant = SerializeDictVariant;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, SerdeError> {
+        Ok(Value::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value, SerdeError> {
+        Ok(Value::Integer(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value, SerdeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value, SerdeError> {
+        Ok(unsigned(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value, SerdeError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value, SerdeError> {
+        Ok(Value::Float(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value, SerdeError> {
+        Ok(Value::String(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value, SerdeError> {
+        Ok(Value::String(v.to_string()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, SerdeError> {
+        Ok(Value::List(
+            v.iter().map(|b| Value::Integer(*b as i64)).collect(),
+        ))
+    }
+
+    fn serialize_none(self) -> Result<Value, SerdeError> {
+        Ok(Value::None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, SerdeError> {
+        Ok(Value::None)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, SerdeError> {
+        Ok(Value::None)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Value, SerdeError> {
+        Ok(Value::String(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value, SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, SerdeError> {
+        let inner = to_value(value)?;
+        Ok(Value::Dict(HashMap::from([(variant.to_string(), inner)])))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeList, SerdeError> {
+        Ok(SerializeList {
+            items: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeList, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeList, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeListVariant, SerdeError> {
+        Ok(SerializeListVariant {
+            variant,
+            items: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeDict, SerdeError> {
+        Ok(SerializeDict {
+            entries: HashMap::with_capacity(len.unwrap_or(0)),
+            next_key: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeDict, SerdeError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeDictVariant, SerdeError> {
+        Ok(SerializeDictVariant {
+            variant,
+            entries: HashMap::with_capacity(len),
+        })
+    }
+}
+
+pub struct SerializeList {
+    items: Vec<Value>,
+}
+
+impl ser::SerializeSeq for SerializeList {
+    type Ok = Value;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.items.push(to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, SerdeError> {
+        Ok(Value::List(self.items))
+    }
+}
+
+impl ser::SerializeTuple for SerializeList {
+    type Ok = Value;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, SerdeError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeList {
+    type Ok = Value;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, SerdeError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+pub struct SerializeListVariant {
+    variant: &'static str,
+    items: Vec<Value>,
+}
+
+impl ser::SerializeTupleVariant for SerializeListVariant {
+    type Ok = Value;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.items.push(to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, SerdeError> {
+        Ok(Value::Dict(HashMap::from([(
+            self.variant.to_string(),
+            Value::List(self.items),
+        )])))
+    }
+}
+
+pub struct SerializeDict {
+    entries: HashMap<String, Value>,
+    next_key: Option<String>,
+}
+
+impl ser::SerializeMap for SerializeDict {
+    type Ok = Value;
+    type Error = SerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerdeError> {
+        // Dict keys are strings, so scalar keys are stringified
+        let key = match to_value(key)? {
+            Value::String(s) | Value::SafeString(s) => s,
+            key @ (Value::Integer(_) | Value::Float(_) | Value::Bool(_)) => key.to_string(),
+            key => {
+                return Err(SerdeError(format!(
+                    "Dict keys must be strings or numbers, found {}",
+                    key.type_name()
+                )))
+            }
+        };
+        self.next_key = Some(key);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        let key = self
+            .next_key
+            .take()
+            .ok_or_else(|| SerdeError("serialize_value called before serialize_key".to_string()))?;
+        self.entries.insert(key, to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, SerdeError> {
+        Ok(Value::Dict(self.entries))
+    }
+}
+
+impl ser::SerializeStruct for SerializeDict {
+    type Ok = Value;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        self.entries.insert(key.to_string(), to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, SerdeError> {
+        Ok(Value::Dict(self.entries))
+    }
+}
+
+pub struct SerializeDictVariant {
+    variant: &'static str,
+    entries: HashMap<String, Value>,
+}
+
+impl ser::SerializeStructVariant for SerializeDictVariant {
+    type Ok = Value;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        self.entries.insert(key.to_string(), to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, SerdeError> {
+        Ok(Value::Dict(HashMap::from([(
+            self.variant.to_string(),
+            Value::Dict(self.entries),
+        )])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Page {
+        title: String,
+        views: u32,
+        draft: Option<bool>,
+        tags: Vec<String>,
+        status: Status,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Status {
+        Published,
+        Scheduled(String),
+        Moved { to: String, permanent: bool },
+        Resized(u32, u32),
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    #[test]
+    fn structs_and_enums_round_trip_through_values() {
+        for status in [
+            Status::Published,
+            Status::Scheduled("monday".to_string()),
+            Status::Moved {
+                to: "/new".to_string(),
+                permanent: true,
+            },
+            Status::Resized(3, 4),
+        ] {
+            let page = Page {
+                title: "Home".to_string(),
+                views: 7,
+                draft: None,
+                tags: vec!["a".to_string()],
+                status,
+            };
+            let value = to_value(&page).unwrap();
+            let json = serde_json::to_value(&value).unwrap();
+            assert_eq!(serde_json::from_value::<Page>(json).unwrap(), page);
+        }
+    }
+
+    #[test]
+    fn values_follow_the_shape_of_the_data() {
+        let value = to_value(&Status::Moved {
+            to: "/new".to_string(),
+            permanent: false,
+        })
+        .unwrap();
+        assert_eq!(
+            value,
+            Value::Dict(HashMap::from([(
+                "Moved".to_string(),
+                Value::Dict(HashMap::from([
+                    ("to".to_string(), string("/new")),
+                    ("permanent".to_string(), Value::Bool(false)),
+                ])),
+            )]))
+        );
+        assert_eq!(to_value(&Status::Published).unwrap(), string("Published"));
+        assert_eq!(
+            to_value(&Status::Resized(3, 4)).unwrap(),
+            Value::Dict(HashMap::from([(
+                "Resized".to_string(),
+                Value::List(vec![Value::Integer(3), Value::Integer(4)]),
+            )]))
+        );
+        assert_eq!(to_value(&None::<i32>).unwrap(), Value::None);
+        assert_eq!(to_value(&Some(3)).unwrap(), Value::Integer(3));
+    }
+
+    #[test]
+    fn unsigned_integers_beyond_i64_become_floats() {
+        assert_eq!(
+            to_value(&(i64::MAX as u64)).unwrap(),
+            Value::Integer(i64::MAX)
+        );
+        assert_eq!(to_value(&u64::MAX).unwrap(), Value::Float(u64::MAX as f64));
+    }
+
+    #[test]
+    fn map_keys_are_stringified_scalars() {
+        let value = to_value(&HashMap::from([(1, "a"), (2, "b")])).unwrap();
+        assert_eq!(
+            value,
+            Value::Dict(HashMap::from([
+                ("1".to_string(), string("a")),
+                ("2".to_string(), string("b")),
+            ]))
+        );
+        let err = to_value(&HashMap::from([(vec![1], "a")])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Dict keys must be strings or numbers, found list"
+        );
+    }
+}
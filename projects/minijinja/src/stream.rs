@@ -0,0 +1,150 @@
+use std::{
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::mpsc::{self, SyncSender},
+    thread,
+};
+
+use crate::error::{Error, ErrorKind};
+
+/// Hands chunks of output from a render on a worker thread to whoever pulls them.
+///
+/// Pass `|chunk| chunks.send(chunk)` as the `on_chunk` of `Vm::render_chunks`.
+pub struct ChunkSender {
+    sender: Sender,
+}
+
+enum Sender {
+    Sync(SyncSender<Result<String, Error>>),
+    #[cfg(feature = "async")]
+    Async(async_channel::Sender<Result<String, Error>>),
+}
+
+impl ChunkSender {
+    /// Waits while the consumer is behind. Fails once the consumer is gone, which ends
+    /// the render.
+    pub fn send(&self, chunk: String) -> io::Result<()> {
+        self.send_item(Ok(chunk))
+    }
+
+    fn send_item(&self, item: Result<String, Error>) -> io::Result<()> {
+        let sent = match &self.sender {
+            Sender::Sync(sender) => sender.send(item).is_ok(),
+            #[cfg(feature = "async")]
+            Sender::Async(sender) => sender.send_blocking(item).is_ok(),
+        };
+        if !sent {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "The rendered output is no longer wanted",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Runs `render` on a new thread and returns the chunks it sends as they are pulled,
+/// with at most `bound` chunks waiting at a time. An error that ends the render is the
+/// last item.
+///
+/// Environments and values can't move between threads, so `render` sets up the
+/// environment itself and renders with `Vm::render_chunks`. Dropping the iterator stops
+/// the render at its next chunk.
+pub fn render_iter<F>(bound: usize, render: F) -> impl Iterator<Item = Result<String, Error>>
+where
+    F: FnOnce(&ChunkSender) -> Result<(), Error> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::sync_channel(bound.max(1));
+    spawn(Sender::Sync(sender), render);
+    receiver.into_iter()
+}
+
+/// Like `render_iter`, for async consumers such as a streaming HTTP response body.
+#[cfg(feature = "async")]
+pub fn render_stream<F>(
+    bound: usize,
+    render: F,
+) -> impl futures_core::Stream<Item = Result<String, Error>>
+where
+    F: FnOnce(&ChunkSender) -> Result<(), Error> + Send + 'static,
+{
+    let (sender, receiver) = async_channel::bounded(bound.max(1));
+    spawn(Sender::Async(sender), render);
+    receiver
+}
+
+fn spawn<F>(sender: Sender, render: F)
+where
+    F: FnOnce(&ChunkSender) -> Result<(), Error> + Send + 'static,
+{
+    thread::spawn(move || {
+        let chunks = ChunkSender { sender };
+        // A panic would otherwise look like the output simply ending
+        let result = panic::catch_unwind(AssertUnwindSafe(|| render(&chunks)))
+            .unwrap_or_else(|_| Err(Error::new(ErrorKind::Output, "Rendering panicked")));
+        if let Err(err) = result {
+            // Nobody is left to tell if the consumer is gone
+            let _ = chunks.send_item(Err(err));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{environment::Environment, loader::MemoryLoader, vm::Vm};
+
+    fn render_page(source: &'static str, chunks: &ChunkSender) -> Result<(), Error> {
+        let mut loader = MemoryLoader::new();
+        loader.add_template("page", source);
+        let mut env = Environment::new();
+        env.set_loader(loader);
+        Vm::new(&env).render_chunks("page", 4, |chunk| chunks.send(chunk))
+    }
+
+    #[test]
+    fn chunks_are_pulled_from_the_worker() {
+        let source = "{% for i in range(5) %}item{{ i }} {% endfor %}";
+        let chunks: Result<Vec<String>, Error> =
+            render_iter(1, move |chunks| render_page(source, chunks)).collect();
+        let chunks = chunks.unwrap();
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), "item0 item1 item2 item3 item4 ");
+    }
+
+    #[test]
+    fn errors_and_panics_end_the_chunks() {
+        let source = "{% for i in [1, 0] %}{{ 1 // i }}{% endfor %}";
+        let items: Vec<_> = render_iter(1, move |chunks| render_page(source, chunks)).collect();
+        let err = items.last().unwrap().as_ref().unwrap_err();
+        assert_eq!(err.message(), "Division by zero");
+
+        let mut items = render_iter(1, |_| panic!("bug"));
+        let err = items.next().unwrap().unwrap_err();
+        assert_eq!(err.message(), "Rendering panicked");
+        assert!(items.next().is_none());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn chunks_can_be_streamed() {
+        use futures_core::Stream;
+        use std::{
+            pin::pin,
+            task::{Context, Poll, Waker},
+        };
+
+        let source = "{% for i in range(5) %}item{{ i }} {% endfor %}";
+        let mut stream = pin!(render_stream(1, move |chunks| render_page(source, chunks)));
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut output = String::new();
+        loop {
+            match stream.as_mut().poll_next(&mut cx) {
+                Poll::Ready(Some(chunk)) => output.push_str(&chunk.unwrap()),
+                Poll::Ready(None) => break,
+                Poll::Pending => thread::yield_now(),
+            }
+        }
+        assert_eq!(output, "item0 item1 item2 item3 item4 ");
+    }
+}
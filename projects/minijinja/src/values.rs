@@ -1,41 +1,699 @@
 use core::fmt;
-use std::collections::HashMap;
+use std::{cmp::Ordering, collections::HashMap, ptr, rc::Rc};
+
+use crate::{ast::BinaryOp, filters::escape_html};
 
 #[derive(Clone, PartialEq)]
 pub enum Value {
     String(String),
+    /// A string that is already escaped and must be output as is
+    SafeString(String),
     Integer(i64),
     Float(f64),
     Bool(bool),
     List(Vec<Value>),
     Dict(HashMap<String, Value>),
     None,
+    /// A missing variable, attribute or key; see `UndefinedBehavior` for how it is treated
+    Undefined,
+    /// A value implemented in Rust, such as the result of `cycler()`
+    Object(Rc<dyn Object>),
+}
+
+/// A value with its own attributes and methods. Copies of the value share the object,
+/// so methods can keep state with interior mutability, as `cycler().next()` does.
+pub trait Object {
+    fn type_name(&self) -> &'static str;
+
+    /// What `{{ object }}` outputs.
+    fn render(&self) -> String;
+
+    fn get_attr(&self, _name: &str) -> Option<Value> {
+        None
+    }
+
+    fn call_method(&self, name: &str, args: &[Value]) -> Result<Value, String> {
+        Err(format!(
+            "{} has no method '{}' taking {} arguments",
+            self.type_name(),
+            name,
+            args.len()
+        ))
+    }
+}
+
+/// Objects are only equal to themselves.
+impl PartialEq for dyn Object {
+    fn eq(&self, other: &dyn Object) -> bool {
+        ptr::addr_eq(self, other)
+    }
 }
 
 impl fmt::Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Value::String(s) => write!(f, "\"{}\"", s),
+            Value::String(s) | Value::SafeString(s) => write!(f, "\"{}\"", s),
             Value::Integer(i) => write!(f, "{}", i),
-            Value::Float(fl) => write!(f, "{}", fl),
+            Value::Float(fl) => write!(f, "{:?}", fl),
             Value::Bool(b) => write!(f, "{}", b),
             Value::List(list) => write!(f, "{:?}", list),
             Value::Dict(dict) => write!(f, "{{{:?}}}", dict),
             Value::None => write!(f, "None"),
+            Value::Undefined => write!(f, "Undefined"),
+            Value::Object(object) => write!(f, "<{} {}>", object.type_name(), object.render()),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::String(s) | Value::SafeString(s) => write!(f, "{}", s),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Float(fl) => write!(f, "{:?}", fl),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::List(list) => write!(f, "{:?}", list),
+            Value::Dict(dict) => write!(f, "{:?}", dict),
+            Value::None => write!(f, "None"),
+            Value::Undefined => Ok(()),
+            Value::Object(object) => write!(f, "{}", object.render()),
         }
     }
 }
 
 impl Value {
-    pub fn to_string(&self) -> String {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) | Value::SafeString(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Float(_) => "float",
+            Value::Bool(_) => "bool",
+            Value::List(_) => "list",
+            Value::Dict(_) => "dict",
+            Value::None => "none",
+            Value::Undefined => "undefined",
+            Value::Object(object) => object.type_name(),
+        }
+    }
+
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Bool(b) => *b,
+            Value::Integer(n) => *n != 0,
+            Value::Float(n) => *n != 0.0,
+            Value::String(s) | Value::SafeString(s) => !s.is_empty(),
+            Value::List(l) => !l.is_empty(),
+            Value::Dict(o) => !o.is_empty(),
+            Value::None | Value::Undefined => false,
+            Value::Object(_) => true,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) | Value::SafeString(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Integer(i) => Some(*i as f64),
+            Value::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    /// The items a `for` loop or a sequence filter walks over; dicts yield their keys in order.
+    pub fn to_list(&self) -> Result<Vec<Value>, String> {
         match self {
-            Value::String(s) => s.clone(),
-            Value::Integer(i) => i.to_string(),
-            Value::Float(fl) => fl.to_string(),
-            Value::Bool(b) => b.to_string(),
-            Value::List(list) => format!("{:?}", list),
-            Value::Dict(dict) => format!("{:?}", dict),
-            Value::None => "None".to_string(),
+            Value::List(list) => Ok(list.clone()),
+            Value::String(s) | Value::SafeString(s) => {
+                Ok(s.chars().map(|c| Value::String(c.to_string())).collect())
+            }
+            Value::Dict(dict) => {
+                let mut keys: Vec<&String> = dict.keys().collect();
+                keys.sort();
+                Ok(keys.into_iter().map(|k| Value::String(k.clone())).collect())
+            }
+            Value::Undefined => Ok(Vec::new()),
+            _ => Err(format!("{} is not iterable", self.type_name())),
+        }
+    }
+
+    /// Calls a built-in method such as `dict.items()` or `name.startswith("A")`.
+    pub fn call_method(&self, name: &str, args: &[Value]) -> Result<Value, String> {
+        if let Value::Object(object) = self {
+            return object.call_method(name, args);
+        }
+        if let Some(s) = self.as_str() {
+            if let Some(result) = self.call_str_method(s, name, args) {
+                return result;
+            }
         }
+        match (self, name, args) {
+            (Value::Dict(dict), "items", []) => Ok(Value::List(
+                sorted_entries(dict)
+                    .into_iter()
+                    .map(|(k, v)| Value::List(vec![Value::String(k.clone()), v.clone()]))
+                    .collect(),
+            )),
+            (Value::Dict(dict), "keys", []) => Ok(Value::List(
+                sorted_entries(dict)
+                    .into_iter()
+                    .map(|(k, _)| Value::String(k.clone()))
+                    .collect(),
+            )),
+            (Value::Dict(dict), "values", []) => Ok(Value::List(
+                sorted_entries(dict)
+                    .into_iter()
+                    .map(|(_, v)| v.clone())
+                    .collect(),
+            )),
+            (Value::Dict(dict), "get", [key]) | (Value::Dict(dict), "get", [key, _]) => {
+                let key = key
+                    .as_str()
+                    .ok_or_else(|| format!("dict keys are strings, not {}", key.type_name()))?;
+                Ok(dict
+                    .get(key)
+                    .cloned()
+                    .unwrap_or_else(|| args.get(1).cloned().unwrap_or(Value::None)))
+            }
+            (Value::List(list), "index", [item]) => list
+                .iter()
+                .position(|v| v.loose_eq(item))
+                .map(|i| Value::Integer(i as i64))
+                .ok_or_else(|| format!("{:?} is not in list", item)),
+            (Value::List(list), "count", [item]) => Ok(Value::Integer(
+                list.iter().filter(|v| v.loose_eq(item)).count() as i64,
+            )),
+            _ => Err(format!(
+                "{} has no method '{}' taking {} arguments",
+                self.type_name(),
+                name,
+                args.len()
+            )),
+        }
+    }
+
+    /// String methods as in Python. Returns `None` for names that aren't one, so the
+    /// error lists the receiver's type.
+    fn call_str_method(
+        &self,
+        s: &str,
+        name: &str,
+        args: &[Value],
+    ) -> Option<Result<Value, String>> {
+        let string_arg = |i: usize| {
+            args[i].as_str().ok_or_else(|| {
+                format!("str.{}() needs a string, not {}", name, args[i].type_name())
+            })
+        };
+        // `strip` and friends take the characters to remove, whitespace by default
+        let strip_chars = || -> Result<Vec<char>, String> {
+            match args {
+                [] | [Value::None] => Ok(Vec::new()),
+                [_] => Ok(string_arg(0)?.chars().collect()),
+                _ => Err(format!("str.{}() takes at most one argument", name)),
+            }
+        };
+        let is_stripped = |chars: &[char], c: char| {
+            if chars.is_empty() {
+                c.is_whitespace()
+            } else {
+                chars.contains(&c)
+            }
+        };
+
+        let result = match (name, args) {
+            ("startswith" | "endswith", [affixes]) => {
+                // Like Python, a list of affixes matches if any of them does
+                let affixes = match affixes {
+                    Value::List(list) => list.as_slice(),
+                    affix => std::slice::from_ref(affix),
+                };
+                affixes
+                    .iter()
+                    .map(|affix| {
+                        affix.as_str().ok_or_else(|| {
+                            format!("str.{}() needs strings, not {}", name, affix.type_name())
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(|affixes| {
+                        Value::Bool(affixes.iter().any(|affix| {
+                            if name == "startswith" {
+                                s.starts_with(affix)
+                            } else {
+                                s.ends_with(affix)
+                            }
+                        }))
+                    })
+            }
+            ("lower", []) => Ok(self.same_kind(s.to_lowercase())),
+            ("upper", []) => Ok(self.same_kind(s.to_uppercase())),
+            ("strip", _) => strip_chars()
+                .map(|chars| self.same_kind(s.trim_matches(|c| is_stripped(&chars, c)).into())),
+            ("lstrip", _) => strip_chars().map(|chars| {
+                self.same_kind(s.trim_start_matches(|c| is_stripped(&chars, c)).into())
+            }),
+            ("rstrip", _) => strip_chars()
+                .map(|chars| self.same_kind(s.trim_end_matches(|c| is_stripped(&chars, c)).into())),
+            ("split", [] | [Value::None]) => Ok(Value::List(
+                s.split_whitespace()
+                    .map(|part| Value::String(part.to_string()))
+                    .collect(),
+            )),
+            ("split", [_]) => string_arg(0).and_then(|sep| {
+                if sep.is_empty() {
+                    return Err("str.split() needs a non-empty separator".to_string());
+                }
+                Ok(Value::List(
+                    s.split(sep)
+                        .map(|part| Value::String(part.to_string()))
+                        .collect(),
+                ))
+            }),
+            ("replace", [_, _]) => string_arg(0).and_then(|old| {
+                // Markup stays markup, with the replacement escaped
+                let new = string_arg(1)?;
+                Ok(match self {
+                    Value::SafeString(_) if !matches!(args[1], Value::SafeString(_)) => {
+                        Value::SafeString(s.replace(old, &escape_html(new)))
+                    }
+                    _ => self.same_kind(s.replace(old, new)),
+                })
+            }),
+            ("count", [_]) => string_arg(0).map(|sub| {
+                let count = if sub.is_empty() {
+                    s.chars().count() + 1
+                } else {
+                    s.matches(sub).count()
+                };
+                Value::Integer(count as i64)
+            }),
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    /// A string derived from this one, which stays safe if this one is.
+    fn same_kind(&self, s: String) -> Value {
+        match self {
+            Value::SafeString(_) => Value::SafeString(s),
+            _ => Value::String(s),
+        }
+    }
+
+    pub fn get_attr(&self, name: &str) -> Result<Value, String> {
+        match self {
+            Value::Object(object) => object
+                .get_attr(name)
+                .ok_or_else(|| format!("{} has no attribute '{}'", object.type_name(), name)),
+            Value::Dict(dict) => dict
+                .get(name)
+                .cloned()
+                .ok_or_else(|| format!("dict has no attribute '{}'", name)),
+            _ => Err(format!("{} has no attribute '{}'", self.type_name(), name)),
+        }
+    }
+
+    pub fn get_item(&self, key: &Value) -> Result<Value, String> {
+        match (self, key) {
+            (Value::List(list), Value::Integer(i)) => index(list.len(), *i)
+                .map(|i| list[i].clone())
+                .ok_or_else(|| format!("list index {} out of range", i)),
+            (Value::String(s) | Value::SafeString(s), Value::Integer(i)) => {
+                let chars: Vec<char> = s.chars().collect();
+                index(chars.len(), *i)
+                    .map(|i| Value::String(chars[i].to_string()))
+                    .ok_or_else(|| format!("string index {} out of range", i))
+            }
+            (Value::Dict(dict), Value::String(k) | Value::SafeString(k)) => dict
+                .get(k)
+                .cloned()
+                .ok_or_else(|| format!("dict has no key {:?}", k)),
+            _ => Err(format!(
+                "Cannot index {} with {}",
+                self.type_name(),
+                key.type_name()
+            )),
+        }
+    }
+
+    /// Implements `item in self`.
+    pub fn contains(&self, item: &Value) -> Result<bool, String> {
+        match (self, item.as_str()) {
+            (Value::String(s) | Value::SafeString(s), Some(sub)) => Ok(s.contains(sub)),
+            (Value::List(list), _) => Ok(list.iter().any(|v| v.loose_eq(item))),
+            (Value::Dict(dict), Some(k)) => Ok(dict.contains_key(k)),
+            _ => Err(format!(
+                "Cannot test if {} is in {}",
+                item.type_name(),
+                self.type_name()
+            )),
+        }
+    }
+
+    /// Equality as templates see it, where `1 == 1.0`.
+    pub fn loose_eq(&self, other: &Value) -> bool {
+        if let (Some(a), Some(b)) = (self.as_str(), other.as_str()) {
+            return a == b;
+        }
+        match (self.as_f64(), other.as_f64()) {
+            (Some(a), Some(b)) => a == b,
+            _ => self == other,
+        }
+    }
+
+    pub fn compare(&self, other: &Value) -> Result<Ordering, String> {
+        let ordering = match (self, other) {
+            (Value::Integer(a), Value::Integer(b)) => Some(a.cmp(b)),
+            _ if self.as_str().is_some() && other.as_str().is_some() => {
+                Some(self.as_str().cmp(&other.as_str()))
+            }
+            _ => match (self.as_f64(), other.as_f64()) {
+                (Some(a), Some(b)) => a.partial_cmp(&b),
+                _ => None,
+            },
+        };
+        ordering.ok_or_else(|| {
+            format!(
+                "Cannot compare {} with {}",
+                self.type_name(),
+                other.type_name()
+            )
+        })
+    }
+
+    /// Applies an arithmetic operator; integers stay integers except for `/`.
+    pub fn arithmetic(&self, op: BinaryOp, rhs: &Value) -> Result<Value, String> {
+        match (op, self, rhs) {
+            (BinaryOp::Add, Value::SafeString(a), Value::SafeString(b)) => {
+                Ok(Value::SafeString(format!("{}{}", a, b)))
+            }
+            (BinaryOp::Add, _, _) if self.as_str().is_some() && rhs.as_str().is_some() => {
+                Ok(Value::String(format!("{}{}", self, rhs)))
+            }
+            (BinaryOp::Add, Value::List(a), Value::List(b)) => {
+                Ok(Value::List(a.iter().chain(b).cloned().collect()))
+            }
+            (BinaryOp::Mul, Value::String(s), Value::Integer(n)) => {
+                Ok(Value::String(repeat(s, *n)?))
+            }
+            (BinaryOp::Mul, Value::SafeString(s), Value::Integer(n)) => {
+                Ok(Value::SafeString(repeat(s, *n)?))
+            }
+            (_, Value::Integer(a), Value::Integer(b)) => integer_arithmetic(op, *a, *b),
+            _ => match (self.as_f64(), rhs.as_f64()) {
+                (Some(a), Some(b)) => float_arithmetic(op, a, b),
+                _ => Err(format!(
+                    "Unsupported operand types for {}: {} and {}",
+                    op,
+                    self.type_name(),
+                    rhs.type_name()
+                )),
+            },
+        }
+    }
+}
+
+/// Resolves a possibly negative index against a sequence length.
+fn index(len: usize, i: i64) -> Option<usize> {
+    let i = if i < 0 { len as i64 + i } else { i };
+    (0..len as i64).contains(&i).then_some(i as usize)
+}
+
+/// `s * n`, with an error instead of an abort when the result doesn't fit in memory.
+fn repeat(s: &str, n: i64) -> Result<String, String> {
+    let n = usize::try_from(n.max(0)).unwrap_or(usize::MAX);
+    if s.is_empty() {
+        return Ok(String::new());
+    }
+    let mut repeated = String::new();
+    s.len()
+        .checked_mul(n)
+        .and_then(|len| repeated.try_reserve_exact(len).ok())
+        .ok_or_else(|| format!("Cannot repeat a string {} times", n))?;
+    for _ in 0..n {
+        repeated.push_str(s);
+    }
+    Ok(repeated)
+}
+
+fn integer_arithmetic(op: BinaryOp, a: i64, b: i64) -> Result<Value, String> {
+    if b == 0 && matches!(op, BinaryOp::Div | BinaryOp::FloorDiv | BinaryOp::Rem) {
+        return Err("Division by zero".to_string());
+    }
+    let result = match op {
+        BinaryOp::Add => a.checked_add(b),
+        BinaryOp::Sub => a.checked_sub(b),
+        BinaryOp::Mul => a.checked_mul(b),
+        BinaryOp::Div => return Ok(Value::Float(a as f64 / b as f64)),
+        // Floor division and modulo round towards negative infinity, as in Python
+        // `i64::MIN / -1` doesn't fit, and neither does the remainder computed alongside it
+        BinaryOp::FloorDiv => a.checked_div(b).map(|q| {
+            if a % b != 0 && (a < 0) != (b < 0) {
+                q - 1
+            } else {
+                q
+            }
+        }),
+        BinaryOp::Rem => a.checked_rem(b).map(|r| {
+            if r != 0 && (r < 0) != (b < 0) {
+                r + b
+            } else {
+                r
+            }
+        }),
+        BinaryOp::Pow if b < 0 => return float_arithmetic(op, a as f64, b as f64),
+        BinaryOp::Pow => u32::try_from(b).ok().and_then(|b| a.checked_pow(b)),
+        _ => return Err(format!("{} is not an arithmetic operator", op)),
+    };
+    result
+        .map(Value::Integer)
+        .ok_or_else(|| format!("Integer overflow in {} {} {}", a, op, b))
+}
+
+fn float_arithmetic(op: BinaryOp, a: f64, b: f64) -> Result<Value, String> {
+    if b == 0.0 && matches!(op, BinaryOp::Div | BinaryOp::FloorDiv | BinaryOp::Rem) {
+        return Err("Division by zero".to_string());
+    }
+    Ok(Value::Float(match op {
+        BinaryOp::Add => a + b,
+        BinaryOp::Sub => a - b,
+        BinaryOp::Mul => a * b,
+        BinaryOp::Div => a / b,
+        BinaryOp::FloorDiv => (a / b).floor(),
+        BinaryOp::Rem => a - b * (a / b).floor(),
+        BinaryOp::Pow => a.powf(b),
+        _ => return Err(format!("{} is not an arithmetic operator", op)),
+    }))
+}
+
+/// Dict entries in key order, so iteration output is deterministic.
+fn sorted_entries(dict: &HashMap<String, Value>) -> Vec<(&String, &Value)> {
+    let mut entries: Vec<(&String, &Value)> = dict.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integer_arithmetic_rounds_like_python() {
+        for (op, a, b, expected) in [
+            (BinaryOp::FloorDiv, 7, 2, 3),
+            (BinaryOp::FloorDiv, -7, 2, -4),
+            (BinaryOp::FloorDiv, 7, -2, -4),
+            (BinaryOp::Rem, -7, 2, 1),
+            (BinaryOp::Rem, 7, -2, -1),
+            (BinaryOp::Pow, 2, 10, 1024),
+        ] {
+            let result = Value::Integer(a).arithmetic(op, &Value::Integer(b));
+            assert_eq!(result, Ok(Value::Integer(expected)), "{} {} {}", a, op, b);
+        }
+        assert_eq!(
+            Value::Integer(1).arithmetic(BinaryOp::Div, &Value::Integer(2)),
+            Ok(Value::Float(0.5))
+        );
+    }
+
+    #[test]
+    fn integer_overflow_is_an_error() {
+        for (op, a, b) in [
+            (BinaryOp::FloorDiv, i64::MIN, -1),
+            (BinaryOp::Rem, i64::MIN, -1),
+            (BinaryOp::Mul, i64::MAX, 2),
+            (BinaryOp::Add, i64::MAX, 1),
+            (BinaryOp::Pow, 10, 100),
+        ] {
+            let err = Value::Integer(a)
+                .arithmetic(op, &Value::Integer(b))
+                .unwrap_err();
+            assert!(err.starts_with("Integer overflow"), "{}", err);
+        }
+        assert_eq!(
+            Value::Integer(1).arithmetic(BinaryOp::Rem, &Value::Integer(0)),
+            Err("Division by zero".to_string())
+        );
+    }
+
+    #[test]
+    fn string_repetition_is_bounded() {
+        let ab = Value::String("ab".to_string());
+        assert_eq!(
+            ab.arithmetic(BinaryOp::Mul, &Value::Integer(3)),
+            Ok(Value::String("ababab".to_string()))
+        );
+        assert_eq!(
+            ab.arithmetic(BinaryOp::Mul, &Value::Integer(-1)),
+            Ok(Value::String(String::new()))
+        );
+        assert!(ab
+            .arithmetic(BinaryOp::Mul, &Value::Integer(i64::MAX))
+            .is_err());
+
+        let safe = Value::SafeString("<b>".to_string());
+        assert_eq!(
+            safe.arithmetic(BinaryOp::Mul, &Value::Integer(2)),
+            Ok(Value::SafeString("<b><b>".to_string()))
+        );
+        assert!(safe
+            .arithmetic(BinaryOp::Mul, &Value::Integer(i64::MAX))
+            .is_err());
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    fn strings(items: &[&str]) -> Value {
+        Value::List(items.iter().map(|s| string(s)).collect())
+    }
+
+    #[test]
+    fn str_methods_work_like_python() {
+        let call = |s: &str, name: &str, args: &[Value]| string(s).call_method(name, args);
+        for (s, name, args, expected) in [
+            ("Ab", "lower", vec![], string("ab")),
+            ("Ab", "upper", vec![], string("AB")),
+            ("  a b ", "strip", vec![], string("a b")),
+            ("xxaxx", "lstrip", vec![string("x")], string("axx")),
+            ("xxaxx", "rstrip", vec![Value::None], string("xxaxx")),
+            (" a  b ", "split", vec![], strings(&["a", "b"])),
+            ("a,,b", "split", vec![string(",")], strings(&["a", "", "b"])),
+            (
+                "aXa",
+                "replace",
+                vec![string("a"), string("b")],
+                string("bXb"),
+            ),
+            ("aaa", "count", vec![string("aa")], Value::Integer(1)),
+            ("ab", "count", vec![string("")], Value::Integer(3)),
+            ("abc", "startswith", vec![string("ab")], Value::Bool(true)),
+            (
+                "abc",
+                "endswith",
+                vec![strings(&["x", "bc"])],
+                Value::Bool(true),
+            ),
+        ] {
+            assert_eq!(call(s, name, &args).unwrap(), expected, "{}.{}", s, name);
+        }
+
+        assert_eq!(
+            call("a", "split", &[string("")]).unwrap_err(),
+            "str.split() needs a non-empty separator"
+        );
+        assert_eq!(
+            call("a", "startswith", &[Value::Integer(1)]).unwrap_err(),
+            "str.startswith() needs strings, not integer"
+        );
+        assert_eq!(
+            call("a", "title", &[]).unwrap_err(),
+            "string has no method 'title' taking 0 arguments"
+        );
+    }
+
+    #[test]
+    fn str_methods_keep_markup_safe() {
+        let safe = Value::SafeString("<b>x</b>".to_string());
+        assert_eq!(
+            safe.call_method("upper", &[]),
+            Ok(Value::SafeString("<B>X</B>".to_string()))
+        );
+        // Replacements are escaped unless they are markup themselves
+        assert_eq!(
+            safe.call_method("replace", &[string("x"), string("<i>")]),
+            Ok(Value::SafeString("<b>&lt;i&gt;</b>".to_string()))
+        );
+        assert_eq!(
+            safe.call_method(
+                "replace",
+                &[string("x"), Value::SafeString("<i>".to_string())]
+            ),
+            Ok(Value::SafeString("<b><i></b>".to_string()))
+        );
+    }
+
+    #[test]
+    fn dict_methods_list_entries_in_key_order() {
+        let dict = Value::Dict(HashMap::from([
+            ("b".to_string(), Value::Integer(2)),
+            ("a".to_string(), Value::Integer(1)),
+        ]));
+        assert_eq!(dict.call_method("keys", &[]), Ok(strings(&["a", "b"])));
+        assert_eq!(
+            dict.call_method("values", &[]),
+            Ok(Value::List(vec![Value::Integer(1), Value::Integer(2)]))
+        );
+        assert_eq!(
+            dict.call_method("items", &[]),
+            Ok(Value::List(vec![
+                Value::List(vec![string("a"), Value::Integer(1)]),
+                Value::List(vec![string("b"), Value::Integer(2)]),
+            ]))
+        );
+        assert_eq!(
+            dict.call_method("get", &[string("a")]),
+            Ok(Value::Integer(1))
+        );
+        assert_eq!(dict.call_method("get", &[string("z")]), Ok(Value::None));
+        assert_eq!(
+            dict.call_method("get", &[string("z"), Value::Integer(0)]),
+            Ok(Value::Integer(0))
+        );
+        assert_eq!(
+            dict.call_method("get", &[Value::Integer(1)]),
+            Err("dict keys are strings, not integer".to_string())
+        );
+    }
+
+    #[test]
+    fn list_methods_compare_loosely() {
+        let list = Value::List(vec![
+            Value::Integer(1),
+            Value::Float(2.0),
+            Value::Integer(2),
+        ]);
+        assert_eq!(
+            list.call_method("index", &[Value::Integer(2)]),
+            Ok(Value::Integer(1))
+        );
+        assert_eq!(
+            list.call_method("count", &[Value::Float(2.0)]),
+            Ok(Value::Integer(2))
+        );
+        assert_eq!(
+            list.call_method("index", &[Value::Integer(3)]),
+            Err("3 is not in list".to_string())
+        );
+        assert_eq!(
+            list.call_method("pop", &[]),
+            Err("list has no method 'pop' taking 0 arguments".to_string())
+        );
     }
 }
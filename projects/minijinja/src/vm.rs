@@ -1,135 +1,1675 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, io, mem, rc::Rc, time::Instant};
+
+use serde::Serialize;
 
 use crate::{
-    ast::{Ast, AstNode},
-    filters::FilterRegistry,
-    macros::MacroRegistry,
+    ast::{BinaryOp, UnaryOp},
+    compiler::{Callee, Instruction, Program},
+    environment::{AutoEscape, Environment, UndefinedBehavior},
+    error::{Error, ErrorKind},
+    filters::{attribute_argument, escape_html, Args},
+    i18n::{interpolate, Translations},
+    macros::{Macro, MacroRegistry},
+    sandbox::{Sandbox, SandboxError},
+    ser::to_value,
     values::Value,
 };
 
-pub struct Vm {
-    variables: HashMap<String, Value>,
-    macros: MacroRegistry,
-    filters: FilterRegistry,
+/// How much output `render_to` buffers before writing it out.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Receives rendered output as it is produced.
+type Sink<'a> = dyn FnMut(String) -> Result<(), Error> + 'a;
+
+pub struct Vm<'env> {
+    env: &'env Environment,
+    globals: HashMap<String, Value>,
+    macros: Rc<MacroRegistry>,
+    sandbox: Option<Sandbox>,
+    translations: Option<Rc<dyn Translations>>,
 }
 
-impl Vm {
-    pub fn new() -> Vm {
+impl<'env> Vm<'env> {
+    pub fn new(env: &'env Environment) -> Vm<'env> {
         Vm {
-            variables: HashMap::new(),
-            macros: MacroRegistry::new(),
-            filters: FilterRegistry::new(),
+            env,
+            globals: HashMap::new(),
+            macros: Rc::new(MacroRegistry::new()),
+            sandbox: None,
+            translations: None,
         }
     }
 
+    /// Sets a global variable, visible everywhere including inside macros.
     pub fn set_variable(&mut self, name: &str, value: Value) {
-        self.variables.insert(name.to_string(), value);
+        self.globals.insert(name.to_string(), value);
+    }
+
+    /// Sets a global variable for each field of `context`, which must serialize to a map,
+    /// e.g. a struct deriving `Serialize`.
+    pub fn set_context<T: Serialize + ?Sized>(&mut self, context: &T) -> Result<(), Error> {
+        match to_value(context).map_err(|err| err.to_string())? {
+            Value::Dict(entries) => {
+                self.globals.extend(entries);
+                Ok(())
+            }
+            value => Err(Error::new(
+                ErrorKind::InvalidOperation,
+                format!(
+                    "The context must be a map or struct, found {}",
+                    value.type_name()
+                ),
+            )),
+        }
     }
 
     pub fn set_macro_registry(&mut self, macros: MacroRegistry) {
-        self.macros = macros;
+        self.macros = Rc::new(macros);
+    }
+
+    /// Renders templates under the sandbox's limits, for templates from untrusted authors.
+    pub fn set_sandbox(&mut self, sandbox: Sandbox) {
+        self.sandbox = Some(sandbox);
+    }
+
+    /// Translates `{% trans %}` blocks and `_()`, `gettext()` and `ngettext()` calls,
+    /// e.g. with a `Catalog` for the language being rendered.
+    pub fn set_translations(&mut self, translations: Rc<dyn Translations>) {
+        self.translations = Some(translations);
+    }
+
+    /// Renders a template from the environment, following its `extends` chain.
+    pub fn render_template(&self, name: &str) -> Result<String, Error> {
+        self.render(name, usize::MAX, &mut |_| Ok(()))
+    }
+
+    /// Renders a template into `out` as it goes, holding at most a few kilobytes of
+    /// output at a time. Output captured by macro calls and `set` blocks is still
+    /// buffered, as it becomes a value.
+    pub fn render_to(&self, name: &str, out: &mut impl io::Write) -> Result<(), Error> {
+        let mut write = |chunk: String| Ok(out.write_all(chunk.as_bytes())?);
+        let rest = self.render(name, CHUNK_SIZE, &mut write)?;
+        write(rest)?;
+        Ok(out.flush()?)
+    }
+
+    /// Renders a template, handing the output to `on_chunk` in pieces of at least
+    /// `chunk_size` bytes (except the last), e.g. to send as a chunked HTTP response.
+    ///
+    /// Rendering can't be suspended, so chunks are pushed rather than pulled. To pull
+    /// them instead, render on a worker thread with `stream::render_iter`, or with
+    /// `stream::render_stream` for async consumers.
+    pub fn render_chunks(
+        &self,
+        name: &str,
+        chunk_size: usize,
+        mut on_chunk: impl FnMut(String) -> io::Result<()>,
+    ) -> Result<(), Error> {
+        let chunk_size = chunk_size.max(1);
+        let mut send = |chunk: String| Ok(on_chunk(chunk)?);
+        let rest = self.render(name, chunk_size, &mut send)?;
+        if !rest.is_empty() {
+            send(rest)?;
+        }
+        Ok(())
+    }
+
+    /// Renders a template, passing output to `sink` whenever `chunk_size` bytes have
+    /// built up at the top level. Returns what is left over.
+    fn render(&self, name: &str, chunk_size: usize, sink: &mut Sink) -> Result<String, Error> {
+        let program = self.env.get_template(name)?;
+        let mut state = State {
+            env: self.env,
+            globals: &self.globals,
+            frames: vec![Frame::new(None, Rc::clone(&self.macros))],
+            output: vec![String::new()],
+            auto_escape: self.env.auto_escape_for(name),
+            escape_stack: Vec::new(),
+            blocks: HashMap::new(),
+            block_stack: Vec::new(),
+            sandbox: self.sandbox.as_ref(),
+            translations: self.translations.as_deref(),
+            started: Instant::now(),
+            iterations: 0,
+            sink,
+            chunk_size,
+            flushed: 0,
+        };
+        state.render_template(name, program)?;
+        Ok(state.output.pop().unwrap_or_default())
+    }
+}
+
+/// The variables of a template, macro call or call block body.
+struct Frame {
+    /// Innermost scope last
+    scopes: Vec<HashMap<String, Value>>,
+    /// The frame whose variables are visible from this one. Macros only see the globals.
+    parent: Option<usize>,
+    macros: Rc<MacroRegistry>,
+    /// Macro namespaces bound by `{% import ... as name %}`
+    imports: HashMap<String, Rc<MacroRegistry>>,
+    caller: Option<Caller>,
+    loops: Vec<LoopState>,
+}
+
+impl Frame {
+    fn new(parent: Option<usize>, macros: Rc<MacroRegistry>) -> Frame {
+        Frame {
+            scopes: vec![HashMap::new()],
+            parent,
+            macros,
+            imports: HashMap::new(),
+            caller: None,
+            loops: Vec::new(),
+        }
+    }
+}
+
+/// The body of a `{% call %}` block, rendered with the variables of the frame that made the call.
+#[derive(Clone)]
+struct Caller {
+    program: Rc<Program>,
+    frame: usize,
+}
+
+struct LoopState {
+    targets: Vec<String>,
+    items: Vec<Value>,
+    next: usize,
+    /// Items that passed the loop filter, while `filtering`
+    kept: Vec<Value>,
+    filtering: bool,
+    /// Where to unwind scopes and escaping to when `break`/`continue` jump out of nested blocks
+    scope_depth: usize,
+    escape_depth: usize,
+}
+
+struct State<'env, 'vm> {
+    env: &'env Environment,
+    globals: &'vm HashMap<String, Value>,
+    frames: Vec<Frame>,
+    /// Output buffers; `set` blocks and macro calls capture into a new one
+    output: Vec<String>,
+    auto_escape: AutoEscape,
+    /// Policies to restore when `autoescape` blocks end
+    escape_stack: Vec<AutoEscape>,
+    /// Overrides for each block name, from the most derived template to the base
+    blocks: HashMap<String, Vec<Rc<Program>>>,
+    /// Blocks being rendered and which level of their override chain, for `super()`
+    block_stack: Vec<(String, usize)>,
+    sandbox: Option<&'vm Sandbox>,
+    translations: Option<&'vm dyn Translations>,
+    started: Instant,
+    iterations: usize,
+    sink: &'vm mut Sink<'vm>,
+    chunk_size: usize,
+    /// Bytes already handed to the sink
+    flushed: usize,
+}
+
+impl State<'_, '_> {
+    fn render_template(&mut self, name: &str, mut program: Rc<Program>) -> Result<(), Error> {
+        let mut seen = vec![name.to_string()];
+        self.blocks.clear();
+        loop {
+            for (block, body) in &program.blocks {
+                self.blocks
+                    .entry(block.clone())
+                    .or_default()
+                    .push(Rc::clone(body));
+            }
+            let Some(parent) = self.run(&program)? else {
+                return Ok(());
+            };
+            if seen.contains(&parent) {
+                return Err(format!("Template '{}' extends itself", parent).into());
+            }
+            program = self.env.get_template(&parent)?;
+            seen.push(parent);
+        }
     }
 
-    pub fn set_filter_registry(&mut self, filters: FilterRegistry) {
-        self.filters = filters;
+    /// Executes a program in the current frame. Returns the parent template named by
+    /// `extends`, if any.
+    fn run(&mut self, program: &Program) -> Result<Option<String>, Error> {
+        let mut pc = 0;
+        self.execute(program, &mut pc)
+            .map_err(|err| program.locate(err, pc.saturating_sub(1)))
     }
 
-    pub fn render(&mut self, ast: &Ast) -> Result<String, String> {
-        let mut output = String::new();
-        for node in &ast.nodes {
-            match node {
-                AstNode::Text(text) => output.push_str(&text),
-                AstNode::Variable(variable, filters) => {
-                    let value = self.evaluate_variable(variable)?;
-                    let filtered_value = self.apply_filters(value, filters)?;
-                    output.push_str(&filtered_value.to_string());
+    fn execute(&mut self, program: &Program, pc: &mut usize) -> Result<Option<String>, Error> {
+        let mut stack: Vec<Value> = Vec::new();
+        let mut parent = None;
+
+        while let Some(instruction) = program.instructions.get(*pc) {
+            *pc += 1;
+            match instruction {
+                Instruction::EmitRaw(text) => self.write(text)?,
+                Instruction::Emit => {
+                    let value = pop(&mut stack);
+                    self.check_defined(&value, format_args!("output"))?;
+                    let text = self.escape(&value);
+                    self.write(&text)?;
                 }
-                AstNode::IfBlock {
-                    condition,
-                    then_block,
-                    else_block,
-                } => {
-                    let condition_value = self.evaluate_variable(condition)?;
-                    if self.is_truthy(&condition_value) {
-                        output.push_str(&self.render(then_block)?);
-                    } else if let Some(else_block) = else_block {
-                        output.push_str(&self.render(else_block)?);
+                Instruction::LoadConst(value) => stack.push(value.clone()),
+                Instruction::Lookup(name) => {
+                    let value = self.lookup_path(name, program, pc)?;
+                    stack.push(value);
+                }
+                Instruction::StoreLocal(name) => {
+                    let value = pop(&mut stack);
+                    self.assign(name, value);
+                }
+                Instruction::BuildList(len) => {
+                    let items = stack.split_off(stack.len() - len);
+                    stack.push(Value::List(items));
+                }
+                Instruction::BuildDict(len) => {
+                    let mut entries = stack.split_off(stack.len() - len * 2).into_iter();
+                    let mut dict = HashMap::new();
+                    while let (Some(key), Some(value)) = (entries.next(), entries.next()) {
+                        dict.insert(key.to_string(), value);
+                    }
+                    stack.push(Value::Dict(dict));
+                }
+                Instruction::GetAttr(name) => {
+                    self.sandboxed(|sandbox| sandbox.check_attribute(name))?;
+                    let value = self.get_attr(pop(&mut stack), name)?;
+                    stack.push(value);
+                }
+                Instruction::GetItem => {
+                    let key = pop(&mut stack);
+                    if let Value::String(name) | Value::SafeString(name) = &key {
+                        self.sandboxed(|sandbox| sandbox.check_attribute(name))?;
+                    }
+                    let value = self.get_item(pop(&mut stack), &key)?;
+                    stack.push(value);
+                }
+                Instruction::UnaryOp(op) => {
+                    let value = pop(&mut stack);
+                    self.check_defined(&value, format_args!("use an operator on"))?;
+                    let value = unary_op(*op, value)?;
+                    stack.push(value);
+                }
+                Instruction::BinaryOp(op) => {
+                    let rhs = pop(&mut stack);
+                    let lhs = pop(&mut stack);
+                    self.check_defined(&lhs, format_args!("use '{}' on", op))?;
+                    self.check_defined(&rhs, format_args!("use '{}' on", op))?;
+                    let value = self.binary_op(*op, lhs, rhs)?;
+                    stack.push(value);
+                }
+                Instruction::Jump(target) => *pc = *target,
+                Instruction::JumpIfFalse(target) => {
+                    let value = pop(&mut stack);
+                    self.check_defined(&value, format_args!("test the truth of"))?;
+                    if !value.is_truthy() {
+                        *pc = *target;
+                    }
+                }
+                Instruction::JumpIfFalseOrPop(target) => {
+                    self.check_defined(
+                        stack.last().unwrap_or(&Value::None),
+                        format_args!("test the truth of"),
+                    )?;
+                    if stack.last().is_some_and(|value| !value.is_truthy()) {
+                        *pc = *target;
+                    } else {
+                        stack.pop();
                     }
                 }
-                AstNode::ForBlock {
-                    loop_var,
-                    collection_var,
-                    body,
+                Instruction::JumpIfTrueOrPop(target) => {
+                    self.check_defined(
+                        stack.last().unwrap_or(&Value::None),
+                        format_args!("test the truth of"),
+                    )?;
+                    if stack.last().is_some_and(Value::is_truthy) {
+                        *pc = *target;
+                    } else {
+                        stack.pop();
+                    }
+                }
+                Instruction::JumpIfBound(name, target) => {
+                    let frame = self.frame();
+                    if frame
+                        .scopes
+                        .last()
+                        .is_some_and(|scope| scope.contains_key(name))
+                    {
+                        *pc = *target;
+                    }
+                }
+                Instruction::ApplyFilter { name, args, kwargs } => {
+                    let (args, kwargs) = pop_args(&mut stack, *args, kwargs);
+                    let value = pop(&mut stack);
+                    if !matches!(name.as_str(), "default" | "d") {
+                        self.check_defined(&value, format_args!("apply filter {} to", name))?;
+                    }
+                    let args = self.args(args, kwargs);
+                    if let Some(attribute) = attribute_argument(name, &args) {
+                        let path = attribute.to_string();
+                        self.sandboxed(|sandbox| sandbox.check_attribute(&path))?;
+                    }
+                    let value = self
+                        .env
+                        .apply_filter(name, &value, &args)
+                        .map_err(|err| over_budget(&args, err))?;
+                    self.sandboxed(|sandbox| sandbox.check_size(&value))?;
+                    stack.push(value);
+                }
+                Instruction::PerformTest { name, args } => {
+                    let args = stack.split_off(stack.len() - args);
+                    let value = pop(&mut stack);
+                    let passed = self.env.perform_test(name, &value, &args)?;
+                    stack.push(Value::Bool(passed));
+                }
+                Instruction::Call {
+                    callee,
+                    args,
+                    kwargs,
+                    caller,
                 } => {
-                    let collection_value = self.evaluate_variable(collection_var)?;
-                    if let Value::List(list) = collection_value {
-                        for item in list {
-                            self.variables.insert(loop_var.clone(), item.clone());
-                            output.push_str(&self.render(body)?);
+                    let (args, kwargs) = pop_args(&mut stack, *args, kwargs);
+                    let value = match callee {
+                        Callee::Macro(name) => self.call_by_name(name, args, kwargs, caller)?,
+                        Callee::Attribute(name, attr) => {
+                            self.call_attribute(name, attr, args, kwargs, caller)?
                         }
+                        Callee::Method(method) => {
+                            self.sandboxed(|sandbox| sandbox.check_attribute(method))?;
+                            let receiver = pop(&mut stack);
+                            if receiver == Value::Undefined {
+                                self.undefined_attr(method)?
+                            } else {
+                                call_method(&receiver, method, args, kwargs, caller)?
+                            }
+                        }
+                    };
+                    stack.push(value);
+                }
+                Instruction::PushScope => self.frame_mut().scopes.push(HashMap::new()),
+                Instruction::PopScope => {
+                    self.frame_mut().scopes.pop();
+                }
+                Instruction::PushAutoEscape => {
+                    let enabled = pop(&mut stack).is_truthy();
+                    self.escape_stack.push(self.auto_escape);
+                    self.auto_escape = if enabled {
+                        AutoEscape::Html
                     } else {
-                        return Err(format!(
-                            "Expected a list for '{}' but found {:?}",
-                            collection_var, collection_value
-                        ));
+                        AutoEscape::None
+                    };
+                }
+                Instruction::PopAutoEscape => {
+                    if let Some(auto_escape) = self.escape_stack.pop() {
+                        self.auto_escape = auto_escape;
+                    }
+                }
+                Instruction::BeginCapture => self.output.push(String::new()),
+                Instruction::EndCapture => {
+                    let captured = self.output.pop().unwrap_or_default();
+                    stack.push(self.markup(captured));
+                }
+                Instruction::PushLoop { targets, filtered } => {
+                    let collection = pop(&mut stack);
+                    self.check_defined(&collection, format_args!("iterate over"))?;
+                    let items = collection.to_list()?;
+                    let escape_depth = self.escape_stack.len();
+                    let frame = self.frame_mut();
+                    frame.scopes.push(HashMap::new());
+                    let scope_depth = frame.scopes.len();
+                    frame.loops.push(LoopState {
+                        targets: targets.clone(),
+                        items,
+                        next: 0,
+                        kept: Vec::new(),
+                        filtering: *filtered,
+                        scope_depth,
+                        escape_depth,
+                    });
+                }
+                Instruction::Iterate(target) => {
+                    if !self.iterate()? {
+                        *pc = *target;
+                    }
+                }
+                Instruction::KeepItem => {
+                    if let Some(state) = self.frame_mut().loops.last_mut() {
+                        state.kept.push(state.items[state.next - 1].clone());
+                    }
+                }
+                Instruction::RestartLoop => {
+                    if let Some(state) = self.frame_mut().loops.last_mut() {
+                        state.items = mem::take(&mut state.kept);
+                        state.next = 0;
+                        state.filtering = false;
+                    }
+                }
+                Instruction::PushDidNotIterate => {
+                    let state = self.frame().loops.last();
+                    stack.push(Value::Bool(
+                        state.is_some_and(|state| state.items.is_empty()),
+                    ));
+                }
+                Instruction::PopLoop => {
+                    if let Some(state) = self.frame_mut().loops.pop() {
+                        self.unwind(state.scope_depth - 1, state.escape_depth);
+                    }
+                }
+                Instruction::DefineMacro(macro_def) => {
+                    Rc::make_mut(&mut self.frame_mut().macros).add_macro(Rc::clone(macro_def));
+                }
+                Instruction::Import(alias) => {
+                    let name = pop(&mut stack).to_string();
+                    let template = self.env.get_template(&name)?;
+                    let mut macros = MacroRegistry::new();
+                    for macro_def in template.macros() {
+                        macros.add_macro(Rc::clone(macro_def));
                     }
+                    self.frame_mut()
+                        .imports
+                        .insert(alias.clone(), Rc::new(macros));
                 }
-                AstNode::MacroCall { name, args } => {
-                    let macro_args: Vec<Value> = args
-                        .iter()
-                        .map(|arg| self.evaluate_variable(arg))
-                        .collect::<Result<_, _>>()?;
-                    let mut vm = self.clone();
-                    if let Err(err) = self.macros.call_macro(name, macro_args, &mut vm) {
-                        return Err(err);
+                Instruction::Extends => parent = Some(pop(&mut stack).to_string()),
+                Instruction::Block(name) => {
+                    let block = self
+                        .blocks
+                        .get(name)
+                        .map(|chain| Rc::clone(&chain[0]))
+                        .ok_or_else(|| format!("Block '{}' not found", name))?;
+                    self.render_block(name, 0, &block)?;
+                }
+                Instruction::Super => {
+                    let (name, level) = self
+                        .block_stack
+                        .last()
+                        .cloned()
+                        .ok_or_else(|| "super() can only be used inside a block".to_string())?;
+                    let parent = self
+                        .blocks
+                        .get(&name)
+                        .and_then(|chain| chain.get(level + 1))
+                        .cloned()
+                        .ok_or_else(|| format!("Block '{}' has no parent block", name))?;
+                    self.render_block(&name, level + 1, &parent)?;
+                }
+                Instruction::Include { ignore_missing } => {
+                    let name = pop(&mut stack).to_string();
+                    let template = match self.env.load_template(&name)? {
+                        Some(template) => template,
+                        None if *ignore_missing => continue,
+                        None => {
+                            return Err(Error::new(
+                                ErrorKind::TemplateNotFound,
+                                format!("Template '{}' not found", name),
+                            ))
+                        }
+                    };
+                    self.include(&name, template)?;
+                }
+                Instruction::Trans {
+                    singular,
+                    plural,
+                    count,
+                    variables,
+                } => {
+                    let values = stack.split_off(stack.len() - variables.len());
+                    let values: HashMap<&str, Value> =
+                        variables.iter().map(String::as_str).zip(values).collect();
+                    for value in values.values() {
+                        self.check_defined(value, format_args!("output"))?;
+                    }
+                    let plural = match (plural, count) {
+                        (Some(plural), Some(count)) => {
+                            let n = plural_count(&values[count.as_str()])?;
+                            Some((plural.as_str(), n))
+                        }
+                        _ => None,
+                    };
+                    // The message is template text; only the variables are escaped
+                    let message = self.translate(singular, plural);
+                    let text = interpolate(&message, |name| {
+                        values.get(name).map(|value| self.escape(value))
+                    })?;
+                    self.write(&text)?;
+                }
+            }
+        }
+        Ok(parent)
+    }
+
+    /// Binds the next loop item. Returns `false` once the items are exhausted.
+    fn iterate(&mut self) -> Result<bool, Error> {
+        let Some(state) = self.frame().loops.last() else {
+            return Ok(false);
+        };
+        if state.next < state.items.len() {
+            self.iterations += 1;
+            let (iterations, started) = (self.iterations, self.started);
+            self.sandboxed(|sandbox| sandbox.check_iterations(iterations))?;
+            self.sandboxed(|sandbox| sandbox.check_time(started))?;
+        }
+        let Some(state) = self.frame().loops.last() else {
+            return Ok(false);
+        };
+        let (scope_depth, escape_depth) = (state.scope_depth, state.escape_depth);
+        self.unwind(scope_depth, escape_depth);
+
+        let Some(state) = self.frame_mut().loops.last_mut() else {
+            return Ok(false);
+        };
+        let Some(item) = state.items.get(state.next).cloned() else {
+            return Ok(false);
+        };
+        let (index, length) = (state.next, state.items.len());
+        state.next += 1;
+        let targets = state.targets.clone();
+        let filtering = state.filtering;
+
+        if let [target] = &targets[..] {
+            self.assign(target, item);
+        } else {
+            match item {
+                Value::List(values) if values.len() == targets.len() => {
+                    for (target, value) in targets.iter().zip(values) {
+                        self.assign(target, value);
                     }
                 }
+                item => {
+                    return Err(format!(
+                        "Cannot unpack {:?} into {} loop variables",
+                        item,
+                        targets.len()
+                    )
+                    .into())
+                }
+            }
+        }
+        // The loop filter sees the item but not the loop counters, which only count kept items
+        if !filtering {
+            self.assign("loop", loop_context(index, length));
+        }
+        Ok(true)
+    }
+
+    /// Drops scopes and `autoescape` overrides above the given depths.
+    fn unwind(&mut self, scope_depth: usize, escape_depth: usize) {
+        self.frame_mut().scopes.truncate(scope_depth);
+        if self.escape_stack.len() > escape_depth {
+            self.auto_escape = self.escape_stack[escape_depth];
+            self.escape_stack.truncate(escape_depth);
+        }
+    }
+
+    fn call_by_name(
+        &mut self,
+        name: &str,
+        args: Vec<Value>,
+        kwargs: HashMap<String, Value>,
+        caller: &Option<Rc<Program>>,
+    ) -> Result<Value, Error> {
+        let macros = Rc::clone(&self.frame().macros);
+        if let Some(macro_def) = macros.get_macro(name) {
+            return self.call_macro(macros, &macro_def, args, kwargs, caller);
+        }
+        if let (Some(call_block), "caller") = (self.frame().caller.clone(), name) {
+            if !args.is_empty() || !kwargs.is_empty() {
+                return Err("caller() takes no arguments".to_string().into());
+            }
+            let call_site = &self.frames[call_block.frame];
+            let mut frame = Frame::new(Some(call_block.frame), Rc::clone(&call_site.macros));
+            frame.caller = call_site.caller.clone();
+            self.push_frame(frame)?;
+            let rendered = self.capture(&call_block.program);
+            self.frames.pop();
+            return Ok(self.markup(rendered?));
+        }
+        if caller.is_some() {
+            return Err(format!("{} is not a macro, so it can't be used with 'call'", name).into());
+        }
+
+        let args = self.args(args, kwargs);
+        match self.env.call_function(name, &args) {
+            Err(err) if *err.kind() == ErrorKind::UnknownFunction => {}
+            result => {
+                let value = result.map_err(|err| over_budget(&args, err))?;
+                self.sandboxed(|sandbox| sandbox.check_size(&value))?;
+                return Ok(value);
+            }
+        }
+        match name {
+            "_" | "gettext" => self.gettext(&args.positional, args.keyword),
+            "ngettext" => self.ngettext(&args.positional, args.keyword),
+            _ => Err(Error::new(
+                ErrorKind::UnknownFunction,
+                format!("No macro or function named {}", name),
+            )),
+        }
+    }
+
+    /// `_(message, **variables)`, also called `gettext`.
+    fn gettext(&self, args: &[Value], variables: HashMap<String, Value>) -> Result<Value, Error> {
+        let [message] = args else {
+            return Err("gettext() takes exactly one message".to_string().into());
+        };
+        let message = message
+            .as_str()
+            .ok_or_else(|| "gettext() needs a string message".to_string())?;
+        self.format_message(&self.translate(message, None), &variables)
+    }
+
+    /// `ngettext(singular, plural, n, **variables)`, where `num` is `n` unless given.
+    fn ngettext(
+        &self,
+        args: &[Value],
+        mut variables: HashMap<String, Value>,
+    ) -> Result<Value, Error> {
+        let [singular, plural, n] = args else {
+            return Err(
+                "ngettext() takes a singular and a plural message and a count"
+                    .to_string()
+                    .into(),
+            );
+        };
+        let (Some(singular), Some(plural)) = (singular.as_str(), plural.as_str()) else {
+            return Err("ngettext() needs string messages".to_string().into());
+        };
+        let n = plural_count(n)?;
+        variables
+            .entry("num".to_string())
+            .or_insert(Value::Integer(n));
+        self.format_message(&self.translate(singular, Some((plural, n))), &variables)
+    }
+
+    /// The translation of a message, or the message itself when there is none. `plural`
+    /// is the plural message and the count that picks the form.
+    fn translate(&self, singular: &str, plural: Option<(&str, i64)>) -> String {
+        let translation = match (self.translations, plural) {
+            (Some(translations), Some((plural, n))) => translations.ngettext(singular, plural, n),
+            (Some(translations), None) => translations.gettext(singular),
+            (None, _) => None,
+        };
+        let untranslated = match plural {
+            Some((plural, n)) if n != 1 => plural,
+            _ => singular,
+        };
+        translation.unwrap_or(untranslated).to_string()
+    }
+
+    /// Fills the variables into a translated message. Translations are trusted like
+    /// template text and the variables are escaped, so the result is markup.
+    fn format_message(
+        &self,
+        message: &str,
+        variables: &HashMap<String, Value>,
+    ) -> Result<Value, Error> {
+        let formatted = interpolate(message, |name| {
+            variables.get(name).map(|value| self.escape(value))
+        })?;
+        Ok(self.markup(formatted))
+    }
+
+    fn call_attribute(
+        &mut self,
+        name: &str,
+        attr: &str,
+        args: Vec<Value>,
+        kwargs: HashMap<String, Value>,
+        caller: &Option<Rc<Program>>,
+    ) -> Result<Value, Error> {
+        if let Some(macros) = self.lookup_import(name) {
+            let macro_def = macros.get_macro(attr).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::UnknownMacro,
+                    format!("Macro {} not found in '{}'", attr, name),
+                )
+            })?;
+            return self.call_macro(macros, &macro_def, args, kwargs, caller);
+        }
+
+        // `loop.cycle(a, b, ...)` picks the argument for the current iteration
+        if name == "loop" && attr == "cycle" && caller.is_none() && kwargs.is_empty() {
+            if args.is_empty() {
+                return Err("loop.cycle() needs at least one value".to_string().into());
+            }
+            let Some(Value::Integer(index)) = self
+                .lookup("loop")
+                .and_then(|value| value.get_attr("index0").ok())
+            else {
+                return Err("loop.cycle() can only be used inside a loop"
+                    .to_string()
+                    .into());
+            };
+            return Ok(args[index as usize % args.len()].clone());
+        }
+
+        self.sandboxed(|sandbox| sandbox.check_attribute(attr))?;
+        match self.lookup(name) {
+            Some(receiver) => Ok(call_method(receiver, attr, args, kwargs, caller)?),
+            None => self.undefined_attr(attr),
+        }
+    }
+
+    /// Renders a macro body in a new frame that only sees the globals, the arguments and
+    /// the macros of the template that defined it.
+    fn call_macro(
+        &mut self,
+        macros: Rc<MacroRegistry>,
+        macro_def: &Macro,
+        args: Vec<Value>,
+        kwargs: HashMap<String, Value>,
+        caller: &Option<Rc<Program>>,
+    ) -> Result<Value, Error> {
+        let mut frame = Frame::new(None, macros);
+        for (param, value) in macro_def.params.iter().zip(macro_def.bind(args, kwargs)?) {
+            match value {
+                Some(value) => {
+                    frame.scopes[0].insert(param.name.clone(), value);
+                }
+                // The body's prelude evaluates defaults
+                None if param.default.is_some() => {}
+                None => {
+                    return Err(format!(
+                        "Macro {} is missing argument '{}'",
+                        macro_def.name, param.name
+                    )
+                    .into())
+                }
+            }
+        }
+        frame.caller = caller.as_ref().map(|program| Caller {
+            program: Rc::clone(program),
+            frame: self.frames.len() - 1,
+        });
+
+        self.push_frame(frame)?;
+        let rendered = self.capture(&macro_def.program);
+        self.frames.pop();
+        Ok(self.markup(rendered?))
+    }
+
+    fn render_block(&mut self, name: &str, level: usize, block: &Program) -> Result<(), Error> {
+        self.block_stack.push((name.to_string(), level));
+        self.frame_mut().scopes.push(HashMap::new());
+        let result = self.run(block);
+        self.frame_mut().scopes.pop();
+        self.block_stack.pop();
+        result.map(|_| ())
+    }
+
+    /// Included templates see the current variables but keep their own blocks.
+    fn include(&mut self, name: &str, template: Rc<Program>) -> Result<(), Error> {
+        let frame = Frame::new(Some(self.frames.len() - 1), Rc::clone(&self.frame().macros));
+        self.push_frame(frame)?;
+        let blocks = mem::take(&mut self.blocks);
+        let block_stack = mem::take(&mut self.block_stack);
+        let escape_stack = mem::take(&mut self.escape_stack);
+        let auto_escape = mem::replace(&mut self.auto_escape, self.env.auto_escape_for(name));
+
+        let result = self.render_template(name, template);
+        self.frames.pop();
+
+        self.blocks = blocks;
+        self.block_stack = block_stack;
+        self.escape_stack = escape_stack;
+        self.auto_escape = auto_escape;
+        result
+    }
+
+    fn capture(&mut self, program: &Program) -> Result<String, Error> {
+        self.output.push(String::new());
+        let result = self.run(program);
+        let captured = self.output.pop().unwrap_or_default();
+        result.map(|_| captured)
+    }
+
+    fn write(&mut self, text: &str) -> Result<(), Error> {
+        if self.sandbox.is_some() {
+            // Captured output counts too, as it is held until it is emitted
+            let buffered: usize = self.output.iter().map(String::len).sum();
+            let total = self.flushed + buffered + text.len();
+            self.sandboxed(|sandbox| sandbox.check_output(total))?;
+        }
+        if let Some(output) = self.output.last_mut() {
+            output.push_str(text);
+        }
+        // Only top-level output is final; captures may still be filtered or discarded
+        if let [output] = self.output.as_mut_slice() {
+            if output.len() >= self.chunk_size {
+                let chunk = mem::take(output);
+                self.flushed += chunk.len();
+                (self.sink)(chunk)?;
             }
         }
-        Ok(output)
+        Ok(())
+    }
+
+    /// Enters a macro, call block or included template, which is where templates recurse.
+    fn push_frame(&mut self, frame: Frame) -> Result<(), Error> {
+        let (depth, started) = (self.frames.len(), self.started);
+        self.sandboxed(|sandbox| sandbox.check_recursion(depth))?;
+        self.sandboxed(|sandbox| sandbox.check_time(started))?;
+        self.frames.push(frame);
+        Ok(())
     }
 
-    fn evaluate_variable(&self, var_name: &str) -> Result<Value, String> {
-        self.variables
-            .get(var_name)
-            .cloned()
-            .ok_or_else(|| format!("Variable {} not found", var_name))
+    /// Arguments for a filter or function, with the sandbox's size budget if there is one.
+    fn args(&self, positional: Vec<Value>, keyword: HashMap<String, Value>) -> Args {
+        let mut args = Args::new(positional, keyword);
+        args.budget = self.sandbox.map(|sandbox| Rc::new(sandbox.size_budget()));
+        args
     }
 
-    fn apply_filters(
+    /// Runs a check when rendering in a sandbox.
+    fn sandboxed(
         &self,
-        value: Value,
-        filters: &[(String, Vec<Value>)],
-    ) -> Result<String, String> {
-        let mut result = value;
-        for (filter_name, _args) in filters {
-            if let Err(e) = self.filters.apply_filter(&filter_name, &result) {
-                return Err(e);
+        check: impl FnOnce(&Sandbox) -> Result<(), SandboxError>,
+    ) -> Result<(), Error> {
+        let Some(sandbox) = self.sandbox else {
+            return Ok(());
+        };
+        Ok(check(sandbox)?)
+    }
+
+    fn frame(&self) -> &Frame {
+        &self.frames[self.frames.len() - 1]
+    }
+
+    fn frame_mut(&mut self) -> &mut Frame {
+        let last = self.frames.len() - 1;
+        &mut self.frames[last]
+    }
+
+    /// Assigns a variable in the innermost scope.
+    fn assign(&mut self, name: &str, value: Value) {
+        if let Some(scope) = self.frame_mut().scopes.last_mut() {
+            scope.insert(name.to_string(), value);
+        }
+    }
+
+    /// Looks through the visible frames, then the template's top level and the globals.
+    fn lookup(&self, name: &str) -> Option<&Value> {
+        let mut index = Some(self.frames.len() - 1);
+        while let Some(frame) = index.map(|index| &self.frames[index]) {
+            if let Some(value) = frame.scopes.iter().rev().find_map(|scope| scope.get(name)) {
+                return Some(value);
+            }
+            index = frame.parent;
+        }
+        self.frames[0].scopes[0]
+            .get(name)
+            .or_else(|| self.globals.get(name))
+    }
+
+    /// Looks a variable up and follows the attribute and constant key lookups right after
+    /// it through borrows, so that `user.name` clones the name rather than the whole user.
+    /// Advances `pc` past the instructions it followed.
+    fn lookup_path(&self, name: &str, program: &Program, pc: &mut usize) -> Result<Value, Error> {
+        let Some(mut value) = self.lookup(name) else {
+            return Ok(Value::Undefined);
+        };
+        while let Value::Dict(dict) = value {
+            let (key, len) = match &program.instructions[*pc..] {
+                [Instruction::GetAttr(attr), ..] => (attr, 1),
+                [Instruction::LoadConst(Value::String(key)), Instruction::GetItem, ..] => (key, 2),
+                _ => break,
+            };
+            *pc += len;
+            self.sandboxed(|sandbox| sandbox.check_attribute(key))?;
+            match dict.get(key) {
+                Some(field) => value = field,
+                None => return Ok(Value::Undefined),
             }
-            result = self.filters.apply_filter(&filter_name, &result)?;
         }
-        Ok(result.to_string())
+        Ok(value.clone())
     }
 
-    fn is_truthy(&self, value: &Value) -> bool {
+    fn lookup_import(&self, name: &str) -> Option<Rc<MacroRegistry>> {
+        let mut index = Some(self.frames.len() - 1);
+        while let Some(frame) = index.map(|index| &self.frames[index]) {
+            if let Some(macros) = frame.imports.get(name) {
+                return Some(Rc::clone(macros));
+            }
+            index = frame.parent;
+        }
+        self.frames[0].imports.get(name).cloned()
+    }
+
+    /// Missing keys are undefined rather than an error.
+    fn get_attr(&self, value: Value, name: &str) -> Result<Value, Error> {
         match value {
-            Value::Bool(b) => *b,
-            Value::Integer(n) => *n != 0,
-            Value::Float(n) => *n != 0.0,
-            Value::String(s) => !s.is_empty(),
-            Value::List(l) => !l.is_empty(),
-            Value::Dict(o) => !o.is_empty(),
-            Value::None => false,
+            Value::Dict(mut dict) => Ok(dict.remove(name).unwrap_or(Value::Undefined)),
+            Value::Undefined => self.undefined_attr(name),
+            value => Ok(value.get_attr(name)?),
+        }
+    }
+
+    fn get_item(&self, value: Value, key: &Value) -> Result<Value, Error> {
+        match (value, key) {
+            (Value::Dict(mut dict), Value::String(k) | Value::SafeString(k)) => {
+                Ok(dict.remove(k).unwrap_or(Value::Undefined))
+            }
+            (Value::Undefined, key) => self.undefined_attr(&key.to_string()),
+            (value, key) => Ok(value.get_item(key)?),
+        }
+    }
+
+    /// Looking into an undefined value is only allowed under `UndefinedBehavior::Chainable`.
+    fn undefined_attr(&self, name: &str) -> Result<Value, Error> {
+        match self.env.undefined_behavior() {
+            UndefinedBehavior::Chainable => Ok(Value::Undefined),
+            _ => Err(Error::new(
+                ErrorKind::Undefined,
+                format!("Cannot get '{}' of an undefined value", name),
+            )),
+        }
+    }
+
+    /// Rejects undefined values under `UndefinedBehavior::Strict`.
+    fn check_defined(&self, value: &Value, action: fmt::Arguments) -> Result<(), Error> {
+        if matches!(value, Value::Undefined)
+            && self.env.undefined_behavior() == UndefinedBehavior::Strict
+        {
+            return Err(Error::new(
+                ErrorKind::Undefined,
+                format!("Cannot {} an undefined value", action),
+            ));
+        }
+        Ok(())
+    }
+
+    fn binary_op(&self, op: BinaryOp, lhs: Value, rhs: Value) -> Result<Value, Error> {
+        // Check repeated strings before allocating them
+        if let (BinaryOp::Mul, Some(s), Value::Integer(n)) = (op, lhs.as_str(), &rhs) {
+            let len = s.len().saturating_mul((*n).max(0) as usize);
+            self.sandboxed(|sandbox| sandbox.check_output(len))?;
+        }
+        let value = match op {
+            BinaryOp::Eq => Ok(Value::Bool(lhs.loose_eq(&rhs))),
+            BinaryOp::Ne => Ok(Value::Bool(!lhs.loose_eq(&rhs))),
+            BinaryOp::Lt => Ok(Value::Bool(lhs.compare(&rhs)?.is_lt())),
+            BinaryOp::Le => Ok(Value::Bool(lhs.compare(&rhs)?.is_le())),
+            BinaryOp::Gt => Ok(Value::Bool(lhs.compare(&rhs)?.is_gt())),
+            BinaryOp::Ge => Ok(Value::Bool(lhs.compare(&rhs)?.is_ge())),
+            BinaryOp::In => Ok(Value::Bool(rhs.contains(&lhs)?)),
+            BinaryOp::NotIn => Ok(Value::Bool(!rhs.contains(&lhs)?)),
+            BinaryOp::Concat => Ok(self.concat(&lhs, &rhs)),
+            _ => lhs.arithmetic(op, &rhs),
+        }?;
+        self.sandboxed(|sandbox| sandbox.check_size(&value))?;
+        Ok(value)
+    }
+
+    /// Formats a value for output under the current escaping policy.
+    fn escape(&self, value: &Value) -> String {
+        match (self.auto_escape, value) {
+            (AutoEscape::Html, Value::SafeString(s)) => s.clone(),
+            (AutoEscape::Html, value) => escape_html(&value.to_string()),
+            (AutoEscape::None, value) => value.to_string(),
+        }
+    }
+
+    /// Joining safe markup with other values escapes the other values, so the result stays safe.
+    fn concat(&self, lhs: &Value, rhs: &Value) -> Value {
+        let has_markup = matches!(lhs, Value::SafeString(_)) || matches!(rhs, Value::SafeString(_));
+        if self.auto_escape == AutoEscape::Html && has_markup {
+            Value::SafeString(format!("{}{}", self.escape(lhs), self.escape(rhs)))
+        } else {
+            Value::String(format!("{}{}", lhs, rhs))
+        }
+    }
+
+    /// Rendered template output, which must not be escaped a second time.
+    fn markup(&self, rendered: String) -> Value {
+        match self.auto_escape {
+            AutoEscape::Html => Value::SafeString(rendered),
+            AutoEscape::None => Value::String(rendered),
         }
     }
 }
 
-impl Clone for Vm {
-    fn clone(&self) -> Vm {
-        Vm {
-            variables: self.variables.clone(),
-            macros: self.macros.clone(),
-            filters: self.filters.clone(),
+/// The compiler keeps the stack balanced, so an empty stack is not expected here.
+fn pop(stack: &mut Vec<Value>) -> Value {
+    stack.pop().unwrap_or(Value::None)
+}
+
+fn pop_args(
+    stack: &mut Vec<Value>,
+    args: usize,
+    kwargs: &[String],
+) -> (Vec<Value>, HashMap<String, Value>) {
+    let kwargs = kwargs
+        .iter()
+        .cloned()
+        .zip(stack.split_off(stack.len() - kwargs.len()))
+        .collect();
+    let args = stack.split_off(stack.len() - args);
+    (args, kwargs)
+}
+
+/// Reports a filter or function that refused to build a value over the sandbox's budget
+/// as the sandbox error it is.
+fn over_budget(args: &Args, err: Error) -> Error {
+    match args
+        .budget
+        .as_ref()
+        .and_then(|budget| budget.take_exceeded())
+    {
+        Some(violation) => violation.into(),
+        None => err,
+    }
+}
+
+fn call_method(
+    receiver: &Value,
+    method: &str,
+    args: Vec<Value>,
+    kwargs: HashMap<String, Value>,
+    caller: &Option<Rc<Program>>,
+) -> Result<Value, String> {
+    if caller.is_some() {
+        return Err(format!("Method {} cannot be used with 'call'", method));
+    }
+    if !kwargs.is_empty() {
+        return Err(format!("Method {} takes no keyword arguments", method));
+    }
+    receiver.call_method(method, &args)
+}
+
+fn unary_op(op: UnaryOp, value: Value) -> Result<Value, String> {
+    match (op, value) {
+        (UnaryOp::Not, value) => Ok(Value::Bool(!value.is_truthy())),
+        (UnaryOp::Neg, Value::Integer(i)) => i
+            .checked_neg()
+            .map(Value::Integer)
+            .ok_or_else(|| format!("Integer overflow in -{}", i)),
+        (UnaryOp::Neg, Value::Float(f)) => Ok(Value::Float(-f)),
+        (UnaryOp::Pos, value @ (Value::Integer(_) | Value::Float(_))) => Ok(value),
+        (_, value) => Err(format!(
+            "Bad operand type for unary operator: {}",
+            value.type_name()
+        )),
+    }
+}
+
+/// The `loop` variable visible inside a for-loop body.
+fn loop_context(index: usize, length: usize) -> Value {
+    let int = |n: usize| Value::Integer(n as i64);
+    Value::Dict(HashMap::from([
+        ("index".to_string(), int(index + 1)),
+        ("index0".to_string(), int(index)),
+        ("revindex".to_string(), int(length - index)),
+        ("revindex0".to_string(), int(length - index - 1)),
+        ("first".to_string(), Value::Bool(index == 0)),
+        ("last".to_string(), Value::Bool(index + 1 == length)),
+        ("length".to_string(), int(length)),
+    ]))
+}
+
+/// The count that picks a plural form.
+fn plural_count(value: &Value) -> Result<i64, String> {
+    match value {
+        Value::Integer(n) => Ok(*n),
+        Value::Float(n) => Ok(*n as i64),
+        value => Err(format!(
+            "Cannot pick a plural form for a {}, expected a number",
+            value.type_name()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{i18n::Catalog, loader::MemoryLoader};
+
+    /// Renders the first of `templates`, which can extend, include or import the others.
+    fn render(templates: &[(&str, &str)]) -> Result<String, Error> {
+        render_with(templates, |_| {})
+    }
+
+    /// Like `render`, with variables, a sandbox or translations set up by `configure`.
+    fn render_with(
+        templates: &[(&str, &str)],
+        configure: impl FnOnce(&mut Vm<'_>),
+    ) -> Result<String, Error> {
+        let mut loader = MemoryLoader::new();
+        for (name, source) in templates {
+            loader.add_template(name, source);
+        }
+        let mut env = Environment::new();
+        env.set_loader(loader);
+        let mut vm = Vm::new(&env);
+        configure(&mut vm);
+        vm.render_template(templates[0].0)
+    }
+
+    #[test]
+    fn child_blocks_override_the_base_and_call_super() {
+        let base = (
+            "base",
+            "<{% block a %}A{% endblock %}|{% block b %}B{% endblock %}>",
+        );
+        let child = (
+            "child",
+            "{% extends 'base' %}ignored{% block a %}[{{ super() }}]{% endblock %}",
+        );
+        let grandchild = (
+            "grandchild",
+            "{% extends 'child' %}{% block a %}({{ super() }}){% endblock %}\
+             {% block b %}b{% endblock %}",
+        );
+        assert_eq!(render(&[child, base]).unwrap(), "<[A]|B>");
+        assert_eq!(render(&[grandchild, child, base]).unwrap(), "<([A])|b>");
+    }
+
+    #[test]
+    fn context_must_be_a_map_or_struct() {
+        #[derive(Serialize)]
+        struct Context {
+            name: &'static str,
+        }
+        let output = render_with(&[("page", "{{ name }}")], |vm| {
+            vm.set_context(&Context { name: "x" }).unwrap();
+        });
+        assert_eq!(output.unwrap(), "x");
+
+        let env = Environment::new();
+        let mut vm = Vm::new(&env);
+        let err = vm.set_context(&[1, 2]).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidOperation);
+        assert_eq!(
+            err.message(),
+            "The context must be a map or struct, found list"
+        );
+    }
+
+    #[test]
+    fn extends_rejects_cycles_and_missing_parents() {
+        let err = render(&[("a", "{% extends 'b' %}"), ("b", "{% extends 'a' %}")]).unwrap_err();
+        assert_eq!(err.message(), "Template 'a' extends itself");
+
+        let err = render(&[("a", "{% extends 'missing' %}")]).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::TemplateNotFound);
+
+        let err = render(&[("a", "{{ super() }}")]).unwrap_err();
+        assert_eq!(err.message(), "super() can only be used inside a block");
+    }
+
+    #[test]
+    fn blocks_inside_loops_cannot_break_out_of_them() {
+        // The block body is its own program, so these used to hang or be dropped
+        for source in [
+            "{% for x in [1, 2, 3] %}{% block b %}{% break %}{% endblock %}{% endfor %}",
+            "{% for x in [1, 2, 3] %}{% block b %}{% if x == 2 %}{% continue %}{% endif %}\
+             {{ x }}{% endblock %}{% endfor %}",
+        ] {
+            let err = render(&[("page", source)]).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::Syntax, "{}", source);
+        }
+        let source = "{% block b %}{% for x in [1, 2, 3] %}{% if x == 2 %}{% continue %}\
+                      {% endif %}{{ x }}{% endfor %}{% endblock %}";
+        assert_eq!(render(&[("page", source)]).unwrap(), "13");
+    }
+
+    #[test]
+    fn includes_see_the_current_variables() {
+        let page = (
+            "page",
+            "{% for x in [1, 2] %}{% include 'item' %}{% endfor %}\
+             {% include 'missing' ignore missing %}",
+        );
+        let item = ("item", "<{{ x }}>");
+        assert_eq!(render(&[page, item]).unwrap(), "<1><2>");
+
+        let err = render(&[("page", "{% include 'missing' %}")]).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::TemplateNotFound);
+    }
+
+    fn render_source(source: &str) -> String {
+        render(&[("page", source)]).unwrap()
+    }
+
+    #[test]
+    fn loops_expose_their_counters() {
+        assert_eq!(
+            render_source(
+                "{% for x in 'abc' %}{{ loop.index }}{{ loop.index0 }}{{ loop.revindex }}\
+                 {{ loop.revindex0 }}{{ x }}{{ loop.length }}\
+                 {% if loop.first %}F{% endif %}{% if loop.last %}L{% endif %} {% endfor %}"
+            ),
+            "1032a3F 2121b3 3210c3L "
+        );
+        assert_eq!(
+            render_source("{% for x in range(5) %}{{ loop.cycle('a', 'b', 'c') }}{% endfor %}"),
+            "abcab"
+        );
+        // The counters belong to the innermost loop and are gone after it
+        assert_eq!(
+            render_source(
+                "{% for a in [1, 2] %}{% for b in [1, 2, 3] %}{% endfor %}\
+                 {{ loop.length }}{% endfor %}{{ loop is defined }}"
+            ),
+            "22false"
+        );
+
+        let err = render(&[("page", "{{ loop.cycle('a') }}")]).unwrap_err();
+        assert_eq!(err.message(), "loop.cycle() can only be used inside a loop");
+        let err =
+            render(&[("page", "{% for x in [1] %}{{ loop.cycle() }}{% endfor %}")]).unwrap_err();
+        assert_eq!(err.message(), "loop.cycle() needs at least one value");
+    }
+
+    #[test]
+    fn loops_unpack_items_into_several_variables() {
+        assert_eq!(
+            render_source("{% for a, b in [[1, 2], [3, 4]] %}{{ a }}{{ b }};{% endfor %}"),
+            "12;34;"
+        );
+        assert_eq!(
+            render_source(
+                "{% for k, v in {'a': 1, 'b': 2}|dictsort %}{{ k }}={{ v }} {% endfor %}"
+            ),
+            "a=1 b=2 "
+        );
+        let err = render(&[("page", "{% for a, b in [[1, 2, 3]] %}{% endfor %}")]).unwrap_err();
+        assert_eq!(
+            err.message(),
+            "Cannot unpack [1, 2, 3] into 2 loop variables"
+        );
+    }
+
+    #[test]
+    fn filtered_loops_count_only_the_kept_items() {
+        assert_eq!(
+            render_source(
+                "{% for x in range(6) if x is odd %}\
+                 {{ loop.index }}/{{ loop.length }}:{{ x }} {% endfor %}"
+            ),
+            "1/3:1 2/3:3 3/3:5 "
+        );
+        // The else branch runs when the filter keeps nothing
+        assert_eq!(
+            render_source("{% for x in [2, 4] if x is odd %}{{ x }}{% else %}none{% endfor %}"),
+            "none"
+        );
+    }
+
+    #[test]
+    fn for_else_runs_only_for_empty_collections() {
+        assert_eq!(
+            render_source("{% for x in [] %}{{ x }}{% else %}empty{% endfor %}"),
+            "empty"
+        );
+        assert_eq!(
+            render_source("{% for x in [1] %}{{ x }}{% else %}empty{% endfor %}"),
+            "1"
+        );
+        // Breaking out of a loop that did iterate doesn't count as empty
+        assert_eq!(
+            render_source("{% for x in [1, 2] %}{{ x }}{% break %}{% else %}empty{% endfor %}"),
+            "1"
+        );
+    }
+
+    #[test]
+    fn break_and_continue_leave_the_innermost_loop() {
+        assert_eq!(
+            render_source(
+                "{% for x in range(10) %}{% if x == 3 %}{% break %}{% endif %}{{ x }}{% endfor %}"
+            ),
+            "012"
+        );
+        assert_eq!(
+            render_source(
+                "{% for x in range(5) %}{% if x is even %}{% continue %}{% endif %}{{ x }}\
+                 {% endfor %}"
+            ),
+            "13"
+        );
+        assert_eq!(
+            render_source(
+                "{% for a in [1, 2] %}{% for b in [1, 2, 3] %}{% if b == 2 %}{% break %}\
+                 {% endif %}{{ a }}{{ b }} {% endfor %}{% endfor %}"
+            ),
+            "11 21 "
+        );
+        let err = render(&[("page", "{% if true %}{% continue %}{% endif %}")]).unwrap_err();
+        assert_eq!(err.message(), "'continue' outside of a loop");
+    }
+
+    #[test]
+    fn loop_controls_unwind_with_and_autoescape_bodies() {
+        assert_eq!(
+            render_source(
+                "{% for x in [1, 2, 3] %}{% with y = x * 10 %}\
+                 {% if y == 20 %}{% continue %}{% endif %}{{ y }} \
+                 {% endwith %}{% endfor %}[{{ y }}]"
+            ),
+            "10 30 []"
+        );
+        assert_eq!(
+            render_source(
+                "{% for x in [1, 2, 3] %}{% with y = x %}{% if y == 2 %}{% break %}{% endif %}\
+                 {{ y }}{% endwith %}{% endfor %}[{{ y }}]"
+            ),
+            "1[]"
+        );
+        // Escaping comes back on after breaking out of an `autoescape false` body
+        assert_eq!(
+            render_markup(
+                "{% for x in [1, 2] %}{% autoescape false %}{{ s }}{% break %}\
+                 {% endautoescape %}{% endfor %}|{{ s }}"
+            ),
+            "<a href='x'>&</a>|&lt;a href=&#39;x&#39;&gt;&amp;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn set_and_with_assign_in_the_current_scope() {
+        assert_eq!(
+            render_source("{% set x = 1 %}{% set x = x + 1 %}{{ x }}"),
+            "2"
+        );
+        // Loops and `with` bodies get their own scope
+        assert_eq!(
+            render_source(
+                "{% set x = 1 %}{% for i in [5] %}{% set x = i %}{{ x }}{% endfor %}{{ x }}"
+            ),
+            "51"
+        );
+        assert_eq!(
+            render_source(
+                "{% set x = 1 %}{% with x = 2, y = x %}{{ x }}{{ y }}{% set z = 3 %}\
+                 {% endwith %}{{ x }}[{{ y }}{{ z }}]"
+            ),
+            "211[]"
+        );
+        // A captured body can't leak assignments either
+        assert_eq!(
+            render_source("{% set c %}{% set x = 9 %}<{{ x }}>{% endset %}{{ c }}[{{ x }}]"),
+            "<9>[]"
+        );
+    }
+
+    #[test]
+    fn macros_bind_positional_keyword_and_default_arguments() {
+        let macro_def = "{% macro tag(name, cls='plain', id=cls ~ '-1') %}\
+                         <{{ name }} {{ cls }} {{ id }}>{% endmacro %}";
+        let render_call = |call: &str| render(&[("page", &format!("{}{}", macro_def, call))]);
+        assert_eq!(render_call("{{ tag('a') }}").unwrap(), "<a plain plain-1>");
+        assert_eq!(
+            render_call("{{ tag('a', id='x') }}").unwrap(),
+            "<a plain x>"
+        );
+        assert_eq!(
+            render_call("{{ tag(cls='big', name='b') }}").unwrap(),
+            "<b big big-1>"
+        );
+
+        for (call, message) in [
+            ("{{ tag() }}", "Macro tag is missing argument 'name'"),
+            (
+                "{{ tag('a', 'b', 'c', 'd') }}",
+                "Macro tag expected at most 3 arguments, but got 4",
+            ),
+            (
+                "{{ tag('a', name='b') }}",
+                "Macro tag got multiple values for argument 'name'",
+            ),
+            (
+                "{{ tag('a', size=2) }}",
+                "Macro tag has no parameter 'size'",
+            ),
+        ] {
+            let err = render_call(call).unwrap_err();
+            assert_eq!(err.message(), message, "{}", call);
+        }
+    }
+
+    #[test]
+    fn macros_see_the_top_level_but_not_the_callers_locals() {
+        assert_eq!(
+            render_source(
+                "{% set site = 'S' %}{% macro m() %}{{ site }}[{{ local }}]{% endmacro %}\
+                 {% for local in [1] %}{{ m() }}{% endfor %}"
+            ),
+            "S[]"
+        );
+    }
+
+    #[test]
+    fn call_blocks_render_through_caller() {
+        assert_eq!(
+            render_source(
+                "{% macro box() %}[{{ caller() }}|{{ caller() }}]{% endmacro %}\
+                 {% for x in [1, 2] %}{% call box() %}{{ x }}{% endcall %}{% endfor %}"
+            ),
+            "[1|1][2|2]"
+        );
+        // A nested call block's caller() refers to its own body
+        assert_eq!(
+            render_source(
+                "{% macro outer() %}({{ caller() }}){% endmacro %}\
+                 {% macro inner() %}<{{ caller() }}>{% endmacro %}\
+                 {% call outer() %}{% call inner() %}x{% endcall %}{% endcall %}"
+            ),
+            "(<x>)"
+        );
+
+        for (source, message) in [
+            (
+                "{% macro m() %}{{ caller(1) }}{% endmacro %}{% call m() %}{% endcall %}",
+                "caller() takes no arguments",
+            ),
+            (
+                "{% call range(3) %}{% endcall %}",
+                "range is not a macro, so it can't be used with 'call'",
+            ),
+        ] {
+            let err = render(&[("page", source)]).unwrap_err();
+            assert_eq!(err.message(), message, "{}", source);
+        }
+    }
+
+    #[test]
+    fn imported_macros_are_called_through_their_alias() {
+        let macros = (
+            "macros",
+            "{% macro greet(name) %}>hi {{ name }}{% endmacro %}\
+             {% macro twice(name) %}{{ greet(name) }}{{ greet(name) }}\
+             {% endmacro %}",
+        );
+        let page = (
+            "page",
+            "{% import 'macros' as m %}{{ m.greet('a') }}|{{ m.twice('b') }}",
+        );
+        assert_eq!(render(&[page, macros]).unwrap(), ">hi a|>hi b>hi b");
+
+        let page = ("page", "{% import 'macros' as m %}{{ m.wave('a') }}");
+        let err = render(&[page, macros]).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::UnknownMacro);
+        assert_eq!(err.message(), "Macro wave not found in 'm'");
+
+        let err = render(&[("page", "{% import 'missing' as m %}")]).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::TemplateNotFound);
+    }
+
+    fn render_markup(source: &str) -> String {
+        render_with(&[("page.html", source)], |vm| {
+            vm.set_variable("s", Value::String("<a href='x'>&</a>".to_string()));
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn html_templates_escape_values_but_not_markup() {
+        assert_eq!(
+            render_markup("{{ s }}|{{ s|safe }}|{{ s|e|e }}"),
+            "&lt;a href=&#39;x&#39;&gt;&amp;&lt;/a&gt;|<a href='x'>&</a>|\
+             &lt;a href=&#39;x&#39;&gt;&amp;&lt;/a&gt;"
+        );
+        let text = render_with(&[("page.txt", "{{ s }}")], |vm| {
+            vm.set_variable("s", Value::String("<b>".to_string()));
+        });
+        assert_eq!(text.unwrap(), "<b>");
+    }
+
+    #[test]
+    fn autoescape_blocks_switch_escaping_for_their_body() {
+        assert_eq!(
+            render_markup("{% autoescape false %}{{ s }}{% endautoescape %}{{ '<' }}"),
+            "<a href='x'>&</a>&lt;"
+        );
+        let text = render(&[(
+            "page.txt",
+            "{% autoescape true %}{{ '<' }}{% endautoescape %}<",
+        )]);
+        assert_eq!(text.unwrap(), "&lt;<");
+    }
+
+    #[test]
+    fn rendered_markup_is_not_escaped_twice() {
+        assert_eq!(
+            render_markup(
+                "{% macro b() %}<b>{{ caller() }}</b>{% endmacro %}{% call b() %}&{% endcall %}"
+            ),
+            "<b>&</b>"
+        );
+        assert_eq!(
+            render_markup("{% set x %}<i>{% endset %}{{ x ~ '<' }}|{{ '<br>'|safe * 2 }}"),
+            "<i>&lt;|<br><br>"
+        );
+    }
+
+    #[test]
+    fn attribute_lookups_read_through_variables() {
+        let render = |source: &str| {
+            render_with(&[("page", source)], |vm| {
+                vm.set_context(
+                    &serde_json::json!({"user": {"name": "Ann", "tags": {"a": [1, 2]}}}),
+                )
+                .unwrap();
+            })
+        };
+        assert_eq!(
+            render("{{ user.name }} {{ user['tags'].a[1] }} {{ user.tags['a']|length }}").unwrap(),
+            "Ann 2 2"
+        );
+        assert_eq!(
+            render("{{ user.missing }}|{{ user.missing is defined }}").unwrap(),
+            "|false"
+        );
+        let err = render("{{ user.missing.name }}").unwrap_err();
+        assert_eq!(err.message(), "Cannot get 'name' of an undefined value");
+        assert_eq!(err.column(), Some(1));
+    }
+
+    #[test]
+    fn attribute_lookups_through_variables_are_sandboxed() {
+        let err = render_with(&[("page", "{{ user.name }}{{ user['secret'] }}")], |vm| {
+            vm.set_context(&serde_json::json!({"user": {"name": "Ann", "secret": "x"}}))
+                .unwrap();
+            let mut sandbox = Sandbox::new();
+            sandbox.allow_attribute("name");
+            vm.set_sandbox(sandbox);
+        })
+        .unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::Sandbox(SandboxError::AttributeNotAllowed("secret".to_string()))
+        );
+    }
+
+    fn render_sandboxed(source: &str) -> Result<String, Error> {
+        render_with(&[("page", source)], |vm| {
+            vm.set_variable("s", Value::String("a".repeat(9000)));
+            vm.set_sandbox(Sandbox::new());
+        })
+    }
+
+    #[test]
+    fn sandbox_rejects_values_over_budget_before_building_them() {
+        let output_limit = ErrorKind::Sandbox(SandboxError::OutputLimit(1 << 20));
+        let iteration_limit = ErrorKind::Sandbox(SandboxError::IterationLimit(100_000));
+        for (source, kind) in [
+            // 9000 copies of 9000 bytes would take 81 MB
+            ("{{ s|replace('a', s)|length }}", &output_limit),
+            (
+                "{{ ['aa']|map('replace', 'a', s * 100)|first }}",
+                &output_limit,
+            ),
+            (
+                "{% set nl %}\n{% endset %}{{ (('a' ~ nl) * 2000)|indent(1000) }}",
+                &output_limit,
+            ),
+            ("{{ s * 1000 }}", &output_limit),
+            ("{{ [1]|batch(1000000, 0) }}", &iteration_limit),
+            ("{{ [1]|slice(1000000) }}", &iteration_limit),
+            ("{{ range(1000000)|length }}", &iteration_limit),
+        ] {
+            let Err(err) = render_sandboxed(source) else {
+                panic!("{} should exceed the budget", source);
+            };
+            assert_eq!(err.kind(), kind, "{}", source);
+        }
+        assert_eq!(
+            render_sandboxed("{{ s|replace('a', 'bb', 2)|length }}").unwrap(),
+            "9002"
+        );
+    }
+
+    const GERMAN_PO: &str = r#"
+msgid ""
+msgstr "Plural-Forms: nplurals=2; plural=(n != 1);\n"
+
+msgid "Hello %(name)s!"
+msgstr "Hallo %(name)s!"
+
+msgid "%(num)s apple"
+msgid_plural "%(num)s apples"
+msgstr[0] "%(num)s Apfel"
+msgstr[1] "%(num)s Äpfel"
+"#;
+
+    fn render_translated(source: &str) -> Result<String, Error> {
+        render_with(&[("page.html", source)], |vm| {
+            vm.set_translations(Rc::new(Catalog::from_po(GERMAN_PO).unwrap()));
+            vm.set_variable("name", Value::String("<Ann>".to_string()));
+        })
+    }
+
+    #[test]
+    fn trans_blocks_pick_translations_and_plural_forms() {
+        assert_eq!(
+            render_translated("{% trans %}Hello {{ name }}!{% endtrans %}").unwrap(),
+            "Hallo &lt;Ann&gt;!"
+        );
+        for (count, expected) in [(1, "1 Apfel"), (3, "3 Äpfel")] {
+            let source = format!(
+                "{{% trans num={} %}}{{{{ num }}}} apple{{% pluralize %}}{{{{ num }}}} apples{{% endtrans %}}",
+                count
+            );
+            assert_eq!(render_translated(&source).unwrap(), expected);
         }
+        assert_eq!(
+            render_translated("{% trans %}Goodbye {{ name }}{% endtrans %}").unwrap(),
+            "Goodbye &lt;Ann&gt;"
+        );
+    }
+
+    #[test]
+    fn gettext_functions_escape_their_variables() {
+        assert_eq!(
+            render_translated("{{ _('Hello %(name)s!', name=name) }}|{{ ngettext('%(num)s apple', '%(num)s apples', 2) }}")
+                .unwrap(),
+            "Hallo &lt;Ann&gt;!|2 Äpfel"
+        );
+        let err = render_translated("{{ ngettext('a', 'b', 'many') }}").unwrap_err();
+        assert_eq!(
+            err.message(),
+            "Cannot pick a plural form for a string, expected a number"
+        );
     }
 }
@@ -0,0 +1,9 @@
+-- Secret contents are encrypted with a per-secret data key, which is stored
+-- encrypted ("wrapped") with the master key identified by master_key_id
+alter table secrets
+  add column contents_nonce bytea,
+  add column data_key bytea,
+  add column data_key_nonce bytea,
+  add column master_key_id text,
+  add constraint secrets_encryption_complete
+    check (num_nulls(contents_nonce, data_key, data_key_nonce, master_key_id) in (0, 4));
@@ -0,0 +1,46 @@
+-- Every write to a secret's contents adds a version instead of overwriting them.
+-- latest_version is counted up per secret and never reused, even after old
+-- versions were deleted by the retention policy
+alter table secrets add column latest_version integer not null default 0;
+
+create table secret_versions (
+  secret uuid not null,
+  version integer not null,
+
+  created_at timestamp with time zone not null default now(),
+  token uuid,
+  restored_from integer,
+
+  contents bytea not null,
+  contents_nonce bytea,
+  data_key bytea,
+  data_key_nonce bytea,
+  master_key_id text,
+
+  primary key(secret, version),
+  foreign key(secret) references secrets(uuid) on delete cascade,
+  foreign key(token) references tokens(uuid) on delete set null,
+  constraint secret_versions_encryption_complete
+    check (num_nulls(contents_nonce, data_key, data_key_nonce, master_key_id) in (0, 4))
+);
+
+-- Existing contents become the first version of their secret
+insert into secret_versions
+  (secret, version, created_at, contents, contents_nonce, data_key, data_key_nonce, master_key_id)
+  select uuid, 1, updated_at, contents, contents_nonce, data_key, data_key_nonce, master_key_id
+  from secrets where contents is not null;
+
+alter table secrets disable trigger set_updated_at;
+update secrets set latest_version = 1 where contents is not null;
+alter table secrets enable trigger set_updated_at;
+
+alter table secrets
+  drop constraint secrets_encryption_complete,
+  drop column contents,
+  drop column contents_nonce,
+  drop column data_key,
+  drop column data_key_nonce,
+  drop column master_key_id;
+
+alter type audit_log_action add value 'secret_rollback';
+alter table audit_log add column version integer;
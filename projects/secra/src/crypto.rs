@@ -0,0 +1,252 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use sha2::{Digest, Sha256};
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CryptoError {
+    #[error("master key needs to be {} hex encoded bytes", KEY_LEN)]
+    InvalidMasterKey,
+
+    #[error("could not read master key file: {0}")]
+    MasterKeyFile(#[from] std::io::Error),
+
+    #[error("master key `{0}` is not configured")]
+    UnknownMasterKey(String),
+
+    #[error("encryption failed")]
+    Encryption,
+
+    #[error("decryption failed")]
+    Decryption,
+
+    #[error("contents are stored as plaintext, run `rewrap-keys` to encrypt them")]
+    Plaintext,
+}
+
+/// Key encrypting the per-secret data keys. Identified by a fingerprint of the key, so
+/// the database records which master key wrapped a data key without revealing it.
+pub struct MasterKey {
+    id: String,
+    cipher: Aes256Gcm,
+}
+
+impl MasterKey {
+    pub fn from_hex(hex_key: &str) -> Result<Self, CryptoError> {
+        let key = hex::decode(hex_key.trim()).map_err(|_| CryptoError::InvalidMasterKey)?;
+        if key.len() != KEY_LEN {
+            return Err(CryptoError::InvalidMasterKey);
+        }
+
+        Ok(Self {
+            id: hex::encode(&Sha256::digest(&key)[..8]),
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// Secret contents encrypted with their own data key, and that data key encrypted with
+/// the master key `master_key_id`.
+#[derive(Debug)]
+pub struct SealedContents {
+    pub ciphertext: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub data_key: Vec<u8>,
+    pub data_key_nonce: Vec<u8>,
+    pub master_key_id: String,
+}
+
+/// The current master key, used for everything newly encrypted, and previous master
+/// keys that are still needed to decrypt data keys until they are re-wrapped.
+pub struct Keyring {
+    current: MasterKey,
+    previous: Vec<MasterKey>,
+}
+
+impl Keyring {
+    pub fn new(current: MasterKey, previous: Vec<MasterKey>) -> Self {
+        Self { current, previous }
+    }
+
+    pub fn current_key_id(&self) -> &str {
+        self.current.id()
+    }
+
+    fn master_key(&self, id: &str) -> Result<&MasterKey, CryptoError> {
+        std::iter::once(&self.current)
+            .chain(&self.previous)
+            .find(|key| key.id == id)
+            .ok_or_else(|| CryptoError::UnknownMasterKey(id.to_string()))
+    }
+
+    /// Encrypts `plaintext` with a fresh data key. `aad` binds the ciphertext and the
+    /// wrapped data key to their owner, so neither can be swapped between secrets.
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Result<SealedContents, CryptoError> {
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let (ciphertext, nonce) = encrypt(&Aes256Gcm::new(&data_key), aad, plaintext)?;
+        let (wrapped_key, data_key_nonce) = encrypt(&self.current.cipher, aad, &data_key)?;
+
+        Ok(SealedContents {
+            ciphertext,
+            nonce,
+            data_key: wrapped_key,
+            data_key_nonce,
+            master_key_id: self.current.id.clone(),
+        })
+    }
+
+    pub fn open(&self, aad: &[u8], sealed: &SealedContents) -> Result<Vec<u8>, CryptoError> {
+        let data_key = self.unwrap_data_key(aad, sealed)?;
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key));
+        decrypt(&cipher, aad, &sealed.ciphertext, &sealed.nonce)
+    }
+
+    /// Wraps the data key with the current master key. The contents themselves stay
+    /// untouched, as they are encrypted with the data key.
+    pub fn rewrap(
+        &self,
+        aad: &[u8],
+        sealed: SealedContents,
+    ) -> Result<SealedContents, CryptoError> {
+        let data_key = self.unwrap_data_key(aad, &sealed)?;
+        let (wrapped_key, data_key_nonce) = encrypt(&self.current.cipher, aad, &data_key)?;
+
+        Ok(SealedContents {
+            data_key: wrapped_key,
+            data_key_nonce,
+            master_key_id: self.current.id.clone(),
+            ..sealed
+        })
+    }
+
+    fn unwrap_data_key(&self, aad: &[u8], sealed: &SealedContents) -> Result<Vec<u8>, CryptoError> {
+        let master_key = self.master_key(&sealed.master_key_id)?;
+        let data_key = decrypt(
+            &master_key.cipher,
+            aad,
+            &sealed.data_key,
+            &sealed.data_key_nonce,
+        )?;
+        if data_key.len() != KEY_LEN {
+            return Err(CryptoError::Decryption);
+        }
+        Ok(data_key)
+    }
+}
+
+fn encrypt(cipher: &Aes256Gcm, aad: &[u8], msg: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg, aad })
+        .map_err(|_| CryptoError::Encryption)?;
+    Ok((ciphertext, nonce.to_vec()))
+}
+
+fn decrypt(
+    cipher: &Aes256Gcm,
+    aad: &[u8],
+    msg: &[u8],
+    nonce: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    if nonce.len() != NONCE_LEN {
+        return Err(CryptoError::Decryption);
+    }
+    cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg, aad })
+        .map_err(|_| CryptoError::Decryption)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> MasterKey {
+        MasterKey::from_hex(&hex::encode([byte; KEY_LEN])).unwrap()
+    }
+
+    #[test]
+    fn master_keys_must_be_32_hex_bytes() {
+        assert!(matches!(
+            MasterKey::from_hex("abcd"),
+            Err(CryptoError::InvalidMasterKey)
+        ));
+        assert!(matches!(
+            MasterKey::from_hex(&"zz".repeat(KEY_LEN)),
+            Err(CryptoError::InvalidMasterKey)
+        ));
+        assert_eq!(key(1).id(), key(1).id());
+        assert_ne!(key(1).id(), key(2).id());
+    }
+
+    #[test]
+    fn sealed_contents_open_again() {
+        let keyring = Keyring::new(key(1), Vec::new());
+        let sealed = keyring.seal(b"secret-a", b"hunter2").unwrap();
+        assert_ne!(sealed.ciphertext, b"hunter2");
+        assert_eq!(sealed.master_key_id, keyring.current_key_id());
+        assert_eq!(keyring.open(b"secret-a", &sealed).unwrap(), b"hunter2");
+    }
+
+    #[test]
+    fn contents_only_open_for_their_owner() {
+        let keyring = Keyring::new(key(1), Vec::new());
+        let sealed = keyring.seal(b"secret-a", b"hunter2").unwrap();
+        assert!(matches!(
+            keyring.open(b"secret-b", &sealed),
+            Err(CryptoError::Decryption)
+        ));
+    }
+
+    #[test]
+    fn previous_keys_open_and_rewrap_after_rotation() {
+        let sealed = Keyring::new(key(1), Vec::new())
+            .seal(b"secret-a", b"hunter2")
+            .unwrap();
+
+        let rotated = Keyring::new(key(2), vec![key(1)]);
+        assert_eq!(rotated.open(b"secret-a", &sealed).unwrap(), b"hunter2");
+
+        let rewrapped = rotated.rewrap(b"secret-a", sealed).unwrap();
+        assert_eq!(rewrapped.master_key_id, rotated.current_key_id());
+        let retired = Keyring::new(key(2), Vec::new());
+        assert_eq!(retired.open(b"secret-a", &rewrapped).unwrap(), b"hunter2");
+    }
+
+    #[test]
+    fn unknown_master_keys_are_reported() {
+        let sealed = Keyring::new(key(1), Vec::new())
+            .seal(b"secret-a", b"hunter2")
+            .unwrap();
+        let other = Keyring::new(key(2), Vec::new());
+        assert!(matches!(
+            other.open(b"secret-a", &sealed),
+            Err(CryptoError::UnknownMasterKey(id)) if id == key(1).id()
+        ));
+    }
+
+    #[test]
+    fn tampered_nonces_fail_to_decrypt() {
+        let keyring = Keyring::new(key(1), Vec::new());
+        let mut sealed = keyring.seal(b"secret-a", b"hunter2").unwrap();
+        sealed.nonce.pop();
+        assert!(matches!(
+            keyring.open(b"secret-a", &sealed),
+            Err(CryptoError::Decryption)
+        ));
+
+        let mut sealed = keyring.seal(b"secret-a", b"hunter2").unwrap();
+        sealed.data_key_nonce.push(0);
+        assert!(matches!(
+            keyring.open(b"secret-a", &sealed),
+            Err(CryptoError::Decryption)
+        ));
+    }
+}
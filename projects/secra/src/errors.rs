@@ -5,11 +5,16 @@ use axum::{
 };
 use tracing::error;
 
+use crate::crypto::CryptoError;
+
 #[derive(Debug, thiserror::Error)]
 pub enum ResponseError {
     #[error("internal server error")]
     AxumHttpError(#[from] axum::http::Error),
 
+    #[error("internal server error")]
+    CryptoError(#[from] CryptoError),
+
     #[error("internal server error")]
     DbError(#[from] sqlx::Error),
 
@@ -28,6 +33,9 @@ impl IntoResponse for ResponseError {
         if let ResponseError::DbError(err) = &self {
             error!("unexpected database error: {:?}", err);
         }
+        if let ResponseError::CryptoError(err) = &self {
+            error!("unexpected encryption error: {:?}", err);
+        }
 
         let status_code = match self {
             ResponseError::Unauthorized() | ResponseError::TypedHeaderRejection(_) => {
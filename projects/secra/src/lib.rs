@@ -1,7 +1,9 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use crypto::{CryptoError, Keyring, MasterKey};
 use sqlx::postgres::PgConnectOptions;
-use std::net::SocketAddr;
+use std::{fs, net::SocketAddr, path::PathBuf, sync::Arc};
 
+pub mod crypto;
 pub mod errors;
 pub mod models;
 pub mod routes;
@@ -16,9 +18,73 @@ pub struct Cli {
     /// The Socket Address the server should listen on
     #[clap(long, short, env = "LISTEN_ADDR", default_value = "[::1]:3000")]
     pub listen_addr: SocketAddr,
+
+    /// The master key encrypting the secrets' data keys, as 64 hex characters
+    #[clap(
+        long,
+        env = "MASTER_KEY",
+        hide_env_values = true,
+        required_unless_present = "master_key_file",
+        conflicts_with = "master_key_file"
+    )]
+    pub master_key: Option<String>,
+
+    /// A file containing the master key, as an alternative to `--master-key`
+    #[clap(long, env = "MASTER_KEY_FILE")]
+    pub master_key_file: Option<PathBuf>,
+
+    /// Previous master keys, comma separated. Data keys wrapped with one of these
+    /// can still be read until `rewrap-keys` has wrapped them with the current one
+    #[clap(
+        long,
+        env = "PREVIOUS_MASTER_KEYS",
+        hide_env_values = true,
+        value_delimiter = ','
+    )]
+    pub previous_master_keys: Vec<String>,
+
+    /// How many versions of each secret to keep. Older versions are deleted when a
+    /// new one is written, by default all of them are kept
+    #[clap(long, env = "SECRET_VERSIONS_KEPT", value_parser = clap::value_parser!(i32).range(1..))]
+    pub secret_versions_kept: Option<i32>,
+
+    /// Serve versions stored before encryption at rest was introduced, which are
+    /// plaintext. Only meant for the time until `rewrap-keys` has encrypted them
+    #[clap(long, env = "ALLOW_PLAINTEXT_SECRETS")]
+    pub allow_plaintext_secrets: bool,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Wrap all data keys with the current master key and exit. Also encrypts
+    /// contents stored before encryption at rest was introduced
+    RewrapKeys,
+}
+
+impl Cli {
+    pub fn keyring(&self) -> Result<Keyring, CryptoError> {
+        let current = match (&self.master_key, &self.master_key_file) {
+            (Some(master_key), _) => MasterKey::from_hex(master_key)?,
+            (None, Some(path)) => MasterKey::from_hex(&fs::read_to_string(path)?)?,
+            (None, None) => unreachable!("clap requires a master key or master key file"),
+        };
+        let previous = self
+            .previous_master_keys
+            .iter()
+            .map(|master_key| MasterKey::from_hex(master_key))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Keyring::new(current, previous))
+    }
 }
 
 #[derive(Clone)]
 pub struct ServerState {
     pub db_pool: sqlx::PgPool,
+    pub keyring: Arc<Keyring>,
+    pub secret_versions_kept: Option<i32>,
+    pub allow_plaintext_secrets: bool,
 }
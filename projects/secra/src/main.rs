@@ -1,21 +1,37 @@
 use clap::Parser;
-use secra::{routes::build_router, Cli, ServerState};
+use secra::{models::Secret, routes::build_router, Cli, Command, ServerState};
 use sqlx::{
     postgres::{PgConnectOptions, PgPoolOptions},
     PgPool,
 };
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 use tokio::{net::TcpListener, signal};
 use tracing::info;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    let keyring = Arc::new(cli.keyring()?);
 
     let db_pool = get_db_pool(cli.database_url).await?;
     sqlx::migrate!().run(&db_pool).await?;
 
-    let router = build_router(ServerState { db_pool });
+    if let Some(Command::RewrapKeys) = cli.command {
+        let updated = Secret::rewrap_keys(&db_pool, &keyring).await?;
+        println!(
+            "Wrapped the data keys of {} secret versions with master key `{}`",
+            updated,
+            keyring.current_key_id()
+        );
+        return Ok(());
+    }
+
+    let router = build_router(ServerState {
+        db_pool,
+        keyring,
+        secret_versions_kept: cli.secret_versions_kept,
+        allow_plaintext_secrets: cli.allow_plaintext_secrets,
+    });
     let listener = TcpListener::bind(cli.listen_addr).await?;
 
     info!("Server listening on {}...", cli.listen_addr);
@@ -6,8 +6,9 @@ use uuid::Uuid;
 #[derive(Debug, sqlx::Type)]
 #[sqlx(type_name = "audit_log_action", rename_all = "snake_case")]
 pub enum AuditLogAction {
-    ReadSecret,
-    WriteSecret,
+    SecretRead,
+    SecretWrite,
+    SecretRollback,
 }
 
 #[derive(Debug)]
@@ -20,6 +21,7 @@ impl AuditLogEntry {
         action: AuditLogAction,
         token: Uuid,
         secret: Uuid,
+        version: Option<i32>,
     ) -> Result<PgQueryResult, sqlx::Error> {
         let client_addr = client_addr.to_canonical();
         let ip_net = match client_addr {
@@ -29,11 +31,12 @@ impl AuditLogEntry {
         .expect("IP address provided here should always be valid");
 
         sqlx::query!(
-            r#"insert into audit_log (client_addr, action, token, secret) values ($1, $2, $3, $4)"#,
+            r#"insert into audit_log (client_addr, action, token, secret, version) values ($1, $2, $3, $4, $5)"#,
             ip_net,
             action as AuditLogAction,
             token,
             secret,
+            version,
         )
         .execute(db)
         .await
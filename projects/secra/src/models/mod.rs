@@ -1,7 +1,9 @@
 pub mod audit_log_entry;
 pub mod secret;
 pub mod token;
+pub mod token_permission;
 
 pub use audit_log_entry::AuditLogEntry;
 pub use secret::Secret;
 pub use token::Token;
+pub use token_permission::TokenPermission;
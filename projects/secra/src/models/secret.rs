@@ -3,41 +3,340 @@ use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
 };
-use sqlx::{postgres::PgQueryResult, PgExecutor};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{PgConnection, PgExecutor, PgPool};
 use uuid::Uuid;
 
+use crate::{
+    crypto::{CryptoError, Keyring, SealedContents},
+    errors::ResponseError,
+};
+
+/// Response header carrying the version of a secret that was read or written
+pub const VERSION_HEADER: &str = "x-secret-version";
+
 #[derive(Debug)]
 pub struct Secret {
     pub uuid: Uuid,
     pub file_name: Option<String>,
+    pub version: Option<i32>,
     pub contents: Option<Vec<u8>>,
 }
 
+/// Contents of a secret version as stored. Versions written before encryption at
+/// rest was introduced are still plaintext, without a nonce or data key.
+struct StoredContents {
+    contents: Vec<u8>,
+    contents_nonce: Option<Vec<u8>>,
+    data_key: Option<Vec<u8>>,
+    data_key_nonce: Option<Vec<u8>>,
+    master_key_id: Option<String>,
+}
+
+enum Contents {
+    Sealed(SealedContents),
+    Plaintext(Vec<u8>),
+}
+
+impl StoredContents {
+    /// Only versions with none of the encryption columns set are plaintext. Versions
+    /// with some of them missing can't be decrypted.
+    fn into_contents(self) -> Result<Contents, CryptoError> {
+        match self {
+            StoredContents {
+                contents: ciphertext,
+                contents_nonce: Some(nonce),
+                data_key: Some(data_key),
+                data_key_nonce: Some(data_key_nonce),
+                master_key_id: Some(master_key_id),
+            } => Ok(Contents::Sealed(SealedContents {
+                ciphertext,
+                nonce,
+                data_key,
+                data_key_nonce,
+                master_key_id,
+            })),
+            StoredContents {
+                contents,
+                contents_nonce: None,
+                data_key: None,
+                data_key_nonce: None,
+                master_key_id: None,
+            } => Ok(Contents::Plaintext(contents)),
+            _ => Err(CryptoError::Decryption),
+        }
+    }
+
+    fn decrypt(
+        self,
+        keyring: &Keyring,
+        secret: Uuid,
+        allow_plaintext: bool,
+    ) -> Result<Vec<u8>, CryptoError> {
+        match self.into_contents()? {
+            Contents::Sealed(sealed) => keyring.open(secret.as_bytes(), &sealed),
+            Contents::Plaintext(plaintext) if allow_plaintext => Ok(plaintext),
+            Contents::Plaintext(_) => Err(CryptoError::Plaintext),
+        }
+    }
+}
+
 impl Secret {
-    pub async fn find<'e>(db: impl PgExecutor<'e>, uuid: Uuid) -> Result<Self, sqlx::Error> {
+    /// Reads the given version of a secret, or its latest one. The contents are `None`
+    /// if they were never written. Plaintext contents are only returned if
+    /// `allow_plaintext` is set.
+    pub async fn find<'e>(
+        db: impl PgExecutor<'e>,
+        keyring: &Keyring,
+        uuid: Uuid,
+        version: Option<i32>,
+        allow_plaintext: bool,
+    ) -> Result<Self, ResponseError> {
+        let row = sqlx::query!(
+            r#"select s.uuid, s.file_name, v.version as "version?", v.contents as "contents?",
+            v.contents_nonce, v.data_key, v.data_key_nonce, v.master_key_id
+            from secrets s left join secret_versions v
+            on v.secret = s.uuid and v.version = coalesce($2, s.latest_version)
+            where s.uuid = $1"#,
+            uuid,
+            version
+        )
+        .fetch_optional(db)
+        .await?
+        .ok_or(ResponseError::NotFound())?;
+
+        let contents = match row.contents {
+            Some(contents) => Some(
+                StoredContents {
+                    contents,
+                    contents_nonce: row.contents_nonce,
+                    data_key: row.data_key,
+                    data_key_nonce: row.data_key_nonce,
+                    master_key_id: row.master_key_id,
+                }
+                .decrypt(keyring, uuid, allow_plaintext)?,
+            ),
+            None if version.is_some() => return Err(ResponseError::NotFound()),
+            None => None,
+        };
+
+        Ok(Self {
+            uuid: row.uuid,
+            file_name: row.file_name,
+            version: row.version,
+            contents,
+        })
+    }
+
+    /// Deletes the secret with all its versions and permissions. Returns false if
+    /// there is no such secret.
+    pub async fn delete<'e>(db: impl PgExecutor<'e>, uuid: Uuid) -> Result<bool, sqlx::Error> {
+        Ok(sqlx::query!("delete from secrets where uuid = $1", uuid)
+            .execute(db)
+            .await?
+            .rows_affected()
+            > 0)
+    }
+
+    /// Stores `contents` as a new version of the secret and returns its number
+    pub async fn add_version(
+        conn: &mut PgConnection,
+        keyring: &Keyring,
+        uuid: Uuid,
+        token: Uuid,
+        contents: &[u8],
+        versions_kept: Option<i32>,
+    ) -> Result<i32, ResponseError> {
+        let sealed = keyring.seal(uuid.as_bytes(), contents)?;
+        let version = Self::next_version(&mut *conn, uuid).await?;
+
+        sqlx::query!(
+            r#"insert into secret_versions (secret, version, token, contents, contents_nonce,
+            data_key, data_key_nonce, master_key_id) values ($1, $2, $3, $4, $5, $6, $7, $8)"#,
+            uuid,
+            version,
+            token,
+            sealed.ciphertext,
+            sealed.nonce,
+            sealed.data_key,
+            sealed.data_key_nonce,
+            sealed.master_key_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Self::delete_old_versions(conn, uuid, version, versions_kept).await?;
+        Ok(version)
+    }
+
+    /// Restores the contents of an earlier version as a new version and returns its
+    /// number. The history stays intact, so a rollback can be undone as well.
+    pub async fn rollback(
+        conn: &mut PgConnection,
+        uuid: Uuid,
+        restored_version: i32,
+        token: Uuid,
+        versions_kept: Option<i32>,
+    ) -> Result<i32, ResponseError> {
+        let version = Self::next_version(&mut *conn, uuid).await?;
+
+        let restored = sqlx::query!(
+            r#"insert into secret_versions (secret, version, token, restored_from, contents,
+            contents_nonce, data_key, data_key_nonce, master_key_id)
+            select secret, $2, $3, version, contents, contents_nonce, data_key, data_key_nonce,
+            master_key_id from secret_versions where secret = $1 and version = $4"#,
+            uuid,
+            version,
+            token,
+            restored_version
+        )
+        .execute(&mut *conn)
+        .await?;
+        if restored.rows_affected() == 0 {
+            return Err(ResponseError::NotFound());
+        }
+
+        Self::delete_old_versions(conn, uuid, version, versions_kept).await?;
+        Ok(version)
+    }
+
+    /// Locks the secret until the end of the transaction, so concurrent writes get
+    /// consecutive version numbers
+    async fn next_version(conn: &mut PgConnection, uuid: Uuid) -> Result<i32, ResponseError> {
+        sqlx::query_scalar!(
+            "update secrets set latest_version = latest_version + 1 where uuid = $1 returning latest_version",
+            uuid
+        )
+        .fetch_optional(conn)
+        .await?
+        .ok_or(ResponseError::NotFound())
+    }
+
+    async fn delete_old_versions(
+        conn: &mut PgConnection,
+        uuid: Uuid,
+        latest_version: i32,
+        versions_kept: Option<i32>,
+    ) -> Result<(), sqlx::Error> {
+        let Some(versions_kept) = versions_kept else {
+            return Ok(());
+        };
+
+        sqlx::query!(
+            "delete from secret_versions where secret = $1 and version <= $2",
+            uuid,
+            latest_version - versions_kept
+        )
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Wraps every data key that is not wrapped with the current master key yet, and
+    /// encrypts versions that are still stored as plaintext. Returns the number of
+    /// versions updated.
+    pub async fn rewrap_keys(db: &PgPool, keyring: &Keyring) -> Result<u64, ResponseError> {
+        let stored = sqlx::query!(
+            r#"select secret, version, contents, contents_nonce, data_key, data_key_nonce,
+            master_key_id from secret_versions where master_key_id is distinct from $1"#,
+            keyring.current_key_id()
+        )
+        .fetch_all(db)
+        .await?;
+
+        let mut updated = 0;
+        for row in stored {
+            let contents = StoredContents {
+                contents: row.contents,
+                contents_nonce: row.contents_nonce,
+                data_key: row.data_key,
+                data_key_nonce: row.data_key_nonce,
+                master_key_id: row.master_key_id,
+            };
+            let sealed = match contents.into_contents()? {
+                Contents::Sealed(sealed) => keyring.rewrap(row.secret.as_bytes(), sealed)?,
+                Contents::Plaintext(plaintext) => {
+                    keyring.seal(row.secret.as_bytes(), &plaintext)?
+                }
+            };
+
+            updated += sqlx::query!(
+                r#"update secret_versions set contents = $1, contents_nonce = $2, data_key = $3,
+                data_key_nonce = $4, master_key_id = $5 where secret = $6 and version = $7"#,
+                sealed.ciphertext,
+                sealed.nonce,
+                sealed.data_key,
+                sealed.data_key_nonce,
+                sealed.master_key_id,
+                row.secret,
+                row.version
+            )
+            .execute(db)
+            .await?
+            .rows_affected();
+        }
+
+        Ok(updated)
+    }
+}
+
+/// Everything about a secret except its contents
+#[derive(Debug, Serialize)]
+pub struct SecretMetadata {
+    pub uuid: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub file_name: Option<String>,
+    pub notes: Option<String>,
+    pub latest_version: i32,
+}
+
+impl SecretMetadata {
+    pub async fn create<'e>(
+        db: impl PgExecutor<'e>,
+        file_name: Option<String>,
+        notes: Option<String>,
+    ) -> Result<Self, sqlx::Error> {
         sqlx::query_as!(
             Self,
-            "select uuid, file_name, contents from secrets where uuid = $1",
-            uuid
+            r#"insert into secrets (file_name, notes) values ($1, $2)
+            returning uuid, created_at, updated_at, file_name, notes, latest_version"#,
+            file_name,
+            notes
         )
         .fetch_one(db)
         .await
     }
 
-    pub async fn update_contents<'e>(
-        &mut self,
-        db: impl PgExecutor<'e>,
-        contents: Vec<u8>,
-    ) -> Result<PgQueryResult, sqlx::Error> {
-        self.contents = Some(contents);
+    pub async fn list<'e>(db: impl PgExecutor<'e>) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as!(
+            Self,
+            r#"select uuid, created_at, updated_at, file_name, notes, latest_version
+            from secrets order by created_at"#
+        )
+        .fetch_all(db)
+        .await
+    }
+}
 
+#[derive(Debug, Serialize)]
+pub struct SecretVersion {
+    pub version: i32,
+    pub created_at: DateTime<Utc>,
+    pub token: Option<Uuid>,
+    pub restored_from: Option<i32>,
+}
+
+impl SecretVersion {
+    pub async fn list<'e>(db: impl PgExecutor<'e>, secret: Uuid) -> Result<Vec<Self>, sqlx::Error> {
         sqlx::query_as!(
             Self,
-            "update secrets set contents = $1 where uuid = $2",
-            self.contents,
-            self.uuid
+            r#"select version, created_at, token, restored_from from secret_versions
+            where secret = $1 order by version desc"#,
+            secret
         )
-        .execute(db)
+        .fetch_all(db)
         .await
     }
 }
@@ -53,10 +352,65 @@ impl IntoResponse for Secret {
             None => Response::builder()
                 .status(StatusCode::NO_CONTENT)
                 .body(Body::empty()),
-            Some(contents) => Response::builder()
-                .header("Content-Disposition", dispo_header)
-                .body(Body::from(contents)),
+            Some(contents) => {
+                let mut response = Response::builder().header("Content-Disposition", dispo_header);
+                if let Some(version) = self.version {
+                    response = response.header(VERSION_HEADER, version);
+                }
+                response.body(Body::from(contents))
+            }
         }
         .expect("Response Builder with known setup should not fail")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::MasterKey;
+
+    fn plaintext() -> StoredContents {
+        StoredContents {
+            contents: b"hunter2".to_vec(),
+            contents_nonce: None,
+            data_key: None,
+            data_key_nonce: None,
+            master_key_id: None,
+        }
+    }
+
+    fn keyring() -> Keyring {
+        Keyring::new(MasterKey::from_hex(&"11".repeat(32)).unwrap(), Vec::new())
+    }
+
+    #[test]
+    fn plaintext_contents_need_to_be_allowed() {
+        let secret = Uuid::new_v4();
+        assert_eq!(
+            plaintext().decrypt(&keyring(), secret, true).unwrap(),
+            b"hunter2"
+        );
+        assert!(matches!(
+            plaintext().decrypt(&keyring(), secret, false),
+            Err(CryptoError::Plaintext)
+        ));
+    }
+
+    #[test]
+    fn partly_encrypted_contents_are_never_plaintext() {
+        let keyring = keyring();
+        let secret = Uuid::new_v4();
+        let sealed = keyring.seal(secret.as_bytes(), b"hunter2").unwrap();
+        let stored = StoredContents {
+            contents: sealed.ciphertext,
+            contents_nonce: Some(sealed.nonce),
+            data_key: None,
+            data_key_nonce: Some(sealed.data_key_nonce),
+            master_key_id: Some(sealed.master_key_id),
+        };
+        assert!(matches!(
+            stored.decrypt(&keyring, secret, true),
+            Err(CryptoError::Decryption)
+        ));
+    }
+}
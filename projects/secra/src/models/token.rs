@@ -8,7 +8,7 @@ use axum_extra::{
     TypedHeader,
 };
 use chrono::{DateTime, Utc};
-use sqlx::{postgres::PgQueryResult, Executor, PgExecutor};
+use sqlx::{postgres::PgQueryResult, PgExecutor};
 use tracing::{info, warn};
 use uuid::Uuid;
 
@@ -24,7 +24,7 @@ pub struct Token {
 
 impl Token {
     pub async fn try_query_with_token<'e>(
-        db: impl Executor<'e>,
+        db: impl PgExecutor<'e>,
         token: &str,
     ) -> Result<Option<Self>, sqlx::Error> {
         sqlx::query_as!(
@@ -36,6 +36,38 @@ impl Token {
         .await
     }
 
+    pub async fn create<'e>(
+        db: impl PgExecutor<'e>,
+        superuser: bool,
+        expires_at: Option<DateTime<Utc>>,
+        notes: Option<String>,
+    ) -> Result<Self, sqlx::Error> {
+        sqlx::query_as!(
+            Self,
+            r#"insert into tokens (superuser, expires_at, notes) values ($1, $2, $3)
+            returning uuid, expires_at, token, superuser"#,
+            superuser,
+            expires_at,
+            notes
+        )
+        .fetch_one(db)
+        .await
+    }
+
+    /// Lets the token expire now, unless it already has. The token itself is kept,
+    /// so the audit log and secret versions can still refer to it. Returns false if
+    /// there is no such token.
+    pub async fn revoke<'e>(db: impl PgExecutor<'e>, uuid: Uuid) -> Result<bool, sqlx::Error> {
+        Ok(sqlx::query!(
+            "update tokens set expires_at = least(expires_at, now()) where uuid = $1",
+            uuid
+        )
+        .execute(db)
+        .await?
+        .rows_affected()
+            > 0)
+    }
+
     pub fn is_expired(&self) -> bool {
         match self.expires_at {
             Some(expires_at) => expires_at < Utc::now(),
@@ -45,7 +77,7 @@ impl Token {
 
     pub async fn update_used_timestamp<'e>(
         &mut self,
-        db: impl Executor<'e>,
+        db: impl PgExecutor<'e>,
     ) -> Result<PgQueryResult, sqlx::Error> {
         sqlx::query!(
             "update tokens set used_at = $1 where uuid = $2",
@@ -122,3 +154,26 @@ where
         Ok(Self(token))
     }
 }
+
+/// Like `ExtractValidToken`, but only accepts superuser tokens
+#[derive(Debug)]
+pub struct ExtractSuperuserToken(pub Token);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ExtractSuperuserToken
+where
+    ServerState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ResponseError;
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ExtractValidToken(token) = ExtractValidToken::from_request_parts(parts, state).await?;
+
+        if !token.superuser {
+            warn!("token=`{}` not allowed to use the admin API", token.uuid);
+            return Err(Self::Rejection::Unauthorized());
+        }
+
+        Ok(Self(token))
+    }
+}
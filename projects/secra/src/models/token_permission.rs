@@ -0,0 +1,63 @@
+use serde::Serialize;
+use sqlx::PgExecutor;
+use uuid::Uuid;
+
+use crate::errors::ResponseError;
+
+#[derive(Debug, Serialize)]
+pub struct TokenPermission {
+    pub token: Uuid,
+    pub secret: Uuid,
+    pub can_read: bool,
+    pub can_write: bool,
+    pub notes: Option<String>,
+}
+
+impl TokenPermission {
+    /// Sets what the token may do with the secret, replacing earlier permissions
+    pub async fn grant<'e>(
+        db: impl PgExecutor<'e>,
+        token: Uuid,
+        secret: Uuid,
+        can_read: bool,
+        can_write: bool,
+        notes: Option<String>,
+    ) -> Result<Self, ResponseError> {
+        sqlx::query_as!(
+            Self,
+            r#"insert into token_permissions (token, secret, can_read, can_write, notes)
+            values ($1, $2, $3, $4, $5)
+            on conflict (token, secret) do update
+            set can_read = excluded.can_read, can_write = excluded.can_write, notes = excluded.notes
+            returning token, secret, can_read, can_write, notes"#,
+            token,
+            secret,
+            can_read,
+            can_write,
+            notes
+        )
+        .fetch_one(db)
+        .await
+        .map_err(|err| match err.as_database_error() {
+            Some(db_err) if db_err.is_foreign_key_violation() => ResponseError::NotFound(),
+            _ => err.into(),
+        })
+    }
+
+    /// Returns false if the token had no permissions for the secret
+    pub async fn revoke<'e>(
+        db: impl PgExecutor<'e>,
+        token: Uuid,
+        secret: Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        Ok(sqlx::query!(
+            "delete from token_permissions where token = $1 and secret = $2",
+            token,
+            secret
+        )
+        .execute(db)
+        .await?
+        .rows_affected()
+            > 0)
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{
+    errors::ResponseError,
+    models::{
+        secret::SecretMetadata, token::ExtractSuperuserToken, Secret, Token, TokenPermission,
+    },
+    ServerState,
+};
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateSecret {
+    file_name: Option<String>,
+    notes: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateToken {
+    #[serde(default)]
+    superuser: bool,
+    expires_at: Option<DateTime<Utc>>,
+    notes: Option<String>,
+}
+
+/// The only response that contains the token itself
+#[derive(Debug, Serialize)]
+pub struct CreatedToken {
+    uuid: Uuid,
+    token: String,
+    superuser: bool,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GrantPermission {
+    #[serde(default)]
+    can_read: bool,
+    #[serde(default)]
+    can_write: bool,
+    notes: Option<String>,
+}
+
+#[axum::debug_handler]
+pub async fn list_secrets(
+    State(state): State<ServerState>,
+    ExtractSuperuserToken(_): ExtractSuperuserToken,
+) -> Result<Response, ResponseError> {
+    let secrets = SecretMetadata::list(&state.db_pool).await?;
+    Ok(Json(secrets).into_response())
+}
+
+#[axum::debug_handler]
+pub async fn create_secret(
+    State(state): State<ServerState>,
+    ExtractSuperuserToken(token): ExtractSuperuserToken,
+    Json(body): Json<CreateSecret>,
+) -> Result<Response, ResponseError> {
+    let secret = SecretMetadata::create(&state.db_pool, body.file_name, body.notes).await?;
+    info!("token=`{}` created secret=`{}`", token.uuid, secret.uuid);
+
+    Ok((StatusCode::CREATED, Json(secret)).into_response())
+}
+
+#[axum::debug_handler]
+pub async fn delete_secret(
+    State(state): State<ServerState>,
+    Path(uuid): Path<Uuid>,
+    ExtractSuperuserToken(token): ExtractSuperuserToken,
+) -> Result<Response, ResponseError> {
+    if !Secret::delete(&state.db_pool, uuid).await? {
+        return Err(ResponseError::NotFound());
+    }
+    info!("token=`{}` deleted secret=`{}`", token.uuid, uuid);
+
+    Ok((StatusCode::NO_CONTENT, Body::empty()).into_response())
+}
+
+#[axum::debug_handler]
+pub async fn create_token(
+    State(state): State<ServerState>,
+    ExtractSuperuserToken(token): ExtractSuperuserToken,
+    Json(body): Json<CreateToken>,
+) -> Result<Response, ResponseError> {
+    let created =
+        Token::create(&state.db_pool, body.superuser, body.expires_at, body.notes).await?;
+    info!("token=`{}` created token=`{}`", token.uuid, created.uuid);
+
+    let created = CreatedToken {
+        uuid: created.uuid,
+        token: created.token,
+        superuser: created.superuser,
+        expires_at: created.expires_at,
+    };
+    Ok((StatusCode::CREATED, Json(created)).into_response())
+}
+
+#[axum::debug_handler]
+pub async fn revoke_token(
+    State(state): State<ServerState>,
+    Path(uuid): Path<Uuid>,
+    ExtractSuperuserToken(token): ExtractSuperuserToken,
+) -> Result<Response, ResponseError> {
+    if !Token::revoke(&state.db_pool, uuid).await? {
+        return Err(ResponseError::NotFound());
+    }
+    info!("token=`{}` revoked token=`{}`", token.uuid, uuid);
+
+    Ok((StatusCode::NO_CONTENT, Body::empty()).into_response())
+}
+
+#[axum::debug_handler]
+pub async fn grant_permission(
+    State(state): State<ServerState>,
+    Path((token_uuid, secret_uuid)): Path<(Uuid, Uuid)>,
+    ExtractSuperuserToken(token): ExtractSuperuserToken,
+    Json(body): Json<GrantPermission>,
+) -> Result<Response, ResponseError> {
+    let permission = TokenPermission::grant(
+        &state.db_pool,
+        token_uuid,
+        secret_uuid,
+        body.can_read,
+        body.can_write,
+        body.notes,
+    )
+    .await?;
+    info!(
+        "token=`{}` granted token=`{}` read={} write={} on secret=`{}`",
+        token.uuid, token_uuid, permission.can_read, permission.can_write, secret_uuid
+    );
+
+    Ok(Json(permission).into_response())
+}
+
+#[axum::debug_handler]
+pub async fn revoke_permission(
+    State(state): State<ServerState>,
+    Path((token_uuid, secret_uuid)): Path<(Uuid, Uuid)>,
+    ExtractSuperuserToken(token): ExtractSuperuserToken,
+) -> Result<Response, ResponseError> {
+    if !TokenPermission::revoke(&state.db_pool, token_uuid, secret_uuid).await? {
+        return Err(ResponseError::NotFound());
+    }
+    info!(
+        "token=`{}` revoked permissions of token=`{}` on secret=`{}`",
+        token.uuid, token_uuid, secret_uuid
+    );
+
+    Ok((StatusCode::NO_CONTENT, Body::empty()).into_response())
+}
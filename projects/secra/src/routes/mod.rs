@@ -1,11 +1,16 @@
 use crate::ServerState;
+use admin::{
+    create_secret, create_token, delete_secret, grant_permission, list_secrets, revoke_permission,
+    revoke_token,
+};
 use axum::{
-    routing::{get, post},
+    routing::{delete, get, post, put},
     Router,
 };
 use health::{db_ready, health_check};
-use secret::{get_secret, update_secret};
+use secret::{get_secret, list_secret_versions, rollback_secret, update_secret};
 
+pub mod admin;
 pub mod health;
 pub mod secret;
 
@@ -15,5 +20,18 @@ pub fn build_router(state: ServerState) -> Router {
         .route("/ready", get(db_ready))
         .route("/secret/:uuid", get(get_secret))
         .route("/secret/:uuid/contents", post(update_secret))
+        .route("/secret/:uuid/versions", get(list_secret_versions))
+        .route(
+            "/secret/:uuid/versions/:version/rollback",
+            post(rollback_secret),
+        )
+        .route("/admin/secrets", get(list_secrets).post(create_secret))
+        .route("/admin/secrets/:uuid", delete(delete_secret))
+        .route("/admin/tokens", post(create_token))
+        .route("/admin/tokens/:uuid", delete(revoke_token))
+        .route(
+            "/admin/tokens/:token/permissions/:secret",
+            put(grant_permission).delete(revoke_permission),
+        )
         .with_state(state)
 }
@@ -1,26 +1,39 @@
 use crate::{
     errors::ResponseError,
-    models::{audit_log_entry::AuditLogAction, token::ExtractValidToken, AuditLogEntry, Secret},
+    models::{
+        audit_log_entry::AuditLogAction,
+        secret::{SecretVersion, VERSION_HEADER},
+        token::ExtractValidToken,
+        AuditLogEntry, Secret,
+    },
     ServerState,
 };
 use axum::{
-    body::{Body, Bytes},
-    extract::{ConnectInfo, Path, State},
+    body::Bytes,
+    extract::{ConnectInfo, Path, Query, State},
     http::StatusCode,
     response::{IntoResponse, Response},
+    Json,
 };
+use serde::Deserialize;
 use std::net::SocketAddr;
 use tracing::warn;
 use uuid::Uuid;
 
+#[derive(Debug, Deserialize)]
+pub struct VersionQuery {
+    version: Option<i32>,
+}
+
 #[axum::debug_handler]
 pub async fn get_secret(
     State(state): State<ServerState>,
     Path(uuid): Path<Uuid>,
+    Query(query): Query<VersionQuery>,
     ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
     ExtractValidToken(token): ExtractValidToken,
 ) -> Result<Response, ResponseError> {
-    if !token.can_read_secret(&state.database, uuid).await? {
+    if !token.can_read_secret(&state.db_pool, uuid).await? {
         warn!(
             "token=`{}` not allowed to read secret=`{}`",
             token.uuid, uuid
@@ -28,13 +41,21 @@ pub async fn get_secret(
         return Err(ResponseError::Unauthorized());
     }
 
-    let secret = Secret::find(&state.database, uuid).await?;
+    let secret = Secret::find(
+        &state.db_pool,
+        &state.keyring,
+        uuid,
+        query.version,
+        state.allow_plaintext_secrets,
+    )
+    .await?;
     let _ = AuditLogEntry::log_action(
-        &state.database,
+        &state.db_pool,
         client_addr.ip(),
         AuditLogAction::SecretRead,
         token.uuid,
         secret.uuid,
+        secret.version,
     )
     .await?;
 
@@ -49,7 +70,7 @@ pub async fn update_secret(
     ExtractValidToken(token): ExtractValidToken,
     body: Bytes,
 ) -> Result<Response, ResponseError> {
-    if !token.can_write_secret(&state.database, uuid).await? {
+    if !token.can_write_secret(&state.db_pool, uuid).await? {
         warn!(
             "token=`{}` not allowed to write secret=`{}`",
             token.uuid, uuid
@@ -57,19 +78,90 @@ pub async fn update_secret(
         return Err(ResponseError::Unauthorized());
     }
 
-    let mut secret = Secret::find(&state.database, uuid).await?;
+    let mut tx = state.db_pool.begin().await?;
+    let version = Secret::add_version(
+        &mut tx,
+        &state.keyring,
+        uuid,
+        token.uuid,
+        &body,
+        state.secret_versions_kept,
+    )
+    .await?;
     let _ = AuditLogEntry::log_action(
-        &state.database,
+        &mut *tx,
         client_addr.ip(),
         AuditLogAction::SecretWrite,
         token.uuid,
-        secret.uuid,
+        uuid,
+        Some(version),
     )
     .await?;
+    tx.commit().await?;
+
+    Ok((
+        StatusCode::NO_CONTENT,
+        [(VERSION_HEADER, version.to_string())],
+    )
+        .into_response())
+}
+
+#[axum::debug_handler]
+pub async fn list_secret_versions(
+    State(state): State<ServerState>,
+    Path(uuid): Path<Uuid>,
+    ExtractValidToken(token): ExtractValidToken,
+) -> Result<Response, ResponseError> {
+    if !token.can_read_secret(&state.db_pool, uuid).await? {
+        warn!(
+            "token=`{}` not allowed to list versions of secret=`{}`",
+            token.uuid, uuid
+        );
+        return Err(ResponseError::Unauthorized());
+    }
 
-    let _ = secret
-        .update_contents(&state.database, body.to_vec())
-        .await?;
+    let versions = SecretVersion::list(&state.db_pool, uuid).await?;
+    Ok(Json(versions).into_response())
+}
+
+#[axum::debug_handler]
+pub async fn rollback_secret(
+    State(state): State<ServerState>,
+    Path((uuid, restored_version)): Path<(Uuid, i32)>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    ExtractValidToken(token): ExtractValidToken,
+) -> Result<Response, ResponseError> {
+    if !token.can_write_secret(&state.db_pool, uuid).await? {
+        warn!(
+            "token=`{}` not allowed to roll back secret=`{}`",
+            token.uuid, uuid
+        );
+        return Err(ResponseError::Unauthorized());
+    }
 
-    Ok((StatusCode::NO_CONTENT, Body::empty()).into_response())
+    let mut tx = state.db_pool.begin().await?;
+    let version = Secret::rollback(
+        &mut tx,
+        uuid,
+        restored_version,
+        token.uuid,
+        state.secret_versions_kept,
+    )
+    .await?;
+    let _ = AuditLogEntry::log_action(
+        &mut *tx,
+        client_addr.ip(),
+        AuditLogAction::SecretRollback,
+        token.uuid,
+        uuid,
+        Some(version),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok((
+        StatusCode::NO_CONTENT,
+        [(VERSION_HEADER, version.to_string())],
+    )
+        .into_response())
 }